@@ -0,0 +1,31 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Record the version of the yubihsm crate that's linked, as resolved in
+//! Cargo.lock, for `oks_util::YUBIHSM_CRATE_VERSION`. Cargo.toml only has
+//! the version requirement.
+
+use std::{env, fs, path::Path};
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").expect("set by cargo");
+    let lock = Path::new(&dir).join("Cargo.lock");
+    println!("cargo:rerun-if-changed={}", lock.display());
+
+    // w/o a Cargo.lock next to Cargo.toml, e.g. when built as part of a
+    // workspace, we can't tell
+    let version = fs::read_to_string(&lock)
+        .ok()
+        .and_then(|lock| yubihsm_version(&lock))
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=OKS_YUBIHSM_VERSION={}", version);
+}
+
+// the version of the yubihsm package in the contents of Cargo.lock
+fn yubihsm_version(lock: &str) -> Option<String> {
+    let mut lines = lock.lines().skip_while(|l| *l != r#"name = "yubihsm""#);
+    lines.next()?;
+    let version = lines.next()?.strip_prefix(r#"version = ""#)?;
+    Some(version.strip_suffix('"')?.to_string())
+}
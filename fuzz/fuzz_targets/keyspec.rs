@@ -0,0 +1,20 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Key specs are read from removable media that may be corrupted: parsing
+//! any input must return a `ConfigError` rather than panic. Run w/
+//! `cargo +nightly fuzz run keyspec` from the root of the repo.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use oks_util::KeySpec;
+use std::str::FromStr;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(data) = std::str::from_utf8(data) {
+        let _ = KeySpec::from_str(data);
+        let _ = KeySpec::many_from_str(data);
+    }
+});
@@ -0,0 +1,528 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use aes::{Aes128, Aes192, Aes256};
+use anyhow::{Context, Result};
+use ccm::{
+    aead::{generic_array::GenericArray, Aead, KeyInit},
+    consts::{U13, U16},
+    Ccm,
+};
+use hex::ToHex;
+use log::debug;
+use pem::Pem;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, fs, path::Path, str::FromStr};
+use yubihsm::{object::Id, wrap};
+use zeroize::Zeroizing;
+
+use crate::{
+    config::{self, ConfigError, OksWrapAlgorithm},
+    HsmError, WrapKeyParams,
+};
+
+/// Tag used in the PEM encapsulation boundaries of wrapped objects.
+const PEM_TAG: &str = "YUBIHSM WRAP";
+
+/// The format that objects exported under wrap are written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BackupFormat {
+    /// The wrap message serialized as JSON.
+    #[default]
+    Json,
+    /// The wrap message (nonce followed by ciphertext) as a PEM encoded
+    /// blob with the tag `YUBIHSM WRAP`.
+    Pem,
+}
+
+impl BackupFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            BackupFormat::Json => "json",
+            BackupFormat::Pem => "pem",
+        }
+    }
+
+    /// Get the name of the file that the backup of the object with the
+    /// provided label is written to.
+    pub fn file_name(&self, label: &str) -> String {
+        format!("{}.wrap.{}", label, self.extension())
+    }
+
+    /// Encode a wrap message, and the metadata describing the object
+    /// wrapped if any, in this format. The metadata is a field alongside the
+    /// message in JSON and explanatory text before the PEM block.
+    pub fn encode(
+        &self,
+        message: wrap::Message,
+        metadata: Option<&BackupMetadata>,
+    ) -> Result<String> {
+        match self {
+            BackupFormat::Json => to_json(&JsonBackup {
+                message,
+                metadata: metadata.cloned(),
+            }),
+            BackupFormat::Pem => {
+                let header = metadata.map(BackupMetadata::to_pem_header);
+                Ok(header.unwrap_or_default()
+                    + &pem::encode(&Pem {
+                        tag: PEM_TAG.to_string(),
+                        contents: message.into_vec(),
+                    }))
+            }
+        }
+    }
+}
+
+impl FromStr for BackupFormat {
+    type Err = ConfigError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "json" => Ok(BackupFormat::Json),
+            "pem" => Ok(BackupFormat::Pem),
+            _ => Err(ConfigError::BadBackupFormat),
+        }
+    }
+}
+
+impl fmt::Display for BackupFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+/// Serialize the provided value as pretty printed JSON w/ a trailing newline.
+/// The types we write derive `Serialize` so fields are always written in
+/// declaration order: the same value always produces the same bytes. This
+/// keeps the files we write to removable media diffable across ceremonies.
+fn to_json<T: Serialize>(value: &T) -> Result<String> {
+    let mut json = serde_json::to_string_pretty(value)?;
+    json.push('\n');
+
+    Ok(json)
+}
+
+/// Where and when an object was exported under wrap. This is written
+/// alongside the wrap message for the audit record and to tell apart the
+/// backups from several YubiHSMs. It isn't authenticated by the wrap key.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BackupMetadata {
+    /// Serial number of the YubiHSM the object was exported from.
+    pub serial: String,
+    /// UTC time of the export, see `utc_timestamp`.
+    pub exported: String,
+    pub id: Id,
+    pub object_type: String,
+    pub label: String,
+    /// Version of this tool that exported the object.
+    pub tool_version: String,
+    /// Names of the capabilities of the object, see
+    /// `config::capability_names`. Backups written before these were
+    /// recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capabilities: Option<Vec<String>>,
+}
+
+// the name of each field of the metadata in the PEM header
+const PEM_SERIAL: &str = "Serial";
+const PEM_EXPORTED: &str = "Exported";
+const PEM_ID: &str = "Object-Id";
+const PEM_TYPE: &str = "Object-Type";
+const PEM_LABEL: &str = "Label";
+const PEM_VERSION: &str = "Tool-Version";
+const PEM_CAPABILITIES: &str = "Capabilities";
+
+impl BackupMetadata {
+    /// The metadata as explanatory text before a PEM block, one
+    /// `<name>: <value>` line per field, see RFC 7468 section 5.2. The
+    /// lines end in CRLF like those written by `pem::encode`.
+    fn to_pem_header(&self) -> String {
+        [
+            (PEM_SERIAL, self.serial.clone()),
+            (PEM_EXPORTED, self.exported.clone()),
+            (PEM_ID, self.id.to_string()),
+            (PEM_TYPE, self.object_type.clone()),
+            (PEM_LABEL, self.label.clone()),
+            (PEM_VERSION, self.tool_version.clone()),
+        ]
+        .into_iter()
+        .chain(
+            self.capabilities
+                .as_ref()
+                .map(|caps| (PEM_CAPABILITIES, caps.join(", "))),
+        )
+        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+        .collect()
+    }
+
+    /// The inverse of `to_pem_header`. Text w/o any of the fields is no
+    /// metadata, text w/ only some of them is an error. The capabilities
+    /// are optional, older backups don't have them.
+    fn from_pem_header(header: &str) -> Result<Option<Self>> {
+        let fields: Vec<(&str, &str)> = header
+            .lines()
+            .filter_map(|l| l.split_once(": "))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        let field = |name: &'static str| {
+            fields
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.to_string())
+                .ok_or(HsmError::BadBackupMetadata(name))
+        };
+
+        Ok(Some(BackupMetadata {
+            serial: field(PEM_SERIAL)?,
+            exported: field(PEM_EXPORTED)?,
+            id: field(PEM_ID)?
+                .parse()
+                .map_err(|_| HsmError::BadBackupMetadata(PEM_ID))?,
+            object_type: field(PEM_TYPE)?,
+            label: field(PEM_LABEL)?,
+            tool_version: field(PEM_VERSION)?,
+            capabilities: field(PEM_CAPABILITIES).ok().map(|caps| {
+                caps.split(',').map(|c| c.trim().to_string()).collect()
+            }),
+        }))
+    }
+}
+
+// a wrap message in the JSON `BackupFormat`: the fields of the message w/
+// the metadata alongside them, backups written before the metadata was
+// recorded have none
+#[derive(Deserialize, Serialize)]
+struct JsonBackup {
+    #[serde(flatten)]
+    message: wrap::Message,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<BackupMetadata>,
+}
+
+/// Decode a wrap message, and the metadata written w/ it if any, in either
+/// of the `BackupFormat`s. The format is detected from the data.
+pub fn decode_backup(
+    data: &str,
+) -> Result<(wrap::Message, Option<BackupMetadata>)> {
+    match data.find("-----BEGIN") {
+        Some(begin) => {
+            let pem = pem::parse(&data[begin..])?;
+            if pem.tag != PEM_TAG {
+                return Err(HsmError::BadPemTag(pem.tag).into());
+            }
+            Ok((
+                wrap::Message::from_vec(pem.contents)?,
+                BackupMetadata::from_pem_header(&data[..begin])?,
+            ))
+        }
+        None => {
+            let backup: JsonBackup = serde_json::from_str(data)?;
+            Ok((backup.message, backup.metadata))
+        }
+    }
+}
+
+/// Decode a wrap message written in either of the `BackupFormat`s.
+pub fn decode_wrapped(data: &str) -> Result<wrap::Message> {
+    Ok(decode_backup(data)?.0)
+}
+
+/// Read a wrap message, and the metadata written w/ it if any, from a file
+/// written in either `BackupFormat`.
+pub fn read_backup(
+    path: &Path,
+) -> Result<(wrap::Message, Option<BackupMetadata>)> {
+    let data = fs::read_to_string(path).with_context(|| {
+        format!("Failed to read wrapped object: {}", path.display())
+    })?;
+
+    decode_backup(&data)
+}
+
+/// Read a wrap message from a file written in either `BackupFormat`.
+pub fn read_wrapped(path: &Path) -> Result<wrap::Message> {
+    Ok(read_backup(path)?.0)
+}
+
+/// Read the metadata written w/ a backup w/o importing it. Backups written
+/// before the metadata was recorded have none.
+pub fn read_metadata(path: &Path) -> Result<Option<BackupMetadata>> {
+    Ok(read_backup(path)?.1)
+}
+
+/// Name of the file in the output directory that the wrap key manifest is
+/// written to.
+pub const WRAP_BACKUP_FILE: &str = "wrap-backup.json";
+
+/// Non-secret metadata describing the wrap key created by `initialize`.
+/// This is everything required to put a wrap key reconstructed from the key
+/// shares back into a YubiHSM with the same parameters, and to check that
+/// the reconstructed key is the one we started with.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct WrapBackup {
+    pub id: Id,
+    pub label: String,
+    pub domains: Vec<usize>,
+    pub capabilities: Vec<String>,
+    pub delegated_capabilities: Vec<String>,
+    pub algorithm: OksWrapAlgorithm,
+    /// See `fingerprint`.
+    pub fingerprint: String,
+    pub shares: u8,
+    pub threshold: u8,
+    /// The check digest of each share, see `shares::digest`. Manifests
+    /// written before these were recorded have none.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub share_digests: Vec<String>,
+}
+
+impl WrapBackup {
+    pub fn new(
+        params: &WrapKeyParams,
+        wrap_key: &[u8],
+        shares: u8,
+        threshold: u8,
+    ) -> Result<Self> {
+        Ok(Self {
+            id: params.id,
+            label: params
+                .label
+                .try_as_str()
+                .map_err(|_| HsmError::BadLabel)?
+                .to_string(),
+            domains: config::domain_numbers(params.domains),
+            capabilities: config::capability_names(params.capabilities),
+            delegated_capabilities: config::capability_names(
+                params.delegated_capabilities,
+            ),
+            algorithm: params.algorithm.into(),
+            fingerprint: fingerprint(wrap_key),
+            shares,
+            threshold,
+            share_digests: Vec::new(),
+        })
+    }
+
+    /// Load a wrap key manifest from the provided path.
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path).with_context(|| {
+            format!("Failed to read wrap key manifest: {}", path.display())
+        })?;
+        debug!("wrap key manifest as json: {}", json);
+
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Write the wrap key manifest to the provided path.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        debug!("writing wrap key manifest to: {}", path.display());
+        fs::write(path, to_json(self)?)?;
+
+        Ok(())
+    }
+
+    /// Get the parameters that the wrap key was put into the YubiHSM with.
+    pub fn params(&self) -> Result<WrapKeyParams> {
+        Ok(WrapKeyParams {
+            id: self.id,
+            label: config::check_label(&self.label)
+                .map_err(|_| HsmError::BadWrapLabel(self.label.clone()))?,
+            domains: config::domains_from_numbers(&self.domains)?,
+            capabilities: config::capabilities_from_names(&self.capabilities)?,
+            delegated_capabilities: config::capabilities_from_names(
+                &self.delegated_capabilities,
+            )?,
+            algorithm: self.algorithm.into(),
+        })
+    }
+}
+
+// the AES-CCM parameters used by the YubiHSM for wrapped objects: a 16 byte
+// tag and a 13 byte nonce
+type Aes128Ccm = Ccm<Aes128, U16, U13>;
+type Aes192Ccm = Ccm<Aes192, U16, U13>;
+type Aes256Ccm = Ccm<Aes256, U16, U13>;
+
+/// Decrypt a wrap message w/ the provided wrap key. The YubiHSM won't
+/// decrypt a wrapped object w/o importing it so this is how we check that a
+/// wrap key reconstituted from the key shares opens existing backups. The
+/// plaintext is the object's metadata and secret in the YubiHSM's format,
+/// it's zeroized when dropped.
+pub fn decrypt_wrapped(
+    wrap_key: &[u8],
+    algorithm: wrap::Algorithm,
+    msg: &wrap::Message,
+) -> Option<Zeroizing<Vec<u8>>> {
+    let nonce = GenericArray::from_slice(&msg.nonce.0);
+    let ciphertext = msg.ciphertext.as_slice();
+    match algorithm {
+        wrap::Algorithm::Aes128Ccm => Aes128Ccm::new_from_slice(wrap_key)
+            .ok()?
+            .decrypt(nonce, ciphertext),
+        wrap::Algorithm::Aes192Ccm => Aes192Ccm::new_from_slice(wrap_key)
+            .ok()?
+            .decrypt(nonce, ciphertext),
+        wrap::Algorithm::Aes256Ccm => Aes256Ccm::new_from_slice(wrap_key)
+            .ok()?
+            .decrypt(nonce, ciphertext),
+    }
+    .ok()
+    .map(Zeroizing::new)
+}
+
+/// The inverse of `decrypt_wrapped`, this is how the mock YubiHSM exports
+/// objects under wrap.
+#[cfg(test)]
+pub(crate) fn encrypt_wrapped(
+    wrap_key: &[u8],
+    algorithm: wrap::Algorithm,
+    nonce: wrap::Nonce,
+    plaintext: &[u8],
+) -> Option<wrap::Message> {
+    let n = GenericArray::from_slice(&nonce.0);
+    let ciphertext = match algorithm {
+        wrap::Algorithm::Aes128Ccm => Aes128Ccm::new_from_slice(wrap_key)
+            .ok()?
+            .encrypt(n, plaintext),
+        wrap::Algorithm::Aes192Ccm => Aes192Ccm::new_from_slice(wrap_key)
+            .ok()?
+            .encrypt(n, plaintext),
+        wrap::Algorithm::Aes256Ccm => Aes256Ccm::new_from_slice(wrap_key)
+            .ok()?
+            .encrypt(n, plaintext),
+    }
+    .ok()?;
+
+    Some(wrap::Message { nonce, ciphertext })
+}
+
+/// The fingerprint of a wrap key is the hex encoded SHA-256 digest of the
+/// key. This is used to identify the wrap key without disclosing it.
+pub fn fingerprint(wrap_key: &[u8]) -> String {
+    Sha256::digest(wrap_key).encode_hex()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DEFAULT_WRAP_LABEL;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_wrap_backup_round_trip() -> Result<()> {
+        let params = WrapKeyParams::with_label(DEFAULT_WRAP_LABEL)?;
+        let backup = WrapBackup::new(&params, &[0u8; 32], 5, 3)?;
+
+        let dir = TempDir::new()?;
+        let path = dir.path().join(WRAP_BACKUP_FILE);
+        backup.write(&path)?;
+
+        let loaded = WrapBackup::load(&path)?;
+        assert_eq!(loaded, backup);
+        assert_eq!(loaded.params()?, params);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_format_round_trip() -> Result<()> {
+        let msg = wrap::Message {
+            nonce: wrap::Nonce([1u8; 13]),
+            ciphertext: vec![2u8; 100],
+        };
+
+        for format in [BackupFormat::Json, BackupFormat::Pem] {
+            let encoded = format.encode(msg.clone(), None)?;
+            let decoded = decode_wrapped(&encoded)?;
+            assert_eq!(decoded.nonce.0, msg.nonce.0);
+            assert_eq!(decoded.ciphertext, msg.ciphertext);
+        }
+
+        // the same message is always encoded identically
+        let json = BackupFormat::Json.encode(msg.clone(), None)?;
+        assert_eq!(json, BackupFormat::Json.encode(msg.clone(), None)?);
+        assert!(json.starts_with("{\n  \"nonce\""));
+        assert!(json.ends_with("}\n"));
+
+        let pem = BackupFormat::Pem.encode(msg, None)?;
+        assert!(pem.starts_with("-----BEGIN YUBIHSM WRAP-----"));
+        assert!(decode_wrapped(&pem.replace("YUBIHSM WRAP", "CERT")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_metadata() -> Result<()> {
+        let msg = wrap::Message {
+            nonce: wrap::Nonce([1u8; 13]),
+            ciphertext: vec![2u8; 100],
+        };
+        let metadata = BackupMetadata {
+            serial: "0012345678".to_string(),
+            exported: "20230301T170500Z".to_string(),
+            id: 3,
+            object_type: "asymmetric-key".to_string(),
+            label: "rot-identity-a".to_string(),
+            tool_version: "0.1.0".to_string(),
+            capabilities: Some(vec![
+                "exportable-under-wrap".to_string(),
+                "sign-ecdsa".to_string(),
+            ]),
+        };
+
+        let dir = TempDir::new()?;
+        for format in [BackupFormat::Json, BackupFormat::Pem] {
+            let path = dir.path().join(format.file_name("backup"));
+            let plain = format.encode(msg.clone(), None)?;
+            fs::write(&path, &plain)?;
+            assert_eq!(read_metadata(&path)?, None);
+
+            // the wrap message is the same w/ or w/o the metadata
+            let encoded = format.encode(msg.clone(), Some(&metadata))?;
+            fs::write(&path, &encoded)?;
+            assert_eq!(read_metadata(&path)?, Some(metadata.clone()));
+            let (decoded, _) = read_backup(&path)?;
+            assert_eq!(format.encode(decoded, None)?, plain);
+        }
+
+        let pem = BackupFormat::Pem.encode(msg, Some(&metadata))?;
+        assert!(pem.starts_with("Serial: 0012345678\r\n"));
+        assert!(pem.contains("Tool-Version: 0.1.0\r\n"));
+        assert!(pem.contains(
+            "Capabilities: exportable-under-wrap, sign-ecdsa\r\n-----BEGIN"
+        ));
+        let partial = pem.replace("Label: rot-identity-a\r\n", "");
+        assert!(decode_backup(&partial).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_wrapped() {
+        let key = [7u8; 32];
+        let alg = wrap::Algorithm::Aes256Ccm;
+        let msg = encrypt_wrapped(&key, alg, wrap::Nonce([1u8; 13]), b"secret")
+            .unwrap();
+        assert_eq!(msg.ciphertext.len(), 6 + 16);
+        assert_eq!(
+            decrypt_wrapped(&key, alg, &msg)
+                .as_deref()
+                .map(Vec::as_slice),
+            Some(&b"secret"[..])
+        );
+        assert!(decrypt_wrapped(&[8u8; 32], alg, &msg).is_none());
+        assert!(decrypt_wrapped(&[7u8; 16], alg, &msg).is_none());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        // SHA-256 of the empty string
+        assert_eq!(
+            fingerprint(&[]),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
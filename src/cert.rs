@@ -0,0 +1,732 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result};
+use std::{
+    fmt::{self, Write},
+    fs,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use x509_parser::{
+    certificate::X509Certificate,
+    der_parser::asn1_rs::BitString,
+    extensions::ParsedExtension,
+    oid_registry::{
+        OID_EC_P256, OID_NIST_EC_P384, OID_SIG_ECDSA_WITH_SHA256,
+        OID_SIG_ECDSA_WITH_SHA384,
+    },
+    pem::Pem,
+    verify::verify_signature,
+    x509::AlgorithmIdentifier,
+};
+
+use crate::HsmError;
+
+/// Print a readable description of each certificate in the PEM file at the
+/// provided path, e.g. a CA cert or a chain of them. The certs are parsed
+/// in-process so this works w/o openssl.
+pub fn show_cert(path: &Path) -> Result<()> {
+    let data = fs::read(path)
+        .with_context(|| format!("Failed to read cert: {}", path.display()))?;
+
+    for (i, description) in describe_pem(&data)?.iter().enumerate() {
+        if i > 0 {
+            println!();
+        }
+        print!("{}", description);
+    }
+
+    Ok(())
+}
+
+/// Describe each certificate in the provided PEM data.
+pub fn describe_pem(data: &[u8]) -> Result<Vec<String>> {
+    let mut descriptions = Vec::new();
+    for pem in Pem::iter_from_buffer(data) {
+        let pem = pem.map_err(|_| HsmError::CertParseFail)?;
+        let cert = pem.parse_x509().map_err(|_| HsmError::CertParseFail)?;
+        descriptions.push(describe(&cert)?);
+    }
+    if descriptions.is_empty() {
+        return Err(HsmError::CertParseFail.into());
+    }
+
+    Ok(descriptions)
+}
+
+// describe the fields of the cert that the OKS cares about
+fn describe(cert: &X509Certificate) -> Result<String> {
+    let validity = cert.validity();
+    let mut out = String::new();
+    writeln!(out, "Subject: {}", cert.subject())?;
+    writeln!(out, "Issuer: {}", cert.issuer())?;
+    writeln!(out, "Serial: {}", cert.raw_serial_as_string())?;
+    writeln!(out, "Not Before: {}", validity.not_before)?;
+    writeln!(out, "Not After: {}", validity.not_after)?;
+
+    for ext in cert.extensions() {
+        let critical = if ext.critical { " (critical)" } else { "" };
+        match ext.parsed_extension() {
+            ParsedExtension::BasicConstraints(bc) => writeln!(
+                out,
+                "Basic Constraints{}: CA:{}{}",
+                critical,
+                bc.ca,
+                bc.path_len_constraint
+                    .map_or(String::new(), |len| format!(", pathlen:{}", len))
+            )?,
+            ParsedExtension::KeyUsage(ku) => {
+                writeln!(out, "Key Usage{}: {}", critical, ku)?
+            }
+            ParsedExtension::ExtendedKeyUsage(eku) => {
+                let mut usages: Vec<String> = [
+                    (eku.any, "Any"),
+                    (eku.server_auth, "Server Auth"),
+                    (eku.client_auth, "Client Auth"),
+                    (eku.code_signing, "Code Signing"),
+                    (eku.email_protection, "Email Protection"),
+                    (eku.time_stamping, "Time Stamping"),
+                    (eku.ocsp_signing, "OCSP Signing"),
+                ]
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, name)| name.to_string())
+                .collect();
+                usages.extend(eku.other.iter().map(|oid| oid.to_id_string()));
+                writeln!(
+                    out,
+                    "Extended Key Usage{}: {}",
+                    critical,
+                    usages.join(", ")
+                )?
+            }
+            ParsedExtension::SubjectKeyIdentifier(ski) => {
+                writeln!(out, "Subject Key Identifier: {:x}", ski)?
+            }
+            ParsedExtension::AuthorityKeyIdentifier(aki) => {
+                if let Some(id) = &aki.key_identifier {
+                    writeln!(out, "Authority Key Identifier: {:x}", id)?
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(out)
+}
+
+/// The fields of a CA cert checked by `verify_ca`.
+#[derive(Debug, PartialEq)]
+pub struct CaCertFields {
+    /// The cert names itself as its issuer and its signature verifies w/
+    /// its own key.
+    pub self_signed: bool,
+    /// The CN from the subject, if any.
+    pub common_name: Option<String>,
+    /// The CA flag from the basic constraints, `None` w/o the extension.
+    pub ca: Option<bool>,
+}
+
+/// Get the fields `verify_ca` checks from the first cert in the provided PEM
+/// data. The cert is parsed & its signature verified in-process.
+pub fn ca_cert_fields(data: &[u8]) -> Result<CaCertFields> {
+    let pem = Pem::iter_from_buffer(data)
+        .next()
+        .ok_or(HsmError::CertParseFail)?
+        .map_err(|_| HsmError::CertParseFail)?;
+    let cert = pem.parse_x509().map_err(|_| HsmError::CertParseFail)?;
+    let fields = CaCertFields {
+        self_signed: cert.subject().as_raw() == cert.issuer().as_raw()
+            && cert.verify_signature(None).is_ok(),
+        common_name: cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(String::from),
+        ca: cert
+            .basic_constraints()
+            .map_err(|_| HsmError::CertParseFail)?
+            .map(|bc| bc.value.ca),
+    };
+
+    Ok(fields)
+}
+
+/// The name of the CA cert in each CA directory created by `ca_init`.
+const CA_CERT_FILE: &str = "ca.cert.pem";
+
+/// A CA found by `verify_tree`.
+#[derive(Debug, PartialEq)]
+pub struct TreeCa {
+    /// The directory holding the CA's `ca.cert.pem`.
+    pub dir: PathBuf,
+    pub subject: String,
+    /// The directory of the CA that issued this CA's cert. This is `None`
+    /// for a self signed root, and for a CA whose issuer isn't in the tree
+    /// or whose cert doesn't verify w/ the issuer's key.
+    pub issuer: Option<PathBuf>,
+}
+
+/// A link in a CA tree that doesn't hold up, see `verify_tree`.
+#[derive(Error, Debug, PartialEq)]
+pub enum BrokenLink {
+    #[error("failed to parse CA cert in {0}")]
+    BadCert(PathBuf),
+    #[error(
+        "no CA in the tree has the subject \"{issuer}\" of the issuer of the \
+        CA in {dir}"
+    )]
+    MissingIssuer { dir: PathBuf, issuer: String },
+    #[error(
+        "signature on the CA cert in {dir} doesn't verify w/ the key of the CA \
+        in {issuer}"
+    )]
+    BadSignature { dir: PathBuf, issuer: PathBuf },
+    #[error(
+        "authority key id of the CA cert in {dir} doesn't match the subject \
+        key id of the CA in {issuer}"
+    )]
+    KeyIdMismatch { dir: PathBuf, issuer: PathBuf },
+    #[error("issuer of the CA cert in {dir} isn't a CA: {issuer}")]
+    IssuerNotCa { dir: PathBuf, issuer: PathBuf },
+}
+
+/// The hierarchy of the CAs under a directory and the links between them
+/// that are broken, see `verify_tree`.
+#[derive(Debug, Default, PartialEq)]
+pub struct CaTree {
+    pub cas: Vec<TreeCa>,
+    pub broken: Vec<BrokenLink>,
+}
+
+impl CaTree {
+    // write the CA w/ the provided index and the CAs it issued, indented by
+    // their depth in the tree
+    fn fmt_ca(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        i: usize,
+        depth: usize,
+        seen: &mut Vec<usize>,
+    ) -> fmt::Result {
+        // a pair of CAs that issued each other's certs would never end
+        if seen.contains(&i) {
+            return Ok(());
+        }
+        seen.push(i);
+        let ca = &self.cas[i];
+        writeln!(
+            f,
+            "{:indent$}{} ({})",
+            "",
+            ca.subject,
+            ca.dir.display(),
+            indent = depth * 2
+        )?;
+        for (j, child) in self.cas.iter().enumerate() {
+            if j != i && child.issuer.as_ref() == Some(&ca.dir) {
+                self.fmt_ca(f, j, depth + 1, seen)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for CaTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut seen = Vec::new();
+        for (i, ca) in self.cas.iter().enumerate() {
+            if ca.issuer.is_none() {
+                self.fmt_ca(f, i, 0, &mut seen)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// the key identifier from the subject / authority key identifier extension
+fn key_id<'a>(cert: &'a X509Certificate, authority: bool) -> Option<&'a [u8]> {
+    cert.extensions()
+        .iter()
+        .find_map(|ext| match ext.parsed_extension() {
+            ParsedExtension::SubjectKeyIdentifier(ski) if !authority => {
+                Some(ski.0)
+            }
+            ParsedExtension::AuthorityKeyIdentifier(aki) if authority => {
+                aki.key_identifier.as_ref().map(|id| id.0)
+            }
+            _ => None,
+        })
+}
+
+/// Walk the CA directories in `root`, each holding a `ca.cert.pem` like
+/// those created by `ca_init`, and check that the CA certs link up: the
+/// issuer of each cert is a CA in the tree (or the cert is self signed),
+/// the signature on the cert verifies w/ the issuer's key and the
+/// authority key id matches the issuer's subject key id. A self signed
+/// root doesn't need an authority key id. The certs are parsed and
+/// verified in-process so this works w/o openssl.
+pub fn verify_tree(root: &Path) -> Result<CaTree> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(root).with_context(|| {
+        format!("Failed to read CA tree: {}", root.display())
+    })? {
+        let dir = entry?.path();
+        if dir.join(CA_CERT_FILE).is_file() {
+            dirs.push(dir);
+        }
+    }
+    dirs.sort();
+
+    // the certs borrow from the PEM so all of them are read first
+    let mut tree = CaTree::default();
+    let mut pems = Vec::new();
+    for dir in dirs {
+        let data = fs::read(dir.join(CA_CERT_FILE))?;
+        match Pem::iter_from_buffer(&data).next() {
+            Some(Ok(pem)) => pems.push((dir, pem)),
+            _ => tree.broken.push(BrokenLink::BadCert(dir)),
+        }
+    }
+    let mut certs = Vec::new();
+    for (dir, pem) in &pems {
+        match pem.parse_x509() {
+            Ok(cert) => certs.push((dir, cert)),
+            Err(_) => tree.broken.push(BrokenLink::BadCert(dir.clone())),
+        }
+    }
+
+    for (dir, cert) in &certs {
+        let dir = dir.to_path_buf();
+        let subject = cert.subject().to_string();
+        // several CAs may have the issuer's name, the one w/ the key that
+        // signed the cert is the issuer
+        let candidates: Vec<_> = certs
+            .iter()
+            .filter(|(_, c)| c.subject().as_raw() == cert.issuer().as_raw())
+            .collect();
+        let issuer = candidates
+            .iter()
+            .find(|(_, c)| cert.verify_signature(Some(c.public_key())).is_ok());
+
+        let (issuer_dir, issuer) = match (issuer, candidates.first()) {
+            (Some((issuer_dir, issuer)), _) => {
+                (issuer_dir.to_path_buf(), issuer)
+            }
+            (None, Some((issuer_dir, _))) => {
+                tree.broken.push(BrokenLink::BadSignature {
+                    dir: dir.clone(),
+                    issuer: issuer_dir.to_path_buf(),
+                });
+                tree.cas.push(TreeCa {
+                    dir,
+                    subject,
+                    issuer: None,
+                });
+                continue;
+            }
+            (None, None) => {
+                tree.broken.push(BrokenLink::MissingIssuer {
+                    dir: dir.clone(),
+                    issuer: cert.issuer().to_string(),
+                });
+                tree.cas.push(TreeCa {
+                    dir,
+                    subject,
+                    issuer: None,
+                });
+                continue;
+            }
+        };
+
+        if !issuer.is_ca() {
+            tree.broken.push(BrokenLink::IssuerNotCa {
+                dir: dir.clone(),
+                issuer: issuer_dir.clone(),
+            });
+        }
+        let (aki, ski) = (key_id(cert, true), key_id(issuer, false));
+        let self_signed = issuer_dir == dir;
+        if (aki.is_none() && !self_signed) || (aki.is_some() && aki != ski) {
+            tree.broken.push(BrokenLink::KeyIdMismatch {
+                dir: dir.clone(),
+                issuer: issuer_dir.clone(),
+            });
+        }
+        let issuer = (issuer_dir != dir).then_some(issuer_dir);
+        tree.cas.push(TreeCa {
+            dir,
+            subject,
+            issuer,
+        });
+    }
+
+    Ok(tree)
+}
+
+/// Check a detached signature over `data` w/ the public key from the first
+/// cert in the PEM data `cert`, e.g. the one for the key that signed it w/
+/// `sign_keyspecs`. The signature is DER encoded ECDSA over the SHA-256
+/// digest for a P-256 key or the SHA-384 digest for a P-384 key, the same
+/// as `sign_report` produces. Returns false if the signature doesn't verify.
+pub fn verify_detached(
+    cert: &[u8],
+    data: &[u8],
+    signature: &[u8],
+) -> Result<bool> {
+    let pem = Pem::iter_from_buffer(cert)
+        .next()
+        .ok_or(HsmError::CertParseFail)?
+        .map_err(|_| HsmError::CertParseFail)?;
+    let cert = pem.parse_x509().map_err(|_| HsmError::CertParseFail)?;
+    let public_key = cert.public_key();
+
+    let curve = public_key
+        .algorithm
+        .parameters
+        .as_ref()
+        .and_then(|p| p.as_oid().ok());
+    let algorithm = match curve {
+        Some(curve) if curve == OID_EC_P256 => OID_SIG_ECDSA_WITH_SHA256,
+        Some(curve) if curve == OID_NIST_EC_P384 => OID_SIG_ECDSA_WITH_SHA384,
+        _ => return Err(HsmError::UnsupportedCertKey.into()),
+    };
+
+    Ok(verify_signature(
+        public_key,
+        &AlgorithmIdentifier::new(algorithm, None),
+        &BitString::new(0, signature),
+        data,
+    )
+    .is_ok())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // P-384 cert and the signature made w/ its key over SIGNED_DATA:
+    // `openssl dgst -sha384 -sign key.pem -out data.sig data`
+    pub(crate) const SIGNER_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIICETCCAZigAwIBAgIUWPiggHKrGpT9mJfxYi8oMiE4VmswCgYIKoZIzj0EAwMw
+QDEfMB0GA1UECgwWT3hpZGUgQ29tcHV0ZXIgQ29tcGFueTEdMBsGA1UEAwwUVGVz
+dCBLZXkgU3BlYyBTaWduZXIwHhcNMjYxMDE2MTkxNTQ0WhcNMzYxMDEzMTkxNTQ0
+WjBAMR8wHQYDVQQKDBZPeGlkZSBDb21wdXRlciBDb21wYW55MR0wGwYDVQQDDBRU
+ZXN0IEtleSBTcGVjIFNpZ25lcjB2MBAGByqGSM49AgEGBSuBBAAiA2IABCmoqYsr
+PfKJoDYNAT7D/F+67ZhBJArAe6x44PpAjY9UrJl/iVGfQeGUx4tN+BVhlnMJXTS/
+JoujqKauKNdW4FIYpia3HAaY6Q6f3Wb9jEgrSjhMcv/1uR+TTTddrj0RL6NTMFEw
+HQYDVR0OBBYEFFahR6F4aoFGaV2ZvfNa92JDVqyuMB8GA1UdIwQYMBaAFFahR6F4
+aoFGaV2ZvfNa92JDVqyuMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwMDZwAw
+ZAIwBjHrx/a3EjzaV+lso6XPYKf9uHry0bBAZz30s2Pf5+GXkhKBEGhmcMAxTlKM
+QNxmAjANNfMRWheJQQ2qrdVyjD23+fZRGcygDvprZy4jz4Jwa9C6l/H2pm170qsq
+0NUq3rM=
+-----END CERTIFICATE-----
+";
+    pub(crate) const SIGNED_DATA: &[u8] =
+        br#"{"label":"rot-identity-a","id":3}"#;
+    pub(crate) const SIGNATURE: &str = concat!(
+        "3064023078e28d0d208467539562864c82f95b8aed0ded5377b8fd80898dffdc",
+        "7d79e054efae262b2c01fe9dc8ac664335a06c150230022c7d1ca3b77d6551a3",
+        "4ad448a6ae688c4525521f9d934eb64d5c4b1337a8bed88b44887c56204008a8",
+        "7e40bda3d69e",
+    );
+
+    // self signed P-384 CA cert w/ the extensions we describe
+    const CA_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIICNTCCAbugAwIBAgICEAAwCgYIKoZIzj0EAwIwSDELMAkGA1UEBhMCVVMxHzAd
+BgNVBAoMFk94aWRlIENvbXB1dGVyIENvbXBhbnkxGDAWBgNVBAMMD1Rlc3QgT2Zm
+bGluZSBDQTAeFw0yNjEwMTYxNzA0NTZaFw0zNjEwMTMxNzA0NTZaMEgxCzAJBgNV
+BAYTAlVTMR8wHQYDVQQKDBZPeGlkZSBDb21wdXRlciBDb21wYW55MRgwFgYDVQQD
+DA9UZXN0IE9mZmxpbmUgQ0EwdjAQBgcqhkjOPQIBBgUrgQQAIgNiAATiuxnfTSIF
+MLwaGzASQvq0ZBC2CTAQEvrB4gkqYLwF+1J6JdwdcSwvKFNxljKJOJIwNlZcWc5N
+GnRtMb7Zkx42gnZ5boEKcdxV6yl3giNTUC8a4H06lOjK94wLtu7R2H2jeDB2MA8G
+A1UdEwEB/wQFMAMBAf8wDgYDVR0PAQH/BAQDAgEGMBMGA1UdJQQMMAoGCCsGAQUF
+BwMDMB0GA1UdDgQWBBTjoKD2QXoRP9maCkMSDTlqBadb3DAfBgNVHSMEGDAWgBTj
+oKD2QXoRP9maCkMSDTlqBadb3DAKBggqhkjOPQQDAgNoADBlAjAqL3NUI8RCWGc+
+Ja4TbdaCh8YzQdPzkYDEHDJuUxF9xMbLGl7eum+50glVD82VfqgCMQCAdgBOGI6n
+1gx6SXwyV0La4bGKCs4hVV4IWUb0V+0R2uj1PGVm+XB9pKjAa83sKQA=
+-----END CERTIFICATE-----
+";
+
+    // self signed P-384 root and an intermediate signed by it
+    const ROOT_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIB/zCCAYagAwIBAgICEAAwCgYIKoZIzj0EAwMwODEfMB0GA1UECgwWT3hpZGUg
+Q29tcHV0ZXIgQ29tcGFueTEVMBMGA1UEAwwMVGVzdCBSb290IENBMB4XDTI2MTAx
+NjE4MjgyNVoXDTM2MTAxMzE4MjgyNVowODEfMB0GA1UECgwWT3hpZGUgQ29tcHV0
+ZXIgQ29tcGFueTEVMBMGA1UEAwwMVGVzdCBSb290IENBMHYwEAYHKoZIzj0CAQYF
+K4EEACIDYgAETt0kj4DFIEq+i4pp1uCzAUkIBL6cUbhOLuykuEgZKfB+BBYFSI1o
+gT8a6wYOIn8FRoBc2WtDxWOGkszcF2wwxzzE2tls9akN6fhja7Nehyjx0Sgqj4R/
+EbzZ6FZqGhRAo2MwYTAdBgNVHQ4EFgQU5rh79EJZzJzznHZVjpZocVc0Y7UwHwYD
+VR0jBBgwFoAU5rh79EJZzJzznHZVjpZocVc0Y7UwDwYDVR0TAQH/BAUwAwEB/zAO
+BgNVHQ8BAf8EBAMCAQYwCgYIKoZIzj0EAwMDZwAwZAIwMi2/5uOGcVnCjoHB8NHz
+vPklLaZZ1c4FkXPLHDMErXh7jDNhX7CFvkZ9bNWoKod8AjB0uh4UW/oV0ZFQ9lqR
+sOnBscCDjyrMTuwQzeC8wKw5OS5sPfCHvmGKM9FtfE0s5+U=
+-----END CERTIFICATE-----
+";
+    const INTERMEDIATE_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIICCDCCAY6gAwIBAgICEAEwCgYIKoZIzj0EAwMwODEfMB0GA1UECgwWT3hpZGUg
+Q29tcHV0ZXIgQ29tcGFueTEVMBMGA1UEAwwMVGVzdCBSb290IENBMB4XDTI2MTAx
+NjE4MjgyNVoXDTM2MTAxMzE4MjgyNVowQDEfMB0GA1UECgwWT3hpZGUgQ29tcHV0
+ZXIgQ29tcGFueTEdMBsGA1UEAwwUVGVzdCBJbnRlcm1lZGlhdGUgQ0EwdjAQBgcq
+hkjOPQIBBgUrgQQAIgNiAAReEfCllMqiL5y+GvIr9Q/SXEbgt4cK5TYMNdZqM1q8
+u+mYenfO01ieET+5/imo1xDKUNC/KWCBheZBI7s1aEZbpwh0Mj9FbesXVHm4Wpvj
+dAbJvP5eLJSSrbya+pj+OwKjYzBhMB0GA1UdDgQWBBTbGinSzj+LLrpqZJS+MQK+
++yB8uDAfBgNVHSMEGDAWgBTmuHv0QlnMnPOcdlWOlmhxVzRjtTAPBgNVHRMBAf8E
+BTADAQH/MA4GA1UdDwEB/wQEAwIBBjAKBggqhkjOPQQDAwNoADBlAjEA5HOqc+uJ
+DsBMNk7Vl69YdeEDOmrjJao8Ud/eMnBtSqI+ri3Oim4Uzc/SZprBXvq3AjBvcisr
+qVv5Gzcgr8M/f1zq4r37Dzl58jgCxFhaBxKdFSwU/8nI/HLV5KRP/MTpuV4=
+-----END CERTIFICATE-----
+";
+    // a root w/ the same subject as `ROOT_CERT` and another key
+    const OTHER_ROOT_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIB/zCCAYagAwIBAgICEAAwCgYIKoZIzj0EAwMwODEfMB0GA1UECgwWT3hpZGUg
+Q29tcHV0ZXIgQ29tcGFueTEVMBMGA1UEAwwMVGVzdCBSb290IENBMB4XDTI2MTAx
+NjE4MjgyNVoXDTM2MTAxMzE4MjgyNVowODEfMB0GA1UECgwWT3hpZGUgQ29tcHV0
+ZXIgQ29tcGFueTEVMBMGA1UEAwwMVGVzdCBSb290IENBMHYwEAYHKoZIzj0CAQYF
+K4EEACIDYgAERkD+PFpEt5zKjURTnz0Sv4TGlb/vpZX7Ze0fiEWk+CUiRMlZDY7i
+xD2mMtYtTaVdLyRtFb4CkaA9n8zph1bivIprnSfTrHLTpZEn6kWZ/cHGF89rbliW
+KiaFd1rU6Lamo2MwYTAdBgNVHQ4EFgQUlC/RHdxOimJI6nLFslN8LQfxupgwHwYD
+VR0jBBgwFoAUlC/RHdxOimJI6nLFslN8LQfxupgwDwYDVR0TAQH/BAUwAwEB/zAO
+BgNVHQ8BAf8EBAMCAQYwCgYIKoZIzj0EAwMDZwAwZAIwCeupeadHflSPrJNUl+eS
+sgz0PSFQUMN3GxjzLR07giW8L1+W/HPWo+feXivPRn6yAjAvtNDW5H5viVmXRaPv
+CDj3+p3Fl6hvWz1v12crhLNF4vZbtQkh+2ysFfYSHipkSwk=
+-----END CERTIFICATE-----
+";
+    // a root w/o an authority key id, a CA it issued w/o one, a leaf it
+    // issued and a CA issued by the leaf
+    const BARE_ROOT_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIB6DCCAW+gAwIBAgICEAAwCgYIKoZIzj0EAwIwPTEfMB0GA1UECgwWT3hpZGUg
+Q29tcHV0ZXIgQ29tcGFueTEaMBgGA1UEAwwRVGVzdCBCYXJlIFJvb3QgQ0EwHhcN
+MjYxMDE2MjAwMjU5WhcNMzYxMDEzMjAwMjU5WjA9MR8wHQYDVQQKDBZPeGlkZSBD
+b21wdXRlciBDb21wYW55MRowGAYDVQQDDBFUZXN0IEJhcmUgUm9vdCBDQTB2MBAG
+ByqGSM49AgEGBSuBBAAiA2IABKwpvzuT8heqa6kh9GfR84Aymw1zOzLG3zVendv9
+MF8WybDZe7KcrCrnSAyzRe+gPYq/GCJon5RFPpOdWGxq5o/pqYhEpR5Y5OW4vScy
+z0sTXbQT2qESgLT5kh5xVSPUnaNCMEAwHQYDVR0OBBYEFO/Ytcj+SLl60NJLNsNI
+OIlv6JOfMA8GA1UdEwEB/wQFMAMBAf8wDgYDVR0PAQH/BAQDAgEGMAoGCCqGSM49
+BAMCA2cAMGQCMD9OffHIAQrffyMvrfmzjfLdrSCjL3vPgEFY14RkBn/Y5WezHXNR
+RCA4FN+pcyppQwIwOl/1L15BASPHATcwldXJjeU8bZLBL72nj+GRBFcXuvKBbmyB
+XkPcISait551rnku
+-----END CERTIFICATE-----
+";
+    const UNLINKED_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIB6TCCAW6gAwIBAgICEAEwCgYIKoZIzj0EAwIwPTEfMB0GA1UECgwWT3hpZGUg
+Q29tcHV0ZXIgQ29tcGFueTEaMBgGA1UEAwwRVGVzdCBCYXJlIFJvb3QgQ0EwHhcN
+MjYxMDE2MjAwMzAyWhcNMzYxMDEzMjAwMzAyWjA8MR8wHQYDVQQKDBZPeGlkZSBD
+b21wdXRlciBDb21wYW55MRkwFwYDVQQDDBBUZXN0IFVubGlua2VkIENBMHYwEAYH
+KoZIzj0CAQYFK4EEACIDYgAE5rD8aVGQ6YNfGYf9zPCktxk3t3WjSbmragVL1hC/
+0TyNKi7kkWXFJFVAtX+Iwf0taPmMS4S0KYX5XflhsPrTiiWSfuIH+ok95ReXjK8q
+xRjKlUw/K44p7K5XN8vzLQmeo0IwQDAdBgNVHQ4EFgQUQP/6S0TrjPh3MkT3e5zK
+YBWXaX4wDwYDVR0TAQH/BAUwAwEB/zAOBgNVHQ8BAf8EBAMCAQYwCgYIKoZIzj0E
+AwIDaQAwZgIxAO8E/SGGZu+cVtqaRQ5eMl2L5ULgWHs2maLHujR2BK4bQS7iAUvX
+Ryx43GbDD3KlJwIxAJBERkkYAhotPUm9if316PF+4qXI+Vp3klf0N51//bU7yp+j
+2O2i7JQXeHX/5jgSug==
+-----END CERTIFICATE-----
+";
+    const LEAF_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIIB/zCCAYWgAwIBAgICEAEwCgYIKoZIzj0EAwIwPTEfMB0GA1UECgwWT3hpZGUg
+Q29tcHV0ZXIgQ29tcGFueTEaMBgGA1UEAwwRVGVzdCBCYXJlIFJvb3QgQ0EwHhcN
+MjYxMDE2MjAwMjU5WhcNMzYxMDEzMjAwMjU5WjA1MR8wHQYDVQQKDBZPeGlkZSBD
+b21wdXRlciBDb21wYW55MRIwEAYDVQQDDAlUZXN0IExlYWYwdjAQBgcqhkjOPQIB
+BgUrgQQAIgNiAAS92GGzz7UJhJT5AVbqHtt/gTLbV7g7q1fkLaaQR78s6MhRRfkl
+VdrLFYSsDFuY9leWjRfj7g1CoFxRxxXn1ljhnj/ftGr0R4QPxIKWOQ65CWnN7wZj
+CvyzVCEobEeXJEyjYDBeMB0GA1UdDgQWBBRmwdAhX7e7dqUCFVhNZxbdrMTd7zAf
+BgNVHSMEGDAWgBTv2LXI/ki5etDSSzbDSDiJb+iTnzAMBgNVHRMBAf8EAjAAMA4G
+A1UdDwEB/wQEAwIHgDAKBggqhkjOPQQDAgNoADBlAjEAiBXyheTbZEKkZxsdzhRG
+zx4DuKFXYLb4fIP7kjwtvDPyB0OYs5QGWVYITkt6iiVvAjBTVaxn7gSuqeDDKQY8
+UxqSA2E8S5YvqSamdn3u/Ve7pZZKb9ErjZv47Shr+b8EHf0=
+-----END CERTIFICATE-----
+";
+    const UNDER_LEAF_CERT: &str = "-----BEGIN CERTIFICATE-----
+MIICBDCCAYmgAwIBAgICEAEwCgYIKoZIzj0EAwIwNTEfMB0GA1UECgwWT3hpZGUg
+Q29tcHV0ZXIgQ29tcGFueTESMBAGA1UEAwwJVGVzdCBMZWFmMB4XDTI2MTAxNjIw
+MDI1OVoXDTM2MTAxMzIwMDI1OVowPjEfMB0GA1UECgwWT3hpZGUgQ29tcHV0ZXIg
+Q29tcGFueTEbMBkGA1UEAwwSVGVzdCBVbmRlciBMZWFmIENBMHYwEAYHKoZIzj0C
+AQYFK4EEACIDYgAExa+0m3ss0BJe1o3gSkL3irqP7afRwLMxYxYPA+NOqMCp+9k5
+aFVZSThFdNb93VGbSxh86Ub24Q08SJ+p7n0hLD04LqREpg8qxv02kpLBnYL2ZR8w
+7bHrbDpRF5sPBs+5o2MwYTAdBgNVHQ4EFgQUIZEoREfEzx4u9WPCSyQxr5q7+tgw
+HwYDVR0jBBgwFoAUZsHQIV+3u3alAhVYTWcW3azE3e8wDwYDVR0TAQH/BAUwAwEB
+/zAOBgNVHQ8BAf8EBAMCAQYwCgYIKoZIzj0EAwIDaQAwZgIxANksE5egkoh/WeRT
+q+OF7SKstQs4/tu5vcpMhBWa7qqxns+AADLdMjXT5WoDkRbl8QIxANZTfowkM+QL
+/jdlbfMqqNNaaxsFueAnhjql5ac/QlUTJqjt751fr96iG+65Ql18WA==
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_verify_tree() -> Result<()> {
+        let dir = TempDir::new()?;
+        let (root, intermediate) =
+            (dir.path().join("root"), dir.path().join("intermediate"));
+        for (ca, cert) in
+            [(&root, ROOT_CERT), (&intermediate, INTERMEDIATE_CERT)]
+        {
+            fs::create_dir(ca)?;
+            fs::write(ca.join(CA_CERT_FILE), cert)?;
+        }
+        // not a CA directory
+        fs::create_dir(dir.path().join("publish"))?;
+
+        let tree = verify_tree(dir.path())?;
+        assert_eq!(tree.broken, []);
+        assert_eq!(
+            tree.cas,
+            [
+                TreeCa {
+                    dir: intermediate.clone(),
+                    subject:
+                        "O=Oxide Computer Company, CN=Test Intermediate CA"
+                            .to_string(),
+                    issuer: Some(root.clone()),
+                },
+                TreeCa {
+                    dir: root.clone(),
+                    subject: "O=Oxide Computer Company, CN=Test Root CA"
+                        .to_string(),
+                    issuer: None,
+                },
+            ]
+        );
+        let shown = tree.to_string();
+        let lines: Vec<&str> = shown.lines().collect();
+        assert!(
+            lines[0].starts_with("O=Oxide Computer Company, CN=Test Root CA")
+        );
+        assert!(lines[1].starts_with(
+            "  O=Oxide Computer Company, CN=Test Intermediate CA"
+        ));
+
+        // a root w/ the right name but the wrong key
+        fs::write(root.join(CA_CERT_FILE), OTHER_ROOT_CERT)?;
+        let tree = verify_tree(dir.path())?;
+        assert_eq!(
+            tree.broken,
+            [BrokenLink::BadSignature {
+                dir: intermediate.clone(),
+                issuer: root.clone(),
+            }]
+        );
+        assert_eq!(tree.cas[0].issuer, None);
+
+        fs::remove_dir_all(&root)?;
+        let tree = verify_tree(dir.path())?;
+        assert!(matches!(
+            &tree.broken[..],
+            [BrokenLink::MissingIssuer { dir, issuer }]
+                if *dir == intermediate && issuer.ends_with("CN=Test Root CA")
+        ));
+
+        fs::write(intermediate.join(CA_CERT_FILE), "not a cert")?;
+        let tree = verify_tree(dir.path())?;
+        assert_eq!(tree.broken, [BrokenLink::BadCert(intermediate)]);
+        assert_eq!(tree.cas, []);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_tree_key_ids() -> Result<()> {
+        let dir = TempDir::new()?;
+        let ca = |name: &str, cert: &str| -> Result<PathBuf> {
+            let ca = dir.path().join(name);
+            fs::create_dir(&ca)?;
+            fs::write(ca.join(CA_CERT_FILE), cert)?;
+            Ok(ca)
+        };
+        // a self signed root doesn't need an authority key id
+        let root = ca("root", BARE_ROOT_CERT)?;
+        assert_eq!(verify_tree(dir.path())?.broken, []);
+
+        let unlinked = ca("unlinked", UNLINKED_CERT)?;
+        let leaf = ca("leaf", LEAF_CERT)?;
+        let under_leaf = ca("under-leaf", UNDER_LEAF_CERT)?;
+        let tree = verify_tree(dir.path())?;
+        assert_eq!(
+            tree.broken,
+            [
+                BrokenLink::IssuerNotCa {
+                    dir: under_leaf,
+                    issuer: leaf.clone(),
+                },
+                BrokenLink::KeyIdMismatch {
+                    dir: unlinked.clone(),
+                    issuer: root.clone(),
+                },
+            ]
+        );
+        // the links still stand, they're reported
+        assert!(tree
+            .cas
+            .iter()
+            .any(|ca| ca.dir == unlinked && ca.issuer == Some(root.clone())));
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_pem() -> Result<()> {
+        let descriptions = describe_pem(CA_CERT.as_bytes())?;
+        assert_eq!(descriptions.len(), 1);
+        let description = &descriptions[0];
+
+        for line in [
+            "Subject: C=US, O=Oxide Computer Company, CN=Test Offline CA",
+            "Serial: 10:00",
+            "Basic Constraints (critical): CA:true",
+            "Extended Key Usage: Code Signing",
+            "Subject Key Identifier: \
+                e3:a0:a0:f6:41:7a:11:3f:d9:9a:0a:43:12:0d:39:6a:05:a7:5b:dc",
+        ] {
+            assert!(description.contains(line), "missing: {}", line);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ca_cert_fields() -> Result<()> {
+        assert_eq!(
+            ca_cert_fields(CA_CERT.as_bytes())?,
+            CaCertFields {
+                self_signed: true,
+                common_name: Some("Test Offline CA".to_string()),
+                ca: Some(true),
+            }
+        );
+        // issued by another CA
+        let fields = ca_cert_fields(INTERMEDIATE_CERT.as_bytes())?;
+        assert!(!fields.self_signed);
+        assert_eq!(fields.common_name.as_deref(), Some("Test Intermediate CA"));
+        assert_eq!(ca_cert_fields(LEAF_CERT.as_bytes())?.ca, Some(false));
+        assert!(ca_cert_fields(b"not a cert").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_chain() -> Result<()> {
+        let chain = format!("{}{}", CA_CERT, CA_CERT);
+        assert_eq!(describe_pem(chain.as_bytes())?.len(), 2);
+        assert!(describe_pem(b"not a cert").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_detached() -> Result<()> {
+        let signature = hex::decode(SIGNATURE)?;
+        let cert = SIGNER_CERT.as_bytes();
+        assert!(verify_detached(cert, SIGNED_DATA, &signature)?);
+        // tampered data, a corrupt signature or another key
+        assert!(!verify_detached(cert, b"{}", &signature)?);
+        assert!(!verify_detached(cert, SIGNED_DATA, &signature[1..])?);
+        assert!(!verify_detached(
+            CA_CERT.as_bytes(),
+            SIGNED_DATA,
+            &signature
+        )?);
+        assert!(
+            verify_detached(b"not a cert", SIGNED_DATA, &signature).is_err()
+        );
+        Ok(())
+    }
+}
@@ -2,14 +2,21 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write},
+    fs,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
 use thiserror::Error;
 use yubihsm::{
     asymmetric,
     object::{Id, Label},
-    Capability, Domain,
+    wrap, Capability, Domain,
 };
 
 #[derive(Error, Debug)]
@@ -17,15 +24,235 @@ pub enum ConfigError {
     #[error("failed conversion from YubiHSM Label")]
     BadLabel,
 
+    #[error(
+        "label \"{label}\" is {len} bytes, the YubiHSM limit is {LABEL_MAX}"
+    )]
+    LabelTooLong { label: String, len: usize },
+
     #[error("failed conversion from YubiHSM Capability")]
     BadCapability,
 
     #[error("failed to parse key spec from JSON")]
-    BadKeySpec { e: serde_json::Error },
+    BadKeySpec {
+        #[source]
+        e: serde_json::Error,
+    },
+
+    #[error("failed to read key spec")]
+    ReadKeySpec {
+        #[source]
+        e: std::io::Error,
+    },
+
+    #[error("invalid ceremony mode, expected \"dev\" or \"prod\"")]
+    BadCeremonyMode,
+
+    #[error("invalid backup format, expected \"json\" or \"pem\"")]
+    BadBackupFormat,
+
+    #[error("invalid share backend, expected \"rusty-secrets\" or \"sharks\"")]
+    BadShareBackend,
+
+    #[error("invalid share encoding, expected \"plain\" or \"base32\"")]
+    BadShareEncoding,
+
+    #[error(
+        "invalid wrap algorithm, expected \"aes128-ccm\", \"aes192-ccm\" \
+        or \"aes256-ccm\""
+    )]
+    BadWrapAlgorithm,
+
+    #[error("invalid summary format, expected \"csv\" or \"json\"")]
+    BadSummaryFormat,
+
+    #[error("invalid output layout, expected \"flat\" or \"per-key\"")]
+    BadOutputLayout,
+
+    #[error("invalid YubiHSM Capability bitmask: {0:#x}")]
+    BadCapabilityMask(u64),
+
+    #[error("unknown YubiHSM Capability: {0}")]
+    BadCapabilityName(String),
+
+    #[error("invalid YubiHSM Domain: {0}")]
+    BadDomain(usize),
+
+    #[error("key spec is missing required field: {0}")]
+    MissingField(&'static str),
+
+    #[error(
+        "invalid common name \"{0}\": must be 1 to {COMMON_NAME_MAX} \
+        printable characters"
+    )]
+    BadCommonName(String),
+
+    #[error(
+        "invalid subject alternative name \"{0}\": expected DNS:<name> or \
+        URI:<uri>"
+    )]
+    BadSan(String),
+
+    #[error(
+        "invalid CA directory name \"{0}\": must be a single path component"
+    )]
+    BadCaDirName(String),
+
+    #[error("key spec file name {0} doesn't end in {KEY_SPEC_SUFFIX}")]
+    BadKeySpecName(PathBuf),
+
+    #[error("key spec file name {path} doesn't match the label \"{label}\"")]
+    KeySpecLabelMismatch { path: PathBuf, label: String },
+
+    #[error("algorithm {algorithm:?} is not allowed for purpose {purpose:?}")]
+    BadPurposeAlgorithm {
+        purpose: Purpose,
+        algorithm: OksAlgorithm,
+    },
+
+    #[error(
+        "hash {hash:?} is too weak for a {algorithm:?} key for purpose \
+        {purpose:?}, use {min:?} or stronger"
+    )]
+    WeakHash {
+        purpose: Purpose,
+        algorithm: OksAlgorithm,
+        hash: Hash,
+        min: Hash,
+    },
+
+    #[error(
+        "key {label} for purpose {purpose:?} is a signing key and must not \
+        be exportable, it has: {}", capabilities.join(", ")
+    )]
+    ExportableSigningKey {
+        label: String,
+        purpose: Purpose,
+        capabilities: Vec<String>,
+    },
+
+    #[error("invalid key usage for purpose {purpose:?}: {reason}")]
+    BadKeyUsage {
+        purpose: Purpose,
+        reason: &'static str,
+    },
+
+    #[error(
+        "invalid extended key usage \"{0}\": expected a name or a dotted OID"
+    )]
+    BadExtendedKeyUsage(String),
+
+    #[error(
+        "extended key usage {usage} is listed twice or implied by purpose \
+        {purpose:?}"
+    )]
+    DuplicateExtendedKeyUsage { purpose: Purpose, usage: String },
+
+    #[error("invalid policy for purpose {purpose:?}: {reason}")]
+    BadPolicy {
+        purpose: Purpose,
+        reason: &'static str,
+    },
+}
+
+/// The kind of ceremony being performed. Safety checks are enforced in
+/// `Prod` ceremonies and relaxed in `Dev` ceremonies. All checks are
+/// gated through the methods on this type so that the difference between
+/// the two modes is described in one place.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CeremonyMode {
+    Dev,
+    #[default]
+    Prod,
+}
+
+/// Minimum length of the password for the auth key created in a `Prod`
+/// ceremony.
+pub const PROD_MIN_PASSWORD_LEN: usize = 12;
+
+impl CeremonyMode {
+    /// Minimum length of the password used to derive a new auth key.
+    pub fn min_password_len(&self) -> usize {
+        match self {
+            CeremonyMode::Dev => 1,
+            CeremonyMode::Prod => PROD_MIN_PASSWORD_LEN,
+        }
+    }
+
+    /// Refuse to overwrite existing backups and other output files.
+    pub fn refuse_collisions(&self) -> bool {
+        *self == CeremonyMode::Prod
+    }
+
+    /// Refuse to create signing keys that can be exported under wrap, see
+    /// `KeySpec::check_export_policy`.
+    pub fn enforces_export_policy(&self) -> bool {
+        *self == CeremonyMode::Prod
+    }
+
+    /// Fail when an attestation cert can't be produced for a key.
+    pub fn require_attestation(&self) -> bool {
+        *self == CeremonyMode::Prod
+    }
+
+    /// Write the backup of a new auth key before deleting the default one.
+    pub fn backup_before_delete(&self) -> bool {
+        *self == CeremonyMode::Prod
+    }
+
+    /// Keep secrets (passwords, wrap keys) out of the logs.
+    pub fn redact_secrets(&self) -> bool {
+        *self == CeremonyMode::Prod
+    }
+
+    /// Display all key shares at once, w/o clearing the screen between
+    /// them, for review by a single operator.
+    pub fn allows_share_review(&self) -> bool {
+        *self == CeremonyMode::Dev
+    }
+
+    /// Keys for production purposes may only be created in a `Prod`
+    /// ceremony.
+    pub fn allows_purpose(&self, purpose: &Purpose) -> bool {
+        *self == CeremonyMode::Prod || !purpose.is_production()
+    }
+
+    /// Render a secret for logging, redacted if required by the mode. Log
+    /// it w/ `logging::SECRET_TARGET` as the target so that it's kept out of
+    /// the log file.
+    pub fn redact<'a>(&self, secret: &'a str) -> &'a str {
+        if self.redact_secrets() {
+            "<redacted>"
+        } else {
+            secret
+        }
+    }
+}
+
+impl FromStr for CeremonyMode {
+    type Err = ConfigError;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "dev" => Ok(CeremonyMode::Dev),
+            "prod" => Ok(CeremonyMode::Prod),
+            _ => Err(ConfigError::BadCeremonyMode),
+        }
+    }
+}
+
+impl fmt::Display for CeremonyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            CeremonyMode::Dev => "dev",
+            CeremonyMode::Prod => "prod",
+        };
+        write!(f, "{}", str)
+    }
 }
 
 // These structs duplicate data from the yubihsm crate
 // The Algorithm and Domain types serialize but won't deserialize
+/// The algorithm in the key spec file format, callers use `KeySpec`.
 #[derive(Copy, Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum OksAlgorithm {
     Rsa4096,
@@ -41,6 +268,61 @@ impl From<OksAlgorithm> for asymmetric::Algorithm {
     }
 }
 
+/// The algorithms a wrap key can be created w/. These are all of the wrap
+/// algorithms the `yubihsm` crate knows, which YubiHSM supports each depends
+/// on its firmware. `Aes256Ccm` is the default.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum OksWrapAlgorithm {
+    Aes128Ccm,
+    Aes192Ccm,
+    #[default]
+    Aes256Ccm,
+}
+
+impl FromStr for OksWrapAlgorithm {
+    type Err = ConfigError;
+
+    fn from_str(algorithm: &str) -> Result<Self, Self::Err> {
+        match algorithm {
+            "aes128-ccm" => Ok(OksWrapAlgorithm::Aes128Ccm),
+            "aes192-ccm" => Ok(OksWrapAlgorithm::Aes192Ccm),
+            "aes256-ccm" => Ok(OksWrapAlgorithm::Aes256Ccm),
+            _ => Err(ConfigError::BadWrapAlgorithm),
+        }
+    }
+}
+
+impl fmt::Display for OksWrapAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OksWrapAlgorithm::Aes128Ccm => write!(f, "aes128-ccm"),
+            OksWrapAlgorithm::Aes192Ccm => write!(f, "aes192-ccm"),
+            OksWrapAlgorithm::Aes256Ccm => write!(f, "aes256-ccm"),
+        }
+    }
+}
+
+impl From<OksWrapAlgorithm> for wrap::Algorithm {
+    fn from(val: OksWrapAlgorithm) -> Self {
+        match val {
+            OksWrapAlgorithm::Aes128Ccm => wrap::Algorithm::Aes128Ccm,
+            OksWrapAlgorithm::Aes192Ccm => wrap::Algorithm::Aes192Ccm,
+            OksWrapAlgorithm::Aes256Ccm => wrap::Algorithm::Aes256Ccm,
+        }
+    }
+}
+
+impl From<wrap::Algorithm> for OksWrapAlgorithm {
+    fn from(val: wrap::Algorithm) -> Self {
+        match val {
+            wrap::Algorithm::Aes128Ccm => OksWrapAlgorithm::Aes128Ccm,
+            wrap::Algorithm::Aes192Ccm => OksWrapAlgorithm::Aes192Ccm,
+            wrap::Algorithm::Aes256Ccm => OksWrapAlgorithm::Aes256Ccm,
+        }
+    }
+}
+
+/// The domain in the key spec file format, callers use `KeySpec`.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub enum OksDomain {
     DOM1,
@@ -54,6 +336,7 @@ impl From<OksDomain> for Domain {
     }
 }
 
+/// The label in the key spec file format, callers use `KeySpec`.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub struct OksLabel(String);
 
@@ -61,35 +344,184 @@ impl TryInto<Label> for OksLabel {
     type Error = ConfigError;
 
     fn try_into(self) -> Result<Label, Self::Error> {
-        match Label::from_bytes(self.0.as_bytes()) {
-            Ok(label) => Ok(label),
-            Err(_e) => Err(ConfigError::BadLabel),
-        }
+        check_label(&self.0)
+    }
+}
+
+/// Upper bound on the length of a YubiHSM object label in bytes.
+pub const LABEL_MAX: usize = 40;
+
+/// Labels within this many bytes of `LABEL_MAX` get a warning so that a
+/// naming scheme is trimmed before a longer variant of it hits the limit.
+pub const LABEL_MARGIN: usize = 4;
+
+/// Convert `label` to a YubiHSM `Label`. Depending on the yubihsm version
+/// `Label::from_bytes` either truncates an over-length label or rejects it,
+/// and labels are the identifiers we match keys on across ceremonies, so
+/// the length is checked here first. The byte length is in the warning and
+/// the error so the operator knows how much to trim.
+pub fn check_label(label: &str) -> Result<Label, ConfigError> {
+    let len = label.len();
+    if len > LABEL_MAX {
+        return Err(ConfigError::LabelTooLong {
+            label: label.to_string(),
+            len,
+        });
+    }
+    if len > LABEL_MAX - LABEL_MARGIN {
+        warn!(
+            "label \"{}\" is {} bytes, within {} bytes of the YubiHSM \
+            limit of {}",
+            label,
+            len,
+            LABEL_MAX - len,
+            LABEL_MAX
+        );
     }
+
+    Label::from_bytes(label.as_bytes()).map_err(|_| ConfigError::BadLabel)
 }
 
+/// The capabilities in the key spec file format, callers use `KeySpec`.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub enum OksCapability {
     All,
+    /// All capabilities except `EXPORT_CAPABILITIES`: for offline roots
+    /// and signing keys that must never leave the YubiHSM. These keys have
+    /// no backup.
+    NonExportable,
+    /// A list of capability names, e.g. `["sign-ecdsa"]`, see
+    /// `capabilities_from_names`.
+    #[serde(untagged)]
+    Names(Vec<String>),
+    /// A capability bitmask as computed by other tools, e.g. `128`. Only
+    /// the bits the YubiHSM defines are allowed.
+    #[serde(untagged)]
+    Mask(u64),
 }
 
-impl From<OksCapability> for Capability {
-    fn from(val: OksCapability) -> Self {
+/// The capabilities that let an object, or the objects it wraps, leave the
+/// YubiHSM under wrap.
+pub const EXPORT_CAPABILITIES: Capability = Capability::from_bits_truncate(
+    Capability::EXPORT_WRAPPED.bits()
+        | Capability::EXPORTABLE_UNDER_WRAP.bits(),
+);
+
+impl TryFrom<OksCapability> for Capability {
+    type Error = ConfigError;
+
+    fn try_from(val: OksCapability) -> Result<Self, Self::Error> {
         match val {
-            OksCapability::All => Capability::all(),
+            OksCapability::All => Ok(Capability::all()),
+            OksCapability::NonExportable => {
+                Ok(Capability::all() - EXPORT_CAPABILITIES)
+            }
+            OksCapability::Names(names) => capabilities_from_names(&names),
+            OksCapability::Mask(mask) => {
+                // the yubihsm crate has placeholder flags for the bits the
+                // YubiHSM doesn't define, these have no name
+                let caps = Capability::from_bits_truncate(mask);
+                if caps.bits() != mask
+                    || capability_names(caps)
+                        .iter()
+                        .any(|name| name.starts_with(UNKNOWN_CAPABILITY))
+                {
+                    Err(ConfigError::BadCapabilityMask(mask))
+                } else {
+                    Ok(caps)
+                }
+            }
         }
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+// prefix of the name of a capability bit the YubiHSM doesn't define
+const UNKNOWN_CAPABILITY: &str = "unknown-capability-";
+
+/// Get the names of the individual capabilities in the provided
+/// `Capability` bitflags, sorted alphabetically. Names are those used by
+/// Yubico (e.g. `sign-pkcs`). The YubiHSM doesn't define some bits, these
+/// are named `unknown-capability-<bit>`.
+pub fn capability_names(caps: Capability) -> Vec<String> {
+    let mut names: Vec<String> = (0..u64::BITS)
+        .filter_map(|bit| Capability::from_bits(1 << bit).map(|c| (bit, c)))
+        .filter(|(_, cap)| caps.contains(*cap))
+        .map(|(bit, cap)| {
+            let mut name = String::new();
+            // the Display impl for Capability returns an error for bits
+            // that it doesn't have a name for
+            match write!(name, "{}", cap) {
+                Ok(()) => name,
+                Err(_) => format!("{}{}", UNKNOWN_CAPABILITY, bit),
+            }
+        })
+        .collect();
+    names.sort();
+
+    names
+}
+
+/// The inverse of `capability_names`.
+pub fn capabilities_from_names(
+    names: &[String],
+) -> Result<Capability, ConfigError> {
+    names.iter().try_fold(Capability::empty(), |caps, name| {
+        let cap = match name.strip_prefix(UNKNOWN_CAPABILITY) {
+            Some(bit) => bit
+                .parse::<u32>()
+                .ok()
+                .and_then(|bit| 1u64.checked_shl(bit))
+                .and_then(Capability::from_bits),
+            None => Capability::from_str(name).ok(),
+        };
+        cap.map(|cap| caps | cap)
+            .ok_or_else(|| ConfigError::BadCapabilityName(name.clone()))
+    })
+}
+
+/// Get the numbers (1 - 16) of the domains in the provided `Domain`
+/// bitflags.
+pub fn domain_numbers(domains: Domain) -> Vec<usize> {
+    (1..=16)
+        .filter(|n| Domain::at(*n).is_ok_and(|d| domains.contains(d)))
+        .collect()
+}
+
+/// The inverse of `domain_numbers`.
+pub fn domains_from_numbers(numbers: &[usize]) -> Result<Domain, ConfigError> {
+    numbers.iter().try_fold(Domain::empty(), |domains, n| {
+        Domain::at(*n)
+            .map(|d| domains | d)
+            .map_err(|_| ConfigError::BadDomain(*n))
+    })
+}
+
+/// The digest used for the signatures on certs issued w/ a key, ordered by
+/// strength.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize,
+)]
 pub enum Hash {
     Sha256,
     Sha384,
+    Sha512,
+}
+
+impl Hash {
+    /// The openssl name for the digest, this is the `default_md` in the
+    /// openssl.cnf.
+    pub fn openssl_name(&self) -> &'static str {
+        match self {
+            Hash::Sha256 => "sha256",
+            Hash::Sha384 => "sha384",
+            Hash::Sha512 => "sha512",
+        }
+    }
 }
 
 /// Values in this enum are mapped to OpenSSL config sections for v3 extensions.
 /// All certs issued by the OKS are assumed to be intermediate CAs.
-#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
 pub enum Purpose {
     ProductionCodeSigningCA,
     DevelopmentCodeSigningCA,
@@ -98,10 +530,141 @@ pub enum Purpose {
     Identity,
 }
 
+impl Purpose {
+    /// Returns true if certificates issued for this purpose are CAs. This
+    /// must agree with the `basicConstraints` in the corresponding section
+    /// of the openssl.cnf.
+    pub fn is_ca(&self) -> bool {
+        matches!(
+            self,
+            Purpose::ProductionCodeSigningCA
+                | Purpose::DevelopmentCodeSigningCA
+                | Purpose::Identity
+        )
+    }
+
+    /// Returns true if keys for this purpose only sign code. These keys
+    /// aren't CAs, they must never leave the YubiHSM.
+    pub fn is_signing_only(&self) -> bool {
+        matches!(
+            self,
+            Purpose::ProductionCodeSigning | Purpose::DevelopmentCodeSigning
+        )
+    }
+
+    /// Returns true if keys / certs for this purpose are for production.
+    pub fn is_production(&self) -> bool {
+        matches!(
+            self,
+            Purpose::ProductionCodeSigningCA | Purpose::ProductionCodeSigning
+        )
+    }
+
+    /// The extendedKeyUsage in the certs issued for this purpose, see
+    /// `KeySpec::extended_key_usage` for those added by the key spec.
+    pub fn extended_key_usage(&self) -> Vec<ExtendedKeyUsage> {
+        match self {
+            Purpose::ProductionCodeSigningCA
+            | Purpose::DevelopmentCodeSigningCA
+            | Purpose::ProductionCodeSigning
+            | Purpose::DevelopmentCodeSigning => {
+                vec![ExtendedKeyUsage::CodeSigning]
+            }
+            Purpose::Identity => vec![ExtendedKeyUsage::ClientAuth],
+        }
+    }
+
+    /// The capabilities a key for this purpose needs w/ the provided
+    /// algorithm: signing, and `EXPORTABLE_UNDER_WRAP` so that it can be
+    /// backed up unless it's signing only.
+    pub fn capabilities(&self, algorithm: asymmetric::Algorithm) -> Capability {
+        let sign = match algorithm {
+            asymmetric::Algorithm::Rsa2048
+            | asymmetric::Algorithm::Rsa3072
+            | asymmetric::Algorithm::Rsa4096 => {
+                Capability::SIGN_PKCS | Capability::SIGN_PSS
+            }
+            asymmetric::Algorithm::Ed25519 => Capability::SIGN_EDDSA,
+            _ => Capability::SIGN_ECDSA,
+        };
+        if self.is_signing_only() {
+            sign
+        } else {
+            sign | Capability::EXPORTABLE_UNDER_WRAP
+        }
+    }
+}
+
+/// The algorithms allowed for keys of each purpose. This table is the policy
+/// for key specs, changes to it must be reviewed against the RFD. A purpose
+/// missing from the table allows no algorithms.
+const PURPOSE_ALGORITHMS: &[(Purpose, &[OksAlgorithm])] = &[
+    (
+        Purpose::ProductionCodeSigningCA,
+        &[OksAlgorithm::Rsa4096, OksAlgorithm::Ecp384],
+    ),
+    (
+        Purpose::DevelopmentCodeSigningCA,
+        &[OksAlgorithm::Rsa4096, OksAlgorithm::Ecp384],
+    ),
+    (
+        Purpose::ProductionCodeSigning,
+        &[OksAlgorithm::Rsa4096, OksAlgorithm::Ecp384],
+    ),
+    (
+        Purpose::DevelopmentCodeSigning,
+        &[OksAlgorithm::Rsa4096, OksAlgorithm::Ecp384],
+    ),
+    (Purpose::Identity, &[OksAlgorithm::Ecp384]),
+];
+
+/// Check the purpose / algorithm pair against `PURPOSE_ALGORITHMS`.
+pub fn check_purpose_algorithm(
+    purpose: Purpose,
+    algorithm: OksAlgorithm,
+) -> Result<(), ConfigError> {
+    let allowed = PURPOSE_ALGORITHMS
+        .iter()
+        .any(|(p, algs)| *p == purpose && algs.contains(&algorithm));
+    if allowed {
+        Ok(())
+    } else {
+        Err(ConfigError::BadPurposeAlgorithm { purpose, algorithm })
+    }
+}
+
+/// The weakest digest allowed for the signatures made w/ each algorithm: a
+/// weaker digest than the key is the weakest link in every cert signed w/
+/// it.
+const ALGORITHM_MIN_HASH: [(OksAlgorithm, Hash); 2] = [
+    (OksAlgorithm::Rsa4096, Hash::Sha256),
+    (OksAlgorithm::Ecp384, Hash::Sha384),
+];
+
+/// Check the digest against `ALGORITHM_MIN_HASH` for a key w/ the provided
+/// purpose & algorithm. The purpose limits the algorithm, see
+/// `check_purpose_algorithm`, so it's checked first.
+pub fn check_hash(
+    purpose: Purpose,
+    algorithm: OksAlgorithm,
+    hash: Hash,
+) -> Result<(), ConfigError> {
+    check_purpose_algorithm(purpose, algorithm)?;
+    match ALGORITHM_MIN_HASH.iter().find(|(a, _)| *a == algorithm) {
+        Some((_, min)) if hash < *min => Err(ConfigError::WeakHash {
+            purpose,
+            algorithm,
+            hash,
+            min: *min,
+        }),
+        _ => Ok(()),
+    }
+}
+
 /// NOTE: These strings correspond to config sections for v3 extensions in the
 /// openssl.cnf.
-impl ToString for Purpose {
-    fn to_string(&self) -> String {
+impl fmt::Display for Purpose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let str = match self {
             Purpose::ProductionCodeSigningCA => "v3_code_signing_prod_ca",
             Purpose::DevelopmentCodeSigningCA => "v3_code_signing_dev_ca",
@@ -109,10 +672,367 @@ impl ToString for Purpose {
             Purpose::DevelopmentCodeSigning => "v3_code_signing_dev",
             Purpose::Identity => "v3_identity",
         };
-        String::from(str)
+        write!(f, "{}", str)
+    }
+}
+
+/// A bit in the X.509 keyUsage extension, see RFC 5280 section 4.2.1.3.
+/// These are named as they are in the openssl.cnf.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum KeyUsage {
+    DigitalSignature,
+    NonRepudiation,
+    KeyEncipherment,
+    DataEncipherment,
+    KeyAgreement,
+    KeyCertSign,
+    #[serde(rename = "cRLSign")]
+    CrlSign,
+    EncipherOnly,
+    DecipherOnly,
+}
+
+impl fmt::Display for KeyUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            KeyUsage::DigitalSignature => "digitalSignature",
+            KeyUsage::NonRepudiation => "nonRepudiation",
+            KeyUsage::KeyEncipherment => "keyEncipherment",
+            KeyUsage::DataEncipherment => "dataEncipherment",
+            KeyUsage::KeyAgreement => "keyAgreement",
+            KeyUsage::KeyCertSign => "keyCertSign",
+            KeyUsage::CrlSign => "cRLSign",
+            KeyUsage::EncipherOnly => "encipherOnly",
+            KeyUsage::DecipherOnly => "decipherOnly",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+/// Check that the key usage bits can be put in the certs issued for the
+/// purpose: there must be at least one, each only once, keyCertSign must
+/// be set if and only if the certs are CAs (basicConstraints CA:true), and
+/// encipherOnly / decipherOnly only mean something w/ keyAgreement.
+fn check_key_usage(
+    purpose: Purpose,
+    usage: &[KeyUsage],
+) -> Result<(), ConfigError> {
+    let bad = |reason| Err(ConfigError::BadKeyUsage { purpose, reason });
+    if usage.is_empty() {
+        return bad("no key usage");
+    }
+    if usage
+        .iter()
+        .enumerate()
+        .any(|(i, u)| usage[..i].contains(u))
+    {
+        return bad("duplicate key usage");
+    }
+    let cert_sign = usage.contains(&KeyUsage::KeyCertSign);
+    if cert_sign && !purpose.is_ca() {
+        return bad("keyCertSign requires CA:true");
+    }
+    if !cert_sign && purpose.is_ca() {
+        return bad("CA certs require keyCertSign");
+    }
+    if (usage.contains(&KeyUsage::EncipherOnly)
+        || usage.contains(&KeyUsage::DecipherOnly))
+        && !usage.contains(&KeyUsage::KeyAgreement)
+    {
+        return bad("encipherOnly and decipherOnly require keyAgreement");
+    }
+
+    Ok(())
+}
+
+/// A purpose in the X.509 extendedKeyUsage extension, see RFC 5280 section
+/// 4.2.1.12. These are named as they are in the openssl.cnf, others are
+/// given as a dotted OID.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ExtendedKeyUsage {
+    ServerAuth,
+    ClientAuth,
+    CodeSigning,
+    EmailProtection,
+    TimeStamping,
+    OcspSigning,
+    Oid(String),
+}
+
+impl FromStr for ExtendedKeyUsage {
+    type Err = ConfigError;
+
+    fn from_str(usage: &str) -> Result<Self, Self::Err> {
+        match usage {
+            "serverAuth" => Ok(ExtendedKeyUsage::ServerAuth),
+            "clientAuth" => Ok(ExtendedKeyUsage::ClientAuth),
+            "codeSigning" => Ok(ExtendedKeyUsage::CodeSigning),
+            "emailProtection" => Ok(ExtendedKeyUsage::EmailProtection),
+            "timeStamping" => Ok(ExtendedKeyUsage::TimeStamping),
+            "OCSPSigning" => Ok(ExtendedKeyUsage::OcspSigning),
+            oid if is_oid(oid) => Ok(ExtendedKeyUsage::Oid(oid.to_string())),
+            _ => Err(ConfigError::BadExtendedKeyUsage(usage.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for ExtendedKeyUsage {
+    type Error = ConfigError;
+
+    fn try_from(usage: String) -> Result<Self, Self::Error> {
+        usage.parse()
+    }
+}
+
+impl From<ExtendedKeyUsage> for String {
+    fn from(usage: ExtendedKeyUsage) -> Self {
+        usage.to_string()
+    }
+}
+
+impl fmt::Display for ExtendedKeyUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            ExtendedKeyUsage::ServerAuth => "serverAuth",
+            ExtendedKeyUsage::ClientAuth => "clientAuth",
+            ExtendedKeyUsage::CodeSigning => "codeSigning",
+            ExtendedKeyUsage::EmailProtection => "emailProtection",
+            ExtendedKeyUsage::TimeStamping => "timeStamping",
+            ExtendedKeyUsage::OcspSigning => "OCSPSigning",
+            ExtendedKeyUsage::Oid(oid) => oid,
+        };
+        write!(f, "{}", str)
+    }
+}
+
+// a dotted OID as openssl accepts it in the openssl.cnf: at least two arcs,
+// the first 0, 1 or 2, each arc a number w/o leading zeros
+fn is_oid(oid: &str) -> bool {
+    let arcs: Vec<&str> = oid.split('.').collect();
+    arcs.len() >= 2
+        && matches!(arcs[0], "0" | "1" | "2")
+        && arcs.iter().all(|arc| {
+            !arc.is_empty()
+                && arc.chars().all(|c| c.is_ascii_digit())
+                && (*arc == "0" || !arc.starts_with('0'))
+        })
+}
+
+/// Check that the extended key usages added by a key spec are each added
+/// only once and aren't already implied by the purpose.
+fn check_extended_key_usage(
+    purpose: Purpose,
+    usage: &[ExtendedKeyUsage],
+) -> Result<(), ConfigError> {
+    let implied = purpose.extended_key_usage();
+    if let Some((_, u)) = usage
+        .iter()
+        .enumerate()
+        .find(|(i, u)| usage[..*i].contains(u) || implied.contains(u))
+    {
+        return Err(ConfigError::DuplicateExtendedKeyUsage {
+            purpose,
+            usage: u.to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+/// A component of the subject DN in a CSR that the CA policy constrains.
+/// These are named as they are in the openssl.cnf.
+#[derive(
+    Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum DnField {
+    CountryName,
+    StateOrProvinceName,
+    OrganizationName,
+    OrganizationalUnitName,
+    CommonName,
+    EmailAddress,
+}
+
+impl fmt::Display for DnField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            DnField::CountryName => "countryName",
+            DnField::StateOrProvinceName => "stateOrProvinceName",
+            DnField::OrganizationName => "organizationName",
+            DnField::OrganizationalUnitName => "organizationalUnitName",
+            DnField::CommonName => "commonName",
+            DnField::EmailAddress => "emailAddress",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+/// How openssl constrains a DN component of the CSR when signing: it must
+/// be the same as in the CA cert (`match`), it must be present
+/// (`supplied`) or it may be left out (`optional`).
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PolicyMatch {
+    Match,
+    Supplied,
+    Optional,
+}
+
+impl fmt::Display for PolicyMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let str = match self {
+            PolicyMatch::Match => "match",
+            PolicyMatch::Supplied => "supplied",
+            PolicyMatch::Optional => "optional",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+/// The constraints on the subject DN of the certs issued for a purpose, in
+/// the order of the `[ policy_match ]` section of the openssl.cnf.
+pub type Policy = BTreeMap<DnField, PolicyMatch>;
+
+/// The DN components that must be supplied in the CSR for each purpose.
+/// Every other component is optional. This is policy for key specs like
+/// `PURPOSE_ALGORITHMS`.
+const PURPOSE_SUPPLIED: &[(Purpose, &[DnField])] = &[
+    (Purpose::ProductionCodeSigningCA, &[DnField::CommonName]),
+    (Purpose::DevelopmentCodeSigningCA, &[DnField::CommonName]),
+    (Purpose::ProductionCodeSigning, &[DnField::CommonName]),
+    (Purpose::DevelopmentCodeSigning, &[DnField::CommonName]),
+    (
+        Purpose::Identity,
+        &[DnField::OrganizationName, DnField::CommonName],
+    ),
+];
+
+/// All of the DN components in the `[ policy_match ]` section.
+const DN_FIELDS: &[DnField] = &[
+    DnField::CountryName,
+    DnField::StateOrProvinceName,
+    DnField::OrganizationName,
+    DnField::OrganizationalUnitName,
+    DnField::CommonName,
+    DnField::EmailAddress,
+];
+
+impl Purpose {
+    /// The policy for the certs issued for this purpose, see
+    /// `PURPOSE_SUPPLIED`.
+    pub fn policy(&self) -> Policy {
+        let supplied = PURPOSE_SUPPLIED
+            .iter()
+            .find(|(p, _)| p == self)
+            .map(|(_, fields)| *fields)
+            .unwrap_or(&[]);
+        DN_FIELDS
+            .iter()
+            .map(|f| {
+                let m = if supplied.contains(f) {
+                    PolicyMatch::Supplied
+                } else {
+                    PolicyMatch::Optional
+                };
+                (*f, m)
+            })
+            .collect()
+    }
+}
+
+/// Check the overrides of the policy for a purpose: every cert we issue
+/// must have a common name, so it can't be made optional.
+fn check_policy(purpose: Purpose, policy: &Policy) -> Result<(), ConfigError> {
+    if policy.get(&DnField::CommonName) == Some(&PolicyMatch::Optional) {
+        return Err(ConfigError::BadPolicy {
+            purpose,
+            reason: "commonName can't be optional",
+        });
+    }
+
+    Ok(())
+}
+
+/// A subject alternative name for the certs issued w/ a key spec. These are
+/// written in the openssl syntax: `DNS:<name>` or `URI:<uri>`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Uri(String),
+}
+
+impl SubjectAltName {
+    /// The name of the SAN type in an openssl.cnf `alt_names` section.
+    pub fn openssl_type(&self) -> &'static str {
+        match self {
+            SubjectAltName::Dns(_) => "DNS",
+            SubjectAltName::Uri(_) => "URI",
+        }
+    }
+
+    pub fn value(&self) -> &str {
+        match self {
+            SubjectAltName::Dns(v) | SubjectAltName::Uri(v) => v,
+        }
+    }
+}
+
+// a DNS name w/ letter / digit / hyphen labels, see RFC 1123
+fn is_dns_name(name: &str) -> bool {
+    name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+// an absolute URI: a scheme followed by a non-empty remainder, w/o anything
+// that could break out of the openssl.cnf
+fn is_uri(uri: &str) -> bool {
+    match uri.split_once(':') {
+        Some((scheme, rest)) => {
+            scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| {
+                    c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')
+                })
+                && !rest.is_empty()
+                && uri.chars().all(|c| c.is_ascii_graphic())
+        }
+        None => false,
+    }
+}
+
+impl FromStr for SubjectAltName {
+    type Err = ConfigError;
+
+    fn from_str(san: &str) -> Result<Self, Self::Err> {
+        let bad = || ConfigError::BadSan(san.to_string());
+        match san.split_once(':').ok_or_else(bad)? {
+            ("DNS", name) if is_dns_name(name) => {
+                Ok(SubjectAltName::Dns(name.to_string()))
+            }
+            ("URI", uri) if is_uri(uri) => {
+                Ok(SubjectAltName::Uri(uri.to_string()))
+            }
+            _ => Err(bad()),
+        }
+    }
+}
+
+impl fmt::Display for SubjectAltName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.openssl_type(), self.value())
     }
 }
 
+// the key spec file format, internal: it's only ever converted to a
+// `KeySpec`, which checks it
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 struct OksKeySpec {
     pub common_name: String,
@@ -123,6 +1043,18 @@ struct OksKeySpec {
     pub hash: Hash,
     pub label: OksLabel,
     pub purpose: Purpose,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub san: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub san_in_ca_cert: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_usage: Option<Vec<KeyUsage>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extended_key_usage: Vec<ExtendedKeyUsage>,
+    #[serde(default, skip_serializing_if = "Policy::is_empty")]
+    pub policy: Policy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_dir_name: Option<String>,
 }
 
 #[derive(Debug)]
@@ -135,6 +1067,23 @@ pub struct KeySpec {
     pub hash: Hash,
     pub label: Label,
     pub purpose: Purpose,
+    /// Subject alternative names for the certs issued w/ this key.
+    pub san: Vec<SubjectAltName>,
+    /// Put the `san` in the self signed CA cert for this key as well. CA
+    /// certs don't get SANs unless this is set.
+    pub san_in_ca_cert: bool,
+    /// The keyUsage for the certs issued w/ this key in place of the one
+    /// implied by the purpose.
+    pub key_usage: Option<Vec<KeyUsage>>,
+    /// Extended key usages for the certs issued w/ this key in addition to
+    /// those implied by the purpose, see `KeySpec::extended_key_usage`.
+    pub extended_key_usage: Vec<ExtendedKeyUsage>,
+    /// Constraints on the subject DN of the certs issued w/ this key in
+    /// place of those implied by the purpose, see `KeySpec::policy`.
+    pub policy: Policy,
+    /// Name of the directory holding the CA for this key in the CA state
+    /// directory, see `KeySpec::ca_dir`.
+    pub ca_dir_name: Option<String>,
 }
 
 impl FromStr for KeySpec {
@@ -147,43 +1096,561 @@ impl FromStr for KeySpec {
     }
 }
 
-impl TryFrom<OksKeySpec> for KeySpec {
-    type Error = ConfigError;
+impl KeySpec {
+    pub fn builder() -> KeySpecBuilder {
+        KeySpecBuilder::default()
+    }
 
-    fn try_from(spec: OksKeySpec) -> Result<Self, Self::Error> {
-        Ok(KeySpec {
-            common_name: spec.common_name,
-            id: spec.id,
-            algorithm: spec.algorithm.into(),
-            capabilities: spec.capabilities.into(),
-            domain: spec.domain.into(),
-            hash: spec.hash,
-            label: spec.label.try_into()?,
-            purpose: spec.purpose,
-        })
+    /// The policy for the certs issued w/ this key: the one for the
+    /// purpose w/ the overrides from the key spec.
+    pub fn policy(&self) -> Policy {
+        let mut policy = self.purpose.policy();
+        policy.extend(self.policy.iter().map(|(f, m)| (*f, *m)));
+        policy
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// The extended key usages for the certs issued w/ this key: those for
+    /// the purpose followed by the ones the key spec adds.
+    pub fn extended_key_usage(&self) -> Vec<ExtendedKeyUsage> {
+        let mut usage = self.purpose.extended_key_usage();
+        usage.extend(self.extended_key_usage.iter().cloned());
+        usage
+    }
 
-    const JSON_RSA4K: &str = r#"{
-            "common_name":
-                "Gimlet RoT Stage0 Code Signing Engineering Offline CA A",
-            "id":1,
-            "algorithm":"Rsa4096",
-            "capabilities":"All",
-            "domain":"DOM1",
-            "hash":"Sha256",
-            "label":"rot-stage0-signing-root-eng-a",
-            "purpose":"ProductionCodeSigning"
-        }"#;
+    /// The directory holding the CA for this key in the CA state directory
+    /// `state`. It's named for the label unless the key spec names it.
+    pub fn ca_dir(&self, state: &Path) -> PathBuf {
+        match &self.ca_dir_name {
+            Some(name) => state.join(name),
+            None => state.join(self.label.to_string()),
+        }
+    }
 
-    #[test]
-    fn test_rsa4k_deserialize() -> Result<()> {
-        let key_spec: OksKeySpec = serde_json::from_str(&JSON_RSA4K)?;
-        assert_eq!(
+    /// Returns true if the key can be exported under wrap, i.e. backed up.
+    pub fn is_exportable(&self) -> bool {
+        self.capabilities
+            .contains(Capability::EXPORTABLE_UNDER_WRAP)
+    }
+
+    /// The capabilities in the spec beyond those its purpose needs, see
+    /// `Purpose::capabilities`.
+    pub fn surplus_capabilities(&self) -> Capability {
+        self.capabilities - self.purpose.capabilities(self.algorithm)
+    }
+
+    /// Check that a key for a signing only purpose has none of the
+    /// `EXPORT_CAPABILITIES`: signing keys are non-exportable roots.
+    pub fn check_export_policy(&self) -> Result<(), ConfigError> {
+        let export = self.capabilities & EXPORT_CAPABILITIES;
+        if self.purpose.is_signing_only() && !export.is_empty() {
+            return Err(ConfigError::ExportableSigningKey {
+                label: self.label.to_string(),
+                purpose: self.purpose,
+                capabilities: capability_names(export),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Parse either a single key spec or a JSON array of key specs.
+    pub fn many_from_str(data: &str) -> Result<Vec<Self>, ConfigError> {
+        // parsed in two steps, rather than as an untagged enum, so that the
+        // error for a malformed spec says what's wrong w/ it
+        let value: serde_json::Value = serde_json::from_str(data)
+            .map_err(|e| ConfigError::BadKeySpec { e })?;
+        let specs: Vec<OksKeySpec> = if value.is_array() {
+            serde_json::from_value(value)
+        } else {
+            serde_json::from_value(value).map(|spec| vec![spec])
+        }
+        .map_err(|e| ConfigError::BadKeySpec { e })?;
+
+        specs.into_iter().map(KeySpec::try_from).collect()
+    }
+}
+
+/// Parse the key specs in each of the provided files, w/o stopping at the
+/// first one that fails. The specs parsed and the error for each file that
+/// couldn't be read or parsed are returned, so that every malformed spec in
+/// a batch is reported at once.
+pub fn parse_all_collecting(
+    paths: &[PathBuf],
+) -> (Vec<KeySpec>, Vec<(PathBuf, ConfigError)>) {
+    let (mut specs, mut errors) = (Vec::new(), Vec::new());
+    for path in paths {
+        let parsed = fs::read_to_string(path)
+            .map_err(|e| ConfigError::ReadKeySpec { e })
+            .and_then(|json| KeySpec::many_from_str(&json));
+        match parsed {
+            Ok(more) => specs.extend(more),
+            Err(e) => errors.push((path.clone(), e)),
+        }
+    }
+
+    (specs, errors)
+}
+
+impl TryFrom<OksKeySpec> for KeySpec {
+    type Error = ConfigError;
+
+    fn try_from(spec: OksKeySpec) -> Result<Self, Self::Error> {
+        KeySpec::builder()
+            .common_name(&spec.common_name)
+            .id(spec.id)
+            .algorithm(spec.algorithm)
+            .capabilities(spec.capabilities.try_into()?)
+            .domain(spec.domain.into())
+            .hash(spec.hash)
+            .label(&spec.label.0)
+            .purpose(spec.purpose)
+            .san(&spec.san)
+            .san_in_ca_cert(spec.san_in_ca_cert)
+            .key_usage(spec.key_usage)
+            .extended_key_usage(spec.extended_key_usage)
+            .policy(spec.policy)
+            .ca_dir_name(spec.ca_dir_name)
+            .build()
+    }
+}
+
+/// Upper bound on the length of the common name in X.520.
+pub const COMMON_NAME_MAX: usize = 64;
+
+/// The common name ends up in the subject of the CA cert so it must be
+/// something we can put there w/o surprises.
+fn check_common_name(common_name: &str) -> Result<(), ConfigError> {
+    let len = common_name.chars().count();
+    if len == 0
+        || len > COMMON_NAME_MAX
+        || common_name.trim() != common_name
+        || common_name.chars().any(char::is_control)
+    {
+        Err(ConfigError::BadCommonName(common_name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// The CA directory name is joined to the CA state directory so it must be
+/// a single, normal path component: anything else could put the CA outside
+/// of the state directory.
+fn check_ca_dir_name(name: &str) -> Result<(), ConfigError> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(c)), None)
+            if c == name && !name.chars().any(char::is_control) =>
+        {
+            Ok(())
+        }
+        _ => Err(ConfigError::BadCaDirName(name.to_string())),
+    }
+}
+
+/// Suffix of the name of a key spec file, e.g. `<label>.keyspec.json`.
+pub const KEY_SPEC_SUFFIX: &str = ".keyspec.json";
+
+/// Check that the name of the key spec file at `path` follows the
+/// convention `<label>.keyspec.json`. This catches a file that was renamed
+/// w/o updating its contents (or vice versa). A file holding an array of
+/// specs can't be named for all of them so only the suffix is checked.
+pub fn check_key_spec_path(
+    path: &Path,
+    specs: &[KeySpec],
+) -> Result<(), ConfigError> {
+    let stem = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(KEY_SPEC_SUFFIX))
+        .ok_or_else(|| ConfigError::BadKeySpecName(path.to_path_buf()))?;
+
+    match specs {
+        [spec] if spec.label.to_string() != stem => {
+            Err(ConfigError::KeySpecLabelMismatch {
+                path: path.to_path_buf(),
+                label: spec.label.to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The fields of a key spec that tie it to the table of keys in the RFD.
+/// Reviewers diff a summary of all key specs against that table to catch a
+/// label or common name that's drifted from what was intended.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct KeySpecSummary {
+    pub label: String,
+    pub common_name: String,
+    pub purpose: Purpose,
+    pub algorithm: String,
+}
+
+impl From<&KeySpec> for KeySpecSummary {
+    fn from(spec: &KeySpec) -> Self {
+        KeySpecSummary {
+            label: spec.label.to_string(),
+            common_name: spec.common_name.clone(),
+            purpose: spec.purpose,
+            algorithm: format!("{:?}", spec.algorithm),
+        }
+    }
+}
+
+/// The format a summary of key specs is written in.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SummaryFormat {
+    /// One line per key spec w/ a header line, fields quoted as needed.
+    #[default]
+    Csv,
+    /// A pretty printed JSON array of objects.
+    Json,
+}
+
+impl FromStr for SummaryFormat {
+    type Err = ConfigError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(SummaryFormat::Csv),
+            "json" => Ok(SummaryFormat::Json),
+            _ => Err(ConfigError::BadSummaryFormat),
+        }
+    }
+}
+
+/// Where `generate` writes the backup and attestation for each key.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputLayout {
+    /// All files in the output directory.
+    #[default]
+    Flat,
+    /// The files for each key in the subdirectory of the output directory
+    /// named like the CA directory of the key, see `KeySpec::ca_dir`, so
+    /// that all the files for a key are in one place.
+    PerKey,
+}
+
+impl OutputLayout {
+    /// The directory the files for the key described by `spec` are written
+    /// to.
+    pub fn key_dir(&self, out_dir: &Path, spec: &KeySpec) -> PathBuf {
+        match self {
+            OutputLayout::Flat => out_dir.to_path_buf(),
+            OutputLayout::PerKey => spec.ca_dir(out_dir),
+        }
+    }
+}
+
+impl FromStr for OutputLayout {
+    type Err = ConfigError;
+
+    fn from_str(layout: &str) -> Result<Self, Self::Err> {
+        match layout {
+            "flat" => Ok(OutputLayout::Flat),
+            "per-key" => Ok(OutputLayout::PerKey),
+            _ => Err(ConfigError::BadOutputLayout),
+        }
+    }
+}
+
+// quote a CSV field if it holds a separator, a quote or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Summarize the provided key specs in the provided format. The specs are
+/// written in the order provided.
+pub fn summarize_key_specs(
+    specs: &[KeySpec],
+    format: SummaryFormat,
+) -> Result<String> {
+    let summaries: Vec<KeySpecSummary> =
+        specs.iter().map(KeySpecSummary::from).collect();
+
+    let mut out = String::new();
+    match format {
+        SummaryFormat::Csv => {
+            writeln!(out, "label,common_name,purpose,algorithm")?;
+            for summary in &summaries {
+                writeln!(
+                    out,
+                    "{},{},{},{}",
+                    csv_field(&summary.label),
+                    csv_field(&summary.common_name),
+                    csv_field(&format!("{:?}", summary.purpose)),
+                    csv_field(&summary.algorithm),
+                )?;
+            }
+        }
+        SummaryFormat::Json => {
+            out = serde_json::to_string_pretty(&summaries)?;
+            out.push('\n');
+        }
+    }
+
+    Ok(out)
+}
+
+/// The canonical mapping from key label to object id. Labels are used as
+/// the primary key for keys across ceremonies and environments so a label
+/// must always be created at the same id. The registry is a JSON object w/
+/// a member for each label, e.g. `data/registry/key-ids.json`.
+pub type IdRegistry = BTreeMap<String, Id>;
+
+/// Load the id registry from the JSON file at `path`.
+pub fn load_id_registry(path: &Path) -> Result<IdRegistry> {
+    let json = fs::read_to_string(path).with_context(|| {
+        format!("Failed to read id registry: {}", path.display())
+    })?;
+    serde_json::from_str(&json).with_context(|| {
+        format!("Failed to parse id registry: {}", path.display())
+    })
+}
+
+/// A key spec that doesn't agree w/ the id registry.
+#[derive(Error, Debug, PartialEq)]
+pub enum IdMismatch {
+    #[error("label \"{label}\" (id {id}) isn't in the id registry")]
+    Unregistered { label: String, id: Id },
+    #[error("label \"{label}\" has id {found}, expected {expected}")]
+    Id {
+        label: String,
+        expected: Id,
+        found: Id,
+    },
+}
+
+/// Check the id of each key spec against the id registry. Each spec w/ an
+/// id other than the one registered for its label, or w/ a label that
+/// isn't registered, is returned in the order provided.
+pub fn check_id_registry(
+    registry: &IdRegistry,
+    specs: &[KeySpec],
+) -> Vec<IdMismatch> {
+    specs
+        .iter()
+        .filter_map(|spec| {
+            let label = spec.label.to_string();
+            match registry.get(&label) {
+                None => Some(IdMismatch::Unregistered { label, id: spec.id }),
+                Some(&expected) if expected != spec.id => {
+                    Some(IdMismatch::Id {
+                        label,
+                        expected,
+                        found: spec.id,
+                    })
+                }
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+/// Construct a `KeySpec` w/o going through JSON. Key specs parsed from
+/// JSON are built w/ this type as well so both are validated the same way.
+/// `capabilities` and `domain` default to all capabilities and `DOM1`, the
+/// only values a JSON key spec can express. All other fields are required.
+#[derive(Debug)]
+pub struct KeySpecBuilder {
+    common_name: Option<String>,
+    id: Option<Id>,
+    algorithm: Option<OksAlgorithm>,
+    capabilities: Capability,
+    domain: Domain,
+    hash: Option<Hash>,
+    label: Option<String>,
+    purpose: Option<Purpose>,
+    san: Vec<String>,
+    san_in_ca_cert: bool,
+    key_usage: Option<Vec<KeyUsage>>,
+    extended_key_usage: Vec<ExtendedKeyUsage>,
+    policy: Policy,
+    ca_dir_name: Option<String>,
+}
+
+impl Default for KeySpecBuilder {
+    fn default() -> Self {
+        Self {
+            common_name: None,
+            id: None,
+            algorithm: None,
+            capabilities: Capability::all(),
+            domain: Domain::DOM1,
+            hash: None,
+            label: None,
+            purpose: None,
+            san: Vec::new(),
+            san_in_ca_cert: false,
+            key_usage: None,
+            extended_key_usage: Vec::new(),
+            policy: Policy::new(),
+            ca_dir_name: None,
+        }
+    }
+}
+
+impl KeySpecBuilder {
+    pub fn common_name(mut self, common_name: &str) -> Self {
+        self.common_name = Some(common_name.to_string());
+        self
+    }
+
+    pub fn id(mut self, id: Id) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: OksAlgorithm) -> Self {
+        self.algorithm = Some(algorithm);
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: Capability) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn domain(mut self, domain: Domain) -> Self {
+        self.domain = domain;
+        self
+    }
+
+    pub fn hash(mut self, hash: Hash) -> Self {
+        self.hash = Some(hash);
+        self
+    }
+
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn purpose(mut self, purpose: Purpose) -> Self {
+        self.purpose = Some(purpose);
+        self
+    }
+
+    /// Subject alternative names in the openssl syntax, see
+    /// `SubjectAltName`.
+    pub fn san<S: AsRef<str>>(mut self, san: &[S]) -> Self {
+        self.san = san.iter().map(|s| s.as_ref().to_string()).collect();
+        self
+    }
+
+    pub fn san_in_ca_cert(mut self, san_in_ca_cert: bool) -> Self {
+        self.san_in_ca_cert = san_in_ca_cert;
+        self
+    }
+
+    /// Override the keyUsage implied by the purpose, see `check_key_usage`.
+    pub fn key_usage(mut self, key_usage: Option<Vec<KeyUsage>>) -> Self {
+        self.key_usage = key_usage;
+        self
+    }
+
+    /// Add to the extended key usages implied by the purpose, see
+    /// `check_extended_key_usage`.
+    pub fn extended_key_usage(
+        mut self,
+        extended_key_usage: Vec<ExtendedKeyUsage>,
+    ) -> Self {
+        self.extended_key_usage = extended_key_usage;
+        self
+    }
+
+    /// Override the DN constraints implied by the purpose, see
+    /// `check_policy`.
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Name the CA directory for the key in place of the label, see
+    /// `check_ca_dir_name`.
+    pub fn ca_dir_name(mut self, ca_dir_name: Option<String>) -> Self {
+        self.ca_dir_name = ca_dir_name;
+        self
+    }
+
+    /// Validate the fields and create the `KeySpec`: the label must be a
+    /// valid YubiHSM label, the common name must pass `check_common_name`,
+    /// each SAN must be a valid `SubjectAltName`, the algorithm must be
+    /// allowed for the purpose and so must the key usage, extended key usage
+    /// and policy, if any. The hash must pass `check_hash`.
+    /// The CA directory name, if any, must be a single path component.
+    pub fn build(self) -> Result<KeySpec, ConfigError> {
+        let common_name = self
+            .common_name
+            .ok_or(ConfigError::MissingField("common_name"))?;
+        let id = self.id.ok_or(ConfigError::MissingField("id"))?;
+        let algorithm = self
+            .algorithm
+            .ok_or(ConfigError::MissingField("algorithm"))?;
+        let hash = self.hash.ok_or(ConfigError::MissingField("hash"))?;
+        let label = self.label.ok_or(ConfigError::MissingField("label"))?;
+        let purpose =
+            self.purpose.ok_or(ConfigError::MissingField("purpose"))?;
+
+        check_common_name(&common_name)?;
+        check_hash(purpose, algorithm, hash)?;
+        if let Some(usage) = &self.key_usage {
+            check_key_usage(purpose, usage)?;
+        }
+        check_extended_key_usage(purpose, &self.extended_key_usage)?;
+        check_policy(purpose, &self.policy)?;
+        if let Some(name) = &self.ca_dir_name {
+            check_ca_dir_name(name)?;
+        }
+        let san = self
+            .san
+            .iter()
+            .map(|s| SubjectAltName::from_str(s))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(KeySpec {
+            common_name,
+            id,
+            algorithm: algorithm.into(),
+            capabilities: self.capabilities,
+            domain: self.domain,
+            hash,
+            label: OksLabel(label).try_into()?,
+            purpose,
+            san,
+            san_in_ca_cert: self.san_in_ca_cert,
+            key_usage: self.key_usage,
+            extended_key_usage: self.extended_key_usage,
+            policy: self.policy,
+            ca_dir_name: self.ca_dir_name,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON_RSA4K: &str = r#"{
+            "common_name":
+                "Gimlet RoT Stage0 Code Signing Engineering Offline CA A",
+            "id":1,
+            "algorithm":"Rsa4096",
+            "capabilities":"All",
+            "domain":"DOM1",
+            "hash":"Sha256",
+            "label":"rot-stage0-signing-root-eng-a",
+            "purpose":"ProductionCodeSigning"
+        }"#;
+
+    #[test]
+    fn test_rsa4k_deserialize() -> Result<()> {
+        let key_spec: OksKeySpec = serde_json::from_str(JSON_RSA4K)?;
+        assert_eq!(
             key_spec.common_name,
             "Gimlet RoT Stage0 Code Signing Engineering Offline CA A",
         );
@@ -198,6 +1665,59 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_surplus_capabilities() -> Result<()> {
+        let spec = KeySpec::from_str(JSON_RSA4K)?;
+        let expected = Capability::SIGN_PKCS | Capability::SIGN_PSS;
+        assert_eq!(spec.surplus_capabilities(), Capability::all() - expected);
+
+        let spec = KeySpec {
+            capabilities: Capability::SIGN_ECDSA
+                | Capability::DERIVE_ECDH
+                | Capability::EXPORTABLE_UNDER_WRAP,
+            ..KeySpec::from_str(JSON_ECP384)?
+        };
+        assert_eq!(
+            capability_names(spec.surplus_capabilities()),
+            ["derive-ecdh", "exportable-under-wrap"]
+        );
+        // a CA is backed up
+        let spec = KeySpec {
+            purpose: Purpose::Identity,
+            ..spec
+        };
+        assert_eq!(
+            capability_names(spec.surplus_capabilities()),
+            ["derive-ecdh"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_capability_mask() -> Result<()> {
+        let caps = Capability::SIGN_ECDSA | Capability::EXPORTABLE_UNDER_WRAP;
+        let spec = |capabilities: &str| {
+            KeySpec::from_str(&JSON_RSA4K.replace(
+                r#""capabilities":"All""#,
+                &format!(r#""capabilities":{}"#, capabilities),
+            ))
+        };
+        let names = serde_json::to_string(&capability_names(caps))?;
+        assert_eq!(spec(&names)?.capabilities, caps);
+        assert_eq!(spec(&caps.bits().to_string())?.capabilities, caps);
+
+        // bits the YubiHSM doesn't define are refused
+        assert!(matches!(
+            spec(&(1u64 << 63).to_string()),
+            Err(ConfigError::BadCapabilityMask(_))
+        ));
+        assert!(matches!(
+            spec(r#"["sign-everything"]"#),
+            Err(ConfigError::BadCapabilityName(_))
+        ));
+        Ok(())
+    }
+
     #[test]
     fn test_oks_spec_convert() -> Result<()> {
         let key_spec = KeySpec::from_str(JSON_RSA4K)?;
@@ -226,7 +1746,7 @@ mod tests {
 
     #[test]
     fn test_ecp384_deserialize() -> Result<()> {
-        let key_spec: OksKeySpec = serde_json::from_str(&JSON_ECP384)?;
+        let key_spec: OksKeySpec = serde_json::from_str(JSON_ECP384)?;
         assert_eq!(key_spec.common_name, "RoT Identity Signing Offline CA",);
         assert_eq!(key_spec.id, 2);
         assert_eq!(key_spec.capabilities, OksCapability::All);
@@ -251,10 +1771,607 @@ mod tests {
         "purpose":"Identity"
     }"#;
 
+    #[test]
+    fn test_many_from_str() -> Result<()> {
+        let json = format!("[{}, {}]", JSON_RSA4K, JSON_ECP384);
+        let specs = KeySpec::many_from_str(&json)?;
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].algorithm, asymmetric::Algorithm::Rsa4096);
+        assert_eq!(specs[1].algorithm, asymmetric::Algorithm::EcP384);
+
+        // a single spec is a batch of one
+        let specs = KeySpec::many_from_str(JSON_IDENTITY)?;
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].purpose, Purpose::Identity);
+
+        assert!(KeySpec::many_from_str("[]")?.is_empty());
+        assert!(KeySpec::many_from_str("[{}]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_key_spec_path() -> Result<()> {
+        let specs = KeySpec::many_from_str(JSON_ECP384)?;
+        let path = Path::new("data/rot-identity-signing-ca.keyspec.json");
+        assert!(check_key_spec_path(path, &specs).is_ok());
+
+        // the file was renamed but its contents weren't
+        let renamed = Path::new("data/rot-identity-a.keyspec.json");
+        assert!(matches!(
+            check_key_spec_path(renamed, &specs),
+            Err(ConfigError::KeySpecLabelMismatch { .. })
+        ));
+        let json = Path::new("data/rot-identity-signing-ca.json");
+        assert!(matches!(
+            check_key_spec_path(json, &specs),
+            Err(ConfigError::BadKeySpecName(_))
+        ));
+
+        // only the suffix is checked for an array of specs
+        let json = format!("[{}, {}]", JSON_RSA4K, JSON_ECP384);
+        let specs = KeySpec::many_from_str(&json)?;
+        assert!(
+            check_key_spec_path(Path::new("all.keyspec.json"), &specs).is_ok()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_label() -> Result<()> {
+        for len in [1, LABEL_MAX - LABEL_MARGIN, LABEL_MAX] {
+            let label = "a".repeat(len);
+            assert_eq!(
+                check_label(&label)?,
+                Label::from_bytes(label.as_bytes())?
+            );
+        }
+
+        // multi-byte characters count by their bytes
+        let label = "é".repeat(LABEL_MAX / 2 + 1);
+        match check_label(&label) {
+            Err(ConfigError::LabelTooLong { len, .. }) => {
+                assert_eq!(len, LABEL_MAX + 2)
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_extended_key_usage() -> Result<()> {
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        assert_eq!(spec.extended_key_usage(), [ExtendedKeyUsage::CodeSigning]);
+
+        let with_usage = |usage: &str| {
+            KeySpec::from_str(&JSON_ECP384.replace(
+                r#""purpose":"#,
+                &format!(r#""extended_key_usage":{},"purpose":"#, usage),
+            ))
+        };
+        let spec = with_usage(r#"["timeStamping", "1.3.6.1.4.1.57551.2"]"#)?;
+        assert_eq!(
+            spec.extended_key_usage(),
+            [
+                ExtendedKeyUsage::CodeSigning,
+                ExtendedKeyUsage::TimeStamping,
+                ExtendedKeyUsage::Oid("1.3.6.1.4.1.57551.2".to_string())
+            ]
+        );
+
+        // implied by the purpose or listed twice
+        for (usage, dup) in [
+            (r#"["codeSigning"]"#, "codeSigning"),
+            (r#"["1.2.3", "1.2.3"]"#, "1.2.3"),
+        ] {
+            assert!(matches!(
+                with_usage(usage),
+                Err(ConfigError::DuplicateExtendedKeyUsage { usage, .. })
+                    if usage == dup
+            ));
+        }
+        for usage in ["codesigning", "1", "3.1", "1.02", "1..2", "1.2."] {
+            assert!(ExtendedKeyUsage::from_str(usage).is_err(), "{:?}", usage);
+            assert!(matches!(
+                with_usage(&format!("[{:?}]", usage)),
+                Err(ConfigError::BadKeySpec { .. })
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_ca_dir_name() -> Result<()> {
+        let state = Path::new("/var/ca");
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        assert_eq!(spec.ca_dir(state), state.join("rot-identity-signing-ca"));
+
+        let with_name = |name: &str| {
+            KeySpec::from_str(&JSON_ECP384.replace(
+                r#""purpose":"#,
+                &format!(r#""ca_dir_name":{:?},"purpose":"#, name),
+            ))
+        };
+        let spec = with_name("identity-2026")?;
+        assert_eq!(spec.ca_dir(state), state.join("identity-2026"));
+        // the HSM key is still referenced by id, not the directory name
+        assert_eq!(spec.id, KeySpec::from_str(JSON_ECP384)?.id);
+
+        for name in ["", ".", "..", "a/b", "../a", "a/", "/a", "a\nb"] {
+            assert!(
+                matches!(with_name(name), Err(ConfigError::BadCaDirName(_))),
+                "{:?}",
+                name
+            );
+        }
+        Ok(())
+    }
+
+    // malformed key specs, e.g. from corrupted media, must be errors and
+    // never panic, see also the fuzz target in fuzz/
+    #[test]
+    fn test_parse_never_panics() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0x6f6b73);
+        let valid = [JSON_RSA4K, JSON_ECP384, JSON_IDENTITY];
+        for _ in 0..5000 {
+            let mut data =
+                valid[rng.gen_range(0..valid.len())].as_bytes().to_vec();
+            for _ in 0..rng.gen_range(1..8) {
+                let i = rng.gen_range(0..data.len());
+                match rng.gen_range(0..4) {
+                    0 => data[i] = rng.gen(),
+                    1 => data.truncate(i),
+                    2 => data.insert(i, rng.gen()),
+                    _ => {
+                        let j = rng.gen_range(0..data.len());
+                        data.swap(i, j)
+                    }
+                }
+                if data.is_empty() {
+                    break;
+                }
+            }
+            let data = String::from_utf8_lossy(&data);
+            let _ = KeySpec::from_str(&data);
+            let _ = KeySpec::many_from_str(&data);
+        }
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..256);
+            let data: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let _ = KeySpec::from_str(&String::from_utf8_lossy(&data));
+        }
+    }
+
+    #[test]
+    fn test_builder() -> Result<()> {
+        let builder = || {
+            KeySpec::builder()
+                .common_name("RoT Identity Signing Offline CA")
+                .id(2)
+                .algorithm(OksAlgorithm::Ecp384)
+                .hash(Hash::Sha384)
+                .label("rot-identity-signing-ca")
+                .purpose(Purpose::Identity)
+        };
+        let spec = builder().build()?;
+        let json = KeySpec::from_str(JSON_IDENTITY)?;
+        assert_eq!(spec.common_name, json.common_name);
+        assert_eq!(spec.id, json.id);
+        assert_eq!(spec.algorithm, json.algorithm);
+        assert_eq!(spec.capabilities, json.capabilities);
+        assert_eq!(spec.domain, json.domain);
+        assert_eq!(spec.hash, json.hash);
+        assert_eq!(spec.label, json.label);
+        assert_eq!(spec.purpose, json.purpose);
+
+        assert!(matches!(
+            KeySpec::builder().id(2).build(),
+            Err(ConfigError::MissingField("common_name"))
+        ));
+        assert!(matches!(
+            builder().label(&"a".repeat(41)).build(),
+            Err(ConfigError::LabelTooLong { len: 41, .. })
+        ));
+        for common_name in ["", " padded", "new\nline", &"a".repeat(65)] {
+            assert!(matches!(
+                builder().common_name(common_name).build(),
+                Err(ConfigError::BadCommonName(_))
+            ));
+        }
+        assert!(matches!(
+            builder().algorithm(OksAlgorithm::Rsa4096).build(),
+            Err(ConfigError::BadPurposeAlgorithm { .. })
+        ));
+        assert!(matches!(
+            builder().hash(Hash::Sha256).build(),
+            Err(ConfigError::WeakHash {
+                algorithm: OksAlgorithm::Ecp384,
+                min: Hash::Sha384,
+                ..
+            })
+        ));
+        assert!(builder().hash(Hash::Sha512).build().is_ok());
+
+        // the JSON path shares the validation
+        let json = JSON_IDENTITY.replace("RoT Identity Signing Offline CA", "");
+        assert!(matches!(
+            KeySpec::from_str(&json),
+            Err(ConfigError::BadCommonName(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_san() -> Result<()> {
+        let json = JSON_IDENTITY.replace(
+            "\"purpose\"",
+            r#""san": ["DNS:rot.oxide.computer", "URI:urn:oxide:rot"],
+            "purpose""#,
+        );
+        let spec = KeySpec::from_str(&json)?;
+        assert_eq!(
+            spec.san,
+            vec![
+                SubjectAltName::Dns("rot.oxide.computer".to_string()),
+                SubjectAltName::Uri("urn:oxide:rot".to_string()),
+            ]
+        );
+        assert!(!spec.san_in_ca_cert);
+        assert!(KeySpec::from_str(JSON_IDENTITY)?.san.is_empty());
+
+        for bad in [
+            "rot.oxide.computer",
+            "IP:127.0.0.1",
+            "DNS:",
+            "DNS:-rot.oxide.computer",
+            "DNS:rot..oxide.computer",
+            "DNS:rot oxide",
+            "URI:no-scheme",
+            "URI:https://oxide.computer/\n[ v3_identity ]",
+        ] {
+            assert!(
+                matches!(
+                    SubjectAltName::from_str(bad),
+                    Err(ConfigError::BadSan(_))
+                ),
+                "accepted: {}",
+                bad
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_usage() -> Result<()> {
+        assert_eq!(KeySpec::from_str(JSON_IDENTITY)?.key_usage, None);
+
+        let with_usage = |usage: &str| {
+            KeySpec::from_str(&JSON_IDENTITY.replace(
+                "\"purpose\"",
+                &format!("\"key_usage\": {},\n\"purpose\"", usage),
+            ))
+        };
+        let spec = with_usage(r#"["keyCertSign"]"#)?;
+        assert_eq!(spec.key_usage, Some(vec![KeyUsage::KeyCertSign]));
+        let spec = with_usage(r#"["digitalSignature", "keyCertSign"]"#)?;
+        let names: Vec<String> = spec
+            .key_usage
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(names, ["digitalSignature", "keyCertSign"]);
+
+        for bad in [
+            "[]",
+            r#"["keyCertSign", "keyCertSign"]"#,
+            // identity certs are CAs
+            r#"["digitalSignature", "cRLSign"]"#,
+            r#"["keyCertSign", "encipherOnly"]"#,
+        ] {
+            assert!(
+                matches!(with_usage(bad), Err(ConfigError::BadKeyUsage { .. })),
+                "accepted: {}",
+                bad
+            );
+        }
+        assert!(matches!(
+            with_usage(r#"["signEverything"]"#),
+            Err(ConfigError::BadKeySpec { .. })
+        ));
+
+        // leaf certs can't sign certs
+        assert!(check_key_usage(
+            Purpose::ProductionCodeSigning,
+            &[KeyUsage::DigitalSignature, KeyUsage::NonRepudiation]
+        )
+        .is_ok());
+        assert!(check_key_usage(
+            Purpose::ProductionCodeSigning,
+            &[KeyUsage::DigitalSignature, KeyUsage::KeyCertSign]
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_policy() -> Result<()> {
+        let signing = |capabilities: &str| {
+            KeySpec::from_str(
+                &JSON_IDENTITY
+                    .replace(r#""All""#, capabilities)
+                    .replace(r#""Identity""#, r#""ProductionCodeSigning""#),
+            )
+        };
+        assert!(matches!(
+            signing(r#""All""#)?.check_export_policy(),
+            Err(ConfigError::ExportableSigningKey { capabilities, .. })
+                if capabilities == ["export-wrapped", "exportable-under-wrap"]
+        ));
+        signing(r#""NonExportable""#)?.check_export_policy()?;
+
+        // CAs are backed up so they're exportable
+        KeySpec::from_str(JSON_IDENTITY)?.check_export_policy()?;
+        assert!(CeremonyMode::Prod.enforces_export_policy());
+        assert!(!CeremonyMode::Dev.enforces_export_policy());
+        Ok(())
+    }
+
+    #[test]
+    fn test_policy() -> Result<()> {
+        let spec = KeySpec::from_str(JSON_IDENTITY)?;
+        assert!(spec.policy.is_empty());
+        let policy = spec.policy();
+        assert_eq!(policy.len(), DN_FIELDS.len());
+        assert_eq!(policy[&DnField::OrganizationName], PolicyMatch::Supplied);
+        assert_eq!(policy[&DnField::CommonName], PolicyMatch::Supplied);
+        assert_eq!(policy[&DnField::CountryName], PolicyMatch::Optional);
+        assert_eq!(
+            Purpose::ProductionCodeSigning.policy()[&DnField::OrganizationName],
+            PolicyMatch::Optional
+        );
+
+        let with_policy = |policy: &str| {
+            KeySpec::from_str(&JSON_IDENTITY.replace(
+                "\"purpose\"",
+                &format!("\"policy\": {},\n\"purpose\"", policy),
+            ))
+        };
+        let spec = with_policy(r#"{"countryName": "match"}"#)?;
+        let policy = spec.policy();
+        assert_eq!(policy[&DnField::CountryName], PolicyMatch::Match);
+        assert_eq!(policy[&DnField::OrganizationName], PolicyMatch::Supplied);
+        assert!(matches!(
+            with_policy(r#"{"commonName": "optional"}"#),
+            Err(ConfigError::BadPolicy { .. })
+        ));
+        assert!(matches!(
+            with_policy(r#"{"countryName": "required"}"#),
+            Err(ConfigError::BadKeySpec { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_capability_names_round_trip() -> Result<()> {
+        let caps = Capability::SIGN_PKCS
+            | Capability::SIGN_ECDSA
+            | Capability::EXPORTABLE_UNDER_WRAP;
+        let names = capability_names(caps);
+        assert_eq!(names, ["exportable-under-wrap", "sign-ecdsa", "sign-pkcs"]);
+
+        let round_trip =
+            names.iter().try_fold(Capability::empty(), |acc, n| {
+                Capability::from_str(n).map(|c| acc | c)
+            });
+        assert_eq!(round_trip, Ok(caps));
+        Ok(())
+    }
+
+    #[test]
+    fn test_capabilities_from_names() -> Result<()> {
+        let caps = Capability::all();
+        assert_eq!(capabilities_from_names(&capability_names(caps))?, caps);
+        assert!(capabilities_from_names(&["sign-everything".into()]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_domain_numbers() -> Result<()> {
+        let domains = Domain::DOM1 | Domain::DOM16;
+        assert_eq!(domain_numbers(domains), [1, 16]);
+        assert_eq!(domains_from_numbers(&[1, 16])?, domains);
+        assert_eq!(
+            domains_from_numbers(&domain_numbers(Domain::all()))?,
+            Domain::all()
+        );
+        assert!(domains_from_numbers(&[17]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_capability_names_unknown() {
+        let names = capability_names(Capability::all());
+        assert_eq!(names.len(), u64::BITS as usize);
+        assert!(names.contains(&"unknown-capability-63".to_string()));
+        assert!(capability_names(Capability::empty()).is_empty());
+    }
+
+    #[test]
+    fn test_ceremony_mode() -> Result<()> {
+        assert!(CeremonyMode::Dev.allows_share_review());
+        assert!(!CeremonyMode::Prod.allows_share_review());
+        let dev = CeremonyMode::from_str("dev")?;
+        let prod = CeremonyMode::from_str("prod")?;
+        assert!(CeremonyMode::from_str("test").is_err());
+        assert_eq!(CeremonyMode::default(), prod);
+
+        assert!(prod.allows_purpose(&Purpose::ProductionCodeSigningCA));
+        assert!(prod.allows_purpose(&Purpose::DevelopmentCodeSigning));
+        assert!(!dev.allows_purpose(&Purpose::ProductionCodeSigning));
+        assert!(dev.allows_purpose(&Purpose::Identity));
+
+        assert_eq!(prod.redact("secret"), "<redacted>");
+        assert_eq!(dev.redact("secret"), "secret");
+        Ok(())
+    }
+
+    #[test]
+    fn test_purpose_algorithm() -> Result<()> {
+        assert!(KeySpec::from_str(JSON_IDENTITY).is_ok());
+        let rsa_identity = JSON_IDENTITY.replace("Ecp384", "Rsa4096");
+        assert!(matches!(
+            KeySpec::from_str(&rsa_identity),
+            Err(ConfigError::BadPurposeAlgorithm {
+                purpose: Purpose::Identity,
+                algorithm: OksAlgorithm::Rsa4096,
+            })
+        ));
+
+        // every purpose allows at least one algorithm
+        for purpose in [
+            Purpose::ProductionCodeSigningCA,
+            Purpose::DevelopmentCodeSigningCA,
+            Purpose::ProductionCodeSigning,
+            Purpose::DevelopmentCodeSigning,
+            Purpose::Identity,
+        ] {
+            assert!([OksAlgorithm::Rsa4096, OksAlgorithm::Ecp384]
+                .iter()
+                .any(|a| check_purpose_algorithm(purpose, *a).is_ok()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_sha512_deserialize() -> Result<()> {
+        let json = JSON_RSA4K.replace("Sha256", "Sha512");
+        let key_spec: OksKeySpec = serde_json::from_str(&json)?;
+        assert_eq!(key_spec.hash, Hash::Sha512);
+        assert_eq!(KeySpec::from_str(&json)?.hash, Hash::Sha512);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_openssl_name() -> Result<()> {
+        // openssl takes the name as the `default_md` for `openssl ca` & as
+        // the digest option for `openssl dgst`, it refuses a name it
+        // doesn't know
+        for hash in [Hash::Sha256, Hash::Sha384, Hash::Sha512] {
+            let output = std::process::Command::new("openssl")
+                .arg("dgst")
+                .arg(format!("-{}", hash.openssl_name()))
+                .stdin(std::process::Stdio::null())
+                .output()?;
+            assert!(
+                output.status.success(),
+                "openssl doesn't know the digest for {:?}: {}",
+                hash,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_extensions_engineering() -> Result<()> {
-        let key_spec: OksKeySpec = serde_json::from_str(&JSON_IDENTITY)?;
+        let key_spec: OksKeySpec = serde_json::from_str(JSON_IDENTITY)?;
         assert_eq!(key_spec.purpose, Purpose::Identity);
         Ok(())
     }
+
+    #[test]
+    fn test_summarize_key_specs() -> Result<()> {
+        let specs = vec![
+            KeySpec::from_str(JSON_RSA4K)?,
+            KeySpec::builder()
+                .common_name("Test, \"Quoted\" CA")
+                .id(2)
+                .algorithm(OksAlgorithm::Ecp384)
+                .hash(Hash::Sha384)
+                .label("test-ca")
+                .purpose(Purpose::DevelopmentCodeSigningCA)
+                .build()?,
+        ];
+
+        let csv = summarize_key_specs(&specs, SummaryFormat::Csv)?;
+        assert_eq!(
+            csv,
+            "label,common_name,purpose,algorithm\n\
+            rot-stage0-signing-root-eng-a,\
+            Gimlet RoT Stage0 Code Signing Engineering Offline CA A,\
+            ProductionCodeSigning,Rsa4096\n\
+            test-ca,\"Test, \"\"Quoted\"\" CA\",DevelopmentCodeSigningCA,EcP384\n"
+        );
+
+        let json = summarize_key_specs(&specs, SummaryFormat::Json)?;
+        let json: serde_json::Value = serde_json::from_str(&json)?;
+        assert_eq!(json[1]["label"], "test-ca");
+        assert_eq!(json[1]["common_name"], "Test, \"Quoted\" CA");
+        assert_eq!(json[1]["purpose"], "DevelopmentCodeSigningCA");
+        assert_eq!(json[1]["algorithm"], "EcP384");
+
+        assert!(SummaryFormat::from_str("xml").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_id_registry() -> Result<()> {
+        let registry: IdRegistry = serde_json::from_str(
+            r#"{"rot-stage0-signing-root-eng-a": 1, "test-ca": 3}"#,
+        )?;
+        let spec = |label: &str, id| {
+            KeySpec::builder()
+                .common_name("Test CA")
+                .id(id)
+                .algorithm(OksAlgorithm::Ecp384)
+                .hash(Hash::Sha384)
+                .label(label)
+                .purpose(Purpose::DevelopmentCodeSigningCA)
+                .build()
+        };
+
+        let specs = [KeySpec::from_str(JSON_RSA4K)?, spec("test-ca", 3)?];
+        assert_eq!(check_id_registry(&registry, &specs), vec![]);
+
+        let specs = [spec("test-ca", 2)?, spec("other-ca", 4)?];
+        assert_eq!(
+            check_id_registry(&registry, &specs),
+            vec![
+                IdMismatch::Id {
+                    label: "test-ca".to_string(),
+                    expected: 3,
+                    found: 2,
+                },
+                IdMismatch::Unregistered {
+                    label: "other-ca".to_string(),
+                    id: 4,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_all_collecting() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let paths: Vec<PathBuf> =
+            ["a.json", "b.json", "c.json", "d.json", "missing.json"]
+                .iter()
+                .map(|name| dir.path().join(name))
+                .collect();
+        fs::write(&paths[0], JSON_RSA4K)?;
+        fs::write(&paths[1], "{ not json")?;
+        fs::write(&paths[2], format!("[{}, {}]", JSON_ECP384, JSON_IDENTITY))?;
+        fs::write(&paths[3], JSON_RSA4K.replace("Rsa4096", "Rsa1024"))?;
+
+        let (specs, errors) = parse_all_collecting(&paths);
+        assert_eq!(specs.len(), 3);
+        let failed: Vec<&PathBuf> = errors.iter().map(|(p, _)| p).collect();
+        assert_eq!(failed, vec![&paths[1], &paths[3], &paths[4]]);
+        assert!(matches!(errors[0].1, ConfigError::BadKeySpec { .. }));
+        assert!(matches!(errors[2].1, ConfigError::ReadKeySpec { .. }));
+        Ok(())
+    }
 }
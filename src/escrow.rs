@@ -0,0 +1,277 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Encrypt a key share to the public key of a key custodian's smartcard,
+//! e.g. the key in the PIV key management slot (9d), so that the share is
+//! carried as a file only the card can decrypt instead of being
+//! transcribed. A share can be longer than RSA-OAEP encrypts w/ a 2048 bit
+//! key so the share is encrypted w/ a random AES-256-GCM key, and only that
+//! key is encrypted w/ RSA-OAEP to the card. The result is a DER encoded CMS
+//! AuthEnvelopedData (RFC 5083) that openssl 3 decrypts w/ the card's
+//! PKCS#11 module, e.g.: `openssl cms -decrypt -inform DER -in share-1.enc
+//! -engine pkcs11 -keyform engine -inkey "pkcs11:id=%03"`
+
+use aes_gcm::{aead::AeadInPlace, Aes256Gcm, KeyInit, Nonce};
+use anyhow::Result;
+use rand::{rngs::OsRng, RngCore};
+use rsa::{pkcs8::DecodePublicKey, Oaep, RsaPublicKey};
+use sha1::{Digest, Sha1};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use x509_parser::{
+    prelude::FromDer, public_key::PublicKey, x509::SubjectPublicKeyInfo,
+};
+use zeroize::Zeroizing;
+
+use crate::{pubkey::PEM_TAG, HsmError};
+
+/// The smallest RSA key a share is encrypted to.
+pub const RECIPIENT_KEY_BITS: usize = 2048;
+
+// the DER encoding of the object identifiers used
+const OID_AUTH_ENVELOPED_DATA: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x10, 0x01, 0x17,
+];
+const OID_DATA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x07, 0x01];
+const OID_RSAES_OAEP: &[u8] =
+    &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x07];
+const OID_AES256_GCM: &[u8] =
+    &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2e];
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: u8 = 16;
+
+/// The public key, in a `PUBLIC KEY` PEM file, that a key custodian's share
+/// is encrypted to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecipientKey {
+    /// The file the key was loaded from.
+    pub path: PathBuf,
+    // the SubjectPublicKeyInfo from the file, DER encoded
+    der: Vec<u8>,
+    // the subject key identifier of the key, the SHA-1 digest of the public
+    // key (RFC 5280 4.2.1.2) that identifies the recipient in the CMS
+    key_id: Vec<u8>,
+}
+
+// a DER TLV w/ the provided tag and the concatenation of `contents`
+fn der(tag: u8, contents: &[&[u8]]) -> Vec<u8> {
+    let len: usize = contents.iter().map(|c| c.len()).sum();
+    let mut tlv = vec![tag];
+    if len < 0x80 {
+        tlv.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        tlv.push(0x80 | bytes.len() as u8);
+        tlv.extend(bytes);
+    }
+    for c in contents {
+        tlv.extend_from_slice(c);
+    }
+    tlv
+}
+
+impl RecipientKey {
+    /// Check that the file at `path` holds an RSA public key of at least
+    /// `RECIPIENT_KEY_BITS`. This is done for every custodian before the
+    /// ceremony starts so that a bad key is caught before there's a share
+    /// to encrypt. Any share fits since only the AES key is encrypted w/
+    /// RSA, the key is used once here so that one `encrypt` can't use is
+    /// refused too.
+    pub fn load(path: &Path) -> Result<Self> {
+        let bad = |reason: &str| {
+            HsmError::BadRecipientKey(path.to_path_buf(), reason.to_string())
+        };
+        let pem = pem::parse(fs::read(path)?).map_err(|_| bad("not PEM"))?;
+        if pem.tag != PEM_TAG {
+            return Err(bad("expected a PUBLIC KEY").into());
+        }
+        let (_, spki) = SubjectPublicKeyInfo::from_der(&pem.contents)
+            .map_err(|_| bad("malformed public key"))?;
+        match spki.parsed() {
+            Ok(PublicKey::RSA(rsa)) if rsa.key_size() >= RECIPIENT_KEY_BITS => {
+            }
+            Ok(PublicKey::RSA(_)) => {
+                return Err(bad("RSA key is too short").into())
+            }
+            _ => return Err(bad("not an RSA key").into()),
+        }
+        let recipient = RecipientKey {
+            path: path.to_path_buf(),
+            key_id: Sha1::digest(&spki.subject_public_key.data).to_vec(),
+            der: pem.contents,
+        };
+        recipient.encrypt(b"")?;
+
+        Ok(recipient)
+    }
+
+    /// Encrypt `plaintext` to the key, see the module docs for the format.
+    /// This is done in memory, nothing is written to disk.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let fail = |reason: String| {
+            HsmError::ShareEncryptFail(self.path.clone(), reason)
+        };
+        let public = RsaPublicKey::from_public_key_der(&self.der)
+            .map_err(|e| fail(e.to_string()))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(key.as_mut());
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let encrypted_key = public
+            .encrypt(&mut OsRng, Oaep::new::<Sha1>(), key.as_ref())
+            .map_err(|e| fail(e.to_string()))?;
+        let mut content = plaintext.to_vec();
+        let tag = Aes256Gcm::new(key.as_ref().into())
+            .encrypt_in_place_detached(
+                Nonce::from_slice(&nonce),
+                b"",
+                &mut content,
+            )
+            .map_err(|e| fail(e.to_string()))?;
+
+        // KeyTransRecipientInfo, version 2 as the recipient is identified
+        // by its subject key identifier, w/ the default OAEP parameters:
+        // SHA-1 and MGF1 w/ SHA-1
+        let recipient = der(
+            0x30,
+            &[
+                &der(0x02, &[&[2]]),
+                &der(0x80, &[&self.key_id]),
+                &der(0x30, &[&der(0x06, &[OID_RSAES_OAEP]), &der(0x30, &[])]),
+                &der(0x04, &[&encrypted_key]),
+            ],
+        );
+        let content_info = der(
+            0x30,
+            &[
+                &der(0x06, &[OID_DATA]),
+                &der(
+                    0x30,
+                    &[
+                        &der(0x06, &[OID_AES256_GCM]),
+                        &der(
+                            0x30,
+                            &[&der(0x04, &[&nonce]), &der(0x02, &[&[TAG_LEN]])],
+                        ),
+                    ],
+                ),
+                &der(0x80, &[&content]),
+            ],
+        );
+        let auth_enveloped = der(
+            0x30,
+            &[
+                &der(0x02, &[&[0]]),
+                &der(0x31, &[&recipient]),
+                &content_info,
+                &der(0x04, &[&tag]),
+            ],
+        );
+
+        Ok(der(
+            0x30,
+            &[
+                &der(0x06, &[OID_AUTH_ENVELOPED_DATA]),
+                &der(0xa0, &[&auth_enveloped]),
+            ],
+        ))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    // write a new key pair w/ the provided `openssl genpkey` options to
+    // `dir`, returning the paths of the private and public keys
+    pub(crate) fn key_pair(
+        dir: &Path,
+        name: &str,
+        options: &[&str],
+    ) -> Result<(PathBuf, PathBuf)> {
+        let key = dir.join(format!("{}.key.pem", name));
+        let public = dir.join(format!("{}.pub.pem", name));
+        let status = Command::new("openssl")
+            .arg("genpkey")
+            .args(options)
+            .arg("-out")
+            .arg(&key)
+            .output()?
+            .status;
+        anyhow::ensure!(status.success(), "openssl genpkey failed");
+        let status = Command::new("openssl")
+            .args(["pkey", "-pubout", "-in"])
+            .arg(&key)
+            .arg("-out")
+            .arg(&public)
+            .output()?
+            .status;
+        anyhow::ensure!(status.success(), "openssl pkey failed");
+        Ok((key, public))
+    }
+
+    // decrypt the output of `RecipientKey::encrypt` w/ the private key in
+    // the PEM file at `key`, on a card in practice
+    pub(crate) fn decrypt(key: &Path, ciphertext: &Path) -> Result<Vec<u8>> {
+        let output = Command::new("openssl")
+            .args(["cms", "-decrypt", "-inform", "DER", "-inkey"])
+            .arg(key)
+            .arg("-in")
+            .arg(ciphertext)
+            .output()?;
+        anyhow::ensure!(
+            output.status.success(),
+            "openssl cms failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Ok(output.stdout)
+    }
+
+    #[test]
+    fn test_recipient_key() -> Result<()> {
+        let dir = TempDir::new()?;
+        let rsa = ["-algorithm", "RSA", "-pkeyopt", "rsa_keygen_bits:2048"];
+        let (key, public) = key_pair(dir.path(), "rsa", &rsa)?;
+
+        let recipient = RecipientKey::load(&public)?;
+        let ciphertext = dir.path().join("share.enc");
+        fs::write(&ciphertext, recipient.encrypt(b"a key share")?)?;
+        assert_eq!(decrypt(&key, &ciphertext)?, b"a key share");
+
+        // far more than RSA-OAEP encrypts w/ a 2048 bit key, the key is
+        // read from the file once
+        let share = "W1-".to_string() + &"ABCD-".repeat(200);
+        fs::remove_file(&public)?;
+        fs::write(&ciphertext, recipient.encrypt(share.as_bytes())?)?;
+        assert_eq!(decrypt(&key, &ciphertext)?, share.as_bytes());
+
+        // the private key, a short key and a non RSA key are refused
+        let short = ["-algorithm", "RSA", "-pkeyopt", "rsa_keygen_bits:1024"];
+        let (_, short) = key_pair(dir.path(), "short", &short)?;
+        let ec = ["-algorithm", "EC", "-pkeyopt", "ec_paramgen_curve:P-256"];
+        let (_, ec) = key_pair(dir.path(), "ec", &ec)?;
+        for path in [&key, &short, &ec] {
+            let err = RecipientKey::load(path).unwrap_err();
+            assert!(
+                matches!(
+                    err.downcast_ref::<HsmError>(),
+                    Some(HsmError::BadRecipientKey(p, _)) if p == path
+                ),
+                "{}",
+                err
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,786 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::Result;
+use yubihsm::{
+    asymmetric, attestation, authentication,
+    device::{ErrorKind, SerialNumber, StorageInfo},
+    object::{self, Id, Label, Type},
+    wrap, Algorithm, Capability, Client, Credentials, Domain,
+};
+
+/// The subset of the `yubihsm::Client` API used by this crate. Functions
+/// that operate on the YubiHSM take an implementation of this trait so that
+/// they can be exercised without hardware.
+pub trait Hsm {
+    fn generate_asymmetric_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        algorithm: asymmetric::Algorithm,
+    ) -> Result<Id>;
+
+    fn export_wrapped(
+        &self,
+        wrap_key_id: Id,
+        object_type: Type,
+        object_id: Id,
+    ) -> Result<wrap::Message>;
+
+    /// Import an object exported under wrap w/ the wrap key w/ the provided
+    /// id. The object is created w/ the id it was exported from.
+    fn import_wrapped(
+        &self,
+        wrap_key_id: Id,
+        message: wrap::Message,
+    ) -> Result<object::Handle>;
+
+    /// Put the wrap key in `key_bytes` into the YubiHSM. The key is
+    /// borrowed so that the caller keeps the only copy it has to zeroize.
+    #[allow(clippy::too_many_arguments)]
+    fn put_wrap_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        delegated_capabilities: Capability,
+        algorithm: wrap::Algorithm,
+        key_bytes: &[u8],
+    ) -> Result<Id>;
+
+    #[allow(clippy::too_many_arguments)]
+    fn put_authentication_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        delegated_capabilities: Capability,
+        algorithm: authentication::Algorithm,
+        authentication_key: authentication::Key,
+    ) -> Result<Id>;
+
+    fn delete_object(&self, object_id: Id, object_type: Type) -> Result<()>;
+
+    fn get_pseudo_random(&self, bytes: usize) -> Result<Vec<u8>>;
+
+    fn sign_attestation_certificate(
+        &self,
+        key_id: Id,
+        attestation_key_id: Option<Id>,
+    ) -> Result<attestation::Certificate>;
+
+    fn get_opaque(&self, object_id: Id) -> Result<Vec<u8>>;
+
+    /// Sign the provided digest w/ the ECDSA key w/ the provided id. The
+    /// signature is DER encoded.
+    fn sign_ecdsa_prehash_raw(
+        &self,
+        key_id: Id,
+        digest: &[u8],
+    ) -> Result<Vec<u8>>;
+
+    /// Get the public key of the asymmetric key w/ the provided id.
+    fn get_public_key(&self, key_id: Id) -> Result<asymmetric::PublicKey>;
+
+    /// Get information about the object with the provided id and type.
+    /// Returns `None` if there is no such object.
+    fn get_object_info(
+        &self,
+        object_id: Id,
+        object_type: Type,
+    ) -> Result<Option<object::Info>>;
+
+    /// List all objects in the YubiHSM.
+    fn list_objects(&self) -> Result<Vec<object::Entry>>;
+
+    /// Open a new session w/ the provided credentials. This is used to check
+    /// that a new auth key works before we rely on it.
+    fn authenticate(&self, credentials: Credentials) -> Result<()>;
+
+    /// Get the total and free storage records / pages in the YubiHSM.
+    fn get_storage_info(&self) -> Result<StorageInfo>;
+
+    /// Get the serial number of the YubiHSM from its device info.
+    fn serial_number(&self) -> Result<SerialNumber>;
+
+    /// Get the algorithms the YubiHSM supports from its device info. This
+    /// depends on the firmware version.
+    fn supported_algorithms(&self) -> Result<Vec<Algorithm>>;
+
+    /// Get the major, minor and build version of the YubiHSM firmware from
+    /// its device info.
+    fn firmware_version(&self) -> Result<(u8, u8, u8)>;
+}
+
+impl Hsm for Client {
+    fn generate_asymmetric_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        algorithm: asymmetric::Algorithm,
+    ) -> Result<Id> {
+        Ok(Client::generate_asymmetric_key(
+            self,
+            key_id,
+            label,
+            domains,
+            capabilities,
+            algorithm,
+        )?)
+    }
+
+    fn export_wrapped(
+        &self,
+        wrap_key_id: Id,
+        object_type: Type,
+        object_id: Id,
+    ) -> Result<wrap::Message> {
+        Ok(Client::export_wrapped(
+            self,
+            wrap_key_id,
+            object_type,
+            object_id,
+        )?)
+    }
+
+    fn import_wrapped(
+        &self,
+        wrap_key_id: Id,
+        message: wrap::Message,
+    ) -> Result<object::Handle> {
+        Ok(Client::import_wrapped(self, wrap_key_id, message)?)
+    }
+
+    fn put_wrap_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        delegated_capabilities: Capability,
+        algorithm: wrap::Algorithm,
+        key_bytes: &[u8],
+    ) -> Result<Id> {
+        // the client takes the key by value and serializes it into the
+        // command w/o zeroizing either, this copy is the one we can't avoid
+        Ok(Client::put_wrap_key(
+            self,
+            key_id,
+            label,
+            domains,
+            capabilities,
+            delegated_capabilities,
+            algorithm,
+            key_bytes.to_vec(),
+        )?)
+    }
+
+    fn put_authentication_key(
+        &self,
+        key_id: Id,
+        label: Label,
+        domains: Domain,
+        capabilities: Capability,
+        delegated_capabilities: Capability,
+        algorithm: authentication::Algorithm,
+        authentication_key: authentication::Key,
+    ) -> Result<Id> {
+        Ok(Client::put_authentication_key(
+            self,
+            key_id,
+            label,
+            domains,
+            capabilities,
+            delegated_capabilities,
+            algorithm,
+            authentication_key,
+        )?)
+    }
+
+    fn delete_object(&self, object_id: Id, object_type: Type) -> Result<()> {
+        Ok(Client::delete_object(self, object_id, object_type)?)
+    }
+
+    fn get_pseudo_random(&self, bytes: usize) -> Result<Vec<u8>> {
+        Ok(Client::get_pseudo_random(self, bytes)?)
+    }
+
+    fn sign_attestation_certificate(
+        &self,
+        key_id: Id,
+        attestation_key_id: Option<Id>,
+    ) -> Result<attestation::Certificate> {
+        Ok(Client::sign_attestation_certificate(
+            self,
+            key_id,
+            attestation_key_id,
+        )?)
+    }
+
+    fn get_opaque(&self, object_id: Id) -> Result<Vec<u8>> {
+        Ok(Client::get_opaque(self, object_id)?)
+    }
+
+    fn sign_ecdsa_prehash_raw(
+        &self,
+        key_id: Id,
+        digest: &[u8],
+    ) -> Result<Vec<u8>> {
+        Ok(Client::sign_ecdsa_prehash_raw(self, key_id, digest)?)
+    }
+
+    fn get_public_key(&self, key_id: Id) -> Result<asymmetric::PublicKey> {
+        Ok(Client::get_public_key(self, key_id)?)
+    }
+
+    fn get_object_info(
+        &self,
+        object_id: Id,
+        object_type: Type,
+    ) -> Result<Option<object::Info>> {
+        match Client::get_object_info(self, object_id, object_type) {
+            Ok(info) => Ok(Some(info)),
+            Err(e) if e.device_error() == Some(ErrorKind::ObjectNotFound) => {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list_objects(&self) -> Result<Vec<object::Entry>> {
+        Ok(Client::list_objects(self, &[])?)
+    }
+
+    fn authenticate(&self, credentials: Credentials) -> Result<()> {
+        Client::open(self.connector().clone(), credentials, false)?;
+        Ok(())
+    }
+
+    fn get_storage_info(&self) -> Result<StorageInfo> {
+        Ok(Client::get_storage_info(self)?)
+    }
+
+    fn serial_number(&self) -> Result<SerialNumber> {
+        Ok(Client::device_info(self)?.serial_number)
+    }
+
+    fn supported_algorithms(&self) -> Result<Vec<Algorithm>> {
+        Ok(Client::device_info(self)?.algorithms)
+    }
+
+    fn firmware_version(&self) -> Result<(u8, u8, u8)> {
+        let info = Client::device_info(self)?;
+        Ok((info.major_version, info.minor_version, info.build_version))
+    }
+}
+
+/// An in-memory stand-in for the YubiHSM used in tests. It tracks the
+/// objects created through the `Hsm` trait, and the secret bytes for the
+/// keys we put into it. The only cryptography it does is wrapping exported
+/// objects (their metadata and secret, serialized as JSON) so that backups
+/// can be checked against the wrap key and imported again.
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use anyhow::{anyhow, bail};
+    use serde::{Deserialize, Serialize};
+    use std::{
+        cell::{Cell, RefCell},
+        collections::BTreeMap,
+        str::FromStr,
+    };
+    use yubihsm::{
+        authentication::DEFAULT_AUTHENTICATION_KEY_ID, object::Origin,
+    };
+
+    pub(crate) const HSM_ATTEST_CERT: &[u8] = b"mock attestation cert";
+
+    /// Serial number of every mock YubiHSM.
+    pub(crate) const SERIAL_NUMBER: &str = "0012345678";
+
+    /// Firmware version of every mock YubiHSM.
+    pub(crate) const FIRMWARE_VERSION: (u8, u8, u8) = (2, 4, 0);
+
+    // the storage of a YubiHSM 2
+    pub(crate) const TOTAL_RECORDS: u16 = 256;
+    pub(crate) const TOTAL_PAGES: u16 = 1024;
+    pub(crate) const PAGE_SIZE: u16 = 126;
+
+    /// Objects in the mock HSM, and their secret bytes, keyed by id and type.
+    type Objects = BTreeMap<(Id, Type), (object::Info, Vec<u8>)>;
+
+    /// The plaintext of an object exported under wrap by the mock.
+    #[derive(Deserialize, Serialize)]
+    struct Wrapped {
+        id: Id,
+        object_type: u8,
+        label: String,
+        domains: u16,
+        capabilities: u64,
+        delegated_capabilities: u64,
+        algorithm: u8,
+        origin: u8,
+        secret: Vec<u8>,
+    }
+
+    impl Wrapped {
+        fn new(info: &object::Info, secret: Vec<u8>) -> Self {
+            Wrapped {
+                id: info.object_id,
+                object_type: info.object_type.to_u8(),
+                label: info.label.to_string(),
+                domains: info.domains.bits(),
+                capabilities: info.capabilities.bits(),
+                delegated_capabilities: info.delegated_capabilities.bits(),
+                algorithm: info.algorithm.to_u8(),
+                origin: info.origin.to_u8(),
+                secret,
+            }
+        }
+    }
+
+    #[derive(Default)]
+    pub(crate) struct MockHsm {
+        pub objects: RefCell<Objects>,
+        /// When set, the named `Hsm` method fails.
+        pub fail: Cell<Option<&'static str>>,
+        /// When set, the total number of storage pages in place of
+        /// `TOTAL_PAGES`.
+        pub total_pages: Cell<Option<u16>>,
+        /// When set, the algorithms the mock supports in place of every
+        /// wrap and asymmetric algorithm.
+        pub algorithms: RefCell<Option<Vec<Algorithm>>>,
+        counter: Cell<u8>,
+    }
+
+    impl MockHsm {
+        /// Create a mock in the default state of a new YubiHSM: the only
+        /// object is the default auth key.
+        pub fn new() -> Self {
+            let hsm = Self::default();
+            hsm.insert(
+                DEFAULT_AUTHENTICATION_KEY_ID,
+                Type::AuthenticationKey,
+                Label::from_bytes(b"DEFAULT AUTHKEY CHANGE THIS ASAP").unwrap(),
+                Domain::all(),
+                Capability::all(),
+                Capability::all(),
+                Algorithm::Authentication(authentication::Algorithm::default()),
+                Origin::Imported,
+                authentication::Key::default().as_secret_slice().to_vec(),
+            )
+            .unwrap();
+            hsm
+        }
+
+        /// Get the secret bytes for an object.
+        pub fn secret(&self, id: Id, object_type: Type) -> Option<Vec<u8>> {
+            self.objects
+                .borrow()
+                .get(&(id, object_type))
+                .map(|(_, secret)| secret.clone())
+        }
+
+        pub fn contains(&self, id: Id, object_type: Type) -> bool {
+            self.objects.borrow().contains_key(&(id, object_type))
+        }
+
+        // the secret and algorithm of the wrap key w/ the provided id
+        fn wrap_key(&self, id: Id) -> Result<(Vec<u8>, wrap::Algorithm)> {
+            let (info, secret) = self
+                .objects
+                .borrow()
+                .get(&(id, Type::WrapKey))
+                .cloned()
+                .ok_or_else(|| anyhow!("no wrap key with id {}", id))?;
+            match info.algorithm {
+                Algorithm::Wrap(algorithm) => Ok((secret, algorithm)),
+                _ => bail!("wrap key {} has no wrap algorithm", id),
+            }
+        }
+
+        fn check_fail(&self, method: &str) -> Result<()> {
+            match self.fail.get() {
+                Some(fail) if fail == method => {
+                    bail!("mock HSM failure in {}", method)
+                }
+                _ => Ok(()),
+            }
+        }
+
+        #[allow(clippy::too_many_arguments)]
+        fn insert(
+            &self,
+            object_id: Id,
+            object_type: Type,
+            label: Label,
+            domains: Domain,
+            capabilities: Capability,
+            delegated_capabilities: Capability,
+            algorithm: Algorithm,
+            origin: Origin,
+            secret: Vec<u8>,
+        ) -> Result<Id> {
+            let mut objects = self.objects.borrow_mut();
+            // like the YubiHSM, id 0 gets the lowest free id
+            let object_id = match object_id {
+                0 => (1..=Id::MAX)
+                    .find(|id| !objects.contains_key(&(*id, object_type)))
+                    .ok_or_else(|| {
+                        anyhow!("no free id for {:?}", object_type)
+                    })?,
+                id => id,
+            };
+            if objects.contains_key(&(object_id, object_type)) {
+                bail!("object {} of type {:?} exists", object_id, object_type);
+            }
+            let info = object::Info {
+                capabilities,
+                object_id,
+                length: secret.len() as u16,
+                domains,
+                object_type,
+                algorithm,
+                sequence: 0,
+                origin,
+                label,
+                delegated_capabilities,
+            };
+            objects.insert((object_id, object_type), (info, secret));
+
+            Ok(object_id)
+        }
+    }
+
+    impl Hsm for MockHsm {
+        fn generate_asymmetric_key(
+            &self,
+            key_id: Id,
+            label: Label,
+            domains: Domain,
+            capabilities: Capability,
+            algorithm: asymmetric::Algorithm,
+        ) -> Result<Id> {
+            self.check_fail("generate_asymmetric_key")?;
+            let secret = self.get_pseudo_random(32)?;
+            self.insert(
+                key_id,
+                Type::AsymmetricKey,
+                label,
+                domains,
+                capabilities,
+                Capability::empty(),
+                Algorithm::Asymmetric(algorithm),
+                Origin::Generated,
+                secret,
+            )
+        }
+
+        fn export_wrapped(
+            &self,
+            wrap_key_id: Id,
+            object_type: Type,
+            object_id: Id,
+        ) -> Result<wrap::Message> {
+            self.check_fail("export_wrapped")?;
+            let (info, secret) = self
+                .objects
+                .borrow()
+                .get(&(object_id, object_type))
+                .cloned()
+                .ok_or_else(|| anyhow!("no object with id {}", object_id))?;
+            if !info
+                .capabilities
+                .contains(Capability::EXPORTABLE_UNDER_WRAP)
+            {
+                bail!("object {} is not exportable under wrap", object_id);
+            }
+
+            let (wrap_key, algorithm) = self.wrap_key(wrap_key_id)?;
+            let nonce: [u8; 13] =
+                self.get_pseudo_random(13)?.try_into().unwrap();
+            crate::backup::encrypt_wrapped(
+                &wrap_key,
+                algorithm,
+                wrap::Nonce(nonce),
+                &serde_json::to_vec(&Wrapped::new(&info, secret))?,
+            )
+            .ok_or_else(|| anyhow!("failed to wrap object {}", object_id))
+        }
+
+        fn import_wrapped(
+            &self,
+            wrap_key_id: Id,
+            message: wrap::Message,
+        ) -> Result<object::Handle> {
+            self.check_fail("import_wrapped")?;
+            let (wrap_key, algorithm) = self.wrap_key(wrap_key_id)?;
+            let plaintext =
+                crate::backup::decrypt_wrapped(&wrap_key, algorithm, &message)
+                    .ok_or_else(|| {
+                        anyhow!("failed to unwrap w/ wrap key {}", wrap_key_id)
+                    })?;
+            let wrapped: Wrapped = serde_json::from_slice(&plaintext)?;
+            let object_type = Type::from_u8(wrapped.object_type)?;
+            let origin = match Origin::from_u8(wrapped.origin)? {
+                Origin::Generated => Origin::WrappedGenerated,
+                Origin::Imported => Origin::WrappedImported,
+                origin => origin,
+            };
+            self.insert(
+                wrapped.id,
+                object_type,
+                Label::from(wrapped.label.as_str()),
+                Domain::from_bits_truncate(wrapped.domains),
+                Capability::from_bits_truncate(wrapped.capabilities),
+                Capability::from_bits_truncate(wrapped.delegated_capabilities),
+                Algorithm::from_u8(wrapped.algorithm)?,
+                origin,
+                wrapped.secret,
+            )?;
+
+            Ok(object::Handle::new(wrapped.id, object_type))
+        }
+
+        fn put_wrap_key(
+            &self,
+            key_id: Id,
+            label: Label,
+            domains: Domain,
+            capabilities: Capability,
+            delegated_capabilities: Capability,
+            algorithm: wrap::Algorithm,
+            key_bytes: &[u8],
+        ) -> Result<Id> {
+            self.check_fail("put_wrap_key")?;
+            if key_bytes.len() != algorithm.key_len() {
+                bail!(
+                    "wrap key is {} bytes, expected {}",
+                    key_bytes.len(),
+                    algorithm.key_len()
+                );
+            }
+            self.insert(
+                key_id,
+                Type::WrapKey,
+                label,
+                domains,
+                capabilities,
+                delegated_capabilities,
+                Algorithm::Wrap(algorithm),
+                Origin::Imported,
+                key_bytes.to_vec(),
+            )
+        }
+
+        fn put_authentication_key(
+            &self,
+            key_id: Id,
+            label: Label,
+            domains: Domain,
+            capabilities: Capability,
+            delegated_capabilities: Capability,
+            algorithm: authentication::Algorithm,
+            authentication_key: authentication::Key,
+        ) -> Result<Id> {
+            self.check_fail("put_authentication_key")?;
+            self.insert(
+                key_id,
+                Type::AuthenticationKey,
+                label,
+                domains,
+                capabilities,
+                delegated_capabilities,
+                Algorithm::Authentication(algorithm),
+                Origin::Imported,
+                authentication_key.as_secret_slice().to_vec(),
+            )
+        }
+
+        fn delete_object(
+            &self,
+            object_id: Id,
+            object_type: Type,
+        ) -> Result<()> {
+            self.check_fail("delete_object")?;
+            match self.objects.borrow_mut().remove(&(object_id, object_type)) {
+                Some(_) => Ok(()),
+                None => bail!("no object with id {}", object_id),
+            }
+        }
+
+        fn get_pseudo_random(&self, bytes: usize) -> Result<Vec<u8>> {
+            self.check_fail("get_pseudo_random")?;
+            // not random, but distinct across calls
+            let counter = self.counter.get().wrapping_add(1);
+            self.counter.set(counter);
+            Ok((0..bytes)
+                .map(|i| (i as u8).wrapping_add(counter))
+                .collect())
+        }
+
+        fn sign_attestation_certificate(
+            &self,
+            key_id: Id,
+            _attestation_key_id: Option<Id>,
+        ) -> Result<attestation::Certificate> {
+            self.check_fail("sign_attestation_certificate")?;
+            if !self.contains(key_id, Type::AsymmetricKey) {
+                bail!("no asymmetric key with id {}", key_id);
+            }
+            Ok(attestation::Certificate(
+                format!("attestation for key {}", key_id).into_bytes(),
+            ))
+        }
+
+        fn get_opaque(&self, object_id: Id) -> Result<Vec<u8>> {
+            self.check_fail("get_opaque")?;
+            match object_id {
+                0 => Ok(HSM_ATTEST_CERT.to_vec()),
+                _ => bail!("no opaque object with id {}", object_id),
+            }
+        }
+
+        // the mock "signature" is the digest followed by the secret of the
+        // key, which is enough to check what was signed w/ which key
+        fn sign_ecdsa_prehash_raw(
+            &self,
+            key_id: Id,
+            digest: &[u8],
+        ) -> Result<Vec<u8>> {
+            self.check_fail("sign_ecdsa_prehash_raw")?;
+            let secret =
+                self.secret(key_id, Type::AsymmetricKey).ok_or_else(|| {
+                    anyhow!("no asymmetric key with id {}", key_id)
+                })?;
+            Ok([digest, &secret].concat())
+        }
+
+        // the secret stretched to the length of a public key for the
+        // algorithm, this only has to be stable
+        fn get_public_key(&self, key_id: Id) -> Result<asymmetric::PublicKey> {
+            self.check_fail("get_public_key")?;
+            let (info, secret) = self
+                .objects
+                .borrow()
+                .get(&(key_id, Type::AsymmetricKey))
+                .cloned()
+                .ok_or_else(|| {
+                    anyhow!("no asymmetric key with id {}", key_id)
+                })?;
+            let algorithm = match info.algorithm {
+                Algorithm::Asymmetric(algorithm) => algorithm,
+                _ => bail!("key {} has no asymmetric algorithm", key_id),
+            };
+            let len = match algorithm {
+                asymmetric::Algorithm::EcP256
+                | asymmetric::Algorithm::EcP384
+                | asymmetric::Algorithm::EcP521 => 2 * algorithm.key_len(),
+                _ => algorithm.key_len(),
+            };
+            Ok(asymmetric::PublicKey {
+                algorithm,
+                bytes: secret.iter().copied().cycle().take(len).collect(),
+            })
+        }
+
+        fn get_object_info(
+            &self,
+            object_id: Id,
+            object_type: Type,
+        ) -> Result<Option<object::Info>> {
+            self.check_fail("get_object_info")?;
+            Ok(self
+                .objects
+                .borrow()
+                .get(&(object_id, object_type))
+                .map(|(info, _)| info.clone()))
+        }
+
+        fn list_objects(&self) -> Result<Vec<object::Entry>> {
+            self.check_fail("list_objects")?;
+            Ok(self
+                .objects
+                .borrow()
+                .values()
+                .map(|(info, _)| object::Entry {
+                    object_id: info.object_id,
+                    object_type: info.object_type,
+                    sequence: info.sequence,
+                })
+                .collect())
+        }
+
+        fn authenticate(&self, credentials: Credentials) -> Result<()> {
+            self.check_fail("authenticate")?;
+            let id = credentials.authentication_key_id;
+            match self.secret(id, Type::AuthenticationKey) {
+                Some(secret)
+                    if secret
+                        == credentials.authentication_key.as_secret_slice() =>
+                {
+                    Ok(())
+                }
+                _ => bail!("authentication w/ key {} failed", id),
+            }
+        }
+
+        fn get_storage_info(&self) -> Result<StorageInfo> {
+            self.check_fail("get_storage_info")?;
+            let objects = self.objects.borrow();
+            let used_pages: u16 = objects
+                .values()
+                .map(|(info, _)| info.length.div_ceil(PAGE_SIZE).max(1))
+                .sum();
+            let total_pages = self.total_pages.get().unwrap_or(TOTAL_PAGES);
+            Ok(StorageInfo {
+                total_records: TOTAL_RECORDS,
+                free_records: TOTAL_RECORDS - objects.len() as u16,
+                total_pages,
+                free_pages: total_pages.saturating_sub(used_pages),
+                page_size: PAGE_SIZE,
+            })
+        }
+
+        fn serial_number(&self) -> Result<SerialNumber> {
+            self.check_fail("serial_number")?;
+            Ok(SerialNumber::from_str(SERIAL_NUMBER)?)
+        }
+
+        fn supported_algorithms(&self) -> Result<Vec<Algorithm>> {
+            self.check_fail("supported_algorithms")?;
+            if let Some(algorithms) = self.algorithms.borrow().as_ref() {
+                return Ok(algorithms.clone());
+            }
+            let wrap = [
+                wrap::Algorithm::Aes128Ccm,
+                wrap::Algorithm::Aes192Ccm,
+                wrap::Algorithm::Aes256Ccm,
+            ]
+            .map(Algorithm::Wrap);
+            let asymmetric = [
+                asymmetric::Algorithm::Rsa2048,
+                asymmetric::Algorithm::Rsa3072,
+                asymmetric::Algorithm::Rsa4096,
+                asymmetric::Algorithm::EcP256,
+                asymmetric::Algorithm::EcP384,
+                asymmetric::Algorithm::EcP521,
+                asymmetric::Algorithm::Ed25519,
+            ]
+            .map(Algorithm::Asymmetric);
+            Ok(wrap.into_iter().chain(asymmetric).collect())
+        }
+
+        fn firmware_version(&self) -> Result<(u8, u8, u8)> {
+            self.check_fail("firmware_version")?;
+            Ok(FIRMWARE_VERSION)
+        }
+    }
+}
@@ -2,42 +2,111 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! Key ceremonies for the YubiHSMs in the offline keystore (OKS): putting
+//! a wrap key into a YubiHSM and splitting it into key shares, generating
+//! keys from key specs and backing them up under wrap, and running the CAs
+//! for those keys.
+//!
+//! The supported ceremony API is the functions in the crate root that take
+//! an `Hsm`, e.g. `initialize`, `restore`, `generate`, `backup_all`,
+//! `rewrap`, `verify_backups`, `refresh_shares`, `migrate_wrap`,
+//! `rotate_auth_key`, `provision`, `export_pubkeys`, `verify_spec`,
+//! `compare_hsms` and `sign_report`, and the CA functions `ca_init`,
+//! `ca_sign` and `verify_ca`. The types they take and return are
+//! re-exported from the crate root, and `prelude` has the ones every
+//! caller needs.
+//!
+//! The modules are public for the `oks-util` binary and may change
+//! between releases: `config` holds the key spec file format (the `Oks*`
+//! types are its serialized form, callers use `KeySpec`), `backup` the
+//! formats of the files written, `shares` the secret sharing and `hsm` the
+//! `Hsm` trait w/ its mock. Functions that exist to plumb the binary's
+//! prompts through, e.g. `migrate_wrap_with_shares` or `share_warning`, are
+//! part of the supported API only where the ceremony functions are.
+
 use anyhow::{Context, Result};
 use fs_extra::dir::CopyOptions;
 use hex::ToHex;
 use log::{debug, error, info, warn};
+use sha2::{Digest, Sha256, Sha384, Sha512};
 use static_assertions as sa;
 use std::{
-    env,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env, fmt,
     fs::{self, Permissions},
-    io,
-    os::unix::fs::PermissionsExt,
-    path::Path,
-    process::Command,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Output},
     str::FromStr,
+    sync::mpsc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 use tempfile::TempDir;
 use thiserror::Error;
+use time::{OffsetDateTime, UtcOffset};
 use yubihsm::{
+    asymmetric,
     authentication::{self, Key, DEFAULT_AUTHENTICATION_KEY_ID},
-    object::{Id, Label, Type},
-    wrap, Capability, Client, Domain,
+    device::{SerialNumber, StorageInfo},
+    object::{self, Id, Label, Type},
+    wrap, Algorithm, Capability, Credentials, Domain,
 };
-use zeroize::Zeroize;
+use zeroize::{Zeroize, Zeroizing};
 
+pub mod backup;
+pub mod cert;
 pub mod config;
+pub mod escrow;
+pub mod hsm;
+pub mod logging;
+pub mod pdf;
+pub mod pubkey;
+pub mod report;
+pub mod shares;
+#[cfg(feature = "tui")]
+mod tui;
+
+pub use backup::{BackupFormat, BackupMetadata, WrapBackup};
+pub use config::{
+    CeremonyMode, ConfigError, Hash, KeySpec, KeySpecBuilder, Purpose,
+};
+pub use hsm::Hsm;
+pub use report::{CeremonyReport, Report};
+pub use shares::{ShareBackend, ShareEncoding};
+
+use backup::WRAP_BACKUP_FILE;
+use config::{IdMismatch, OutputLayout, SummaryFormat, PROD_MIN_PASSWORD_LEN};
+use escrow::RecipientKey;
 
-use config::{KeySpec, Purpose};
+/// The types every caller of the ceremony API needs:
+/// `use oks_util::prelude::*;`.
+pub mod prelude {
+    pub use crate::{
+        BackupFormat, CeremonyMode, ConfigError, Confirm, Hsm, HsmError,
+        KeySpec, Purpose, Report, ShareBackend, ShareDisplay, ShareEncoding,
+        WrapKeyParams,
+    };
+}
 
+// the default wrap algorithm, see `config::OksWrapAlgorithm`
 const ALG: wrap::Algorithm = wrap::Algorithm::Aes256Ccm;
 const CAPS: Capability = Capability::all();
 const DELEGATED_CAPS: Capability = Capability::all();
 const DOMAIN: Domain = Domain::all();
 const ID: Id = 0x1;
-const KEY_LEN: usize = 32;
-const LABEL: &str = "backup";
+
+/// The length in bytes of a key for the provided wrap algorithm. This is the
+/// only source of the size of the wrap key so that it can't disagree w/ the
+/// algorithm the key is put into the YubiHSM with.
+fn key_len(alg: wrap::Algorithm) -> usize {
+    alg.key_len()
+}
+
+/// The label given to the wrap key when none is provided.
+pub const DEFAULT_WRAP_LABEL: &str = "backup";
 
 const SHARES: u8 = 5;
 const THRESHOLD: u8 = 3;
@@ -51,658 +120,9086 @@ pub enum HsmError {
     BadDomain,
     #[error("failed conversion from YubiHSM Label")]
     BadLabel,
+    #[error(
+        "invalid wrap key label \"{0}\": {} bytes, must be 1 to {}",
+        .0.len(),
+        config::LABEL_MAX
+    )]
+    BadWrapLabel(String),
+    #[error("{algorithm:?} wrap key must be {expected} bytes, got {found}")]
+    BadWrapKeyLen {
+        algorithm: wrap::Algorithm,
+        expected: usize,
+        found: usize,
+    },
+    #[error(
+        "shares recovered a {found} byte secret but the {algorithm:?} wrap \
+        key is {expected} bytes, are they from another ceremony?"
+    )]
+    RecoveredKeyLen {
+        algorithm: wrap::Algorithm,
+        expected: usize,
+        found: usize,
+    },
+    #[error("backup in {0} doesn't match the exported object")]
+    BackupVerifyFail(PathBuf),
+    #[error("operator declined to {0}")]
+    NotConfirmed(&'static str),
+    #[error("secret sharing selftest failed: {0}")]
+    SelfTestFail(String),
+    #[error("key spec for \"{0}\" duplicates the id or label of another")]
+    DuplicateKeySpec(String),
+    #[error(
+        "not enough storage in the YubiHSM: keys need {records} records \
+        and {bytes} bytes, {free_records} records and {free_bytes} bytes \
+        are free"
+    )]
+    InsufficientStorage {
+        records: usize,
+        bytes: usize,
+        free_records: usize,
+        free_bytes: usize,
+    },
+    #[error("malformed share: {0}")]
+    BadShare(String),
+    #[error("share is in {found} encoding, previous shares are in {expected}")]
+    MixedShareEncoding {
+        expected: ShareEncoding,
+        found: ShareEncoding,
+    },
+    #[error("share {0} has already been entered")]
+    DuplicateShare(String),
+    #[error(
+        "share w/ check digest {0} isn't one of the shares in the manifest, \
+        it may be from before the shares were refreshed"
+    )]
+    UnknownShare(String),
+    #[error("no free id for a new object of type {0:?}")]
+    NoFreeId(Type),
+    #[error("no {1} w/ id {0} in the YubiHSM")]
+    NoSuchObject(Id, Type),
+    #[error("{1} w/ id {0} changed while it was rewrapped")]
+    RewrapChanged(Id, Type),
+    #[error("YubiHSM serial number is {found}, expected {expected}")]
+    SerialMismatch {
+        expected: SerialNumber,
+        found: SerialNumber,
+    },
+    #[error(
+        "key \"{label}\" from the interrupted ceremony doesn't match its \
+        spec: {mismatches}"
+    )]
+    ResumeMismatch { label: String, mismatches: String },
+    #[error("key \"{label}\" was created w/ id {found}, expected {expected}")]
+    IdMismatch {
+        label: String,
+        expected: Id,
+        found: Id,
+    },
+    #[error("unexpected PEM tag \"{0}\" for wrapped object")]
+    BadPemTag(String),
+    #[error("backup metadata is missing or has an invalid {0}")]
+    BadBackupMetadata(&'static str),
+    #[error("public key of unsupported algorithm or length: {0:?}")]
+    BadPublicKey(asymmetric::Algorithm),
+    #[error("key {label} is {found:?}, the key spec says {expected:?}")]
+    KeyAlgorithmMismatch {
+        label: String,
+        expected: asymmetric::Algorithm,
+        found: asymmetric::Algorithm,
+    },
+    #[error(
+        "unknown placeholder in share warning: {0}, expected {{shares}} or \
+        {{threshold}}"
+    )]
+    BadShareWarning(String),
+    #[error("bad openssl.cnf template: {0}")]
+    BadCnfTemplate(String),
     #[error("Invalid purpose for root CA key")]
     BadPurpose,
-    #[error("failed to generate certificate")]
-    CertGenFail,
-    #[error("failed to create self signed cert for key")]
-    SelfCertGenFail,
+    #[error("{0} is not allowed in {1} ceremony")]
+    NotAllowed(&'static str, CeremonyMode),
+    #[error("key purpose {purpose:?} not allowed in {mode} ceremony")]
+    ModePurpose {
+        mode: CeremonyMode,
+        purpose: Purpose,
+    },
+    #[error("output directory {path} is unusable: {reason}")]
+    BadOutDir { path: PathBuf, reason: String },
+    #[error(
+        "{object} in domains {domains:?} isn't accessible w/ an auth key in \
+        domains {auth_domains:?}"
+    )]
+    AuthDomains {
+        object: String,
+        domains: Vec<usize>,
+        auth_domains: Vec<usize>,
+    },
+    #[error("{0} is in use by something other than yubihsm-connector")]
+    ConnectorAddrInUse(&'static str),
+    #[error("refusing to reuse state of another CA: {0}")]
+    ConflictingCa(PathBuf),
+    #[error(
+        "invalid CA serial number \"{0}\": must be 1 to {CA_SERIAL_MAX_LEN} \
+        hex digits and not 0"
+    )]
+    BadCaSerial(String),
+    #[error("YubiHSM pkcs11 module not found: {0}")]
+    Pkcs11ModuleMissing(PathBuf),
+    #[error("refusing to overwrite existing file: {0}")]
+    OutputExists(PathBuf),
+    #[error(
+        "refusing to write key shares to {share_dir}: it's in the directory \
+        for public data {public}"
+    )]
+    ShareDirInPublic { share_dir: PathBuf, public: PathBuf },
+    #[error("refusing to wipe {0}: not a regular file")]
+    NotRegularFile(PathBuf),
+    #[error("not removing {0}: it was replaced while being wiped")]
+    WipedFileReplaced(PathBuf),
+    #[error(
+        "invalid ceremony name or id \"{0}\": must be letters, digits, \
+        '.', '_' and '-'"
+    )]
+    BadCeremonyName(String),
+    #[error("ceremony directory exists, refusing to reuse it: {0}")]
+    CeremonyExists(PathBuf),
+    #[error("no response to prompt within {0:?}, aborting")]
+    PromptTimeout(Duration),
+    #[error("interrupted by the operator")]
+    Interrupted,
+    #[error("password must be at least {0} characters")]
+    WeakPassword(usize),
+    #[error("key {0} can't sign: {1}")]
+    BadSigningKey(Id, String),
+    #[error("key spec {0} has no signature")]
+    KeySpecUnsigned(PathBuf),
+    #[error("signature on key spec {0} doesn't verify")]
+    KeySpecSignature(PathBuf),
+    #[error("cert public key isn't a P-256 or P-384 key")]
+    UnsupportedCertKey,
+    #[error("openssl {stage} failed w/ {status}: {}", stderr.trim())]
+    OpensslFailed {
+        stage: &'static str,
+        status: ExitStatus,
+        stdout: String,
+        stderr: String,
+    },
+    #[error("failed to parse certificate")]
+    CertParseFail,
+    #[error("bad recipient key {0}: {1}")]
+    BadRecipientKey(PathBuf, String),
+    #[error("failed to encrypt a key share to {0}: {1}")]
+    ShareEncryptFail(PathBuf, String),
+    #[error("{found} recipient keys for {expected} key shares")]
+    RecipientCount { expected: usize, found: usize },
+    #[error("unable to recover key: {0}")]
+    RecoverFail(String),
+    #[error("wrap key fingerprint is {found}, expected {expected}")]
+    FingerprintMismatch { expected: String, found: String },
+    #[error(
+        "wrap key w/ id {id} and label \"{label}\" already exists, use \
+        --force to replace it"
+    )]
+    WrapKeyExists { id: Id, label: String },
+    #[error("no wrap key w/ id {0}")]
+    NoWrapKey(Id),
+    #[error(
+        "migrated backups must be written to a directory other than the \
+        old backups: {0}"
+    )]
+    MigrateInPlace(PathBuf),
+    #[error("wrap key doesn't decrypt backup {0}")]
+    BackupDecryptFail(PathBuf),
+    #[error("wrap key {id} is missing capabilities: {}", missing.join(", "))]
+    WrapKeyCapabilities { id: Id, missing: Vec<String> },
+    #[error(
+        "wrap key {id} can't import {} w/ all its capabilities, its \
+        delegated capabilities are missing: {}",
+        path.display(),
+        missing.join(", ")
+    )]
+    WrapKeyDelegated {
+        id: Id,
+        path: PathBuf,
+        missing: Vec<String>,
+    },
+    #[error("the YubiHSM doesn't support the {0:?} wrap algorithm")]
+    UnsupportedWrapAlgorithm(wrap::Algorithm),
     #[error("your yubihms is broke")]
     Version,
 }
 
+/// The parameters used when putting the wrap key into the YubiHSM. The
+/// same parameters must be used when the wrap key is restored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WrapKeyParams {
+    pub id: Id,
+    pub label: Label,
+    pub domains: Domain,
+    pub capabilities: Capability,
+    pub delegated_capabilities: Capability,
+    pub algorithm: wrap::Algorithm,
+}
+
+impl WrapKeyParams {
+    /// Create wrap key parameters with the provided label. All other
+    /// parameters take their default values, the algorithm is `Aes256Ccm`.
+    pub fn with_label(label: &str) -> Result<Self, HsmError> {
+        if label.is_empty() {
+            return Err(HsmError::BadWrapLabel(label.to_string()));
+        }
+        let label = config::check_label(label)
+            .map_err(|_| HsmError::BadWrapLabel(label.to_string()))?;
+
+        Ok(Self {
+            id: ID,
+            label,
+            domains: DOMAIN,
+            capabilities: CAPS,
+            delegated_capabilities: DELEGATED_CAPS,
+            algorithm: ALG,
+        })
+    }
+
+    /// Log the capabilities and delegated capabilities of the wrap key.
+    fn log_caps(&self) {
+        debug!(
+            "wrap key capabilities: {}",
+            config::capability_names(self.capabilities).join(" | ")
+        );
+        debug!(
+            "wrap key delegated capabilities: {}",
+            config::capability_names(self.delegated_capabilities).join(" | ")
+        );
+    }
+}
+
 const PASSWD_PROMPT: &str = "Enter new HSM password: ";
 const PASSWD_PROMPT2: &str = "Enter password again to confirm: ";
 
-/// Generate an asymmetric key from the provided specification.
+/// Generate asymmetric keys from the provided specifications. `key_spec`
+/// is either a file holding a key spec or a JSON array of key specs, or a
+/// directory of such files. When `opts.check_names` is set the name of
+/// each file must follow the `<label>.keyspec.json` convention, see
+/// `config::check_key_spec_path`. All specs are checked before any key is
+/// created. Each key is exported under wrap and written to `out_dir` in
+/// `opts.format`. The domains of each key must be accessible w/ an auth key
+/// in `auth_domains`. The files for each key are written to the directory
+/// for it in `out_dir` from `opts.layout`. If `opts.verify_with` is
+/// provided the signature on each key spec file must verify w/ the key in
+/// that cert, see `verify_keyspecs`. The returned report describes the keys
+/// and files created.
+///
+/// The keys are generated one at a time. The YubiHSM executes one command
+/// at a time whether it's reached over USB or through yubihsm-connector
+/// over HTTP, which forwards to the same USB device, so concurrent workers
+/// wouldn't make a batch any faster. They would only interleave the steps
+/// of different keys in the report and the transcript.
 pub fn generate(
-    client: &Client,
+    client: &impl Hsm,
     key_spec: &Path,
+    auth_domains: Domain,
     out_dir: &Path,
-) -> Result<()> {
-    let json = fs::read_to_string(key_spec)?;
-    debug!("spec as json: {}", json);
+    opts: &CeremonyOptions,
+) -> Result<Report> {
+    generate_with_domain(
+        client,
+        key_spec,
+        None,
+        auth_domains,
+        out_dir,
+        opts,
+        false,
+    )
+}
 
-    let spec = config::KeySpec::from_str(&json)?;
-    debug!("KeySpec from {}: {:#?}", key_spec.display(), spec);
+/// Generate asymmetric keys like `generate` but in `domain` in place of the
+/// domains from the key specs, e.g. a scratch domain to rehearse a ceremony
+/// on production YubiHSMs w/o creating keys in the live domains. The
+/// YubiHSM restores an object imported from a backup to the domains it was
+/// exported from, so a key in the scratch domain can't be moved to another:
+/// after the rehearsal the keys are deleted and generated again.
+pub fn generate_in_domain(
+    client: &impl Hsm,
+    key_spec: &Path,
+    domain: Domain,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+) -> Result<Report> {
+    generate_with_domain(
+        client,
+        key_spec,
+        Some(domain),
+        auth_domains,
+        out_dir,
+        opts,
+        false,
+    )
+}
 
-    let id = client.generate_asymmetric_key(
-        spec.id,
-        spec.label.clone(),
-        spec.domain,
-        spec.capabilities,
-        spec.algorithm,
-    )?;
-    debug!("new {:#?} key w/ id: {}", spec.algorithm, id);
+/// Resume a `generate` ceremony that was interrupted part way through a
+/// batch of keys, w/ the same specs and output directory. A key that
+/// exists already must match its spec, see `verify_spec`, or nothing is
+/// done. It's skipped if its backup and attestation were written, and if
+/// it was created but not backed up or attested those are done now. The
+/// keys that don't exist are generated as usual. A backup is checked by
+/// decoding it and the id and label in its metadata, it can't be decrypted
+/// w/o the wrap key.
+pub fn resume_generate(
+    client: &impl Hsm,
+    key_spec: &Path,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+) -> Result<Report> {
+    generate_with_domain(
+        client,
+        key_spec,
+        None,
+        auth_domains,
+        out_dir,
+        opts,
+        true,
+    )
+}
 
-    debug!(
-        "exporting new asymmetric key under wrap-key w/ id: {}",
-        WRAP_ID
-    );
-    let msg = client.export_wrapped(WRAP_ID, Type::AsymmetricKey, id)?;
-    let msg_json = serde_json::to_string(&msg)?;
+// what's left to do for a key when resuming an interrupted ceremony
+#[derive(Debug, PartialEq)]
+enum KeyState {
+    New,
+    Done,
+    Unfinished { export: bool, attest: bool },
+}
 
-    debug!("exported asymmetric key: {:#?}", msg_json);
+// check that the backup of a key from an interrupted ceremony was written
+// in full: it decodes and its metadata names the key, `export_key` always
+// writes the metadata so a backup w/o it isn't one this ceremony wrote
+fn backup_written(spec: &KeySpec, path: &Path) -> bool {
+    match backup::read_backup(path) {
+        Ok((_, Some(metadata))) => {
+            metadata.id == spec.id && metadata.label == spec.label.to_string()
+        }
+        Ok((_, None)) => {
+            warn!(
+                "backup {} has no metadata, it can't be verified",
+                path.display()
+            );
+            false
+        }
+        Err(e) => {
+            warn!("backup {} is unreadable: {:#}", path.display(), e);
+            false
+        }
+    }
+}
 
-    let mut out_pathbuf = out_dir.to_path_buf();
-    out_pathbuf.push(format!("{}.wrap.json", spec.label));
+// find out what's left to do for the key described by the spec when
+// resuming an interrupted ceremony, an existing key must match its spec
+fn key_state(
+    client: &impl Hsm,
+    spec: &KeySpec,
+    out_dir: &Path,
+    mode: CeremonyMode,
+    format: BackupFormat,
+) -> Result<KeyState> {
+    let mismatches = verify_spec(client, spec)?;
+    if let [SpecMismatch::Missing(_)] = mismatches[..] {
+        return Ok(KeyState::New);
+    }
+    if !mismatches.is_empty() {
+        let mismatches: Vec<String> =
+            mismatches.iter().map(ToString::to_string).collect();
+        return Err(HsmError::ResumeMismatch {
+            label: spec.label.to_string(),
+            mismatches: mismatches.join("; "),
+        }
+        .into());
+    }
 
-    debug!("writing to: {}", out_pathbuf.display());
-    fs::write(out_pathbuf, msg_json)?;
+    let (out_path, attest_path) = key_paths(spec, out_dir, format);
+    let export = spec.is_exportable() && !backup_written(spec, &out_path);
+    let attest = mode.require_attestation() && !attest_path.is_file();
+    if export || attest {
+        info!(
+            "resuming: finishing key {}, export: {}, attest: {}",
+            spec.label, export, attest
+        );
+        Ok(KeyState::Unfinished { export, attest })
+    } else {
+        info!("resuming: skipping key {}, it's complete", spec.label);
+        Ok(KeyState::Done)
+    }
+}
 
-    // get yubihsm attestation
-    info!("Getting attestation for key with label: {}", spec.label);
-    let attest_cert = client.sign_attestation_certificate(2, None)?;
-    let attest_path = out_dir.join(format!("{}.attest.cert.pem", spec.label));
-    fs::write(attest_path, attest_cert)?;
+// the body of `generate`, the keys are created in `domain` if provided and
+// the keys from an interrupted ceremony are picked up if `resume` is set
+fn generate_with_domain(
+    client: &impl Hsm,
+    key_spec: &Path,
+    domain: Option<Domain>,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    resume: bool,
+) -> Result<Report> {
+    let (mode, format) = (opts.mode, opts.format);
+    check_out_dir(out_dir)?;
+    let mut specs = load_verified_key_specs(
+        key_spec,
+        opts.check_names,
+        opts.verify_with.as_deref(),
+    )?;
+    if let Some(domain) = domain {
+        info!(
+            "generating keys in domains {:?} in place of those from the specs",
+            config::domain_numbers(domain)
+        );
+        for spec in &mut specs {
+            spec.domain = domain;
+        }
+    }
 
-    Ok(())
-}
+    // check all specs before we create any keys
+    let (mut ids, mut labels) = (HashSet::new(), HashSet::new());
+    let mut todo = Vec::new();
+    for spec in &specs {
+        if !ids.insert(spec.id) || !labels.insert(spec.label.to_string()) {
+            return Err(
+                HsmError::DuplicateKeySpec(spec.label.to_string()).into()
+            );
+        }
+        check_purpose(mode, &spec.purpose)?;
+        check_export_policy(mode, spec)?;
+        warn_surplus_capabilities(spec);
+        check_domains(auth_domains, &spec.label.to_string(), spec.domain)?;
+        let key_dir = opts.layout.key_dir(out_dir, spec);
+        let state = if resume {
+            key_state(client, spec, &key_dir, mode, format)?
+        } else {
+            KeyState::New
+        };
+        let (out_path, attest_path) = key_paths(spec, &key_dir, format);
+        let (export, attest) = match state {
+            KeyState::New => (true, true),
+            KeyState::Done => continue,
+            KeyState::Unfinished { export, attest } => (export, attest),
+        };
+        if export {
+            check_collision(mode, &out_path)?;
+        }
+        if attest {
+            check_collision(mode, &attest_path)?;
+        }
+        todo.push((spec, key_dir, state));
+    }
+    let new: Vec<&KeySpec> = todo
+        .iter()
+        .filter(|(_, _, state)| *state == KeyState::New)
+        .map(|(spec, _, _)| *spec)
+        .collect();
+    check_storage(&client.get_storage_info()?, &new)?;
+    if todo.iter().any(|(spec, _, _)| spec.is_exportable()) {
+        check_wrap_capabilities(
+            client,
+            opts.wrap_id,
+            Capability::EXPORT_WRAPPED,
+        )?;
+    }
 
-// NOTE: before using the pkcs11 engine the connector must be running:
-// sudo systemctl start yubihsm-connector
-macro_rules! openssl_cnf_fmt {
-    () => {
-        r#"
-openssl_conf                = default_modules
+    let mut report = Report::default();
+    for (spec, key_dir, state) in todo {
+        if !key_dir.is_dir() {
+            debug!("creating directory: {}", key_dir.display());
+            fs::create_dir(&key_dir)?;
+        }
+        match state {
+            KeyState::New => {
+                generate_key(client, spec, &key_dir, opts, &mut report)?
+            }
+            KeyState::Unfinished { export, attest } => {
+                let (out_path, attest_path) = key_paths(spec, &key_dir, format);
+                if export {
+                    export_key(
+                        client,
+                        spec,
+                        opts.wrap_id,
+                        &out_path,
+                        format,
+                        &mut report,
+                    )?;
+                }
+                if attest {
+                    attest_key(client, spec, &attest_path, mode, &mut report)?;
+                }
+            }
+            KeyState::Done => (),
+        }
+    }
 
-[default_modules]
-engines                     = engine_section
-oid_section                 = OIDs
+    Ok(report)
+}
 
-[engine_section]
-pkcs11                      = pkcs11_section
+/// Name of the file in the output directory that `export_pubkeys` writes
+/// the bundle of public keys to.
+pub const PUBKEY_BUNDLE_FILE: &str = "public-keys.pem";
 
-[pkcs11_section]
-engine_id                   = pkcs11
-MODULE_PATH                 = /usr/lib/pkcs11/yubihsm_pkcs11.so
-INIT_ARGS                   = connector=http://127.0.0.1:12345 debug
-init                        = 0
-# PIN format: "<auth key id><auth key password>"
-# password must be 12 characters, 4 for the key id, 8 for the password
-#PIN                         = "0001password"
+/// Get the public key of the key generated for each of the provided specs
+/// and write each to `<label>.pub.pem` in `out_dir`, and all of them to a
+/// single bundle, `PUBKEY_BUNDLE_FILE`, for the relying parties. The keys
+/// are PEM encoded SubjectPublicKeyInfo w/ the label of the key before each,
+/// see `pubkey::spki_pem`. The paths of the files written are returned.
+pub fn export_pubkeys(
+    client: &impl Hsm,
+    specs: &[KeySpec],
+    out_dir: &Path,
+    mode: CeremonyMode,
+) -> Result<Vec<PathBuf>> {
+    let bundle_path = out_dir.join(PUBKEY_BUNDLE_FILE);
+    check_collision(mode, &bundle_path)?;
+    for spec in specs {
+        check_collision(
+            mode,
+            &out_dir.join(format!("{}.pub.pem", spec.label)),
+        )?;
+    }
 
-[ ca ]
-default_ca                  = CA_default
+    let mut written = Vec::new();
+    let mut bundle = String::new();
+    for spec in specs {
+        debug!("getting public key for key w/ id: {}", spec.id);
+        let key = client.get_public_key(spec.id)?;
+        if key.algorithm != spec.algorithm {
+            return Err(HsmError::KeyAlgorithmMismatch {
+                label: spec.label.to_string(),
+                expected: spec.algorithm,
+                found: key.algorithm,
+            }
+            .into());
+        }
+        let pem = pubkey::spki_pem(&key, &spec.label.to_string())?;
 
-[ CA_default ]
-dir                         = ./
-crl_dir                     = $dir/crl
-database                    = $dir/index.txt
-new_certs_dir               = $dir/newcerts
-certificate                 = $dir/ca.cert.pem
-serial                      = $dir/serial
-# key format:   <slot>:<key id>
-private_key                 = 0:{key:#04}
-name_opt                    = ca_default
-cert_opt                    = ca_default
-# certs may be retired, but they won't expire
-default_enddate             = 99991231235959Z
-default_crl_days            = 30
-default_md                  = {hash:?}
-preserve                    = no
-policy                      = policy_match
-email_in_dn                 = no
-rand_serial                 = no
-unique_subject              = yes
+        let path = out_dir.join(format!("{}.pub.pem", spec.label));
+        debug!("writing public key to: {}", path.display());
+        fs::write(&path, &pem)?;
+        written.push(path);
+        bundle.push_str(&pem);
+    }
+    debug!("writing public key bundle to: {}", bundle_path.display());
+    fs::write(&bundle_path, bundle)?;
+    written.push(bundle_path);
 
-[ policy_match ]
-countryName                 = optional
-stateOrProvinceName         = optional
-organizationName            = optional
-organizationalUnitName      = optional
-commonName                  = supplied
-emailAddress                = optional
+    Ok(written)
+}
 
-[ req ]
-default_md                  = {hash:?}
-string_mask                 = utf8only
-default_enddate             = 99991231235959Z
+/// Prefix of the values in the key specs written by `export_keyspecs` for
+/// the fields the YubiHSM doesn't record. A key spec w/ one of these won't
+/// parse until it's completed by hand.
+pub const KEYSPEC_PLACEHOLDER: &str = "TODO:";
 
-[ v3_code_signing_prod_ca ]
-subjectKeyIdentifier        = hash
-authorityKeyIdentifier      = keyid:always,issuer
-basicConstraints            = critical,CA:true
-keyUsage                    = critical, keyCertSign, cRLSign
+/// Write a best effort key spec, `<label>.keyspec.json`, to `out_dir` for
+/// each asymmetric key in the YubiHSM, e.g. to bootstrap the specs for a
+/// YubiHSM provisioned before this tool. The id, label and capabilities
+/// come from the YubiHSM. The common name, hash and purpose aren't
+/// recorded there, and key specs can only express some domains and
+/// algorithms, so these fields get a value starting w/
+/// `KEYSPEC_PLACEHOLDER` that must be completed by hand. Existing files
+/// are never overwritten. The paths of the files written are returned.
+pub fn export_keyspecs(
+    client: &impl Hsm,
+    out_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let todo = |what: &str| {
+        serde_json::Value::from(format!("{} {}", KEYSPEC_PLACEHOLDER, what))
+    };
+    let unsupported = |algorithm: &dyn fmt::Debug| {
+        todo(&format!(
+            "algorithm {:?} isn't allowed in a key spec",
+            algorithm
+        ))
+    };
+    let mut specs = Vec::new();
+    for info in object_infos(client)?.into_values() {
+        if info.object_type != Type::AsymmetricKey {
+            continue;
+        }
+        let algorithm = match info.algorithm.asymmetric() {
+            Some(asymmetric::Algorithm::Rsa4096) => {
+                serde_json::to_value(config::OksAlgorithm::Rsa4096)?
+            }
+            Some(asymmetric::Algorithm::EcP384) => {
+                serde_json::to_value(config::OksAlgorithm::Ecp384)?
+            }
+            Some(algorithm) => unsupported(&algorithm),
+            None => unsupported(&info.algorithm),
+        };
+        let domain = if info.domains == Domain::DOM1 {
+            serde_json::to_value(config::OksDomain::DOM1)?
+        } else {
+            todo(&format!(
+                "domains {:?} can't be expressed in a key spec",
+                config::domain_numbers(info.domains)
+            ))
+        };
+        let spec = serde_json::json!({
+            "common_name": todo("common name isn't recorded in the YubiHSM"),
+            "id": info.object_id,
+            "algorithm": algorithm,
+            "capabilities": config::capability_names(info.capabilities),
+            "domain": domain,
+            "hash": todo("hash isn't recorded in the YubiHSM"),
+            "label": info.label.to_string(),
+            "purpose": todo("purpose isn't recorded in the YubiHSM"),
+        });
+        let path = out_dir.join(format!(
+            "{}{}",
+            file_safe(&info.label),
+            config::KEY_SPEC_SUFFIX
+        ));
+        if path.exists() || specs.iter().any(|(p, _)| *p == path) {
+            return Err(HsmError::OutputExists(path).into());
+        }
+        specs.push((path, spec));
+    }
 
-[ v3_code_signing_prod ]
-subjectKeyIdentifier        = hash
-authorityKeyIdentifier      = keyid:always,issuer
-basicConstraints            = critical,CA:false
-keyUsage                    = critical, digitalSignature
+    let mut written = Vec::new();
+    for (path, spec) in specs {
+        let mut json = serde_json::to_string_pretty(&spec)?;
+        json.push('\n');
+        debug!("writing key spec to: {}", path.display());
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?
+            .write_all(json.as_bytes())?;
+        written.push(path);
+    }
+    if !written.is_empty() {
+        warn!(
+            "the key specs written to {} are incomplete: fill in the fields \
+            starting w/ \"{}\"",
+            out_dir.display(),
+            KEYSPEC_PLACEHOLDER
+        );
+    }
 
-[ v3_code_signing_dev_ca ]
-subjectKeyIdentifier        = hash
-authorityKeyIdentifier      = keyid:always,issuer
-basicConstraints            = critical,CA:true
-keyUsage                    = critical, keyCertSign, cRLSign
-certificatePolicies         = critical,development-device-only
+    Ok(written)
+}
 
-[ v3_code_signing_dev ]
-subjectKeyIdentifier        = hash
-authorityKeyIdentifier      = keyid:always,issuer
-basicConstraints            = critical,CA:false
-keyUsage                    = critical, digitalSignature
-certificatePolicies         = critical,development-device-only
+/// The free and used storage in the YubiHSM.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Storage {
+    pub free_records: usize,
+    pub used_records: usize,
+    pub free_bytes: usize,
+    pub used_bytes: usize,
+}
 
-[ v3_identity ]
-subjectKeyIdentifier        = hash
-authorityKeyIdentifier      = keyid:always,issuer
-basicConstraints            = critical,CA:true
-keyUsage                    = critical, keyCertSign, cRLSign
+impl From<&StorageInfo> for Storage {
+    fn from(info: &StorageInfo) -> Self {
+        let page_size = usize::from(info.page_size);
+        Storage {
+            free_records: info.free_records.into(),
+            used_records: (info.total_records - info.free_records).into(),
+            free_bytes: usize::from(info.free_pages) * page_size,
+            used_bytes: usize::from(info.total_pages - info.free_pages)
+                * page_size,
+        }
+    }
+}
 
-[ OIDs ]
-development-device-only = 1.3.6.1.4.1.57551.1
-"#
-    };
+impl fmt::Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} records free, {} used; {} bytes free, {} used",
+            self.free_records,
+            self.used_records,
+            self.free_bytes,
+            self.used_bytes
+        )
+    }
 }
 
-/// Get password for pkcs11 operations to keep the user from having to enter
-/// the password multiple times (once for signing the CSR, one for signing
-/// the cert). We also prefix the password with '0002' so the YubiHSM
-/// PKCS#11 module knows which key to use
-fn passwd_to_env(env_str: &str) -> Result<()> {
-    let mut password = "0002".to_string();
-    password.push_str(&rpassword::prompt_password("Enter YubiHSM Password: ")?);
-    std::env::set_var(env_str, password);
+/// Check that we're talking to the YubiHSM w/ the expected serial number.
+/// When several are plugged in this keeps a ceremony from initializing or
+/// resetting the wrong one, so it's done before anything else.
+pub fn check_serial(client: &impl Hsm, expected: SerialNumber) -> Result<()> {
+    let found = client.serial_number()?;
+    if found != expected {
+        return Err(HsmError::SerialMismatch { expected, found }.into());
+    }
+    info!("YubiHSM serial number: {}", found);
 
     Ok(())
 }
 
-pub fn ca_init(key_spec: &Path, ca_state: &Path, out: &Path) -> Result<()> {
-    let json = fs::read_to_string(key_spec)?;
-    debug!("spec as json: {}", json);
+/// Get the free and used storage in the YubiHSM.
+pub fn storage(client: &impl Hsm) -> Result<Storage> {
+    Ok(Storage::from(&client.get_storage_info()?))
+}
 
-    let spec = config::KeySpec::from_str(&json)?;
-    debug!("KeySpec from {}: {:#?}", key_spec.display(), spec);
+/// Version of the yubihsm crate this is built w/, from Cargo.lock, see
+/// build.rs. It's "unknown" if there was no Cargo.lock to read.
+pub const YUBIHSM_CRATE_VERSION: &str = env!("OKS_YUBIHSM_VERSION");
 
-    // sanity check: no signing keys at CA init
-    // this makes me think we need different types for this:
-    // one for the CA keys, one for the children we sign
-    match spec.purpose {
-        Purpose::ProductionCodeSigningCA
-        | Purpose::DevelopmentCodeSigningCA
-        | Purpose::Identity => (),
-        _ => return Err(HsmError::BadPurpose.into()),
+/// The versions of the software and hardware in use, for support tickets
+/// and audit records: when a YubiHSM is refused as incompatible this says
+/// exactly what was in play.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VersionReport {
+    pub tool: String,
+    pub yubihsm_crate: String,
+    /// What's listening at the address of the yubihsm-connector.
+    pub connector: ConnectorStatus,
+    pub firmware: (u8, u8, u8),
+    pub serial: SerialNumber,
+}
+
+impl fmt::Display for VersionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (major, minor, build) = self.firmware;
+        writeln!(f, "{}: {}", env!("CARGO_PKG_NAME"), self.tool)?;
+        writeln!(f, "yubihsm crate: {}", self.yubihsm_crate)?;
+        match &self.connector {
+            ConnectorStatus::Running(version) => writeln!(
+                f,
+                "yubihsm-connector: {}",
+                version.as_deref().unwrap_or("unknown version")
+            )?,
+            ConnectorStatus::Absent => {
+                writeln!(f, "yubihsm-connector: not running")?
+            }
+            ConnectorStatus::Other => writeln!(
+                f,
+                "yubihsm-connector: not running, something else is \
+                listening at {}",
+                CONNECTOR_ADDR
+            )?,
+        }
+        writeln!(f, "YubiHSM firmware: {}.{}.{}", major, minor, build)?;
+        write!(f, "YubiHSM serial number: {}", self.serial)
     }
+}
 
-    passwd_to_env("OKM_HSM_PKCS11_AUTH")?;
-    // check that password works before using it
-    // doing this after we've already created a buch of directories will
-    // leave us in an inconsistent state
+/// Get the versions of this tool, the yubihsm crate, the yubihsm-connector
+/// (if it's running) and the firmware of the YubiHSM along w/ its serial
+/// number.
+pub fn version_report(client: &impl Hsm) -> Result<VersionReport> {
+    version_report_at(client, CONNECTOR_ADDR)
+}
 
-    let pwd = std::env::current_dir()?;
-    debug!("got current directory: {:?}", pwd);
+// `version_report` w/ the connector at `connector_addr`
+fn version_report_at(
+    client: &impl Hsm,
+    connector_addr: &str,
+) -> Result<VersionReport> {
+    Ok(VersionReport {
+        tool: env!("CARGO_PKG_VERSION").to_string(),
+        yubihsm_crate: YUBIHSM_CRATE_VERSION.to_string(),
+        connector: connector_status(connector_addr)?,
+        firmware: client.firmware_version()?,
+        serial: client.serial_number()?,
+    })
+}
 
-    // setup CA directory structure
-    let label = spec.label.to_string();
-    let ca_dir = ca_state.join(&label);
-    info!("bootstrapping CA files in: {}", ca_dir.display());
-    fs::create_dir(&ca_dir)?;
-    debug!("setting current directory: {}", ca_dir.display());
-    std::env::set_current_dir(&ca_dir)?;
+/// Label of the key created and deleted by `benchmark`.
+pub const BENCHMARK_LABEL: &str = "oks-benchmark";
 
-    // copy the key spec file to the ca state dir
-    fs::write("key.spec", json)?;
+/// The latency of an operation timed by `benchmark`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Latency {
+    pub operation: String,
+    pub runs: usize,
+    pub mean: Duration,
+    pub median: Duration,
+    pub p99: Duration,
+}
 
-    bootstrap_ca(&spec)?;
+impl Latency {
+    fn from_samples(operation: &str, mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        let runs = samples.len();
+        // nearest rank, w/ a single sample all of them are that sample
+        let rank = |p: usize| samples[(runs * p).div_ceil(100).max(1) - 1];
+        Latency {
+            operation: operation.to_string(),
+            runs,
+            mean: samples.iter().sum::<Duration>() / runs as u32,
+            median: rank(50),
+            p99: rank(99),
+        }
+    }
+}
 
-    debug!("starting connector");
-    let mut connector = Command::new("yubihsm-connector").spawn()?;
+impl fmt::Display for Latency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "{}: {} runs, mean {:.1}ms, median {:.1}ms, p99 {:.1}ms",
+            self.operation,
+            self.runs,
+            ms(self.mean),
+            ms(self.median),
+            ms(self.p99)
+        )
+    }
+}
 
-    debug!("connector started");
-    thread::sleep(Duration::from_millis(1000));
+/// Time `runs` of each of a few cheap operations and a single key
+/// generation w/ the YubiHSM, so that a slow YubiHSM or connector can be
+/// told apart from a stuck ceremony before one starts. Nothing is changed:
+/// the key is created in `domains` w/ the label `BENCHMARK_LABEL` and
+/// deleted right away.
+pub fn benchmark(
+    client: &impl Hsm,
+    domains: Domain,
+    runs: usize,
+) -> Result<Vec<Latency>> {
+    fn time<T>(
+        runs: usize,
+        mut f: impl FnMut() -> Result<T>,
+    ) -> Result<Vec<Duration>> {
+        (0..runs.max(1))
+            .map(|_| {
+                let start = Instant::now();
+                f().map(|_| start.elapsed())
+            })
+            .collect()
+    }
 
-    // We're chdir-ing around and that makes it a PITA to keep track of file
-    // paths. Stashing everything in a tempdir make it easier to copy it all
-    // out when we're done.
-    let tmp_dir = TempDir::new()?;
-    let csr = tmp_dir.path().join(format!("{}.csr.pem", label));
+    let mut latencies = vec![Latency::from_samples(
+        "get-pseudo-random",
+        time(runs, || client.get_pseudo_random(32))?,
+    )];
+    // the auth key for the session is always there to get the info for
+    if let Some(entry) = client.list_objects()?.first() {
+        latencies.push(Latency::from_samples(
+            "get-object-info",
+            time(runs, || {
+                client.get_object_info(entry.object_id, entry.object_type)
+            })?,
+        ));
+    }
 
-    let mut cmd = Command::new("openssl");
-    let output = cmd
-        .arg("req")
-        .arg("-config")
-        .arg("openssl.cnf")
-        .arg("-new")
-        .arg("-subj")
-        .arg(format!("/CN={}/", spec.common_name))
-        .arg("-engine")
-        .arg("pkcs11")
-        .arg("-keyform")
-        .arg("engine")
-        .arg("-key")
-        .arg(format!("0:{:#04}", spec.id))
-        .arg("-passin")
-        .arg("env:OKM_HSM_PKCS11_AUTH")
-        .arg("-out")
-        .arg(&csr)
-        .output()?;
+    let start = Instant::now();
+    let id = client.generate_asymmetric_key(
+        0,
+        Label::from(BENCHMARK_LABEL),
+        domains,
+        Capability::SIGN_ECDSA,
+        asymmetric::Algorithm::EcP256,
+    )?;
+    let elapsed = start.elapsed();
+    client.delete_object(id, Type::AsymmetricKey)?;
+    latencies.push(Latency::from_samples(
+        "generate-asymmetric-key",
+        vec![elapsed],
+    ));
 
-    info!("executing command: \"{:#?}\"", cmd);
+    for latency in &latencies {
+        info!("{}", latency);
+    }
+    Ok(latencies)
+}
 
-    if !output.status.success() {
-        warn!("command failed with status: {}", output.status);
-        warn!("stderr: \"{}\"", String::from_utf8_lossy(&output.stderr));
-        connector.kill()?;
-        return Err(HsmError::SelfCertGenFail.into());
+/// Get the info for the object w/ the provided id and type formatted for
+/// display, one field per line. The domains and capabilities are expanded
+/// to their numbers and names.
+pub fn show_object(
+    client: &impl Hsm,
+    id: Id,
+    object_type: Type,
+) -> Result<String> {
+    let info = client
+        .get_object_info(id, object_type)?
+        .ok_or(HsmError::NoSuchObject(id, object_type))?;
+
+    Ok([
+        format!("Id: {}", info.object_id),
+        format!("Type: {}", info.object_type),
+        format!("Label: {}", info.label),
+        format!("Algorithm: {:?}", info.algorithm),
+        format!("Domains: {:?}", config::domain_numbers(info.domains)),
+        format!(
+            "Capabilities: {}",
+            config::capability_names(info.capabilities).join(", ")
+        ),
+        format!(
+            "Delegated Capabilities: {}",
+            config::capability_names(info.delegated_capabilities).join(", ")
+        ),
+        format!("Origin: {:?}", info.origin),
+        format!("Sequence: {}", info.sequence),
+    ]
+    .join("\n"))
+}
+
+// Estimate of the storage used by a key w/ the provided algorithm. RSA keys
+// are stored w/ the modulus and the 5 CRT parameters (each half the size of
+// the modulus), EC keys w/ the private scalar and the public point.
+fn key_storage(algorithm: asymmetric::Algorithm) -> usize {
+    let len = algorithm.key_len();
+    if matches!(
+        algorithm,
+        asymmetric::Algorithm::Rsa2048
+            | asymmetric::Algorithm::Rsa3072
+            | asymmetric::Algorithm::Rsa4096
+    ) {
+        len + 5 * len / 2
+    } else {
+        3 * len
     }
+}
 
-    //  generate cert for CA root
-    //  select v3 extensions from ... key spec?
-    let mut cmd = Command::new("openssl");
-    let output = cmd
-        .arg("ca")
-        .arg("-batch")
-        .arg("-selfsign")
-        .arg("-config")
-        .arg("openssl.cnf")
-        .arg("-engine")
-        .arg("pkcs11")
-        .arg("-keyform")
-        .arg("engine")
-        .arg("-keyfile")
-        .arg(format!("0:{:#04}", spec.id))
-        .arg("-extensions")
-        .arg(spec.purpose.to_string())
-        .arg("-passin")
-        .arg("env:OKM_HSM_PKCS11_AUTH")
-        .arg("-in")
-        .arg(&csr)
-        .arg("-out")
-        .arg("ca.cert.pem")
-        .output()?;
+/// Check that the keys described by the specs fit in the free storage of
+/// the YubiHSM. Each key takes a record and a whole number of pages.
+fn check_storage(
+    info: &StorageInfo,
+    specs: &[&KeySpec],
+) -> Result<(), HsmError> {
+    let page_size = usize::from(info.page_size.max(1));
+    let pages: usize = specs
+        .iter()
+        .map(|spec| key_storage(spec.algorithm).div_ceil(page_size))
+        .sum();
+    let (records, bytes) = (specs.len(), pages * page_size);
+    let storage = Storage::from(info);
+    debug!(
+        "keys need {} records and {} bytes, {}",
+        records, bytes, storage
+    );
 
-    info!("executing command: \"{:#?}\"", cmd);
+    if records > storage.free_records || bytes > storage.free_bytes {
+        Err(HsmError::InsufficientStorage {
+            records,
+            bytes,
+            free_records: storage.free_records,
+            free_bytes: storage.free_bytes,
+        })
+    } else {
+        Ok(())
+    }
+}
 
-    if !output.status.success() {
-        warn!("command failed with status: {}", output.status);
-        warn!("stderr: \"{}\"", String::from_utf8_lossy(&output.stderr));
-        connector.kill()?;
-        return Err(HsmError::SelfCertGenFail.into());
+/// Check that the wrap key w/ the provided id exists and has the
+/// capabilities required for the operation about to run, e.g.
+/// `Capability::EXPORT_WRAPPED` before exporting under wrap. A misconfigured
+/// wrap key then produces an error naming the missing capabilities instead
+/// of a cryptic failure from the YubiHSM.
+pub fn check_wrap_capabilities(
+    client: &impl Hsm,
+    wrap_id: Id,
+    required: Capability,
+) -> Result<()> {
+    let info = client
+        .get_object_info(wrap_id, Type::WrapKey)?
+        .ok_or(HsmError::NoWrapKey(wrap_id))?;
+    let missing = required - info.capabilities;
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(HsmError::WrapKeyCapabilities {
+            id: wrap_id,
+            missing: config::capability_names(missing),
+        }
+        .into())
     }
+}
 
-    connector.kill()?;
+/// Check that the delegated capabilities of the wrap key w/ the provided id
+/// include the capabilities recorded in the metadata of the backup at
+/// `path`. The YubiHSM gives an imported object no capabilities beyond the
+/// delegated capabilities of the wrap key, so w/o this a key could come
+/// back w/ fewer capabilities than it was backed up with. Backups w/o the
+/// capabilities in their metadata can't be checked.
+fn check_delegated_capabilities(
+    client: &impl Hsm,
+    wrap_id: Id,
+    path: &Path,
+    metadata: Option<&BackupMetadata>,
+) -> Result<()> {
+    let names = match metadata.and_then(|m| m.capabilities.as_ref()) {
+        Some(names) => names,
+        None => {
+            debug!("no capabilities in the metadata of {}", path.display());
+            return Ok(());
+        }
+    };
+    let info = client
+        .get_object_info(wrap_id, Type::WrapKey)?
+        .ok_or(HsmError::NoWrapKey(wrap_id))?;
+    let missing =
+        config::capabilities_from_names(names)? - info.delegated_capabilities;
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(HsmError::WrapKeyDelegated {
+            id: wrap_id,
+            path: path.to_path_buf(),
+            missing: config::capability_names(missing),
+        }
+        .into())
+    }
+}
 
-    let cert = tmp_dir.path().join(format!("{}.cert.pem", label));
-    fs::copy("ca.cert.pem", cert)?;
+/// Check that the output directory exists and that we can write to it. We
+/// do this before talking to the YubiHSM so that a bad path doesn't leave
+/// behind a key that we failed to back up.
+fn check_out_dir(out_dir: &Path) -> Result<()> {
+    let bad = |reason: String| HsmError::BadOutDir {
+        path: out_dir.to_path_buf(),
+        reason,
+    };
+    if !out_dir.is_dir() {
+        return Err(bad("not an existing directory".to_string()).into());
+    }
+    // the probe file is removed when dropped
+    tempfile::tempfile_in(out_dir).map_err(|e| bad(e.to_string()))?;
 
-    env::set_current_dir(pwd)?;
+    Ok(())
+}
 
-    // copy contents of temp directory to out
-    debug!("tmpdir: {:?}", tmp_dir);
-    let paths = fs::read_dir(tmp_dir.path())?
-        .map(|res| res.map(|e| e.path()))
-        .collect::<Result<Vec<_>, io::Error>>()?;
-    let opts = CopyOptions::default().overwrite(true);
-    fs_extra::move_items(&paths, out, &opts)?;
+/// Check that an object in `domains` is accessible w/ an auth key in
+/// `auth_domains`. A session can only create objects in the domains of its
+/// auth key so the domains of each object it manages, including the wrap
+/// key, must be a subset of them.
+pub fn check_domains(
+    auth_domains: Domain,
+    object: &str,
+    domains: Domain,
+) -> Result<(), HsmError> {
+    if domains.is_empty() || !auth_domains.contains(domains) {
+        Err(HsmError::AuthDomains {
+            object: object.to_string(),
+            domains: config::domain_numbers(domains),
+            auth_domains: config::domain_numbers(auth_domains),
+        })
+    } else {
+        Ok(())
+    }
+}
 
-    Ok(())
+/// Load the key specs from a file or from each JSON file in a directory,
+/// in the order of the file names, optionally checking the file names.
+pub fn load_key_specs(
+    key_spec: &Path,
+    check_names: bool,
+) -> Result<Vec<KeySpec>> {
+    load_verified_key_specs(key_spec, check_names, None)
 }
 
-pub fn ca_sign(
+// `load_key_specs`, checking the signature on each file w/ the cert at
+// `verify_with` (if any) before it's parsed, see `verify_keyspecs`
+fn load_verified_key_specs(
     key_spec: &Path,
-    csr: &Path,
-    state: &Path,
-    publish: &Path,
+    check_names: bool,
+    verify_with: Option<&Path>,
+) -> Result<Vec<KeySpec>> {
+    let cert_pem = verify_with.map(read_cert).transpose()?;
+    let mut specs = Vec::new();
+    for path in key_spec_paths(key_spec)? {
+        let json = fs::read(&path)?;
+        if let Some(cert_pem) = &cert_pem {
+            verify_keyspec(&path, &json, cert_pem)?;
+        }
+        let json = String::from_utf8(json).with_context(|| {
+            format!("Failed to parse key spec: {}", path.display())
+        })?;
+        debug!("spec as json: {}", json);
+
+        let more = KeySpec::many_from_str(&json).with_context(|| {
+            format!("Failed to parse key spec: {}", path.display())
+        })?;
+        debug!("KeySpecs from {}: {:#?}", path.display(), more);
+        if check_names {
+            config::check_key_spec_path(&path, &more)?;
+        }
+        specs.extend(more);
+    }
+
+    Ok(specs)
+}
+
+/// Summarize the key spec at `key_spec`, or every key spec in the
+/// directory, w/ the label, common name, purpose and algorithm of each key
+/// so they can be checked against the RFD.
+pub fn summarize_key_specs(
+    key_spec: &Path,
+    format: SummaryFormat,
+) -> Result<String> {
+    config::summarize_key_specs(&load_key_specs(key_spec, false)?, format)
+}
+
+/// Check the id of the key spec at `key_spec`, or of every key spec in the
+/// directory, against the id registry at `registry`. See
+/// `config::check_id_registry`.
+pub fn check_key_ids(
+    key_spec: &Path,
+    registry: &Path,
+) -> Result<Vec<IdMismatch>> {
+    let registry = config::load_id_registry(registry)?;
+    Ok(config::check_id_registry(
+        &registry,
+        &load_key_specs(key_spec, false)?,
+    ))
+}
+
+// the key spec file, or the JSON files in the key spec directory sorted by
+// name
+fn key_spec_paths(key_spec: &Path) -> Result<Vec<PathBuf>> {
+    if !key_spec.is_dir() {
+        return Ok(vec![key_spec.to_path_buf()]);
+    }
+
+    let mut paths = fs::read_dir(key_spec)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    paths.retain(|p| p.is_file() && p.extension() == Some("json".as_ref()));
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Parse the key spec at `key_spec`, or every key spec in the directory,
+/// returning the error for each file that fails. See
+/// `config::parse_all_collecting`.
+pub fn check_key_specs(
+    key_spec: &Path,
+) -> Result<Vec<(PathBuf, config::ConfigError)>> {
+    let (_, errors) = config::parse_all_collecting(&key_spec_paths(key_spec)?);
+    Ok(errors)
+}
+
+// the paths of the wrapped key and the attestation cert for a key spec
+fn key_paths(
+    spec: &KeySpec,
+    out_dir: &Path,
+    format: BackupFormat,
+) -> (PathBuf, PathBuf) {
+    (
+        out_dir.join(format.file_name(&spec.label.to_string())),
+        out_dir.join(format!("{}.attest.cert.pem", spec.label)),
+    )
+}
+
+// generate the key described by the spec, export it under wrap and get its
+// attestation, recording the key and the files written in the report
+fn generate_key(
+    client: &impl Hsm,
+    spec: &KeySpec,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    report: &mut Report,
 ) -> Result<()> {
-    // deserialize spec file
-    let json = fs::read_to_string(key_spec)?;
-    debug!("spec as json: {}", json);
+    let (mode, format) = (opts.mode, opts.format);
+    let (out_pathbuf, attest_path) = key_paths(spec, out_dir, format);
 
-    let spec = config::KeySpec::from_str(&json)?;
-    debug!("KeySpec from {}: {:#?}", key_spec.display(), spec);
+    debug!(
+        "generating key w/ capabilities: {}",
+        config::capability_names(spec.capabilities).join(" | ")
+    );
+    let id = report.time(&format!("generate {}", spec.label), || {
+        client.generate_asymmetric_key(
+            spec.id,
+            spec.label.clone(),
+            spec.domain,
+            spec.capabilities,
+            spec.algorithm,
+        )
+    })?;
+    debug!("new {:#?} key w/ id: {}", spec.algorithm, id);
+    report.add_object(id, Type::AsymmetricKey, &spec.label);
+    // everything downstream of the ceremony finds the key by the id in its
+    // spec, a key anywhere else may as well not exist
+    if id != spec.id {
+        error!(
+            "key {} was created w/ id {} in place of {}, it must be deleted \
+            before retrying",
+            spec.label, id, spec.id
+        );
+        return Err(HsmError::IdMismatch {
+            label: spec.label.to_string(),
+            expected: spec.id,
+            found: id,
+        }
+        .into());
+    }
 
-    // sanity check: no signing keys at CA init
-    // this makes me think we need different types for this:
-    // one for the CA keys, one for the children we sign
-    match spec.purpose {
-        Purpose::ProductionCodeSigning
-        | Purpose::DevelopmentCodeSigning
-        | Purpose::Identity => (),
-        _ => return Err(HsmError::BadPurpose.into()),
+    export_key(client, spec, opts.wrap_id, &out_pathbuf, format, report)?;
+    attest_key(client, spec, &attest_path, mode, report)
+}
+
+// export the key described by the spec under wrap to `out_path`, a key
+// that isn't exportable is recorded in the report as having no backup
+fn export_key(
+    client: &impl Hsm,
+    spec: &KeySpec,
+    wrap_id: Id,
+    out_path: &Path,
+    format: BackupFormat,
+    report: &mut Report,
+) -> Result<()> {
+    if spec.is_exportable() {
+        debug!(
+            "exporting new asymmetric key under wrap-key w/ id: {}",
+            wrap_id
+        );
+        let msg = report.time(&format!("export {}", spec.label), || {
+            client.export_wrapped(wrap_id, Type::AsymmetricKey, spec.id)
+        })?;
+        let metadata = backup_metadata(client, spec.id, Type::AsymmetricKey)?;
+        let msg = format.encode(msg, Some(&metadata))?;
+
+        debug!("exported asymmetric key: {:#?}", msg);
+
+        debug!("writing to: {}", out_path.display());
+        fs::write(out_path, msg)?;
+        report.add_file(out_path);
+    } else {
+        warn!(
+            "key {} isn't exportable under wrap: NO BACKUP EXISTS, it can't \
+            be recovered if the YubiHSM is lost",
+            spec.label
+        );
+        report.add_no_backup(&spec.label);
+    }
+
+    Ok(())
+}
+
+// get the attestation for the key described by the spec and write it to
+// `attest_path`, a failure is only an error if the mode requires it
+fn attest_key(
+    client: &impl Hsm,
+    spec: &KeySpec,
+    attest_path: &Path,
+    mode: CeremonyMode,
+    report: &mut Report,
+) -> Result<()> {
+    info!("Getting attestation for key with label: {}", spec.label);
+    // the attestation is for the key that was just generated, that's what a
+    // prod ceremony requires it for
+    let attest_cert = report.time(&format!("attest {}", spec.label), || {
+        client.sign_attestation_certificate(spec.id, None)
+    });
+    match attest_cert {
+        Ok(attest_cert) => {
+            fs::write(attest_path, attest_cert)?;
+            report.add_file(attest_path);
+        }
+        Err(e) if !mode.require_attestation() => {
+            warn!("{} ceremony: skipping attestation: {}", mode, e)
+        }
+        Err(e) => return Err(e),
+    }
+
+    Ok(())
+}
+
+/// Sign the canonical serialization of the ceremony report w/ the ECDSA key
+/// w/ the provided id, proving that the report was produced on this
+/// YubiHSM. The report is written to `path` and the DER encoded signature
+/// alongside it in `<path>.sig`. The signature can be checked w/ the public
+/// key from the cert for the signing key, e.g.:
+/// `openssl dgst -sha384 -verify key.pub.pem -signature <path>.sig <path>`
+pub fn sign_report(
+    client: &impl Hsm,
+    signing_key_id: Id,
+    report: &CeremonyReport,
+    path: &Path,
+) -> Result<Report> {
+    let json = report.to_canonical_json()?;
+    let signature = sign_ecdsa(client, signing_key_id, &json)?;
+
+    let sig_path = sig_path(path);
+    fs::write(path, &json)?;
+    fs::write(&sig_path, signature)?;
+    info!("signed report {} w/ key {}", path.display(), signing_key_id);
+
+    let mut written = Report::default();
+    written.add_file(path);
+    written.add_file(&sig_path);
+    Ok(written)
+}
+
+// sign `data` w/ the ECDSA key w/ the provided id over the digest that
+// matches the size of its curve
+fn sign_ecdsa(
+    client: &impl Hsm,
+    signing_key_id: Id,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let info = client
+        .get_object_info(signing_key_id, Type::AsymmetricKey)?
+        .ok_or_else(|| {
+            HsmError::BadSigningKey(signing_key_id, "no such key".to_string())
+        })?;
+    let digest = match info.algorithm {
+        Algorithm::Asymmetric(
+            asymmetric::Algorithm::EcP256
+            | asymmetric::Algorithm::EcK256
+            | asymmetric::Algorithm::EcBp256,
+        ) => Sha256::digest(data).to_vec(),
+        Algorithm::Asymmetric(
+            asymmetric::Algorithm::EcP384 | asymmetric::Algorithm::EcBp384,
+        ) => Sha384::digest(data).to_vec(),
+        Algorithm::Asymmetric(
+            asymmetric::Algorithm::EcP521 | asymmetric::Algorithm::EcBp512,
+        ) => Sha512::digest(data).to_vec(),
+        algorithm => {
+            return Err(HsmError::BadSigningKey(
+                signing_key_id,
+                format!("{:?} isn't an ECDSA algorithm", algorithm),
+            )
+            .into())
+        }
+    };
+    client.sign_ecdsa_prehash_raw(signing_key_id, &digest)
+}
+
+// the detached signature for the file at `path` is written alongside it
+fn sig_path(path: &Path) -> PathBuf {
+    let mut sig_path = path.as_os_str().to_owned();
+    sig_path.push(".sig");
+    PathBuf::from(sig_path)
+}
+
+/// Sign each key spec file in `dir` (or the single key spec file) w/ the
+/// ECDSA key w/ the provided id, e.g. an existing identity key. The DER
+/// encoded signature for each is written alongside it in `<file>.sig`, see
+/// `verify_keyspecs`. The key must be on the P-256 or P-384 curve, the
+/// signatures `verify_keyspecs` can check, or nothing is signed.
+pub fn sign_keyspecs(
+    client: &impl Hsm,
+    signing_key_id: Id,
+    dir: &Path,
+) -> Result<Report> {
+    let info = client
+        .get_object_info(signing_key_id, Type::AsymmetricKey)?
+        .ok_or_else(|| {
+            HsmError::BadSigningKey(signing_key_id, "no such key".to_string())
+        })?;
+    match info.algorithm {
+        Algorithm::Asymmetric(
+            asymmetric::Algorithm::EcP256 | asymmetric::Algorithm::EcP384,
+        ) => (),
+        algorithm => {
+            return Err(HsmError::BadSigningKey(
+                signing_key_id,
+                format!(
+                    "signatures w/ {:?} keys can't be verified, only P-256 \
+                    & P-384",
+                    algorithm
+                ),
+            )
+            .into())
+        }
+    }
+
+    let mut written = Report::default();
+    for path in key_spec_paths(dir)? {
+        let signature = sign_ecdsa(client, signing_key_id, &fs::read(&path)?)?;
+        let sig_path = sig_path(&path);
+        fs::write(&sig_path, signature)?;
+        info!(
+            "signed key spec {} w/ key {}",
+            path.display(),
+            signing_key_id
+        );
+        written.add_file(&sig_path);
+    }
+
+    Ok(written)
+}
+
+/// Check the signature made by `sign_keyspecs` on the key spec file at
+/// `key_spec`, or on each key spec file in the directory, w/ the public key
+/// from the PEM encoded `cert`. A key spec w/o a signature or w/ one that
+/// doesn't verify is an error: it may have been tampered w/ on the media.
+pub fn verify_keyspecs(key_spec: &Path, cert: &Path) -> Result<()> {
+    let cert_pem = read_cert(cert)?;
+    for path in key_spec_paths(key_spec)? {
+        verify_keyspec(&path, &fs::read(&path)?, &cert_pem)?;
+    }
+
+    Ok(())
+}
+
+fn read_cert(cert: &Path) -> Result<Vec<u8>> {
+    fs::read(cert)
+        .with_context(|| format!("Failed to read cert: {}", cert.display()))
+}
+
+// check the signature on the contents of the key spec file at `path`, the
+// contents are passed in so that the bytes checked are the bytes used
+fn verify_keyspec(path: &Path, json: &[u8], cert_pem: &[u8]) -> Result<()> {
+    let signature = match fs::read(sig_path(path)) {
+        Ok(signature) => signature,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(HsmError::KeySpecUnsigned(path.to_path_buf()).into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if !cert::verify_detached(cert_pem, json, &signature)? {
+        return Err(HsmError::KeySpecSignature(path.to_path_buf()).into());
+    }
+    debug!("signature on key spec {} verified", path.display());
+
+    Ok(())
+}
+
+/// Refuse to create keys for purposes that aren't allowed in the current
+/// ceremony mode.
+fn check_purpose(mode: CeremonyMode, purpose: &Purpose) -> Result<()> {
+    if mode.allows_purpose(purpose) {
+        Ok(())
+    } else {
+        Err(HsmError::ModePurpose {
+            mode,
+            purpose: *purpose,
+        }
+        .into())
+    }
+}
+
+/// Refuse to create signing keys that can be exported under wrap if the
+/// ceremony mode enforces the policy, otherwise warn about them.
+fn check_export_policy(mode: CeremonyMode, spec: &KeySpec) -> Result<()> {
+    match spec.check_export_policy() {
+        Err(e) if mode.enforces_export_policy() => Err(e.into()),
+        Err(e) => {
+            warn!("{} ceremony: {}", mode, e);
+            Ok(())
+        }
+        Ok(()) => Ok(()),
+    }
+}
+
+/// Warn about the capabilities in the spec that the purpose of the key
+/// doesn't need, in any mode, so that over-broad specs are caught in review.
+fn warn_surplus_capabilities(spec: &KeySpec) {
+    let surplus = spec.surplus_capabilities();
+    if !surplus.is_empty() {
+        warn!(
+            "key {} has capabilities that purpose {:?} doesn't need: {}",
+            spec.label,
+            spec.purpose,
+            config::capability_names(surplus).join(", ")
+        );
+    }
+}
+
+/// Check whether writing to the provided path would overwrite an existing
+/// file. This is an error if the ceremony mode refuses collisions.
+fn check_collision(mode: CeremonyMode, path: &Path) -> Result<()> {
+    if !path.exists() {
+        Ok(())
+    } else if mode.refuse_collisions() {
+        Err(HsmError::OutputExists(path.to_path_buf()).into())
+    } else {
+        warn!("{} ceremony: overwriting file: {}", mode, path.display());
+        Ok(())
+    }
+}
+
+/// A difference between a key spec and the object in the YubiHSM at the id
+/// from the spec.
+#[derive(Error, Debug, PartialEq)]
+pub enum SpecMismatch {
+    #[error("no asymmetric key w/ id {0}")]
+    Missing(Id),
+    #[error("label is \"{found}\", expected \"{expected}\"")]
+    Label { expected: String, found: String },
+    #[error("domains are {found:?}, expected {expected:?}")]
+    Domains {
+        expected: Vec<usize>,
+        found: Vec<usize>,
+    },
+    #[error("capabilities missing: {missing:?}, unexpected: {unexpected:?}")]
+    Capabilities {
+        missing: Vec<String>,
+        unexpected: Vec<String>,
+    },
+    #[error("algorithm is {found:?}, expected {expected:?}")]
+    Algorithm {
+        expected: asymmetric::Algorithm,
+        found: Algorithm,
+    },
+}
+
+/// Compare the asymmetric key at the id from the key spec w/ the spec. Each
+/// difference in label, domains, capabilities and algorithm is returned.
+/// This catches keys that the YubiHSM created w/ other parameters than we
+/// asked for and keys created from a different spec.
+pub fn verify_spec(
+    client: &impl Hsm,
+    spec: &KeySpec,
+) -> Result<Vec<SpecMismatch>> {
+    let info = match client.get_object_info(spec.id, Type::AsymmetricKey)? {
+        Some(info) => info,
+        None => return Ok(vec![SpecMismatch::Missing(spec.id)]),
+    };
+    debug!("object info for key {}: {:#?}", spec.id, info);
+
+    let mut report = Vec::new();
+    if info.label != spec.label {
+        report.push(SpecMismatch::Label {
+            expected: spec.label.to_string(),
+            found: info.label.to_string(),
+        });
+    }
+    if info.domains != spec.domain {
+        report.push(SpecMismatch::Domains {
+            expected: config::domain_numbers(spec.domain),
+            found: config::domain_numbers(info.domains),
+        });
+    }
+    if info.capabilities != spec.capabilities {
+        report.push(SpecMismatch::Capabilities {
+            missing: config::capability_names(
+                spec.capabilities - info.capabilities,
+            ),
+            unexpected: config::capability_names(
+                info.capabilities - spec.capabilities,
+            ),
+        });
+    }
+    if info.algorithm != Algorithm::Asymmetric(spec.algorithm) {
+        report.push(SpecMismatch::Algorithm {
+            expected: spec.algorithm,
+            found: info.algorithm,
+        });
+    }
+
+    Ok(report)
+}
+
+/// A difference between the objects in two YubiHSMs, see `compare_hsms`.
+/// Objects are matched by id and type, `a` and `b` are the values from the
+/// first and second YubiHSM.
+#[derive(Error, Debug, PartialEq)]
+pub enum ObjectDiff {
+    #[error("{object_type} {id} \"{label}\" is only in the first YubiHSM")]
+    OnlyInA {
+        id: Id,
+        object_type: Type,
+        label: String,
+    },
+    #[error("{object_type} {id} \"{label}\" is only in the second YubiHSM")]
+    OnlyInB {
+        id: Id,
+        object_type: Type,
+        label: String,
+    },
+    #[error("{object_type} {id}: label is \"{a}\" and \"{b}\"")]
+    Label {
+        id: Id,
+        object_type: Type,
+        a: String,
+        b: String,
+    },
+    #[error("{object_type} {id}: domains are {a:?} and {b:?}")]
+    Domains {
+        id: Id,
+        object_type: Type,
+        a: Vec<usize>,
+        b: Vec<usize>,
+    },
+    #[error(
+        "{object_type} {id}: capabilities only in the first: {only_a:?}, \
+        only in the second: {only_b:?}"
+    )]
+    Capabilities {
+        id: Id,
+        object_type: Type,
+        only_a: Vec<String>,
+        only_b: Vec<String>,
+    },
+    #[error("{object_type} {id}: algorithm is {a:?} and {b:?}")]
+    Algorithm {
+        id: Id,
+        object_type: Type,
+        a: Algorithm,
+        b: Algorithm,
+    },
+}
+
+// the info for each object in the YubiHSM keyed by id and type
+fn object_infos(
+    client: &impl Hsm,
+) -> Result<BTreeMap<(Id, Type), object::Info>> {
+    let mut infos = BTreeMap::new();
+    for entry in client.list_objects()? {
+        let (id, object_type) = (entry.object_id, entry.object_type);
+        // an object deleted since it was listed isn't a difference
+        if let Some(info) = client.get_object_info(id, object_type)? {
+            infos.insert((id, object_type), info);
+        }
+    }
+
+    Ok(infos)
+}
+
+// the differences between two objects w/ the same id and type
+fn compare_objects(a: &object::Info, b: &object::Info) -> Vec<ObjectDiff> {
+    let (id, object_type) = (a.object_id, a.object_type);
+    let mut diffs = Vec::new();
+    if a.label != b.label {
+        diffs.push(ObjectDiff::Label {
+            id,
+            object_type,
+            a: a.label.to_string(),
+            b: b.label.to_string(),
+        });
+    }
+    if a.domains != b.domains {
+        diffs.push(ObjectDiff::Domains {
+            id,
+            object_type,
+            a: config::domain_numbers(a.domains),
+            b: config::domain_numbers(b.domains),
+        });
+    }
+    if a.capabilities != b.capabilities {
+        diffs.push(ObjectDiff::Capabilities {
+            id,
+            object_type,
+            only_a: config::capability_names(a.capabilities - b.capabilities),
+            only_b: config::capability_names(b.capabilities - a.capabilities),
+        });
+    }
+    if a.algorithm != b.algorithm {
+        diffs.push(ObjectDiff::Algorithm {
+            id,
+            object_type,
+            a: a.algorithm,
+            b: b.algorithm,
+        });
+    }
+
+    diffs
+}
+
+/// Compare the objects in two YubiHSMs, e.g. a primary and the backup that
+/// should mirror it. Objects are matched by id and type. Objects in only
+/// one of them are returned, as are the differences in label, domains,
+/// capabilities and algorithm of the objects in both. The differences are
+/// ordered by id and type, an empty list means the inventories match.
+pub fn compare_hsms(
+    client_a: &impl Hsm,
+    client_b: &impl Hsm,
+) -> Result<Vec<ObjectDiff>> {
+    let a = object_infos(client_a)?;
+    let b = object_infos(client_b)?;
+    debug!("comparing {} objects w/ {}", a.len(), b.len());
+
+    let keys: BTreeSet<&(Id, Type)> = a.keys().chain(b.keys()).collect();
+    let mut diffs = Vec::new();
+    for key @ &(id, object_type) in keys {
+        match (a.get(key), b.get(key)) {
+            (Some(a), Some(b)) => diffs.extend(compare_objects(a, b)),
+            (Some(a), None) => diffs.push(ObjectDiff::OnlyInA {
+                id,
+                object_type,
+                label: a.label.to_string(),
+            }),
+            (None, Some(b)) => diffs.push(ObjectDiff::OnlyInB {
+                id,
+                object_type,
+                label: b.label.to_string(),
+            }),
+            (None, None) => unreachable!("key is from one of the maps"),
+        }
+    }
+
+    Ok(diffs)
+}
+
+/// Places the YubiHSM pkcs11 module is installed on the platforms we know
+/// of, the first that exists is the default.
+#[cfg(target_os = "macos")]
+const PKCS11_MODULES: &[&str] = &[
+    "/usr/local/lib/pkcs11/yubihsm_pkcs11.dylib",
+    "/opt/homebrew/lib/pkcs11/yubihsm_pkcs11.dylib",
+];
+#[cfg(not(target_os = "macos"))]
+const PKCS11_MODULES: &[&str] = &[
+    "/usr/lib/pkcs11/yubihsm_pkcs11.so",
+    "/usr/lib64/pkcs11/yubihsm_pkcs11.so",
+    "/usr/lib/x86_64-linux-gnu/pkcs11/yubihsm_pkcs11.so",
+    "/usr/lib/aarch64-linux-gnu/pkcs11/yubihsm_pkcs11.so",
+];
+
+/// Get the default path to the YubiHSM pkcs11 module for this platform:
+/// the first of the usual install locations that exists. Platforms w/o a
+/// fixed location (e.g. NixOS) must provide the path explicitly.
+pub fn default_pkcs11_module() -> PathBuf {
+    PKCS11_MODULES
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| PathBuf::from(PKCS11_MODULES[0]))
+}
+
+/// The PKCS#11 module that openssl reaches the YubiHSM through.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pkcs11Module {
+    /// The path to the module, see `default_pkcs11_module`.
+    pub path: PathBuf,
+    /// Enable debug output from the module, it logs verbosely.
+    pub debug: bool,
+}
+
+/// Address of the yubihsm-connector used by the pkcs11 engine.
+const CONNECTOR_ADDR: &str = "127.0.0.1:12345";
+const CONNECTOR_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What's listening at the address of the yubihsm-connector.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectorStatus {
+    Absent,
+    /// A yubihsm-connector, w/ the version it reports.
+    Running(Option<String>),
+    /// Something other than a yubihsm-connector.
+    Other,
+}
+
+// ask whatever is listening at `addr` for the yubihsm-connector status
+fn connector_status(addr: &str) -> Result<ConnectorStatus> {
+    let addr: SocketAddr = addr.parse()?;
+    let mut stream = match TcpStream::connect_timeout(&addr, CONNECTOR_TIMEOUT)
+    {
+        Ok(stream) => stream,
+        Err(_) => return Ok(ConnectorStatus::Absent),
+    };
+    stream.set_read_timeout(Some(CONNECTOR_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTOR_TIMEOUT))?;
+
+    let request =
+        format!("GET /connector/status HTTP/1.0\r\nHost: {}\r\n\r\n", addr);
+    let mut response = String::new();
+    let status = stream
+        .write_all(request.as_bytes())
+        .and_then(|_| stream.read_to_string(&mut response));
+    match status {
+        Ok(_) if response.lines().any(|l| l.trim() == "status=OK") => {
+            let version = response
+                .lines()
+                .find_map(|l| l.trim().strip_prefix("version="))
+                .map(str::to_string);
+            Ok(ConnectorStatus::Running(version))
+        }
+        _ => Ok(ConnectorStatus::Other),
+    }
+}
+
+/// The yubihsm-connector used by the pkcs11 engine. A connector that's
+/// already running (e.g. one managed by systemd) is reused, otherwise we
+/// start one. Only a connector that we started is killed.
+struct ConnectorProcess(Option<Child>);
+
+impl ConnectorProcess {
+    fn start() -> Result<Self> {
+        match connector_status(CONNECTOR_ADDR)? {
+            ConnectorStatus::Running(_) => {
+                info!("using running yubihsm-connector at {}", CONNECTOR_ADDR);
+                Ok(Self(None))
+            }
+            ConnectorStatus::Other => {
+                Err(HsmError::ConnectorAddrInUse(CONNECTOR_ADDR).into())
+            }
+            ConnectorStatus::Absent => {
+                debug!("starting connector");
+                let child = Command::new("yubihsm-connector").spawn()?;
+
+                debug!("connector started");
+                thread::sleep(Duration::from_millis(1000));
+                Ok(Self(Some(child)))
+            }
+        }
+    }
+
+    fn kill(&mut self) -> Result<()> {
+        if let Some(child) = &mut self.0 {
+            child.kill()?;
+        }
+
+        Ok(())
+    }
+}
+
+// the token appended to the pkcs11 INIT_ARGS to enable debug output
+fn pkcs11_init_debug(debug: bool) -> &'static str {
+    if debug {
+        " debug"
+    } else {
+        ""
+    }
+}
+
+// NOTE: before using the pkcs11 engine the connector must be running:
+// sudo systemctl start yubihsm-connector
+macro_rules! openssl_cnf_fmt {
+    () => {
+        r#"
+openssl_conf                = default_modules
+
+[default_modules]
+engines                     = engine_section
+oid_section                 = OIDs
+
+[engine_section]
+pkcs11                      = pkcs11_section
+
+[pkcs11_section]
+engine_id                   = pkcs11
+MODULE_PATH                 = {module}
+INIT_ARGS                   = connector=http://{connector}{debug}
+init                        = 0
+# PIN format: "<auth key id><auth key password>"
+# password must be 12 characters, 4 for the key id, 8 for the password
+#PIN                         = "0001password"
+
+[ ca ]
+default_ca                  = CA_default
+
+[ CA_default ]
+dir                         = ./
+crl_dir                     = $dir/crl
+database                    = $dir/index.txt
+new_certs_dir               = $dir/newcerts
+certificate                 = $dir/ca.cert.pem
+serial                      = $dir/serial
+# key format:   <slot>:<key id>
+private_key                 = 0:{key:#04}
+name_opt                    = ca_default
+cert_opt                    = ca_default
+# certs may be retired, but they won't expire
+default_enddate             = 99991231235959Z
+default_crl_days            = 30
+default_md                  = {hash}
+preserve                    = no
+policy                      = policy_match
+email_in_dn                 = no
+rand_serial                 = no
+unique_subject              = yes
+
+[ policy_match ]
+countryName                 = optional
+stateOrProvinceName         = optional
+organizationName            = optional
+organizationalUnitName      = optional
+commonName                  = supplied
+emailAddress                = optional
+
+[ req ]
+default_md                  = {hash}
+string_mask                 = utf8only
+default_enddate             = 99991231235959Z
+
+[ v3_code_signing_prod_ca ]
+subjectKeyIdentifier        = hash
+authorityKeyIdentifier      = keyid:always,issuer
+basicConstraints            = critical,CA:true
+keyUsage                    = critical, keyCertSign, cRLSign
+
+[ v3_code_signing_prod ]
+subjectKeyIdentifier        = hash
+authorityKeyIdentifier      = keyid:always,issuer
+basicConstraints            = critical,CA:false
+keyUsage                    = critical, digitalSignature
+
+[ v3_code_signing_dev_ca ]
+subjectKeyIdentifier        = hash
+authorityKeyIdentifier      = keyid:always,issuer
+basicConstraints            = critical,CA:true
+keyUsage                    = critical, keyCertSign, cRLSign
+certificatePolicies         = critical,development-device-only
+
+[ v3_code_signing_dev ]
+subjectKeyIdentifier        = hash
+authorityKeyIdentifier      = keyid:always,issuer
+basicConstraints            = critical,CA:false
+keyUsage                    = critical, digitalSignature
+certificatePolicies         = critical,development-device-only
+
+[ v3_identity ]
+subjectKeyIdentifier        = hash
+authorityKeyIdentifier      = keyid:always,issuer
+basicConstraints            = critical,CA:true
+keyUsage                    = critical, keyCertSign, cRLSign
+
+[ OIDs ]
+development-device-only = 1.3.6.1.4.1.57551.1
+"#
+    };
+}
+
+/// The placeholders an openssl.cnf template provided in place of
+/// `openssl_cnf_fmt` must have: w/o them openssl won't find the key in the
+/// YubiHSM.
+const CNF_TEMPLATE_REQUIRED: [&str; 4] = ["key", "hash", "module", "connector"];
+
+/// The built-in openssl.cnf template, a starting point for a template passed
+/// to `openssl_cnf`. Its `{key:#04}` is the same as `{key}`.
+pub fn builtin_cnf_template() -> &'static str {
+    openssl_cnf_fmt!()
+}
+
+// the format specs a placeholder may have: the built-in template is a format
+// string & `{key:#04}` is how it fills in the key id, same as `{key}`
+const CNF_TEMPLATE_SPECS: [(&str, &str); 1] = [("key", "#04")];
+
+// a piece of an openssl.cnf template: text that's copied as is or a `{...}`
+// placeholder w/o its format spec, `${var}` is openssl's own variable syntax
+// and is text
+enum CnfToken<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+// split an openssl.cnf template into text & placeholders
+fn cnf_tokens(template: &str) -> Result<Vec<CnfToken<'_>>, HsmError> {
+    let mut tokens = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start
+            + rest[start..].find('}').ok_or_else(|| {
+                HsmError::BadCnfTemplate(rest[start..].into())
+            })?;
+        if rest[..start].ends_with('$') {
+            tokens.push(CnfToken::Text(&rest[..=end]));
+        } else {
+            let placeholder = &rest[start + 1..end];
+            let name = match placeholder.split_once(':') {
+                Some((name, spec))
+                    if CNF_TEMPLATE_SPECS.contains(&(name, spec)) =>
+                {
+                    name
+                }
+                Some(_) => {
+                    return Err(HsmError::BadCnfTemplate(format!(
+                        "unsupported format spec {{{}}}",
+                        placeholder
+                    )))
+                }
+                None => placeholder,
+            };
+            tokens.push(CnfToken::Text(&rest[..start]));
+            tokens.push(CnfToken::Placeholder(name));
+        }
+        rest = &rest[end + 1..];
+    }
+    tokens.push(CnfToken::Text(rest));
+
+    Ok(tokens)
+}
+
+// the lines of the section w/ the provided header, e.g. `[ policy_match ]`
+fn cnf_section<'a>(cnf: &'a str, header: &str) -> Option<Vec<&'a str>> {
+    let mut lines = cnf.lines().skip_while(|line| line.trim() != header);
+    lines.next()?;
+    Some(lines.take_while(|line| !line.starts_with('[')).collect())
+}
+
+/// Check an openssl.cnf template for the CA of `key_spec` before it's used,
+/// see `openssl_cnf` for the placeholders. The template must also have the
+/// sections the extensions from the key spec are put in: the v3 extensions
+/// section for its purpose w/ a keyUsage, and `[ policy_match ]`. This is
+/// done before the ceremony starts so that a bad template doesn't leave a
+/// CA half way through initialization.
+pub fn check_cnf_template(
+    template: &str,
+    key_spec: &KeySpec,
+) -> Result<(), HsmError> {
+    let placeholders: Vec<&str> = cnf_tokens(template)?
+        .into_iter()
+        .filter_map(|token| match token {
+            CnfToken::Placeholder(p) => Some(p),
+            CnfToken::Text(_) => None,
+        })
+        .collect();
+    if let Some(p) = placeholders.iter().find(|p| {
+        !CNF_TEMPLATE_REQUIRED.contains(p)
+            && !["debug", "label", "common_name"].contains(p)
+    }) {
+        return Err(HsmError::BadCnfTemplate(format!(
+            "unknown placeholder {{{}}}",
+            p
+        )));
+    }
+    if let Some(p) = CNF_TEMPLATE_REQUIRED
+        .iter()
+        .find(|p| !placeholders.contains(p))
+    {
+        return Err(HsmError::BadCnfTemplate(format!(
+            "missing placeholder {{{}}}",
+            p
+        )));
+    }
+
+    // w/o these `cnf_with_key_usage` & co have nothing to change and the
+    // certs would be issued w/o the extensions from the key spec
+    let purpose = format!("[ {} ]", key_spec.purpose);
+    match cnf_section(template, &purpose) {
+        Some(lines) if lines.iter().any(|l| l.starts_with("keyUsage")) => (),
+        Some(_) => {
+            return Err(HsmError::BadCnfTemplate(format!(
+                "no keyUsage in section {}",
+                purpose
+            )))
+        }
+        None => {
+            return Err(HsmError::BadCnfTemplate(format!(
+                "missing section {}",
+                purpose
+            )))
+        }
+    }
+    if cnf_section(template, "[ policy_match ]").is_none() {
+        return Err(HsmError::BadCnfTemplate(
+            "missing section [ policy_match ]".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Fill in the placeholders in an openssl.cnf template for the CA of
+/// `key_spec`, or in `openssl_cnf_fmt` if there's no template. In a template
+/// `{key}` is the id of the CA key as `private_key` expects it, `{hash}` the
+/// digest, `{module}` the path to the PKCS#11 module, `{connector}` the
+/// address of the connector, `{debug}` enables debug output from the
+/// module, `{label}` is the label of the CA key & `{common_name}` the common
+/// name of the CA. Any other placeholder is an error, see
+/// `check_cnf_template`. `builtin_cnf_template` is the template used w/o one.
+pub fn openssl_cnf(
+    template: Option<&str>,
+    key_spec: &KeySpec,
+    pkcs11: &Pkcs11Module,
+) -> Result<String, HsmError> {
+    let template = match template {
+        Some(t) => t,
+        None => {
+            return Ok(format!(
+                openssl_cnf_fmt!(),
+                key = key_spec.id,
+                hash = key_spec.hash.openssl_name(),
+                module = pkcs11.path.display(),
+                debug = pkcs11_init_debug(pkcs11.debug),
+                connector = CONNECTOR_ADDR,
+            ))
+        }
+    };
+    check_cnf_template(template, key_spec)?;
+
+    let mut cnf = String::new();
+    for token in cnf_tokens(template)? {
+        match token {
+            CnfToken::Text(text) => cnf.push_str(text),
+            CnfToken::Placeholder("key") => {
+                cnf.push_str(&format!("{:#04}", key_spec.id))
+            }
+            CnfToken::Placeholder("hash") => {
+                cnf.push_str(key_spec.hash.openssl_name())
+            }
+            CnfToken::Placeholder("module") => {
+                cnf.push_str(&pkcs11.path.display().to_string())
+            }
+            CnfToken::Placeholder("connector") => cnf.push_str(CONNECTOR_ADDR),
+            CnfToken::Placeholder("debug") => {
+                cnf.push_str(pkcs11_init_debug(pkcs11.debug))
+            }
+            CnfToken::Placeholder("label") => {
+                cnf.push_str(&key_spec.label.to_string())
+            }
+            CnfToken::Placeholder("common_name") => {
+                cnf.push_str(&key_spec.common_name)
+            }
+            CnfToken::Placeholder(_) => {
+                unreachable!("checked by check_cnf_template")
+            }
+        }
+    }
+
+    Ok(cnf)
+}
+
+/// Get password for pkcs11 operations to keep the user from having to enter
+/// the password multiple times (once for signing the CSR, one for signing
+/// the cert). We also prefix the password with '0002' so the YubiHSM
+/// PKCS#11 module knows which key to use
+fn passwd_to_env(env_str: &str) -> Result<()> {
+    let mut password = "0002".to_string();
+    password.push_str(&rpassword::prompt_password("Enter YubiHSM Password: ")?);
+    std::env::set_var(env_str, password);
+
+    Ok(())
+}
+
+pub fn ca_init(
+    key_spec: &Path,
+    ca_state: &Path,
+    out: &Path,
+    mode: CeremonyMode,
+    initial_serial: &CaSerial,
+    pkcs11: &Pkcs11Module,
+    cnf_template: Option<&Path>,
+) -> Result<()> {
+    let json = fs::read_to_string(key_spec)?;
+    debug!("spec as json: {}", json);
+
+    let spec = config::KeySpec::from_str(&json)?;
+    debug!("KeySpec from {}: {:#?}", key_spec.display(), spec);
+
+    // sanity check: no signing keys at CA init
+    // this makes me think we need different types for this:
+    // one for the CA keys, one for the children we sign
+    match spec.purpose {
+        Purpose::ProductionCodeSigningCA
+        | Purpose::DevelopmentCodeSigningCA
+        | Purpose::Identity => (),
+        _ => return Err(HsmError::BadPurpose.into()),
+    }
+    check_purpose(mode, &spec.purpose)?;
+    // openssl only fails to load the engine when it's first used
+    if !pkcs11.path.is_file() {
+        return Err(HsmError::Pkcs11ModuleMissing(pkcs11.path.clone()).into());
+    }
+    let cnf_template = cnf_template.map(fs::read_to_string).transpose()?;
+    if let Some(template) = &cnf_template {
+        check_cnf_template(template, &spec)?;
+    }
+
+    passwd_to_env("OKM_HSM_PKCS11_AUTH")?;
+    // check that password works before using it
+    // doing this after we've already created a buch of directories will
+    // leave us in an inconsistent state
+
+    let pwd = std::env::current_dir()?;
+    debug!("got current directory: {:?}", pwd);
+
+    // setup CA directory structure
+    let label = spec.label.to_string();
+    let ca_dir = spec.ca_dir(ca_state);
+    info!("bootstrapping CA files in: {}", ca_dir.display());
+    bootstrap_ca(
+        &ca_dir,
+        &spec,
+        &json,
+        initial_serial,
+        pkcs11,
+        cnf_template.as_deref(),
+    )?;
+
+    debug!("setting current directory: {}", ca_dir.display());
+    std::env::set_current_dir(&ca_dir)?;
+
+    let mut connector = ConnectorProcess::start()?;
+
+    // We're chdir-ing around and that makes it a PITA to keep track of file
+    // paths. Stashing everything in a tempdir make it easier to copy it all
+    // out when we're done.
+    let tmp_dir = TempDir::new()?;
+    let csr = tmp_dir.path().join(format!("{}.csr.pem", label));
+
+    let mut cmd = Command::new("openssl");
+    cmd.arg("req")
+        .arg("-config")
+        .arg("openssl.cnf")
+        .arg("-new")
+        .arg("-subj")
+        .arg(format!("/CN={}/", spec.common_name))
+        .arg("-engine")
+        .arg("pkcs11")
+        .arg("-keyform")
+        .arg("engine")
+        .arg("-key")
+        .arg(format!("0:{:#04}", spec.id))
+        .arg("-passin")
+        .arg("env:OKM_HSM_PKCS11_AUTH")
+        .arg("-out")
+        .arg(&csr);
+    let output = report::timed("openssl req", || cmd.output()).0?;
+
+    info!("executing command: \"{:#?}\"", cmd);
+
+    if let Err(e) = check_openssl("req", &output) {
+        connector.kill()?;
+        return Err(e.into());
+    }
+
+    //  generate cert for CA root
+    //  select v3 extensions from ... key spec?
+    let mut cmd = Command::new("openssl");
+    cmd.arg("ca")
+        .arg("-batch")
+        .arg("-selfsign")
+        .arg("-config")
+        .arg("openssl.cnf")
+        .arg("-engine")
+        .arg("pkcs11")
+        .arg("-keyform")
+        .arg("engine")
+        .arg("-keyfile")
+        .arg(format!("0:{:#04}", spec.id))
+        .arg("-extensions")
+        .arg(extensions(&spec, true))
+        .arg("-passin")
+        .arg("env:OKM_HSM_PKCS11_AUTH")
+        .arg("-in")
+        .arg(&csr)
+        .arg("-out")
+        .arg("ca.cert.pem");
+    let output = report::timed("openssl ca -selfsign", || cmd.output()).0?;
+
+    info!("executing command: \"{:#?}\"", cmd);
+
+    if let Err(e) = check_openssl("ca -selfsign", &output) {
+        connector.kill()?;
+        return Err(e.into());
+    }
+
+    connector.kill()?;
+
+    let cert = tmp_dir.path().join(format!("{}.cert.pem", label));
+    fs::copy("ca.cert.pem", cert)?;
+
+    env::set_current_dir(pwd)?;
+
+    // copy contents of temp directory to out
+    debug!("tmpdir: {:?}", tmp_dir);
+    let paths = fs::read_dir(tmp_dir.path())?
+        .map(|res| res.map(|e| e.path()))
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    let opts = CopyOptions::default().overwrite(true);
+    fs_extra::move_items(&paths, out, &opts)?;
+
+    Ok(())
+}
+
+// name of the v3 extensions section used to issue certs w/ the SANs from
+// the key spec, see `cnf_with_san`
+fn san_section(purpose: &Purpose) -> String {
+    format!("{}_san", purpose)
+}
+
+/// Get the v3 extensions section used for certs issued w/ the key spec.
+/// Certs get the SANs from the key spec, if any, but the self signed CA cert
+/// only gets them if the key spec asks for it.
+fn extensions(spec: &KeySpec, ca_cert: bool) -> String {
+    if !spec.san.is_empty() && (!ca_cert || spec.san_in_ca_cert) {
+        san_section(&spec.purpose)
+    } else {
+        spec.purpose.to_string()
+    }
+}
+
+/// Append a copy of the v3 extensions section for the purpose of the key
+/// spec to the openssl.cnf that adds the SANs from the key spec. The cnf
+/// is returned unchanged if the key spec has no SANs.
+fn cnf_with_san(mut cnf: String, spec: &KeySpec) -> String {
+    if spec.san.is_empty() {
+        return cnf;
+    }
+
+    let header = format!("[ {} ]", spec.purpose);
+    let section: Vec<&str> = cnf
+        .lines()
+        .skip_while(|line| line.trim() != header)
+        .skip(1)
+        .take_while(|line| !line.trim().is_empty() && !line.starts_with('['))
+        .collect();
+    let alt_names = format!("{}_alt_names", spec.purpose);
+
+    let mut san = format!("\n[ {} ]\n", san_section(&spec.purpose));
+    for line in section {
+        san.push_str(line);
+        san.push('\n');
+    }
+    san.push_str(&format!(
+        "{:<28}= @{}\n\n[ {} ]\n",
+        "subjectAltName", alt_names, alt_names
+    ));
+    let mut counts = HashMap::new();
+    for name in &spec.san {
+        let count = counts.entry(name.openssl_type()).or_insert(0);
+        *count += 1;
+        let key = format!("{}.{}", name.openssl_type(), count);
+        san.push_str(&format!("{:<28}= {}\n", key, name.value()));
+    }
+    cnf.push_str(&san);
+
+    cnf
+}
+
+/// Replace the keyUsage in the v3 extensions section for the purpose of
+/// the key spec, and in the copy of it for SANs, w/ the key usage from the
+/// key spec. The cnf is returned unchanged if the key spec has none.
+fn cnf_with_key_usage(cnf: String, spec: &KeySpec) -> String {
+    let usage = match &spec.key_usage {
+        Some(usage) => usage,
+        None => return cnf,
+    };
+    let usage: Vec<String> = usage.iter().map(ToString::to_string).collect();
+    let line = format!("{:<28}= critical, {}", "keyUsage", usage.join(", "));
+
+    let sections = [
+        format!("[ {} ]", spec.purpose),
+        format!("[ {} ]", san_section(&spec.purpose)),
+    ];
+    let mut in_section = false;
+    let mut out = String::new();
+    for l in cnf.lines() {
+        if l.starts_with('[') {
+            in_section = sections.iter().any(|s| l.trim() == s);
+        }
+        if in_section && l.starts_with("keyUsage") {
+            out.push_str(&line);
+        } else {
+            out.push_str(l);
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Put the extendedKeyUsage for the key spec, see
+/// `KeySpec::extended_key_usage`, after the keyUsage in the v3 extensions
+/// section for the purpose of the key spec and in the copy of it for SANs,
+/// or at the end of a section w/o a keyUsage. The extendedKeyUsage already
+/// in those sections, if any, is replaced.
+fn cnf_with_extended_key_usage(cnf: String, spec: &KeySpec) -> String {
+    let usage: Vec<String> = spec
+        .extended_key_usage()
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    let line = format!("{:<28}= {}", "extendedKeyUsage", usage.join(", "));
+
+    let sections = [
+        format!("[ {} ]", spec.purpose),
+        format!("[ {} ]", san_section(&spec.purpose)),
+    ];
+    let mut in_section = false;
+    let mut inserted = false;
+    let mut out: Vec<&str> = Vec::new();
+    // the end of a section is before the blank lines separating it from
+    // the next
+    fn end_of_section<'a>(out: &mut Vec<&'a str>, line: &'a str) {
+        let end = out
+            .iter()
+            .rposition(|l| !l.trim().is_empty())
+            .map_or(0, |i| i + 1);
+        out.insert(end, line);
+    }
+    for l in cnf.lines() {
+        if l.starts_with('[') {
+            if in_section && !inserted {
+                end_of_section(&mut out, &line);
+            }
+            in_section = sections.iter().any(|s| l.trim() == s);
+            inserted = false;
+        }
+        if in_section && l.starts_with("extendedKeyUsage") {
+            continue;
+        }
+        out.push(l);
+        if in_section && l.starts_with("keyUsage") {
+            out.push(&line);
+            inserted = true;
+        }
+    }
+    if in_section && !inserted {
+        end_of_section(&mut out, &line);
+    }
+
+    out.iter().map(|l| format!("{}\n", l)).collect()
+}
+
+/// Replace the constraints in the `[ policy_match ]` section w/ the policy
+/// for the certs issued w/ the key spec, see `KeySpec::policy`.
+fn cnf_with_policy(cnf: String, spec: &KeySpec) -> String {
+    let mut in_section = false;
+    let mut out = String::new();
+    for l in cnf.lines() {
+        if l.starts_with('[') {
+            if in_section {
+                // keep the blank line between sections
+                out.push('\n');
+            }
+            in_section = l.trim() == "[ policy_match ]";
+            out.push_str(l);
+            out.push('\n');
+            if in_section {
+                for (field, m) in spec.policy() {
+                    let field = field.to_string();
+                    out.push_str(&format!("{:<28}= {}\n", field, m));
+                }
+            }
+        } else if !in_section {
+            out.push_str(l);
+            out.push('\n');
+        }
+    }
+
+    out
+}
+
+/// Check the output of an openssl command. If it failed the output is
+/// logged and returned in the error so that the reason isn't lost.
+fn check_openssl(stage: &'static str, output: &Output) -> Result<(), HsmError> {
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+    warn!("openssl {} failed with status: {}", stage, output.status);
+    warn!("stdout: \"{}\"", stdout);
+    warn!("stderr: \"{}\"", stderr);
+
+    Err(HsmError::OpensslFailed {
+        stage,
+        status: output.status,
+        stdout,
+        stderr,
+    })
+}
+
+pub fn ca_sign(
+    key_spec: &Path,
+    csr: &Path,
+    state: &Path,
+    publish: &Path,
+    mode: CeremonyMode,
+) -> Result<()> {
+    // deserialize spec file
+    let json = fs::read_to_string(key_spec)?;
+    debug!("spec as json: {}", json);
+
+    let spec = config::KeySpec::from_str(&json)?;
+    debug!("KeySpec from {}: {:#?}", key_spec.display(), spec);
+
+    // sanity check: no signing keys at CA init
+    // this makes me think we need different types for this:
+    // one for the CA keys, one for the children we sign
+    match spec.purpose {
+        Purpose::ProductionCodeSigning
+        | Purpose::DevelopmentCodeSigning
+        | Purpose::Identity => (),
+        _ => return Err(HsmError::BadPurpose.into()),
+    }
+    check_purpose(mode, &spec.purpose)?;
+
+    passwd_to_env("OKM_HSM_PKCS11_AUTH")?;
+
+    // get canonical path to CSR before chdir into CA dir
+    let csr = fs::canonicalize(csr)?;
+    debug!("canonical CSR: {}", csr.display());
+    let publish = fs::canonicalize(publish)?;
+    debug!("canonical publish: {}", publish.display());
+
+    // pushd into ca dir based on spec file
+    let pwd = std::env::current_dir()?;
+    debug!("got current directory: {:?}", pwd);
+
+    let ca_dir = spec.ca_dir(state);
+    std::env::set_current_dir(&ca_dir)?;
+    debug!("setting current directory: {}", ca_dir.display());
+
+    // the CA's openssl.cnf has the key usage of the key spec it was created
+    // w/ and the policy for its self signed cert, the ones for this key
+    // spec go in a copy used for this cert only
+    let cnf_dir = TempDir::new()?;
+    let cnf = cnf_dir.path().join("openssl.cnf");
+    let ca_cnf = fs::read_to_string("openssl.cnf")?;
+    fs::write(
+        &cnf,
+        cnf_with_policy(
+            cnf_with_extended_key_usage(
+                cnf_with_key_usage(ca_cnf, &spec),
+                &spec,
+            ),
+            &spec,
+        ),
+    )?;
+
+    let mut connector = ConnectorProcess::start()?;
+
+    // cert file name takes prefix from CSR file name, appends ".cert.pem"
+    debug!("canonical csr: {}", csr.display());
+    let csr_filename = csr
+        .file_name()
+        .unwrap()
+        .to_os_string()
+        .into_string()
+        .unwrap();
+    let csr_prefix = match csr_filename.find('.') {
+        Some(i) => csr_filename[..i].to_string(),
+        None => csr_filename,
+    };
+    let cert = publish.join(format!("{}.cert.pem", csr_prefix));
+
+    // execute CA command
+    let mut cmd = Command::new("openssl");
+    cmd.arg("ca")
+        .arg("-batch")
+        .arg("-config")
+        .arg(&cnf)
+        .arg("-engine")
+        .arg("pkcs11")
+        .arg("-keyform")
+        .arg("engine")
+        .arg("-keyfile")
+        .arg(format!("0:{:#04}", spec.id))
+        .arg("-extensions")
+        .arg(extensions(&spec, false))
+        .arg("-passin")
+        .arg("env:OKM_HSM_PKCS11_AUTH")
+        .arg("-in")
+        .arg(&csr)
+        .arg("-out")
+        .arg(&cert);
+
+    info!("executing command: \"{:#?}\"", cmd);
+    let output = report::timed("openssl ca", || cmd.output()).0?;
+
+    if let Err(e) = check_openssl("ca", &output) {
+        connector.kill()?;
+        return Err(e.into());
+    }
+
+    // kill connector
+    connector.kill()?;
+
+    std::env::set_current_dir(pwd)?;
+
+    Ok(())
+}
+
+/// An inconsistency between the files in a CA directory created by
+/// `ca_init` and the key spec that the CA was created from.
+#[derive(Error, Debug, PartialEq)]
+pub enum CaInconsistency {
+    #[error("missing CA file: {0}")]
+    MissingFile(PathBuf),
+    #[error("CA cert is not self signed")]
+    NotSelfSigned,
+    #[error("CA cert subject CN is \"{found}\", expected \"{expected}\"")]
+    SubjectMismatch { expected: String, found: String },
+    #[error("CA cert basicConstraints CA:{found:?}, expected CA:{expected}")]
+    BasicConstraintsMismatch { expected: bool, found: Option<bool> },
+    #[error(
+        "openssl.cnf private_key is {found:?}, expected key id {expected}"
+    )]
+    KeyIdMismatch { expected: Id, found: Option<Id> },
+    #[error("serial file does not contain a hex serial number: \"{0}\"")]
+    BadSerial(String),
+}
+
+/// Check that the CA directory created by `ca_init` is consistent with the
+/// key spec that was copied into it. The CA cert is parsed in-process, see
+/// `cert::ca_cert_fields`, and this does not require the YubiHSM. Each
+/// inconsistency found is returned so the operator can decide whether the
+/// CA is safe to use.
+pub fn verify_ca(ca_dir: &Path) -> Result<Vec<CaInconsistency>> {
+    let mut report = Vec::new();
+
+    let spec_path = ca_dir.join("key.spec");
+    let json = fs::read_to_string(&spec_path).with_context(|| {
+        format!("Failed to read key spec from {}", spec_path.display())
+    })?;
+    let spec = config::KeySpec::from_str(&json)?;
+    debug!("KeySpec from {}: {:#?}", spec_path.display(), spec);
+
+    for file in ["index.txt", "serial", "openssl.cnf", "ca.cert.pem"] {
+        let path = ca_dir.join(file);
+        if !path.is_file() {
+            report.push(CaInconsistency::MissingFile(path));
+        }
+    }
+
+    let serial = ca_dir.join("serial");
+    if serial.is_file() {
+        let serial = fs::read_to_string(serial)?;
+        let serial = serial.trim();
+        if CaSerial::from_str(serial).is_err() {
+            report.push(CaInconsistency::BadSerial(serial.to_string()));
+        }
+    }
+
+    let cnf = ca_dir.join("openssl.cnf");
+    if cnf.is_file() {
+        let found = private_key_id(&fs::read_to_string(cnf)?);
+        if found != Some(spec.id) {
+            report.push(CaInconsistency::KeyIdMismatch {
+                expected: spec.id,
+                found,
+            });
+        }
+    }
+
+    let cert = ca_dir.join("ca.cert.pem");
+    if !cert.is_file() {
+        return Ok(report);
+    }
+
+    let fields = cert::ca_cert_fields(&fs::read(&cert)?)?;
+    if !fields.self_signed {
+        report.push(CaInconsistency::NotSelfSigned);
+    }
+    let found = fields.common_name.unwrap_or_default();
+    if found != spec.common_name {
+        report.push(CaInconsistency::SubjectMismatch {
+            expected: spec.common_name.clone(),
+            found,
+        });
+    }
+    let found = fields.ca;
+    if found != Some(spec.purpose.is_ca()) {
+        report.push(CaInconsistency::BasicConstraintsMismatch {
+            expected: spec.purpose.is_ca(),
+            found,
+        });
+    }
+
+    Ok(report)
+}
+
+/// Get the key id from the `private_key` line in an openssl.cnf generated
+/// from `openssl_cnf_fmt`. The expected format is `<slot>:<key id>`.
+fn private_key_id(cnf: &str) -> Option<Id> {
+    cnf.lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == "private_key")
+        .and_then(|(_, value)| value.trim().split_once(':'))
+        .and_then(|(_, id)| id.parse().ok())
+}
+
+/// The maximum number of hex digits in a serial number: RFC 5280 limits
+/// serial numbers to 20 octets.
+pub const CA_SERIAL_MAX_LEN: usize = 40;
+
+/// A serial number for the `serial` file of an openssl CA. openssl reads
+/// this file as hex w/ an even number of digits, the serial number is
+/// normalized to that. Serial numbers can be far larger than any integer
+/// type so they're kept as the hex string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaSerial(String);
+
+impl FromStr for CaSerial {
+    type Err = HsmError;
+
+    fn from_str(serial: &str) -> Result<Self, Self::Err> {
+        let bad = || HsmError::BadCaSerial(serial.to_string());
+        let digits = serial.trim().trim_start_matches('0');
+        if digits.len() > CA_SERIAL_MAX_LEN
+            || !digits.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(bad());
+        }
+        // serial numbers must be positive, see RFC 5280 section 4.1.2.2
+        if digits.is_empty() {
+            return Err(bad());
+        }
+        let mut hex = digits.to_ascii_uppercase();
+        if hex.len() % 2 == 1 {
+            hex.insert(0, '0');
+        }
+
+        Ok(CaSerial(hex))
+    }
+}
+
+impl fmt::Display for CaSerial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The serial number of the first cert issued by a new CA.
+pub const INITIAL_CA_SERIAL: &str = "1000";
+
+/// Create the directory structure and initial files expected by the `openssl ca` tool.
+/// This can be re-run in the directory of a CA that failed to initialize.
+/// The openssl.cnf is made from `cnf_template` if provided, see
+/// `openssl_cnf`.
+fn bootstrap_ca(
+    ca_dir: &Path,
+    key_spec: &KeySpec,
+    json: &str,
+    initial_serial: &CaSerial,
+    pkcs11: &Pkcs11Module,
+    cnf_template: Option<&str>,
+) -> Result<()> {
+    // A CA directory left behind by a failed `ca_init` is reused, but not
+    // one that belongs to a CA that was created or to a different key spec.
+    let cert = ca_dir.join("ca.cert.pem");
+    if cert.exists() {
+        return Err(HsmError::ConflictingCa(cert).into());
+    }
+    let spec_path = ca_dir.join("key.spec");
+    if spec_path.exists() && fs::read_to_string(&spec_path)? != json {
+        return Err(HsmError::ConflictingCa(spec_path).into());
+    }
+
+    // create directories expected by `openssl ca`: crl, newcerts
+    for dir in ["crl", "newcerts"] {
+        let dir = ca_dir.join(dir);
+        debug!("creating directory: {}?", dir.display());
+        fs::create_dir_all(dir)?;
+    }
+
+    // the 'private' directory is a special case w/ restricted permissions
+    let priv_dir = ca_dir.join("private");
+    debug!("creating directory: {}?", priv_dir.display());
+    fs::create_dir_all(&priv_dir)?;
+    let perms = Permissions::from_mode(0o700);
+    debug!(
+        "setting permissions on directory {} to {:#?}",
+        priv_dir.display(),
+        perms
+    );
+    fs::set_permissions(priv_dir, perms)?;
+
+    // copy the key spec file to the ca state dir
+    fs::write(spec_path, json)?;
+
+    // touch 'index.txt' file
+    use std::fs::OpenOptions;
+    let index = ca_dir.join("index.txt");
+    debug!("touching file {}", index.display());
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(index)?;
+
+    // write initial serial number to 'serial' (echo 1000 > serial) unless
+    // a previous attempt already did
+    let serial = ca_dir.join("serial");
+    if !serial.exists() {
+        debug!(
+            "setting initial serial number to \"{}\" in file \"{}\"",
+            initial_serial,
+            serial.display()
+        );
+        fs::write(serial, initial_serial.to_string())?;
+    }
+
+    // create & write out an openssl.cnf, the pkcs11 module logs verbosely
+    // w/ `debug` so it's only enabled when asked for
+    let cnf = openssl_cnf(cnf_template, key_spec, pkcs11)?;
+    // the policy for the key spec isn't applied here: the subject of the
+    // self signed CA cert only has the common name, `ca_sign` applies the
+    // policy of the key spec for each cert it issues
+    let cnf = cnf_with_san(
+        cnf_with_extended_key_usage(
+            cnf_with_key_usage(cnf, key_spec),
+            key_spec,
+        ),
+        key_spec,
+    );
+    fs::write(ca_dir.join("openssl.cnf"), cnf)?;
+
+    Ok(())
+}
+
+/// How the key shares are displayed to the key custodians.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ShareDisplay {
+    /// One share at a time, clearing the screen between them.
+    #[default]
+    OneAtATime,
+    /// All shares at once w/o clearing the screen between them, for review
+    /// by a single operator. This is insecure: every share is visible to
+    /// anyone who can see the screen. It's only allowed in `Dev` ceremonies.
+    All,
+    /// One share at a time in a full screen terminal UI that shows the
+    /// progress of the ceremony, see `tui`.
+    #[cfg(feature = "tui")]
+    Tui,
+    /// Each share on its own PDF page written to a directory on removable
+    /// media as `share-<n>.pdf`, see `pdf`, instead of displaying them. A
+    /// PDF of a share is as sensitive as the share: this is only for air
+    /// gapped printing where the files are printed immediately and the
+    /// media is wiped. Each custodian enters the check digest from their
+    /// printout before the media is wiped.
+    Pdf(SharePrint),
+    /// Each share encrypted to the key of its custodian's smartcard, one
+    /// key per share in order, and written to the output directory as
+    /// `share-<n>.enc` instead of displaying them, see `escrow`. No check
+    /// digest is read back in the ceremony, the custodians only see their
+    /// shares once they decrypt them: a share is checked against the digest
+    /// in the manifest when it's entered to restore the wrap key.
+    Escrow(Vec<RecipientKey>),
+}
+
+/// Where and how the key shares are written for printing, see
+/// `ShareDisplay::Pdf`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SharePrint {
+    /// Directory the PDFs are written to, on removable media that's wiped
+    /// once they're printed. It can't be in the directory for public data,
+    /// see `check_share_dir`.
+    pub dir: PathBuf,
+    /// Id of the ceremony printed on each page, see `ceremony_id`.
+    pub ceremony: String,
+    /// Also print each share as a QR code.
+    pub qr: bool,
+}
+
+/// How irreversible steps in a ceremony are confirmed by the operator.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Confirm {
+    /// Prompt the operator, waiting for the optional timeout for a response.
+    Prompt(Option<Duration>),
+    /// Proceed w/o asking, this is for non-interactive use only.
+    Auto,
+}
+
+impl Confirm {
+    /// Confirm deletion of the default auth key. `backed_up` is whether the
+    /// backup of the new auth key has been written and verified.
+    fn delete_default_auth(&self, backed_up: bool) -> Result<()> {
+        println!(
+            "\nWARNING: The default authentication key will now be deleted.\n\
+            THIS CAN NOT BE UNDONE. After this the YubiHSM can only be used\n\
+            with the new auth key, derived from the password entered earlier."
+        );
+        if backed_up {
+            println!(
+                "A backup of the new auth key has been written and verified."
+            );
+        } else {
+            println!("THE NEW AUTH KEY HAS NOT BEEN BACKED UP.");
+        }
+
+        let timeout = match self {
+            Confirm::Auto => {
+                warn!("deletion of the default auth key confirmed w/o prompt");
+                debug!(
+                    target: logging::TRANSCRIPT_TARGET,
+                    "deletion of the default auth key confirmed w/o prompt"
+                );
+                return Ok(());
+            }
+            Confirm::Prompt(timeout) => *timeout,
+        };
+        println!("Type \"delete\" and press enter to continue:");
+        if read_line(timeout)?.trim() == "delete" {
+            debug!(
+                target: logging::TRANSCRIPT_TARGET,
+                "operator confirmed deletion of the default auth key"
+            );
+            Ok(())
+        } else {
+            debug!(
+                target: logging::TRANSCRIPT_TARGET,
+                "operator declined deletion of the default auth key"
+            );
+            Err(HsmError::NotConfirmed("delete the default auth key").into())
+        }
+    }
+}
+
+/// The options for the ceremonies that create keys, shared by `generate`,
+/// `initialize`, `provision` and `migrate_wrap`. Each reads the options
+/// that apply to it, e.g. only `generate` reads the layout and the key
+/// spec checks, and only `initialize` reads how the shares are displayed.
+/// The defaults are those of a production ceremony in which the operator
+/// confirms each irreversible step.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CeremonyOptions {
+    /// The safety checks that are enforced.
+    pub mode: CeremonyMode,
+    /// The format backups are written in.
+    pub format: BackupFormat,
+    /// Where the files for each key are written.
+    pub layout: OutputLayout,
+    /// The backend the wrap key is split w/.
+    pub backend: ShareBackend,
+    /// The encoding the key shares are shown in.
+    pub encoding: ShareEncoding,
+    /// How the key shares are shown to the key custodians.
+    pub display: ShareDisplay,
+    /// Show a passphrase for each key share, see `custodian_share`.
+    pub passphrases: bool,
+    /// How long to wait for the operator at a prompt, forever if `None`.
+    pub timeout: Option<Duration>,
+    /// How irreversible steps are confirmed.
+    pub confirm: Confirm,
+    /// Require key spec file names to follow the `<label>.keyspec.json`
+    /// convention, see `config::check_key_spec_path`.
+    pub check_names: bool,
+    /// The cert the signature on each key spec file must verify w/, see
+    /// `verify_keyspecs`.
+    pub verify_with: Option<PathBuf>,
+    /// Id of the wrap key the keys are exported under by `generate`.
+    pub wrap_id: Id,
+}
+
+impl Default for CeremonyOptions {
+    fn default() -> Self {
+        Self {
+            mode: CeremonyMode::default(),
+            format: BackupFormat::default(),
+            layout: OutputLayout::default(),
+            backend: ShareBackend::default(),
+            encoding: ShareEncoding::default(),
+            display: ShareDisplay::default(),
+            passphrases: false,
+            timeout: None,
+            confirm: Confirm::Prompt(None),
+            check_names: false,
+            verify_with: None,
+            wrap_id: WRAP_ID,
+        }
+    }
+}
+
+/// Export every object in the YubiHSM that's exportable under wrap under
+/// the wrap key w/ id `wrap_id`. Each object is written to `out_dir` in its
+/// own `<type>-<id>-<label>.json` file, or `.pem` w/ `BackupFormat::Pem`.
+/// Objects that aren't exportable are skipped and reported, and so is the
+/// wrap key itself: a backup under itself could never be imported. The
+/// paths of the files written are returned.
+pub fn backup_all(
+    client: &impl Hsm,
+    wrap_id: Id,
+    out_dir: &Path,
+    mode: CeremonyMode,
+    format: BackupFormat,
+) -> Result<Vec<PathBuf>> {
+    check_wrap_capabilities(client, wrap_id, Capability::EXPORT_WRAPPED)?;
+    let mut written = Vec::new();
+    let mut skipped = 0;
+
+    for entry in client.list_objects()? {
+        let (id, object_type) = (entry.object_id, entry.object_type);
+        let info = match client.get_object_info(id, object_type)? {
+            Some(info) => info,
+            None => continue,
+        };
+        if !info
+            .capabilities
+            .contains(Capability::EXPORTABLE_UNDER_WRAP)
+        {
+            warn!(
+                "skipping {} w/ id {} and label \"{}\": not exportable \
+                under wrap",
+                object_type, id, info.label
+            );
+            skipped += 1;
+            continue;
+        }
+        if (id, object_type) == (wrap_id, Type::WrapKey) {
+            warn!(
+                "skipping wrap key w/ id {}: it can't be exported under itself",
+                id
+            );
+            skipped += 1;
+            continue;
+        }
+
+        let path = out_dir.join(object_backup_file(
+            object_type,
+            id,
+            &info.label,
+            format,
+        ));
+        check_collision(mode, &path)?;
+
+        debug!(
+            "exporting {} w/ id {} under wrap key {}",
+            object_type, id, wrap_id
+        );
+        let msg = client.export_wrapped(wrap_id, object_type, id)?;
+        let metadata = backup_metadata(client, id, object_type)?;
+        debug!("writing to: {}", path.display());
+        fs::write(&path, format.encode(msg, Some(&metadata))?)?;
+        written.push(path);
+    }
+
+    info!(
+        "exported {} objects under wrap key {}, skipped {}",
+        written.len(),
+        wrap_id,
+        skipped
+    );
+    Ok(written)
+}
+
+// describe an object exported from the YubiHSM for the audit record, this
+// is written alongside the wrap message in the backup
+fn backup_metadata(
+    client: &impl Hsm,
+    id: Id,
+    object_type: Type,
+) -> Result<BackupMetadata> {
+    let info = client
+        .get_object_info(id, object_type)?
+        .ok_or(HsmError::NoSuchObject(id, object_type))?;
+
+    Ok(BackupMetadata {
+        serial: client.serial_number()?.to_string(),
+        exported: utc_timestamp(OffsetDateTime::now_utc()),
+        id,
+        object_type: object_type.to_string(),
+        label: info.label.to_string(),
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        capabilities: Some(config::capability_names(info.capabilities)),
+    })
+}
+
+// the fields of the object info that exporting an object mustn't change
+fn same_object(a: &object::Info, b: &object::Info) -> bool {
+    a.object_id == b.object_id
+        && a.object_type == b.object_type
+        && a.label == b.label
+        && a.domains == b.domains
+        && a.capabilities == b.capabilities
+        && a.delegated_capabilities == b.delegated_capabilities
+        && a.algorithm == b.algorithm
+        && a.origin == b.origin
+        && a.length == b.length
+        && a.sequence == b.sequence
+}
+
+/// Export the object w/ the provided id and type under the wrap key w/ id
+/// `dst_wrap_id`, e.g. when the backup key is rotated. If the object isn't
+/// in the YubiHSM it's first imported from `src_backup`, a backup made
+/// under the wrap key w/ id `src_wrap_id`. The info for the object must be
+/// unchanged by the export. The new backup is written to `out_dir` and
+/// named like the backups written by `backup_all`, its path is returned.
+#[allow(clippy::too_many_arguments)]
+pub fn rewrap(
+    client: &impl Hsm,
+    src_wrap_id: Id,
+    dst_wrap_id: Id,
+    object_id: Id,
+    object_type: Type,
+    src_backup: Option<&Path>,
+    out_dir: &Path,
+    mode: CeremonyMode,
+    format: BackupFormat,
+) -> Result<PathBuf> {
+    check_wrap_capabilities(client, dst_wrap_id, Capability::EXPORT_WRAPPED)?;
+
+    let before =
+        match (client.get_object_info(object_id, object_type)?, src_backup) {
+            (Some(info), backup) => {
+                if let Some(backup) = backup {
+                    warn!(
+                        "{} w/ id {} is in the YubiHSM, not importing {}",
+                        object_type,
+                        object_id,
+                        backup.display()
+                    );
+                }
+                info
+            }
+            (None, Some(backup)) => {
+                check_wrap_capabilities(
+                    client,
+                    src_wrap_id,
+                    Capability::IMPORT_WRAPPED,
+                )?;
+                info!(
+                    "importing {} under wrap key {}",
+                    backup.display(),
+                    src_wrap_id
+                );
+                let (msg, metadata) = backup::read_backup(backup)?;
+                check_delegated_capabilities(
+                    client,
+                    src_wrap_id,
+                    backup,
+                    metadata.as_ref(),
+                )?;
+                let handle = client.import_wrapped(src_wrap_id, msg)?;
+                if handle.object_id != object_id
+                    || handle.object_type != object_type
+                {
+                    return Err(HsmError::BackupVerifyFail(
+                        backup.to_path_buf(),
+                    )
+                    .into());
+                }
+                client
+                    .get_object_info(object_id, object_type)?
+                    .ok_or(HsmError::NoSuchObject(object_id, object_type))?
+            }
+            (None, None) => {
+                return Err(
+                    HsmError::NoSuchObject(object_id, object_type).into()
+                )
+            }
+        };
+
+    let path = out_dir.join(object_backup_file(
+        object_type,
+        object_id,
+        &before.label,
+        format,
+    ));
+    check_collision(mode, &path)?;
+
+    debug!(
+        "exporting {} w/ id {} under wrap key {}",
+        object_type, object_id, dst_wrap_id
+    );
+    let msg = client.export_wrapped(dst_wrap_id, object_type, object_id)?;
+    let after = client.get_object_info(object_id, object_type)?;
+    if !after.is_some_and(|after| same_object(&before, &after)) {
+        return Err(HsmError::RewrapChanged(object_id, object_type).into());
+    }
+
+    let metadata = backup_metadata(client, object_id, object_type)?;
+    debug!("writing to: {}", path.display());
+    fs::write(&path, format.encode(msg, Some(&metadata))?)?;
+    info!(
+        "rewrapped {} w/ id {} under wrap key {}: {}",
+        object_type,
+        object_id,
+        dst_wrap_id,
+        path.display()
+    );
+
+    Ok(path)
+}
+
+/// A backup that `verify_backups` couldn't import.
+#[derive(Debug, PartialEq)]
+pub struct BackupFailure {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl fmt::Display for BackupFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.reason)
+    }
+}
+
+// the backups in the directory and its subdirectories, e.g. those for each
+// key in the `OutputLayout::PerKey` layout: the `*.wrap.json` and
+// `*.wrap.pem` files and those written by `backup_all`, sorted by path
+fn backup_files(backup_dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = |dir: &Path| {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<PathBuf>, _>>()
+    };
+    let mut paths = Vec::new();
+    for path in entries(backup_dir)? {
+        if path.is_dir() {
+            paths.extend(entries(&path)?);
+        } else {
+            paths.push(path);
+        }
+    }
+    paths.retain(|p| {
+        let name = p.file_name().unwrap_or_default().to_string_lossy();
+        p.is_file()
+            && (name.ends_with(".wrap.json")
+                || name.ends_with(".wrap.pem")
+                || is_object_backup_file(&name))
+    });
+    paths.sort();
+
+    Ok(paths)
+}
+
+/// Check that each backup in `backup_dir` or its subdirectories (the
+/// `*.wrap.json` and `*.wrap.pem` files and those written by `backup_all`)
+/// still imports under the wrap key w/ id `wrap_id`:
+/// each is imported and the object created is deleted again. This is the
+/// drill for recovering from the loss of a YubiHSM. The YubiHSM imports an
+/// object at the id it was exported from, so this must be run on a YubiHSM
+/// holding the wrap key but not the objects backed up, e.g. a spare
+/// restored from the key shares. An object that exists already is never
+/// touched: its import fails and the backup is reported. Each backup that
+/// fails to import is returned.
+pub fn verify_backups(
+    client: &impl Hsm,
+    wrap_id: Id,
+    backup_dir: &Path,
+) -> Result<Vec<BackupFailure>> {
+    check_wrap_capabilities(client, wrap_id, Capability::IMPORT_WRAPPED)?;
+
+    let mut failures = Vec::new();
+    for path in backup_files(backup_dir)? {
+        let imported =
+            backup::read_backup(&path).and_then(|(msg, metadata)| {
+                check_delegated_capabilities(
+                    client,
+                    wrap_id,
+                    &path,
+                    metadata.as_ref(),
+                )?;
+                client.import_wrapped(wrap_id, msg)
+            });
+        let handle = match imported {
+            Ok(handle) => handle,
+            Err(e) => {
+                failures.push(BackupFailure {
+                    path,
+                    reason: format!("{:#}", e),
+                });
+                continue;
+            }
+        };
+        debug!(
+            "imported {} w/ id {} from {}",
+            handle.object_type,
+            handle.object_id,
+            path.display()
+        );
+        client
+            .delete_object(handle.object_id, handle.object_type)
+            .with_context(|| {
+                format!(
+                    "Failed to delete {} w/ id {} imported from {}",
+                    handle.object_type,
+                    handle.object_id,
+                    path.display()
+                )
+            })?;
+        info!("backup imports: {}", path.display());
+    }
+
+    Ok(failures)
+}
+
+// check that a ceremony name or id can be used as a directory name as is
+fn check_ceremony_name(name: &str) -> Result<(), HsmError> {
+    let safe = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+    if !safe || name.is_empty() || name == "." || name == ".." {
+        return Err(HsmError::BadCeremonyName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// A UTC time in the ISO 8601 basic format, e.g. `20230301T170500Z`. This
+/// is safe to use in a file name.
+pub fn utc_timestamp(time: OffsetDateTime) -> String {
+    let time = time.to_offset(UtcOffset::UTC);
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    )
+}
+
+/// Id for a ceremony: its name and the UTC time it started, e.g.
+/// `rot-keys-20230301T170500Z`.
+pub fn ceremony_id(name: &str, start: OffsetDateTime) -> Result<String> {
+    check_ceremony_name(name)?;
+    Ok(format!("{}-{}", name, utc_timestamp(start)))
+}
+
+/// The directory for the outputs of the ceremony w/ the provided id in
+/// `out_dir`, it's created by `create_ceremony_dir`.
+pub fn ceremony_dir(out_dir: &Path, id: &str) -> Result<PathBuf, HsmError> {
+    check_ceremony_name(id)?;
+    Ok(out_dir.join(id))
+}
+
+/// Create the directory for the outputs of a ceremony, see `ceremony_dir`.
+/// Each ceremony gets its own directory so an existing one is refused
+/// unless `reuse` is set, e.g. to run the next step of a ceremony. Whether
+/// an existing directory was reused is returned. Nothing is logged: the
+/// log file goes in this directory so it's created before logging is set
+/// up.
+pub fn create_ceremony_dir(dir: &Path, reuse: bool) -> Result<bool> {
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::create_dir(dir) {
+        Ok(()) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            if !reuse || !dir.is_dir() {
+                return Err(HsmError::CeremonyExists(dir.to_path_buf()).into());
+            }
+            Ok(true)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// make an object label safe for use in a file name
+fn file_safe(label: &Label) -> String {
+    label
+        .to_string()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+// the name of the file the backup of an object is written to by
+// `backup_all` & `rewrap`: `<type>-<id>-<label>.<json|pem>`
+fn object_backup_file(
+    object_type: Type,
+    id: Id,
+    label: &Label,
+    format: BackupFormat,
+) -> String {
+    format!(
+        "{}-{}-{}.{}",
+        object_type,
+        id,
+        file_safe(label),
+        format.extension()
+    )
+}
+
+// check if a file name is one `object_backup_file` makes
+fn is_object_backup_file(name: &str) -> bool {
+    let name = match name
+        .strip_suffix(".json")
+        .or_else(|| name.strip_suffix(".pem"))
+    {
+        Some(name) => name,
+        None => return false,
+    };
+    // the type has dashes of its own, e.g. `asymmetric-key`
+    name.match_indices('-').any(|(i, _)| {
+        Type::from_str(&name[..i]).is_ok()
+            && name[i + 1..]
+                .split_once('-')
+                .is_some_and(|(id, _)| id.parse::<Id>().is_ok())
+    })
+}
+
+// consts for our authentication credential
+/// Domains of the auth key created by `initialize` unless others are provided.
+pub const AUTH_DOMAINS: Domain = Domain::all();
+const AUTH_CAPS: Capability = Capability::all();
+const AUTH_DELEGATED: Capability = Capability::all();
+const AUTH_ID: Id = 2;
+const AUTH_LABEL: &str = "admin";
+const HSM_ATTEST_FILE: &str = "hsm.attest.cert.pem";
+
+/// This function prompts the user to enter M of the N backup shares. It
+/// uses these shares to reconstitute the wrap key. This wrap key can then
+/// be used to restore previously backed up / export wrapped keys. If a wrap
+/// key already exists in the slot we restore to this function fails unless
+/// `force` is set, in which case the existing wrap key is replaced.
+///
+/// When the path to the manifest written by `initialize` is provided the
+/// wrap key is restored with the parameters from the manifest instead of
+/// `wrap`, and the restored key must match the fingerprint in the manifest.
+/// The domains of the wrap key must be accessible w/ an auth key in
+/// `auth_domains`. The returned report describes the restored key and its
+/// fingerprint.
+pub fn restore(
+    client: &impl Hsm,
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    manifest: Option<&Path>,
+    mode: CeremonyMode,
+    force: bool,
+    timeout: Option<Duration>,
+) -> Result<Report> {
+    restore_from(
+        client,
+        wrap,
+        auth_domains,
+        manifest,
+        mode,
+        force,
+        |count, digests| read_shares(count, digests, timeout),
+    )
+}
+
+/// Restore the wrap key like `restore` from shares that have already been
+/// collected, e.g. to test the restore path w/o a TTY. The shares get the
+/// checks applied to the shares entered by the key custodians but a share
+/// that's rejected is an error. Shares encrypted under a passphrase must
+/// be entered interactively.
+pub fn restore_with_shares(
+    client: &impl Hsm,
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    manifest: Option<&Path>,
+    mode: CeremonyMode,
+    force: bool,
+    shares: Vec<String>,
+) -> Result<Report> {
+    restore_from(
+        client,
+        wrap,
+        auth_domains,
+        manifest,
+        mode,
+        force,
+        |count, digests| check_shares(count, digests, shares),
+    )
+}
+
+// the body of `restore`, the shares are collected w/ `shares` once the
+// wrap key slot has been checked, it gets the number of shares required
+// and the check digests of the shares from the manifest
+fn restore_from(
+    client: &impl Hsm,
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    manifest: Option<&Path>,
+    mode: CeremonyMode,
+    force: bool,
+    shares: impl FnOnce(u8, &[String]) -> Result<Vec<String>>,
+) -> Result<Report> {
+    let manifest = manifest.map(WrapBackup::load).transpose()?;
+    let (wrap, threshold) = match &manifest {
+        Some(manifest) => {
+            info!("restoring wrap key w/ parameters from manifest");
+            (manifest.params()?, manifest.threshold)
+        }
+        None => (wrap.clone(), THRESHOLD),
+    };
+    let fingerprint = manifest.as_ref().map(|m| m.fingerprint.as_str());
+    check_domains(auth_domains, &wrap.label.to_string(), wrap.domains)?;
+
+    // check before collecting shares so the custodians don't enter their
+    // shares for nothing
+    check_wrap_algorithm(client, &wrap)?;
+    let replace = check_wrap_slot(client, wrap.id, force)?;
+
+    let digests = manifest
+        .as_ref()
+        .map_or(&[][..], |m| m.share_digests.as_slice());
+    let shares = shares(threshold, digests)?;
+    // never the shares themselves, stdout may be captured in unattended
+    // runs
+    for share in &shares {
+        info!("using key share w/ check digest: {}", shares::digest(share));
+    }
+
+    restore_wrap_key(client, &wrap, fingerprint, mode, replace, shares)
+}
+
+/// Recover the wrap key from `shares` and return its fingerprint, w/o a
+/// YubiHSM. The fingerprint can be compared to the one recorded by
+/// `initialize` to check that a set of shares is good, e.g. as a drill. The
+/// shares get the checks applied to the shares entered by the key
+/// custodians and the wrap key is only ever held in memory. W/ the manifest
+/// written by `initialize` the threshold & share digests are taken from it
+/// and the fingerprint must match it, w/o one `THRESHOLD` shares are
+/// required.
+pub fn recover_fingerprint(
+    shares: Vec<String>,
+    manifest_path: Option<&Path>,
+) -> Result<String> {
+    let manifest = manifest_path.map(WrapBackup::load).transpose()?;
+    let wrap_key = match &manifest {
+        Some(manifest) => {
+            let shares = check_shares(
+                manifest.threshold,
+                &manifest.share_digests,
+                shares,
+            )?;
+            recover_checked(manifest, shares)?
+        }
+        None => {
+            let shares = check_shares(THRESHOLD, &[], shares)?;
+            Zeroizing::new(shares::recover(shares)?)
+        }
+    };
+
+    Ok(backup::fingerprint(&wrap_key))
+}
+
+/// Check that the wrap key can be split and recovered w/ the number of shares
+/// and threshold used by `initialize`. See `shares::selftest`.
+pub fn selftest() -> Result<()> {
+    shares::selftest(THRESHOLD, SHARES)?;
+    info!(
+        "selftest passed: every {} of {} shares recover the secret",
+        THRESHOLD, SHARES
+    );
+
+    Ok(())
+}
+
+/// Prompt the user for `count` distinct key shares. A share that's already
+/// been entered, that can't be decoded, that's in a different encoding
+/// than the first share, or whose check digest isn't one of `digests` (if
+/// any) is rejected and the user is prompted again. Several shares may be
+/// pasted at once, the user is only prompted for the shares still missing.
+/// The key custodian is prompted for the passphrase of a share encrypted
+/// under one. The shares are returned decoded.
+fn read_shares(
+    count: u8,
+    digests: &[String],
+    timeout: Option<Duration>,
+) -> Result<Vec<String>> {
+    collect_shares(
+        count,
+        digests,
+        || read_lines(timeout),
+        || Ok(rpassword::prompt_password("Enter passphrase for share: ")?),
+    )
+}
+
+// collect `count` shares from the blocks of lines returned by `next_lines`,
+// w/ the passphrases for wrapped shares from `next_passphrase`, see
+// `read_shares`
+fn collect_shares(
+    count: u8,
+    digests: &[String],
+    mut next_lines: impl FnMut() -> Result<Vec<String>>,
+    mut next_passphrase: impl FnMut() -> Result<String>,
+) -> Result<Vec<String>> {
+    let count = usize::from(count);
+    let mut shares: Vec<String> = Vec::new();
+    let mut encoding = None;
+
+    while shares.len() < count {
+        println!("Enter share[{}]: ", shares.len() + 1);
+        // an extra newline from a paste isn't a share
+        let mut lines = next_lines()?
+            .iter()
+            .map(|line| normalize_share(line))
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<String>>()
+            .into_iter();
+        if lines.len() == 0 {
+            continue;
+        }
+        for line in lines.by_ref() {
+            if shares::is_wrapped(&line) {
+                // a decrypted share doesn't count towards the encoding of
+                // the shares entered
+                let mut passphrase = next_passphrase()?;
+                let share = shares::unwrap_share(&line, &passphrase);
+                passphrase.zeroize();
+                let share = share.and_then(|share| {
+                    accept_share(&shares, digests, None, &share)
+                });
+                match share {
+                    Ok(share) => shares.push(share),
+                    Err(e) => error!("share rejected: {}", e),
+                }
+            } else {
+                match accept_share(&shares, digests, encoding, &line) {
+                    Ok(share) => {
+                        encoding = Some(ShareEncoding::detect(&line));
+                        shares.push(share);
+                    }
+                    Err(e) => error!("share rejected: {}", e),
+                }
+            }
+            transcript_share(shares.len());
+            if shares.len() == count {
+                break;
+            }
+        }
+        if lines.len() > 0 {
+            warn!(
+                "ignoring {} lines entered after the last share required",
+                lines.len()
+            );
+        }
+        println!("{} of {} shares accepted", shares.len(), count);
+    }
+
+    Ok(shares)
+}
+
+// record the outcome of entering a share in the transcript w/o the share or
+// the reason it was rejected, which may quote it
+fn transcript_share(accepted: usize) {
+    debug!(
+        target: logging::TRANSCRIPT_TARGET,
+        "share entered, {} accepted so far",
+        accepted
+    );
+}
+
+/// Check pre-collected shares like `collect_shares` checks the shares
+/// entered by the key custodians, there must be at least `count`. The
+/// shares are returned decoded.
+fn check_shares(
+    count: u8,
+    digests: &[String],
+    lines: Vec<String>,
+) -> Result<Vec<String>> {
+    let mut shares: Vec<String> = Vec::new();
+    let mut encoding = None;
+
+    for line in lines.iter().map(|line| normalize_share(line)) {
+        if line.is_empty() {
+            continue;
+        }
+        if shares::is_wrapped(&line) {
+            return Err(HsmError::BadShare(
+                "shares encrypted under a passphrase must be entered \
+                interactively"
+                    .to_string(),
+            )
+            .into());
+        }
+        shares.push(accept_share(&shares, digests, encoding, &line)?);
+        encoding = Some(ShareEncoding::detect(&line));
+    }
+    if shares.len() < count as usize {
+        return Err(HsmError::BadShare(format!(
+            "got {} shares, {} are required",
+            shares.len(),
+            count
+        ))
+        .into());
+    }
+
+    Ok(shares)
+}
+
+/// Normalize a line entered by a key custodian: a paste from a terminal can
+/// carry carriage returns (e.g. over SSH or from Windows) and surrounding
+/// whitespace, none of which are part of a share.
+fn normalize_share(line: &str) -> String {
+    line.replace('\r', "").trim().to_string()
+}
+
+/// Decode a share entered by a key custodian and check that it's distinct
+/// from the shares already entered. If an encoding is provided the share
+/// must be in that encoding. If check digests are provided, e.g. from the
+/// manifest, the digest of the share must be one of them.
+fn accept_share(
+    shares: &[String],
+    digests: &[String],
+    encoding: Option<ShareEncoding>,
+    line: &str,
+) -> Result<String, HsmError> {
+    let found = ShareEncoding::detect(line);
+    match encoding {
+        Some(expected) if expected != found => {
+            return Err(HsmError::MixedShareEncoding { expected, found })
+        }
+        _ => (),
+    }
+    let share = shares::decode(line)?;
+    check_share(shares, &share)?;
+    let digest = shares::digest(&share);
+    if !digests.is_empty() && !digests.contains(&digest) {
+        return Err(HsmError::UnknownShare(digest));
+    }
+
+    Ok(share)
+}
+
+/// The title displayed w/ a share, e.g. "Share 2 of 5". The number is the
+/// index encoded in the share so that it matches the share payload, falling
+/// back to the position of the share in the list.
+fn share_title(share: &str, position: usize, total: usize) -> String {
+    let index = shares::index(share).map_or(position + 1, usize::from);
+    format!("Share {} of {}", index, total)
+}
+
+/// Check that `share` is distinct from the shares already entered: it must
+/// have a different value and a different share index.
+fn check_share(shares: &[String], share: &str) -> Result<(), HsmError> {
+    let index = shares::index(share);
+    for entered in shares {
+        if entered.trim() == share.trim()
+            || (index.is_some() && shares::index(entered) == index)
+        {
+            return Err(HsmError::DuplicateShare(
+                index.map_or("?".to_string(), |i| i.to_string()),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reconstitute the wrap key from the provided shares and put it in the
+/// YubiHSM. If `replace` is set the existing wrap key is deleted first. If
+/// a fingerprint is provided the reconstituted key must match it.
+fn restore_wrap_key(
+    client: &impl Hsm,
+    wrap: &WrapKeyParams,
+    fingerprint: Option<&str>,
+    mode: CeremonyMode,
+    replace: bool,
+    shares: Vec<String>,
+) -> Result<Report> {
+    let wrap_key = Zeroizing::new(shares::recover(shares)?);
+
+    debug!(
+        target: logging::SECRET_TARGET,
+        "restored wrap key: {}",
+        mode.redact(&wrap_key.encode_hex::<String>())
+    );
+
+    // w/o a manifest there's no fingerprint to catch shares from another
+    // ceremony, and `put_wrap_key` only checks the length after the
+    // existing wrap key is deleted
+    let expected = key_len(wrap.algorithm);
+    if wrap_key.len() != expected {
+        return Err(HsmError::RecoveredKeyLen {
+            algorithm: wrap.algorithm,
+            expected,
+            found: wrap_key.len(),
+        }
+        .into());
+    }
+
+    if let Some(expected) = fingerprint {
+        check_fingerprint(&wrap_key, expected)?;
+    }
+    let found = backup::fingerprint(&wrap_key);
+
+    if replace {
+        warn!("deleting existing wrap key w/ id: {}", wrap.id);
+        client.delete_object(wrap.id, Type::WrapKey)?;
+    }
+
+    // put restored wrap key the YubiHSM as a wrap key
+    let id = put_wrap_key(client, wrap, &wrap_key)?;
+    info!("wrap id: {}", id);
+
+    let mut report = Report {
+        fingerprint: Some(found),
+        ..Default::default()
+    };
+    report.add_object(id, Type::WrapKey, &wrap.label);
+
+    Ok(report)
+}
+
+/// Check whether the slot that the wrap key is restored to is occupied.
+/// Returns `true` if there's an existing wrap key that must be deleted
+/// before the restored key can be put. An existing key is only replaced if
+/// `force` is set, whatever the ceremony mode.
+fn check_wrap_slot(client: &impl Hsm, id: Id, force: bool) -> Result<bool> {
+    let info = match client.get_object_info(id, Type::WrapKey)? {
+        Some(info) => info,
+        None => {
+            debug!("no wrap key w/ id: {}", id);
+            return Ok(false);
+        }
+    };
+
+    warn!(
+        "wrap key w/ id {} already exists with label: \"{}\"",
+        id, info.label
+    );
+    if force {
+        Ok(true)
+    } else {
+        Err(HsmError::WrapKeyExists {
+            id,
+            label: info.label.to_string(),
+        }
+        .into())
+    }
+}
+
+/// Initialize a new YubiHSM 2 by creating:
+/// - a new wap key for backup
+/// - a new auth key derived from a user supplied password
+///
+/// The new auth key is created in `auth_domains`, these must include the
+/// domains of the wrap key.
+///
+/// This new auth key is backed up / exported under wrap using the new wrap
+/// key. This backup is written to the provided directory path. Finally this
+/// function removes the default authentication credentials. The returned
+/// report describes the keys and files created.
+pub fn initialize(
+    client: &impl Hsm,
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    warning: &str,
+) -> Result<Report> {
+    initialize_with_secret(
+        client,
+        wrap,
+        auth_domains,
+        out_dir,
+        opts,
+        warning,
+        || prng_wrap_key(client, wrap),
+    )
+}
+
+/// Initialize a new YubiHSM 2 like `initialize` but w/ a wrap key supplied
+/// by the caller, e.g. one derived from an external entropy source or one
+/// that's shared w/ other YubiHSMs, rather than one from the YubiHSM PRNG.
+/// The key must be the right length for the algorithm of the wrap key, this
+/// is checked before the YubiHSM is changed. The key is zeroized when it's
+/// no longer needed.
+pub fn initialize_with_key(
+    client: &impl Hsm,
+    wrap_key: Zeroizing<Vec<u8>>,
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    warning: &str,
+) -> Result<Report> {
+    check_wrap_key_len(wrap, &wrap_key)?;
+    initialize_with_secret(
+        client,
+        wrap,
+        auth_domains,
+        out_dir,
+        opts,
+        warning,
+        move || Ok(wrap_key),
+    )
+}
+
+// check that the fingerprint of a reconstituted wrap key is `expected`
+fn check_fingerprint(wrap_key: &[u8], expected: &str) -> Result<(), HsmError> {
+    let found = backup::fingerprint(wrap_key);
+    if found != expected {
+        return Err(HsmError::FingerprintMismatch {
+            expected: expected.to_string(),
+            found,
+        });
+    }
+    debug!("reconstituted wrap key matches fingerprint: {}", found);
+
+    Ok(())
+}
+
+// reconstitute the wrap key described by the manifest from the provided
+// shares, the key must match the fingerprint from the manifest
+fn recover_checked(
+    manifest: &WrapBackup,
+    shares: Vec<String>,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let wrap_key = Zeroizing::new(shares::recover(shares)?);
+    check_fingerprint(&wrap_key, &manifest.fingerprint)?;
+
+    Ok(wrap_key)
+}
+
+/// Reconstitute the wrap key described by the manifest from shares entered
+/// by the key custodians. The key must match the fingerprint from the
+/// manifest.
+pub fn recover_wrap_key(
+    manifest_path: &Path,
+    timeout: Option<Duration>,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let manifest = WrapBackup::load(manifest_path)?;
+    let shares =
+        read_shares(manifest.threshold, &manifest.share_digests, timeout)?;
+    let wrap_key = recover_checked(&manifest, shares)?;
+
+    Ok(wrap_key)
+}
+
+/// The outcome of provisioning one YubiHSM, see `provision`.
+#[derive(Debug)]
+pub struct ProvisionStatus {
+    pub name: String,
+    pub result: Result<Report>,
+}
+
+/// Put the same wrap key into each of the provided YubiHSMs, named for
+/// display, and personalize each w/ its own auth key as `initialize` does.
+/// Any of the YubiHSMs can then restore the backups made by the others. The
+/// operator is prompted for the password of each new auth key. The files
+/// for each YubiHSM are written to a directory in `out_dir` named for it.
+/// A failure on one YubiHSM doesn't stop the others, the status of each is
+/// returned. The wrap key isn't split: it's either recovered from existing
+/// shares or it's been split already, e.g. by `initialize_with_key`.
+pub fn provision(
+    clients: &[(String, impl Hsm)],
+    wrap_key: Zeroizing<Vec<u8>>,
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+) -> Result<Vec<ProvisionStatus>> {
+    provision_with_passwords(
+        clients,
+        &wrap_key,
+        wrap,
+        auth_domains,
+        out_dir,
+        opts,
+        |name| {
+            println!("Set the password for the new auth key on {}", name);
+            new_password(opts.mode)
+        },
+    )
+}
+
+// the body of `provision` w/ the source of the passwords injected
+fn provision_with_passwords(
+    clients: &[(String, impl Hsm)],
+    wrap_key: &[u8],
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    mut password: impl FnMut(&str) -> Result<String>,
+) -> Result<Vec<ProvisionStatus>> {
+    let (mode, format) = (opts.mode, opts.format);
+    check_wrap_key_len(wrap, wrap_key)?;
+    check_domains(auth_domains, &wrap.label.to_string(), wrap.domains)?;
+
+    let mut statuses = Vec::new();
+    for (name, client) in clients {
+        let hsm_dir = out_dir.join(file_safe(&Label::from(name.as_str())));
+        let result = (|| {
+            fs::create_dir_all(&hsm_dir)?;
+            let auth_wrap_file = auth_wrap_file(AUTH_ID, format);
+            for file in [&auth_wrap_file, HSM_ATTEST_FILE] {
+                check_collision(mode, &hsm_dir.join(file))?;
+            }
+
+            let mut password = password(name)?;
+            let report = provision_one(
+                client,
+                wrap_key,
+                wrap,
+                auth_domains,
+                &hsm_dir,
+                opts,
+                &password,
+            );
+            password.zeroize();
+            report
+        })();
+        match &result {
+            Ok(_) => info!("provisioned {}", name),
+            Err(e) => error!("failed to provision {}: {:#}", name, e),
+        }
+        statuses.push(ProvisionStatus {
+            name: name.clone(),
+            result,
+        });
+    }
+
+    Ok(statuses)
+}
+
+// put the wrap key into a YubiHSM and personalize it
+fn provision_one(
+    client: &impl Hsm,
+    wrap_key: &[u8],
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    password: &str,
+) -> Result<Report> {
+    let id = put_wrap_key(client, wrap, wrap_key)?;
+    let mut report = Report {
+        fingerprint: Some(backup::fingerprint(wrap_key)),
+        ..Default::default()
+    };
+    report.add_object(id, Type::WrapKey, &wrap.label);
+    report.merge(personalize(
+        client,
+        id,
+        auth_domains,
+        out_dir,
+        opts,
+        password,
+    )?);
+
+    Ok(report)
+}
+
+/// The instructions displayed to the key custodians by `initialize` before
+/// the key shares are recorded, unless others are provided. See
+/// `share_warning` for the placeholders.
+pub const SHARE_WARNING: &str = "\
+WARNING: The wrap / backup key has been created and stored in the
+YubiHSM. It will now be split into {shares} key shares. The operator must
+record these shares as they're displayed. Failure to do so will
+result in the inability to reconstruct this key and restore
+backups.
+
+Press enter to begin the key share recording process ...";
+
+/// The instructions displayed to the key custodians by `refresh_shares`
+/// before the new shares are recorded, unless others are provided. See
+/// `share_warning` for the placeholders.
+pub const REFRESH_SHARE_WARNING: &str = "\
+WARNING: The wrap / backup key will now be split into {shares} new key
+shares, {threshold} of which are required to reconstruct it. The wrap key
+itself is unchanged: THE OLD KEY SHARES REMAIN VALID. Old shares
+must be destroyed, or the wrap key rotated, to revoke them. W/ the
+updated manifest this tool only accepts the new shares.
+
+Press enter to begin the key share recording process ...";
+
+/// The instructions displayed to the key custodians by `migrate_wrap`
+/// before the shares of the new wrap key are recorded, unless others are
+/// provided. See `share_warning` for the placeholders.
+pub const MIGRATE_SHARE_WARNING: &str = "\
+WARNING: The backups have been migrated to a new wrap key. It will now be
+split into {shares} key shares, {threshold} of which are required to
+reconstruct it. The old key shares remain valid for the old backups only.
+
+Press enter to begin the key share recording process ...";
+
+/// Fill in the placeholders in the instructions displayed to the key
+/// custodians: `{shares}` is the number of key shares and `{threshold}` is
+/// the number required to reconstruct the wrap key. Any other placeholder
+/// is an error so that a typo doesn't end up on screen in a ceremony.
+pub fn share_warning(
+    template: &str,
+    shares: u8,
+    threshold: u8,
+) -> Result<String, HsmError> {
+    let mut warning = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        warning.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| HsmError::BadShareWarning(rest[start..].into()))?;
+        match &rest[start + 1..start + end] {
+            "shares" => warning.push_str(&shares.to_string()),
+            "threshold" => warning.push_str(&threshold.to_string()),
+            p => return Err(HsmError::BadShareWarning(format!("{{{}}}", p))),
+        }
+        rest = &rest[start + end + 1..];
+    }
+    warning.push_str(rest);
+
+    Ok(warning)
+}
+
+/// The body of `initialize` w/ the source of the wrap key injected, see
+/// `setup_with_secret`.
+fn initialize_with_secret(
+    client: &impl Hsm,
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    warning: &str,
+    secret: impl FnOnce() -> Result<Zeroizing<Vec<u8>>>,
+) -> Result<Report> {
+    let (mode, format, encoding) = (opts.mode, opts.format, opts.encoding);
+    let (passphrases, timeout) = (opts.passphrases, opts.timeout);
+    if opts.display == ShareDisplay::All && !mode.allows_share_review() {
+        return Err(HsmError::NotAllowed("displaying all shares", mode).into());
+    }
+    let warning = share_warning(warning, SHARES, THRESHOLD)?;
+
+    // check for collisions and unusable domains before we change the state
+    // of the HSM
+    let auth_wrap_file = auth_wrap_file(AUTH_ID, format);
+    for file in [&auth_wrap_file, HSM_ATTEST_FILE, WRAP_BACKUP_FILE] {
+        check_collision(mode, &out_dir.join(file))?;
+    }
+    let share_files = match &opts.display {
+        ShareDisplay::Pdf(print) => {
+            check_share_dir(&print.dir, out_dir)?;
+            Some((print.dir.as_path(), share_pdf_file as fn(usize) -> String))
+        }
+        ShareDisplay::Escrow(recipients) => {
+            if recipients.len() != usize::from(SHARES) {
+                return Err(HsmError::RecipientCount {
+                    expected: SHARES.into(),
+                    found: recipients.len(),
+                }
+                .into());
+            }
+            Some((out_dir, share_enc_file as fn(usize) -> String))
+        }
+        _ => None,
+    };
+    if let Some((dir, share_file)) = share_files {
+        // a share file is never overwritten, even in a dev ceremony
+        for n in 1..=SHARES {
+            let path = dir.join(share_file(n.into()));
+            if path.exists() {
+                return Err(HsmError::OutputExists(path).into());
+            }
+        }
+    }
+    check_domains(auth_domains, &wrap.label.to_string(), wrap.domains)?;
+    check_wrap_algorithm(client, wrap)?;
+
+    let mut password = new_password(mode)?;
+    let shares = setup_with_secret(
+        client,
+        wrap,
+        auth_domains,
+        out_dir,
+        opts,
+        &password,
+        secret,
+    );
+    password.zeroize();
+    let (shares, report) = shares?;
+
+    println!("{}", warning);
+
+    wait_for_line(timeout)?;
+    clear_screen();
+    debug!(
+        target: logging::TRANSCRIPT_TARGET,
+        "operator acknowledged the share warning, recording {} key shares",
+        shares.len()
+    );
+
+    match &opts.display {
+        ShareDisplay::Pdf(print) => {
+            write_share_pdfs(&shares, encoding, passphrases, print)?;
+            println!("When the key shares are printed, press enter");
+            wait_for_line(timeout)?;
+            read_back_printed(&shares, print, |n| prompt_digest(n, timeout))?;
+        }
+        ShareDisplay::Escrow(recipients) => {
+            write_share_escrow(
+                &shares,
+                encoding,
+                passphrases,
+                recipients,
+                out_dir,
+            )?;
+        }
+        display => display_shares(
+            &shares,
+            encoding,
+            display.clone(),
+            passphrases,
+            timeout,
+        )?,
+    }
+
+    Ok(report)
+}
+
+// name of the file the PDF of the share w/ the provided ordinal is written to
+fn share_pdf_file(n: usize) -> String {
+    format!("share-{}.pdf", n)
+}
+
+// `path` made absolute w/ symlinks resolved, the part of it that doesn't
+// exist yet is appended as is
+fn resolve_path(path: &Path) -> Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    for existing in path.ancestors() {
+        if let Ok(resolved) = existing.canonicalize() {
+            let rest = path.strip_prefix(existing).expect("an ancestor");
+            return Ok(resolved.join(rest));
+        }
+    }
+
+    Ok(path)
+}
+
+/// Check that the key shares can be printed from `share_dir`: it can't be
+/// the directory for public data, `public`, or be anywhere in it. The
+/// public data is copied off the ceremony machine and published, the key
+/// shares must never go w/ it.
+pub fn check_share_dir(share_dir: &Path, public: &Path) -> Result<()> {
+    let (resolved, public_resolved) =
+        (resolve_path(share_dir)?, resolve_path(public)?);
+    if resolved.starts_with(&public_resolved) {
+        return Err(HsmError::ShareDirInPublic {
+            share_dir: share_dir.to_path_buf(),
+            public: public.to_path_buf(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Write each key share, in the provided encoding and w/ its check digest,
+/// to its own single page PDF in the directory from `print` named
+/// `share-<n>.pdf` for printing. The files are only readable by the owner.
+/// Shares are otherwise never written to disk: this is only for air gapped
+/// printing workflows where the files are printed immediately and the
+/// media they're written to is wiped. The paths of the files are returned.
+fn write_share_pdfs(
+    shares: &[String],
+    encoding: ShareEncoding,
+    passphrases: bool,
+    print: &SharePrint,
+) -> Result<Vec<PathBuf>> {
+    let out_dir = &print.dir;
+    warn!(
+        "writing key shares to {}: print them, then wipe the media",
+        out_dir.display()
+    );
+    let mut paths = Vec::new();
+    let result = (|| {
+        for (i, share) in shares.iter().enumerate() {
+            let share_num = i + 1;
+            let mut displayed =
+                custodian_share(share, encoding, share_num, passphrases)?;
+            let mut pdf = pdf::share_page(&pdf::SharePage {
+                ceremony: &print.ceremony,
+                title: &share_title(share, i, shares.len()),
+                share: &displayed,
+                digest: &shares::digest(share),
+                qr: print.qr,
+            });
+            displayed.zeroize();
+
+            let path = out_dir.join(share_pdf_file(share_num));
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)?;
+            paths.push(path.clone());
+            let written = file.write_all(&pdf);
+            pdf.zeroize();
+            written?;
+            debug!(
+                target: logging::TRANSCRIPT_TARGET,
+                "key share {} written to {}",
+                share_num,
+                path.display()
+            );
+        }
+        Ok::<(), anyhow::Error>(())
+    })();
+    // a partial set of shares is of no use to anyone but an attacker
+    if let Err(e) = result {
+        for path in &paths {
+            if let Err(e) = secure_wipe(path) {
+                error!("failed to wipe {}: {:#}", path.display(), e);
+            }
+        }
+        return Err(e);
+    }
+    println!(
+        "The key shares have been written to {}. Print them, the key \
+        custodians will then enter the check digest from their printout.",
+        out_dir.display()
+    );
+
+    Ok(paths)
+}
+
+// name of the file the encrypted share w/ the provided ordinal is written to
+fn share_enc_file(n: usize) -> String {
+    format!("share-{}.enc", n)
+}
+
+/// Encrypt each key share, in the provided encoding, to the key of its
+/// custodian in `recipients` and write it to `out_dir` as `share-<n>.enc`,
+/// see `escrow`. The paths of the files are returned. Only the custodian's
+/// card can decrypt their share, the plaintext is never written to disk.
+/// Every share is encrypted before any file is written so that a failure
+/// doesn't leave some custodians w/ a share and others w/o.
+fn write_share_escrow(
+    shares: &[String],
+    encoding: ShareEncoding,
+    passphrases: bool,
+    recipients: &[RecipientKey],
+    out_dir: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut ciphertexts = Vec::new();
+    for (i, (share, recipient)) in shares.iter().zip(recipients).enumerate() {
+        let displayed = Zeroizing::new(custodian_share(
+            share,
+            encoding,
+            i + 1,
+            passphrases,
+        )?);
+        ciphertexts.push(recipient.encrypt(displayed.as_bytes())?);
+    }
+
+    let mut paths = Vec::new();
+    for (i, (ciphertext, recipient)) in
+        ciphertexts.iter().zip(recipients).enumerate()
+    {
+        let share_num = i + 1;
+        let path = out_dir.join(share_enc_file(share_num));
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?
+            .write_all(ciphertext)?;
+        debug!(
+            target: logging::TRANSCRIPT_TARGET,
+            "key share {} encrypted to {} and written to {}",
+            share_num,
+            recipient.path.display(),
+            path.display()
+        );
+        paths.push(path);
+    }
+    println!(
+        "The key shares have been encrypted to the custodians' keys and \
+        written to {}.",
+        out_dir.display()
+    );
+
+    Ok(paths)
+}
+
+/// Overwrite the file at `path` w/ zeros, flush it to the device and then
+/// remove it. Only regular files are wiped, a symlink is refused so this
+/// never follows a link out of the directory it was pointed at. This is
+/// best effort: on copy-on-write filesystems (btrfs, ZFS, APFS) and on
+/// flash storage w/ wear leveling (SSDs, USB sticks, SD cards) the zeros
+/// are written to new blocks and the old contents may survive until those
+/// blocks are reused. Destroying the media, or never writing secrets to
+/// it, is the only reliable protection. It's meant for the secret files
+/// this tool wrote itself, see `wipe_share_pdfs`.
+pub fn secure_wipe(path: &Path) -> Result<()> {
+    let not_regular = || HsmError::NotRegularFile(path.to_path_buf());
+    // the checks are made on the file that's opened so that a symlink
+    // swapped in after a check isn't followed, O_NONBLOCK keeps a FIFO from
+    // blocking the open
+    let mut file = match fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+    {
+        Ok(file) => file,
+        // a symlink, a directory or a FIFO w/o a reader
+        Err(e)
+            if matches!(
+                e.raw_os_error(),
+                Some(libc::ELOOP | libc::EISDIR | libc::ENXIO)
+            ) =>
+        {
+            return Err(not_regular().into())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let metadata = file.metadata()?;
+    if !metadata.file_type().is_file() {
+        return Err(not_regular().into());
+    }
+
+    let zeros = [0u8; 4096];
+    let mut remaining = metadata.len();
+    while remaining > 0 {
+        let len = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..len])?;
+        remaining -= len as u64;
+    }
+    file.sync_all()?;
+    drop(file);
+    // only the path can be removed, make sure it's still the file that was
+    // wiped & not one swapped in after the open
+    let current = fs::symlink_metadata(path)?;
+    if (current.dev(), current.ino()) != (metadata.dev(), metadata.ino()) {
+        return Err(HsmError::WipedFileReplaced(path.to_path_buf()).into());
+    }
+    fs::remove_file(path)?;
+    debug!(
+        target: logging::TRANSCRIPT_TARGET,
+        "wiped {}",
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Wipe the key share PDFs written by `initialize` w/
+/// `ShareDisplay::Pdf` from `dir` after they've been printed, see
+/// `secure_wipe`. Only the files named `share-<n>.pdf` are touched. The
+/// paths of the files wiped are returned.
+pub fn wipe_share_pdfs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<PathBuf>, _>>()?;
+    paths.retain(|path| {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("share-"))
+            .and_then(|name| name.strip_suffix(".pdf"))
+            .and_then(|n| n.parse::<usize>().ok())
+            .is_some_and(|n| {
+                path.file_name() == Some(share_pdf_file(n).as_ref())
+            })
+    });
+    paths.sort();
+    for path in &paths {
+        secure_wipe(path)?;
+        info!("wiped key share: {}", path.display());
+    }
+
+    Ok(paths)
+}
+
+/// Display the key shares to the key custodians in the provided encoding,
+/// either one at a time or all at once. Each share is displayed w/ its
+/// check digest, see `shares::digest`. Once a share is off the screen its
+/// custodian enters the digest they recorded, if it doesn't match the share
+/// is displayed again. When they're displayed all at once every digest is
+/// entered after the screen is cleared and all of them are displayed again
+/// if any doesn't match.
+fn display_shares(
+    shares: &[String],
+    encoding: ShareEncoding,
+    display: ShareDisplay,
+    passphrases: bool,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let _guard = ClearOnInterrupt::install();
+    if display == ShareDisplay::All {
+        warn!("displaying all key shares at once: THIS IS INSECURE");
+        loop {
+            println!(
+                "WARNING: ALL KEY SHARES ARE DISPLAYED AT ONCE. Anyone who \
+                can\nsee the screen can reconstruct the wrap key. This is \
+                for\ndevelopment ceremonies only.\n"
+            );
+            for (i, share) in shares.iter().enumerate() {
+                println!(
+                    "{}:\n\n{}\n\nCheck digest: {}\n",
+                    share_title(share, i, shares.len()),
+                    custodian_share(share, encoding, i + 1, passphrases)?,
+                    shares::digest(share)
+                );
+            }
+            println!("When you are done reviewing the key shares, press enter");
+            wait_for_line(timeout)?;
+            clear_screen();
+            debug!(
+                target: logging::TRANSCRIPT_TARGET,
+                "all {} key shares displayed at once",
+                shares.len()
+            );
+
+            let mut confirmed = true;
+            for (i, share) in shares.iter().enumerate() {
+                let entered = prompt_digest(i + 1, timeout)?;
+                confirmed &= digest_confirmed(share, i + 1, &entered);
+            }
+            if confirmed {
+                return Ok(());
+            }
+            error!(
+                "a check digest doesn't match, the shares may have been \
+                recorded wrong: displaying them again"
+            );
+        }
+    }
+
+    #[cfg(feature = "tui")]
+    if display == ShareDisplay::Tui {
+        tui::display_shares(
+            shares.len(),
+            |i| {
+                Ok((
+                    share_title(&shares[i], i, shares.len()),
+                    format!(
+                        "{}\n\nCheck digest: {}",
+                        custodian_share(
+                            &shares[i],
+                            encoding,
+                            i + 1,
+                            passphrases
+                        )?,
+                        shares::digest(&shares[i])
+                    ),
+                ))
+            },
+            passphrases,
+            |i| {
+                let entered = prompt_digest(i + 1, timeout)?;
+                Ok(digest_confirmed(&shares[i], i + 1, &entered))
+            },
+            timeout,
+        )?;
+        debug!(
+            target: logging::TRANSCRIPT_TARGET,
+            "{} key shares displayed in the TUI",
+            shares.len()
+        );
+        return Ok(());
+    }
+
+    for (i, share) in shares.iter().enumerate() {
+        let share_num = i + 1;
+        println!(
+            "When key custodian {share} is steated, press enter to display \
+            share {share}",
+            share = share_num
+        );
+        wait_for_line(timeout)?;
+        let digest = shares::digest(share);
+        loop {
+            let displayed =
+                custodian_share(share, encoding, share_num, passphrases)?;
+
+            // Can we generate a QR code, photograph it & then recover the key
+            // by reading them back through the camera?
+            println!(
+                "\n{}:\n\n{}\n\nCheck digest: {}\n",
+                share_title(share, i, shares.len()),
+                displayed,
+                digest
+            );
+            println!(
+                "When you are done recording this key share and its check \
+                digest, press enter"
+            );
+            wait_for_line(timeout)?;
+            clear_screen();
+
+            let entered = prompt_digest(share_num, timeout)?;
+            if digest_confirmed(share, share_num, &entered) {
+                break;
+            }
+            error!(
+                "the check digest doesn't match, the share may have been \
+                recorded wrong: displaying it again"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// ask key custodian `share_num` for the check digest they recorded
+fn prompt_digest(
+    share_num: usize,
+    timeout: Option<Duration>,
+) -> Result<String> {
+    println!(
+        "Key custodian {}, enter the check digest you recorded:",
+        share_num
+    );
+    read_line(timeout)
+}
+
+// whether the check digest entered by key custodian `share_num` is that of
+// their share, the outcome goes in the transcript
+fn digest_confirmed(share: &str, share_num: usize, entered: &str) -> bool {
+    if shares::digest_matches(&shares::digest(share), entered) {
+        debug!(
+            target: logging::TRANSCRIPT_TARGET,
+            "key custodian {} recorded their share and confirmed its check \
+            digest",
+            share_num
+        );
+        true
+    } else {
+        debug!(
+            target: logging::TRANSCRIPT_TARGET,
+            "key custodian {} entered a check digest that doesn't match",
+            share_num
+        );
+        false
+    }
+}
+
+/// Have each key custodian enter the check digest from the printout of
+/// their share, see `write_share_pdfs`, until it matches. `entered` gets the
+/// digest entered by the custodian w/ the provided ordinal. A mismatch
+/// means the share was printed or read wrong, the PDF is still there to
+/// print it again.
+fn read_back_printed(
+    shares: &[String],
+    print: &SharePrint,
+    mut entered: impl FnMut(usize) -> Result<String>,
+) -> Result<()> {
+    for (i, share) in shares.iter().enumerate() {
+        let share_num = i + 1;
+        while !digest_confirmed(share, share_num, &entered(share_num)?) {
+            error!(
+                "the check digest doesn't match, check the printout of {} or \
+                print it again",
+                print.dir.join(share_pdf_file(share_num)).display()
+            );
+        }
+    }
+    println!(
+        "The check digests of the printed key shares match. Wipe the media \
+        they were written to."
+    );
+
+    Ok(())
+}
+
+/// Get the share as it's displayed to a key custodian. If `passphrases` is
+/// set the custodian is asked for a passphrase and the share is encrypted
+/// under it, see `shares::wrap_share`. An empty passphrase leaves the share
+/// in the provided encoding.
+fn custodian_share(
+    share: &str,
+    encoding: ShareEncoding,
+    custodian: usize,
+    passphrases: bool,
+) -> Result<String> {
+    if !passphrases {
+        return Ok(encoding.encode(share)?);
+    }
+
+    let prompt = format!(
+        "Key custodian {}, enter a passphrase to encrypt your share \
+        (empty for none): ",
+        custodian
+    );
+    loop {
+        let mut passphrase = rpassword::prompt_password(&prompt)?;
+        if passphrase.is_empty() {
+            debug!(
+                target: logging::TRANSCRIPT_TARGET,
+                "key custodian {} chose not to encrypt their share",
+                custodian
+            );
+            return Ok(encoding.encode(share)?);
+        }
+        let mut passphrase2 =
+            rpassword::prompt_password("Enter passphrase again to confirm: ")?;
+        let matched = passphrase == passphrase2;
+        passphrase2.zeroize();
+        if matched {
+            let wrapped = shares::wrap_share(share, &passphrase);
+            passphrase.zeroize();
+            debug!(
+                target: logging::TRANSCRIPT_TARGET,
+                "key custodian {} encrypted their share under a passphrase",
+                custodian
+            );
+            return Ok(wrapped?);
+        }
+        passphrase.zeroize();
+        error!("the passphrases entered do not match");
+    }
+}
+
+/// Split the wrap key described by the manifest into a new set of shares
+/// for a new set of key custodians. The YubiHSM won't give up the wrap key
+/// so it's reconstituted from `threshold` of the existing shares, checked
+/// against the fingerprint from the manifest, then split again into
+/// `new_shares` shares w/ a threshold of `new_threshold`. The defaults are
+/// the values from the manifest. The manifest is updated to describe the
+/// new shares. If a backend is provided the new shares are created with it,
+/// this is how shares are migrated between backends.
+///
+/// The wrap key is unchanged so the old shares remain valid. `warning` is
+/// displayed before the new shares are recorded, see `share_warning`. The
+/// returned report has the updated manifest and the fingerprint of the wrap
+/// key.
+#[allow(clippy::too_many_arguments)]
+pub fn refresh_shares(
+    client: &impl Hsm,
+    manifest_path: &Path,
+    new_shares: Option<u8>,
+    new_threshold: Option<u8>,
+    backend: Option<ShareBackend>,
+    encoding: ShareEncoding,
+    mode: CeremonyMode,
+    timeout: Option<Duration>,
+    warning: &str,
+) -> Result<Report> {
+    let manifest = WrapBackup::load(manifest_path)?;
+    let new_shares = new_shares.unwrap_or(manifest.shares);
+    let new_threshold = new_threshold.unwrap_or(manifest.threshold);
+    let warning = share_warning(warning, new_shares, new_threshold)?;
+    if client
+        .get_object_info(manifest.id, Type::WrapKey)?
+        .is_none()
+    {
+        warn!(
+            "the YubiHSM has no wrap key w/ id {}, refreshing shares anyway",
+            manifest.id
+        );
+    }
+
+    let shares =
+        read_shares(manifest.threshold, &manifest.share_digests, timeout)?;
+
+    let (manifest, shares) =
+        resplit(&manifest, shares, new_shares, new_threshold, backend, mode)?;
+
+    println!("{}", warning);
+
+    wait_for_line(timeout)?;
+    clear_screen();
+    debug!(
+        target: logging::TRANSCRIPT_TARGET,
+        "operator acknowledged the share refresh warning, recording {} new \
+        key shares",
+        manifest.shares
+    );
+
+    display_shares(
+        &shares,
+        encoding,
+        ShareDisplay::OneAtATime,
+        false,
+        timeout,
+    )?;
+    // the manifest describes the new shares once they've been recorded, a
+    // failure before leaves the old shares in use
+    manifest.write(manifest_path)?;
+
+    Ok(Report {
+        files: vec![manifest_path.to_path_buf()],
+        fingerprint: Some(manifest.fingerprint),
+        ..Default::default()
+    })
+}
+
+// reconstitute the wrap key described by the manifest from the provided
+// shares and split it into `shares` new shares w/ the provided threshold,
+// using the backend that produced the old shares unless one is provided
+fn resplit(
+    manifest: &WrapBackup,
+    old_shares: Vec<String>,
+    shares: u8,
+    threshold: u8,
+    backend: Option<ShareBackend>,
+    mode: CeremonyMode,
+) -> Result<(WrapBackup, Vec<String>)> {
+    let old_backend = old_shares
+        .first()
+        .map(|share| ShareBackend::detect(share))
+        .unwrap_or_default();
+    let wrap_key = recover_checked(manifest, old_shares)?;
+    debug!(
+        target: logging::SECRET_TARGET,
+        "recovered wrap key: {}",
+        mode.redact(&wrap_key.encode_hex::<String>())
+    );
+
+    let backend = backend.unwrap_or(old_backend);
+    if backend != old_backend {
+        info!("migrating shares from {} to {}", old_backend, backend);
+    }
+    let new_shares = backend.split(threshold, shares, &wrap_key)?;
+
+    let manifest = WrapBackup {
+        shares,
+        threshold,
+        share_digests: new_shares.iter().map(|s| shares::digest(s)).collect(),
+        ..manifest.clone()
+    };
+
+    Ok((manifest, new_shares))
+}
+
+/// New parameters for the wrap key, see `reconfigure_wrap_key`. Parameters
+/// that aren't set keep their current values.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WrapKeyUpdate {
+    pub domains: Option<Domain>,
+    pub capabilities: Option<Capability>,
+    pub delegated_capabilities: Option<Capability>,
+}
+
+impl WrapKeyUpdate {
+    fn apply(&self, params: &WrapKeyParams) -> WrapKeyParams {
+        WrapKeyParams {
+            domains: self.domains.unwrap_or(params.domains),
+            capabilities: self.capabilities.unwrap_or(params.capabilities),
+            delegated_capabilities: self
+                .delegated_capabilities
+                .unwrap_or(params.delegated_capabilities),
+            ..params.clone()
+        }
+    }
+}
+
+/// Change the domains and / or capabilities of the wrap key described by
+/// the manifest w/o changing the key itself. The YubiHSM can't change the
+/// parameters of an object in place, nor will it export the wrap key, so
+/// the key is reconstituted from `threshold` of the key shares, checked
+/// against the fingerprint from the manifest and against `backup`, an
+/// existing backup that it must decrypt. Only then is the wrap key deleted
+/// and put back at the same id w/ the new parameters. The manifest is
+/// updated to describe the new parameters, the key shares remain valid.
+pub fn reconfigure_wrap_key(
+    client: &impl Hsm,
+    manifest_path: &Path,
+    update: &WrapKeyUpdate,
+    backup: &Path,
+    auth_domains: Domain,
+    mode: CeremonyMode,
+    timeout: Option<Duration>,
+) -> Result<Report> {
+    let manifest = WrapBackup::load(manifest_path)?;
+    let shares =
+        read_shares(manifest.threshold, &manifest.share_digests, timeout)?;
+    let (manifest, mut report) = reconfigure(
+        client,
+        &manifest,
+        update,
+        backup,
+        auth_domains,
+        mode,
+        shares,
+    )?;
+    manifest.write(manifest_path)?;
+    report.add_file(manifest_path);
+
+    Ok(report)
+}
+
+// the body of `reconfigure_wrap_key` w/ the shares already entered, this
+// returns the updated manifest
+fn reconfigure(
+    client: &impl Hsm,
+    manifest: &WrapBackup,
+    update: &WrapKeyUpdate,
+    backup: &Path,
+    auth_domains: Domain,
+    mode: CeremonyMode,
+    shares: Vec<String>,
+) -> Result<(WrapBackup, Report)> {
+    let old = manifest.params()?;
+    let new = update.apply(&old);
+    check_domains(auth_domains, &new.label.to_string(), new.domains)?;
+    if client.get_object_info(old.id, Type::WrapKey)?.is_none() {
+        return Err(HsmError::NoWrapKey(old.id).into());
+    }
+
+    // nothing is changed until we know we have the right key
+    let wrap_key = recover_checked(manifest, shares)?;
+    let msg = backup::read_wrapped(backup)?;
+    if backup::decrypt_wrapped(&wrap_key, old.algorithm, &msg).is_none() {
+        return Err(HsmError::BackupDecryptFail(backup.to_path_buf()).into());
+    }
+    debug!(
+        target: logging::SECRET_TARGET,
+        "recovered wrap key: {}",
+        mode.redact(&wrap_key.encode_hex::<String>())
+    );
+
+    warn!("deleting wrap key w/ id {} to reconfigure it", old.id);
+    client.delete_object(old.id, Type::WrapKey)?;
+    if let Err(e) = put_wrap_key(client, &new, &wrap_key) {
+        // put the key back the way it was rather than leave the YubiHSM w/o
+        // a wrap key
+        error!("failed to put reconfigured wrap key, restoring it: {:#}", e);
+        put_wrap_key(client, &old, &wrap_key)?;
+        return Err(e);
+    }
+    info!("reconfigured wrap key w/ id {}", new.id);
+    new.log_caps();
+
+    let mut report = Report::default();
+    report.add_object(new.id, Type::WrapKey, &new.label);
+    // the shares are unchanged
+    let manifest = WrapBackup {
+        share_digests: manifest.share_digests.clone(),
+        ..WrapBackup::new(&new, &wrap_key, manifest.shares, manifest.threshold)?
+    };
+
+    Ok((manifest, report))
+}
+
+/// Migrate the wrap key described by the manifest, and every backup made
+/// under it, to a new wrap key using `algorithm`, e.g. to move to a
+/// stronger wrap algorithm. The key custodians enter `threshold` of the
+/// existing key shares and record the shares of the new wrap key, see
+/// `migrate_wrap_with_shares`. The new backups and the manifest for the new
+/// wrap key are written to `out_dir`, the old ones are left as they are.
+/// `warning` is displayed before the new shares are recorded, see
+/// `share_warning`.
+pub fn migrate_wrap(
+    client: &impl Hsm,
+    manifest_path: &Path,
+    algorithm: wrap::Algorithm,
+    backup_dir: &Path,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    warning: &str,
+) -> Result<Report> {
+    let (encoding, timeout) = (opts.encoding, opts.timeout);
+    let manifest = WrapBackup::load(manifest_path)?;
+    // the new key is split like the old one
+    let warning = share_warning(warning, manifest.shares, manifest.threshold)?;
+    let shares =
+        read_shares(manifest.threshold, &manifest.share_digests, timeout)?;
+
+    migrate_wrap_with_shares(
+        client,
+        &manifest,
+        shares,
+        algorithm,
+        backup_dir,
+        out_dir,
+        opts.mode,
+        opts.format,
+        |shares| {
+            println!("{}", warning);
+            wait_for_line(timeout)?;
+            clear_screen();
+            display_shares(
+                shares,
+                encoding,
+                ShareDisplay::OneAtATime,
+                false,
+                timeout,
+            )
+        },
+    )
+}
+
+/// The body of `migrate_wrap` w/ the old shares already entered. Each step
+/// is verified before the next and the old backups remain valid throughout:
+/// - the old wrap key is reconstituted from the shares and checked against
+///   the fingerprint from the manifest, it must decrypt every backup in
+///   `backup_dir`
+/// - the backed up objects that aren't in the YubiHSM are imported under
+///   the old wrap key, which must be in the YubiHSM
+/// - a new wrap key is put into a free id, every object is exported under
+///   it to `out_dir` and each new backup must decrypt w/ the new key
+/// - the new key is split into as many shares as the old one and the
+///   shares are handed to `record`, e.g. to display them to the custodians
+/// - only then is the manifest for the new key written to `out_dir` and
+///   the old wrap key replaced by the new one
+///
+/// A failure before the old wrap key is replaced removes the new one, the
+/// files written to `out_dir` and the objects imported from `backup_dir`.
+#[allow(clippy::too_many_arguments)]
+pub fn migrate_wrap_with_shares(
+    client: &impl Hsm,
+    manifest: &WrapBackup,
+    old_shares: Vec<String>,
+    algorithm: wrap::Algorithm,
+    backup_dir: &Path,
+    out_dir: &Path,
+    mode: CeremonyMode,
+    format: BackupFormat,
+    record: impl FnOnce(&[String]) -> Result<()>,
+) -> Result<Report> {
+    let old = manifest.params()?;
+    let new = WrapKeyParams {
+        algorithm,
+        ..old.clone()
+    };
+    if backup_dir.canonicalize()? == out_dir.canonicalize()? {
+        return Err(HsmError::MigrateInPlace(out_dir.to_path_buf()).into());
+    }
+    let manifest_path = out_dir.join(WRAP_BACKUP_FILE);
+    check_collision(mode, &manifest_path)?;
+    check_wrap_algorithm(client, &new)?;
+    check_wrap_capabilities(client, old.id, Capability::IMPORT_WRAPPED)?;
+
+    // nothing is changed until we know we have the right key, and that it
+    // opens every backup
+    let backend = old_shares
+        .first()
+        .map(|share| ShareBackend::detect(share))
+        .unwrap_or_default();
+    let old_key = recover_checked(manifest, old_shares)?;
+    let backups = backup_files(backup_dir)?;
+    let mut messages = Vec::new();
+    for path in &backups {
+        let (msg, metadata) = backup::read_backup(path)?;
+        if backup::decrypt_wrapped(&old_key, old.algorithm, &msg).is_none() {
+            return Err(HsmError::BackupDecryptFail(path.clone()).into());
+        }
+        messages.push((path, msg, metadata));
+    }
+    info!("the old wrap key decrypts all {} backups", backups.len());
+
+    let mut objects = Vec::new();
+    let mut imported = Vec::new();
+    for (path, msg, metadata) in messages {
+        // metadata w/ an unknown object type is treated like none
+        let object = metadata.as_ref().and_then(|m| {
+            Type::from_str(&m.object_type).ok().map(|t| (m.id, t))
+        });
+        if let Some((id, object_type)) = object {
+            if client.get_object_info(id, object_type)?.is_some() {
+                debug!("{} w/ id {} is in the YubiHSM", object_type, id);
+                objects.push((id, object_type));
+                continue;
+            }
+        }
+        check_delegated_capabilities(client, old.id, path, metadata.as_ref())?;
+        let handle = client.import_wrapped(old.id, msg).with_context(|| {
+            format!("Failed to import backup: {}", path.display())
+        })?;
+        info!("imported {}", path.display());
+        objects.push((handle.object_id, handle.object_type));
+        imported.push((handle.object_id, handle.object_type));
+    }
+
+    let new_key = Zeroizing::new(client.get_pseudo_random(key_len(algorithm))?);
+    debug!(
+        target: logging::SECRET_TARGET,
+        "new wrap key: {}",
+        mode.redact(&new_key.encode_hex::<String>())
+    );
+    let temp_id = put_wrap_key(
+        client,
+        &WrapKeyParams {
+            id: 0,
+            ..new.clone()
+        },
+        &new_key,
+    )?;
+    info!("put new wrap key w/ id {}", temp_id);
+
+    let mut written = Vec::new();
+    let exported = (|| {
+        let mut report = Report::default();
+        for (id, object_type) in objects {
+            let path = rewrap(
+                client,
+                old.id,
+                temp_id,
+                id,
+                object_type,
+                None,
+                out_dir,
+                mode,
+                format,
+            )?;
+            written.push(path.clone());
+            let msg = backup::read_wrapped(&path)?;
+            if backup::decrypt_wrapped(&new_key, algorithm, &msg).is_none() {
+                return Err(HsmError::BackupDecryptFail(path).into());
+            }
+            report.add_file(&path);
+        }
+
+        let new_shares =
+            backend.split(manifest.threshold, manifest.shares, &new_key)?;
+        let new_manifest = WrapBackup {
+            share_digests: new_shares
+                .iter()
+                .map(|s| shares::digest(s))
+                .collect(),
+            ..WrapBackup::new(
+                &new,
+                &new_key,
+                manifest.shares,
+                manifest.threshold,
+            )?
+        };
+        record(&new_shares)?;
+
+        // the manifest is renamed into place so that there's never one
+        // for a key whose shares weren't recorded
+        let tmp_path = out_dir.join(format!("{}.tmp", WRAP_BACKUP_FILE));
+        written.push(tmp_path.clone());
+        new_manifest.write(&tmp_path)?;
+        fs::rename(&tmp_path, &manifest_path)?;
+        written.push(manifest_path.clone());
+        report.add_file(&manifest_path);
+        report.fingerprint = Some(new_manifest.fingerprint);
+
+        Ok::<_, anyhow::Error>(report)
+    })();
+    let mut report = match exported {
+        Ok(report) => report,
+        Err(e) => {
+            error!("migration failed, removing the new wrap key: {:#}", e);
+            for path in written.iter().filter(|p| p.exists()) {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("failed to remove {}: {}", path.display(), e);
+                }
+            }
+            for (id, object_type) in imported {
+                warn!("deleting imported {} w/ id {}", object_type, id);
+                client.delete_object(id, object_type)?;
+            }
+            client.delete_object(temp_id, Type::WrapKey)?;
+            return Err(e);
+        }
+    };
+
+    warn!("replacing wrap key w/ id {} w/ the new wrap key", old.id);
+    client.delete_object(old.id, Type::WrapKey)?;
+    if let Err(e) = put_wrap_key(client, &new, &new_key) {
+        // put the old key back rather than leave the YubiHSM w/o a wrap key
+        error!("failed to put new wrap key, restoring the old: {:#}", e);
+        put_wrap_key(client, &old, &old_key)?;
+        return Err(e);
+    }
+    client.delete_object(temp_id, Type::WrapKey)?;
+    info!("migrated wrap key w/ id {} to {:?}", new.id, algorithm);
+    report.add_object(new.id, Type::WrapKey, &new.label);
+
+    Ok(report)
+}
+
+/// Replace the auth key with id `auth_id` with a new one derived from a
+/// password entered by the user. The new auth key is put in the first free
+/// id after `auth_id` and it's backed up under the wrap key with id
+/// `wrap_id`. The old auth key is deleted only after we've successfully
+/// authenticated with the new one. The returned report describes the new
+/// auth key, its backup and the auth key deleted.
+pub fn rotate_auth_key(
+    client: &impl Hsm,
+    auth_id: Id,
+    wrap_id: Id,
+    out_dir: &Path,
+    mode: CeremonyMode,
+    format: BackupFormat,
+) -> Result<Report> {
+    let new_id = free_auth_id(client, auth_id)?;
+    // check for collisions before we change the state of the HSM
+    check_collision(mode, &out_dir.join(auth_wrap_file(new_id, format)))?;
+
+    let mut password = new_password(mode)?;
+    let report = rotate_auth(
+        client, auth_id, new_id, wrap_id, out_dir, format, &password,
+    );
+    password.zeroize();
+    let report = report?;
+
+    info!("auth key {} replaced by auth key {}", auth_id, new_id);
+    Ok(report)
+}
+
+/// Export the existing auth key w/ id `auth_id` under the wrap key w/ id
+/// `wrap_id` and write it to `out_dir`, e.g. to replace a lost `admin.json`.
+/// Nothing in the YubiHSM is created or deleted. The path of the backup is
+/// returned.
+pub fn backup_auth_key(
+    client: &impl Hsm,
+    auth_id: Id,
+    wrap_id: Id,
+    out_dir: &Path,
+    mode: CeremonyMode,
+    format: BackupFormat,
+) -> Result<PathBuf> {
+    if client
+        .get_object_info(auth_id, Type::AuthenticationKey)?
+        .is_none()
+    {
+        return Err(
+            HsmError::NoSuchObject(auth_id, Type::AuthenticationKey).into()
+        );
+    }
+    let path = out_dir.join(auth_wrap_file(auth_id, format));
+    check_collision(mode, &path)?;
+
+    backup_auth(client, wrap_id, auth_id, out_dir, format)?;
+    info!("auth key {} backed up to {}", auth_id, path.display());
+
+    Ok(path)
+}
+
+// find the first id after `auth_id` not occupied by an auth key
+fn free_auth_id(client: &impl Hsm, auth_id: Id) -> Result<Id> {
+    for id in auth_id.saturating_add(1)..=Id::MAX {
+        if client
+            .get_object_info(id, Type::AuthenticationKey)?
+            .is_none()
+        {
+            return Ok(id);
+        }
+    }
+
+    Err(HsmError::NoFreeId(Type::AuthenticationKey).into())
+}
+
+// put a new auth key derived from the password at `new_id`, back it up and
+// check that it works, then delete the auth key `auth_id`
+fn rotate_auth(
+    client: &impl Hsm,
+    auth_id: Id,
+    new_id: Id,
+    wrap_id: Id,
+    out_dir: &Path,
+    format: BackupFormat,
+    password: &str,
+) -> Result<Report> {
+    // the new auth key can manage the same objects as the old one
+    let old = client.get_object_info(auth_id, Type::AuthenticationKey)?;
+    let domains = old.as_ref().map_or(AUTH_DOMAINS, |info| info.domains);
+    put_auth_key(client, new_id, domains, password)?;
+
+    let res =
+        backup_auth(client, wrap_id, new_id, out_dir, format).and_then(|_| {
+            debug!("authenticating w/ new auth key {}", new_id);
+            client.authenticate(Credentials::new(
+                new_id,
+                Key::derive_from_password(password.as_bytes()),
+            ))
+        });
+    if let Err(e) = res {
+        error!(
+            "new auth key {} failed, keeping auth key {}",
+            new_id, auth_id
+        );
+        if let Err(e) = client.delete_object(new_id, Type::AuthenticationKey) {
+            warn!("failed to delete new auth key {}: {}", new_id, e);
+        }
+        return Err(e);
+    }
+
+    debug!("deleting old auth key {}", auth_id);
+    client.delete_object(auth_id, Type::AuthenticationKey)?;
+
+    let mut report = Report::default();
+    report.add_object(new_id, Type::AuthenticationKey, &AUTH_LABEL.into());
+    report.add_file(&out_dir.join(auth_wrap_file(new_id, format)));
+    let old_label = old.map_or_else(|| AUTH_LABEL.into(), |info| info.label);
+    report.add_deleted(auth_id, Type::AuthenticationKey, &old_label);
+
+    Ok(report)
+}
+
+/// `setup_with_secret` w/ the wrap key from the YubiHSM PRNG, as done by
+/// `initialize`.
+#[cfg(test)]
+fn setup(
+    client: &impl Hsm,
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    password: &str,
+) -> Result<(Vec<String>, Report)> {
+    setup_with_secret(
+        client,
+        wrap,
+        auth_domains,
+        out_dir,
+        opts,
+        password,
+        || prng_wrap_key(client, wrap),
+    )
+}
+
+// get a key's worth of bytes for the wrap key from the YubiHSM PRNG
+fn prng_wrap_key(
+    client: &impl Hsm,
+    wrap: &WrapKeyParams,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let len = key_len(wrap.algorithm);
+    let wrap_key = Zeroizing::new(client.get_pseudo_random(len)?);
+    info!("got {} bytes from YubiHSM PRNG", len);
+
+    Ok(wrap_key)
+}
+
+/// Create the wrap key from the provided source, replace the default auth
+/// key with one derived from the provided password and split the wrap key
+/// into shares. The shares are returned for display to the key custodians
+/// along w/ a report of the keys and files created.
+fn setup_with_secret(
+    client: &impl Hsm,
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    password: &str,
+    secret: impl FnOnce() -> Result<Zeroizing<Vec<u8>>>,
+) -> Result<(Vec<String>, Report)> {
+    let wrap_key = secret()?;
+    debug!(
+        target: logging::SECRET_TARGET,
+        "got wrap key: {}",
+        opts.mode.redact(&wrap_key.encode_hex::<String>())
+    );
+
+    // put the random bytes into the YubiHSM as a wrap key
+    let id = put_wrap_key(client, wrap, &wrap_key)?;
+    debug!("wrap id: {}", id);
+    // Future commands assume that our wrap key has id 1. If we got a wrap
+    // key with any other id the HSM isn't in the state we think it is.
+    assert_eq!(id, WRAP_ID);
+
+    // do the stuff from replace-auth.sh
+    let mut report = Report::default();
+    report.add_object(id, Type::WrapKey, &wrap.label);
+    report.merge(personalize(
+        client,
+        WRAP_ID,
+        auth_domains,
+        out_dir,
+        opts,
+        password,
+    )?);
+
+    let shares = opts.backend.split(THRESHOLD, SHARES, &wrap_key)?;
+
+    // record how the wrap key was created so it can be restored the same way
+    let manifest = WrapBackup {
+        share_digests: shares.iter().map(|s| shares::digest(s)).collect(),
+        ..WrapBackup::new(wrap, &wrap_key, SHARES, THRESHOLD)?
+    };
+    let manifest_path = out_dir.join(WRAP_BACKUP_FILE);
+    manifest.write(&manifest_path)?;
+    report.add_file(&manifest_path);
+    report.fingerprint = Some(manifest.fingerprint);
+
+    Ok((shares, report))
+}
+
+// check that the key is the right length for the algorithm of the wrap key
+fn check_wrap_key_len(
+    wrap: &WrapKeyParams,
+    wrap_key: &[u8],
+) -> Result<(), HsmError> {
+    let expected = key_len(wrap.algorithm);
+    if wrap_key.len() != expected {
+        return Err(HsmError::BadWrapKeyLen {
+            algorithm: wrap.algorithm,
+            expected,
+            found: wrap_key.len(),
+        });
+    }
+
+    Ok(())
+}
+
+// check that the YubiHSM supports the algorithm of the wrap key, which wrap
+// algorithms it supports depends on its firmware
+fn check_wrap_algorithm(client: &impl Hsm, wrap: &WrapKeyParams) -> Result<()> {
+    let supported = client.supported_algorithms()?;
+    if !supported.contains(&Algorithm::Wrap(wrap.algorithm)) {
+        return Err(HsmError::UnsupportedWrapAlgorithm(wrap.algorithm).into());
+    }
+
+    Ok(())
+}
+
+/// Put the provided key into the YubiHSM as a wrap key. The key must be the
+/// right length for the algorithm of the wrap key, and the YubiHSM must
+/// support the algorithm.
+fn put_wrap_key(
+    client: &impl Hsm,
+    wrap: &WrapKeyParams,
+    wrap_key: &[u8],
+) -> Result<Id> {
+    check_wrap_key_len(wrap, wrap_key)?;
+    check_wrap_algorithm(client, wrap)?;
+    debug!("putting wrap key w/ label: {}", wrap.label);
+    wrap.log_caps();
+    client
+        .put_wrap_key(
+            wrap.id,
+            wrap.label.clone(),
+            wrap.domains,
+            wrap.capabilities,
+            wrap.delegated_capabilities,
+            wrap.algorithm,
+            wrap_key,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to put wrap key into YubiHSM domains {:?} with id {}",
+                wrap.domains, wrap.id
+            )
+        })
+}
+
+/// Get a new password for the auth key from the user. The user is prompted
+/// until they enter the same password twice and it's long enough for the
+/// ceremony mode.
+fn new_password(mode: CeremonyMode) -> Result<String> {
+    if mode.min_password_len() < PROD_MIN_PASSWORD_LEN {
+        warn!(
+            "{} ceremony: passwords shorter than {} characters are allowed",
+            mode, PROD_MIN_PASSWORD_LEN
+        );
+    }
+    let password = loop {
+        let mut password = rpassword::prompt_password(PASSWD_PROMPT)?;
+        if password.len() < mode.min_password_len() {
+            password.zeroize();
+            error!("{}", HsmError::WeakPassword(mode.min_password_len()));
+            continue;
+        }
+        let mut password2 = rpassword::prompt_password(PASSWD_PROMPT2)?;
+        if password != password2 {
+            error!("the passwords entered do not match");
+        } else {
+            password2.zeroize();
+            break password;
+        }
+    };
+    debug!(
+        target: logging::TRANSCRIPT_TARGET,
+        "operator entered the new password twice"
+    );
+    debug!(
+        target: logging::SECRET_TARGET,
+        "got the same password twice: {}",
+        mode.redact(&password)
+    );
+
+    Ok(password)
+}
+
+// create a new auth key from the provided password, export the new auth key
+// under the wrap key with the provided id, then remove the default auth key
+// the report describes the new auth key and the files written
+fn personalize(
+    client: &impl Hsm,
+    wrap_id: Id,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+    password: &str,
+) -> Result<Report> {
+    let (mode, format, confirm) = (opts.mode, opts.format, opts.confirm);
+    debug!(
+        "personalizing with wrap key {} and out_dir {}",
+        wrap_id,
+        out_dir.display()
+    );
+
+    put_auth_key(client, AUTH_ID, auth_domains, password)?;
+    let mut report = Report::default();
+    report.add_object(AUTH_ID, Type::AuthenticationKey, &AUTH_LABEL.into());
+
+    if mode.backup_before_delete() {
+        backup_auth(client, wrap_id, AUTH_ID, out_dir, format)?;
+        confirm.delete_default_auth(true)?;
+        delete_default_auth(client)?;
+    } else {
+        warn!(
+            "{} ceremony: deleting default auth key before backup of new \
+            auth key",
+            mode
+        );
+        confirm.delete_default_auth(false)?;
+        delete_default_auth(client)?;
+        backup_auth(client, wrap_id, AUTH_ID, out_dir, format)?;
+    }
+    report.add_file(&out_dir.join(auth_wrap_file(AUTH_ID, format)));
+
+    // dump cert for default attesation key in hsm
+    debug!("extracting attestation certificate");
+    match client.get_opaque(0) {
+        Ok(attest_cert) => {
+            let attest_path = out_dir.join(HSM_ATTEST_FILE);
+            debug!("writing attestation cert to: {}", attest_path.display());
+            fs::write(&attest_path, attest_cert)?;
+            report.add_file(&attest_path);
+        }
+        Err(e) if !mode.require_attestation() => {
+            warn!("{} ceremony: skipping attestation: {}", mode, e)
+        }
+        Err(e) => return Err(e),
+    }
+
+    Ok(report)
+}
+
+// create a new auth key w/ the provided id and domains from the provided
+// password
+fn put_auth_key(
+    client: &impl Hsm,
+    id: Id,
+    domains: Domain,
+    password: &str,
+) -> Result<()> {
+    // not compatible with Zeroizing wrapper
+    let auth_key = Key::derive_from_password(password.as_bytes());
+
+    debug!("putting new auth key from provided password");
+    debug!(
+        "auth key capabilities: {}",
+        config::capability_names(AUTH_CAPS).join(" | ")
+    );
+    debug!(
+        "auth key delegated capabilities: {}",
+        config::capability_names(AUTH_DELEGATED).join(" | ")
+    );
+    client.put_authentication_key(
+        id,
+        AUTH_LABEL.into(),
+        domains,
+        AUTH_CAPS,
+        AUTH_DELEGATED,
+        authentication::Algorithm::default(), // can't be used in const
+        auth_key,
+    )?;
+
+    Ok(())
+}
+
+fn delete_default_auth(client: &impl Hsm) -> Result<()> {
+    debug!("deleting default auth key");
+    client.delete_object(
+        DEFAULT_AUTHENTICATION_KEY_ID,
+        Type::AuthenticationKey,
+    )?;
+
+    Ok(())
+}
+
+// name of the file that the backup of the auth key w/ the provided id is
+// written to: the auth key created by `initialize` keeps the plain label
+fn auth_wrap_file(auth_id: Id, format: BackupFormat) -> String {
+    if auth_id == AUTH_ID {
+        format.file_name(AUTH_LABEL)
+    } else {
+        format.file_name(&format!("{}-{}", AUTH_LABEL, auth_id))
+    }
+}
+
+// export the auth key w/ id `auth_id` under the wrap key with the provided
+// id and write it to the output directory
+fn backup_auth(
+    client: &impl Hsm,
+    wrap_id: Id,
+    auth_id: Id,
+    out_dir: &Path,
+    format: BackupFormat,
+) -> Result<()> {
+    check_wrap_capabilities(client, wrap_id, Capability::EXPORT_WRAPPED)?;
+    debug!(
+        "exporting auth key {} under wrap-key w/ id: {}",
+        auth_id, wrap_id
+    );
+    let msg =
+        client.export_wrapped(wrap_id, Type::AuthenticationKey, auth_id)?;
+    let metadata = backup_metadata(client, auth_id, Type::AuthenticationKey)?;
+    let msg = format.encode(msg, Some(&metadata))?;
+
+    debug!("msg: {:#?}", msg);
+
+    let auth_wrap_path = out_dir.join(auth_wrap_file(auth_id, format));
+    debug!("writing to: {}", auth_wrap_path.display());
+    fs::write(&auth_wrap_path, &msg)?;
+
+    // read the backup back to be sure it's what we exported
+    let (written, metadata) = backup::read_backup(&auth_wrap_path)?;
+    if format.encode(written, metadata.as_ref())? != msg {
+        return Err(HsmError::BackupVerifyFail(auth_wrap_path).into());
+    }
+    debug!("verified backup: {}", auth_wrap_path.display());
+
+    Ok(())
+}
+
+// terminal control characters that clear the screen and the scrollback,
+// then move the cursor to the top left
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[3J\x1b[1;1H";
+
+/// This clears the screen, and the scrollback in terminals that support it,
+/// using terminal control characters.
+fn clear_screen() {
+    print!("{}", CLEAR_SCREEN);
+    let _ = io::stdout().flush();
+}
+
+/// While this guard is alive an interrupt (Ctrl-C) clears the screen before
+/// exiting so that a key share isn't left on it. The previous handler is
+/// restored when the guard is dropped.
+struct ClearOnInterrupt(libc::sighandler_t);
+
+impl ClearOnInterrupt {
+    fn install() -> Self {
+        let handler = clear_and_exit as extern "C" fn(libc::c_int);
+        // SAFETY: the handler only calls async-signal-safe functions
+        let previous = unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t)
+        };
+        ClearOnInterrupt(previous)
+    }
+}
+
+impl Drop for ClearOnInterrupt {
+    fn drop(&mut self) {
+        // SAFETY: this restores the handler replaced in `install`
+        unsafe { libc::signal(libc::SIGINT, self.0) };
+    }
+}
+
+// SIGINT handler installed by `ClearOnInterrupt`, this can't use stdout
+// (it may be locked by the interrupted thread) so it writes to the fd
+extern "C" fn clear_and_exit(_: libc::c_int) {
+    // SAFETY: write and _exit are async-signal-safe
+    unsafe {
+        libc::write(
+            libc::STDOUT_FILENO,
+            CLEAR_SCREEN.as_ptr() as *const libc::c_void,
+            CLEAR_SCREEN.len(),
+        );
+        libc::_exit(130);
+    }
+}
+
+/// This function is used when displaying key shares as a way for the user to
+/// control progression through the key shares displayed in the terminal.
+fn wait_for_line(timeout: Option<Duration>) -> Result<()> {
+    let _ = read_line(timeout)?;
+
+    Ok(())
+}
+
+/// Read a line from stdin. If a timeout is provided and no line is read
+/// before it expires the screen is cleared, so that any secrets displayed
+/// aren't left on it, and an error is returned.
+fn read_line(timeout: Option<Duration>) -> Result<String> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return next_line(),
+    };
+
+    // stdin can't be read w/ a timeout so we read it from another thread
+    // and wait for the result
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(next_line());
+    });
+
+    match rx.recv_timeout(timeout) {
+        Ok(line) => line,
+        Err(_) => {
+            clear_screen();
+            Err(HsmError::PromptTimeout(timeout).into())
+        }
+    }
+}
+
+/// Read a line from stdin like `read_line`, along w/ any lines already
+/// waiting to be read, e.g. the rest of several shares pasted at once.
+fn read_lines(timeout: Option<Duration>) -> Result<Vec<String>> {
+    let mut lines = vec![read_line(timeout)?];
+    while input_pending() {
+        match io::stdin().lines().next() {
+            Some(line) => lines.push(line?),
+            None => break,
+        }
+    }
+
+    Ok(lines)
+}
+
+// whether stdin can be read w/o blocking
+fn input_pending() -> bool {
+    let mut fd = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `fd` is a valid pollfd for the duration of the call
+    let ready = unsafe { libc::poll(&mut fd, 1, 0) };
+    ready > 0 && fd.revents & libc::POLLIN != 0
+}
+
+fn next_line() -> Result<String> {
+    match io::stdin().lines().next() {
+        Some(line) => Ok(line?),
+        None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Hash;
+    use hsm::mock::{MockHsm, HSM_ATTEST_CERT};
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    const JSON_ECP384: &str = r#"{
+        "common_name": "RoT Identity Offline CA",
+        "id": 3,
+        "algorithm":"Ecp384",
+        "capabilities":"All",
+        "domain":"DOM1",
+        "hash":"Sha384",
+        "label":"rot-identity-a",
+        "purpose":"Identity"
+    }"#;
+
+    fn wrap() -> WrapKeyParams {
+        WrapKeyParams::with_label(DEFAULT_WRAP_LABEL).unwrap()
+    }
+
+    // the options of a prod ceremony run w/o prompts
+    fn opts() -> CeremonyOptions {
+        CeremonyOptions {
+            mode: CeremonyMode::Prod,
+            format: BackupFormat::Json,
+            backend: ShareBackend::RustySecrets,
+            layout: OutputLayout::Flat,
+            display: ShareDisplay::OneAtATime,
+            confirm: Confirm::Auto,
+            ..Default::default()
+        }
+    }
+
+    // the arguments to `setup`, `generate` & `initialize_with_key` w/ the
+    // values most tests use, a test overrides only those it cares about
+    struct Fixture<'a> {
+        out_dir: &'a Path,
+        wrap: WrapKeyParams,
+        auth_domains: Domain,
+        opts: CeremonyOptions,
+    }
+
+    impl<'a> Fixture<'a> {
+        fn new(out_dir: &'a Path) -> Self {
+            Fixture {
+                out_dir,
+                wrap: wrap(),
+                auth_domains: AUTH_DOMAINS,
+                opts: opts(),
+            }
+        }
+
+        fn wrap(self, wrap: &WrapKeyParams) -> Self {
+            Fixture {
+                wrap: wrap.clone(),
+                ..self
+            }
+        }
+
+        fn auth_domains(self, auth_domains: Domain) -> Self {
+            Fixture {
+                auth_domains,
+                ..self
+            }
+        }
+
+        fn mode(mut self, mode: CeremonyMode) -> Self {
+            self.opts.mode = mode;
+            self
+        }
+
+        fn format(mut self, format: BackupFormat) -> Self {
+            self.opts.format = format;
+            self
+        }
+
+        fn backend(mut self, backend: ShareBackend) -> Self {
+            self.opts.backend = backend;
+            self
+        }
+
+        fn layout(mut self, layout: OutputLayout) -> Self {
+            self.opts.layout = layout;
+            self
+        }
+
+        fn display(mut self, display: ShareDisplay) -> Self {
+            self.opts.display = display;
+            self
+        }
+
+        fn verify_with(mut self, cert: &Path) -> Self {
+            self.opts.verify_with = Some(cert.to_path_buf());
+            self
+        }
+
+        fn setup(&self, hsm: &impl Hsm) -> Result<(Vec<String>, Report)> {
+            setup(
+                hsm,
+                &self.wrap,
+                self.auth_domains,
+                self.out_dir,
+                &self.opts,
+                PASSWORD,
+            )
+        }
+
+        fn generate(&self, hsm: &impl Hsm, key_spec: &Path) -> Result<Report> {
+            generate(hsm, key_spec, self.auth_domains, self.out_dir, &self.opts)
+        }
+
+        fn initialize_with_key(
+            &self,
+            hsm: &impl Hsm,
+            wrap_key: Zeroizing<Vec<u8>>,
+        ) -> Result<Report> {
+            initialize_with_key(
+                hsm,
+                wrap_key,
+                &self.wrap,
+                self.auth_domains,
+                self.out_dir,
+                &self.opts,
+                SHARE_WARNING,
+            )
+        }
+    }
+
+    #[test]
+    fn test_wrap_label() -> Result<()> {
+        let hsm = MockHsm::new();
+        let wrap = WrapKeyParams::with_label("backup-dev")?;
+        Fixture::new(TempDir::new()?.path())
+            .wrap(&wrap)
+            .mode(CeremonyMode::Dev)
+            .setup(&hsm)?;
+
+        let info = hsm.get_object_info(wrap.id, Type::WrapKey)?.unwrap();
+        assert_eq!(info.label, Label::from_bytes(b"backup-dev")?);
+
+        assert!(WrapKeyParams::with_label("").is_err());
+        assert!(WrapKeyParams::with_label(&"a".repeat(40)).is_ok());
+        assert!(WrapKeyParams::with_label(&"a".repeat(41)).is_err());
+        Ok(())
+    }
+
+    // write the key spec to a file in the provided directory
+    fn key_spec(dir: &Path) -> Result<PathBuf> {
+        let path = dir.join("key.spec");
+        fs::write(&path, JSON_ECP384)?;
+        Ok(path)
+    }
+
+    #[test]
+    fn test_setup_with_secret() -> Result<()> {
+        let secret: Vec<u8> = (0..key_len(ALG) as u8).collect();
+        for backend in [ShareBackend::RustySecrets, ShareBackend::Sharks] {
+            let out = TempDir::new()?;
+            let hsm = MockHsm::new();
+            let (shares, _) = setup_with_secret(
+                &hsm,
+                &wrap(),
+                AUTH_DOMAINS,
+                out.path(),
+                &CeremonyOptions { backend, ..opts() },
+                PASSWORD,
+                || Ok(Zeroizing::new(secret.clone())),
+            )?;
+            assert_eq!(
+                hsm.secret(WRAP_ID, Type::WrapKey),
+                Some(secret.clone())
+            );
+
+            // every combination of THRESHOLD shares recovers the secret
+            for subset in
+                shares::combinations(SHARES as usize, THRESHOLD as usize)
+            {
+                let subset: Vec<String> =
+                    subset.iter().map(|i| shares[*i].clone()).collect();
+                assert_eq!(shares::recover(subset)?, secret);
+            }
+        }
+
+        // the secret must be the size of the wrap key
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        assert!(setup_with_secret(
+            &hsm,
+            &wrap(),
+            AUTH_DOMAINS,
+            out.path(),
+            &opts(),
+            PASSWORD,
+            || Ok(Zeroizing::new(vec![0; 16])),
+        )
+        .is_err());
+        assert!(!hsm.contains(WRAP_ID, Type::WrapKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_share_warning() -> Result<()> {
+        let warning = share_warning(SHARE_WARNING, 5, 3)?;
+        assert!(warning.contains("split into 5 key shares."));
+        assert!(!warning.contains('{'));
+        for default in [REFRESH_SHARE_WARNING, MIGRATE_SHARE_WARNING] {
+            let warning = share_warning(default, 5, 3)?;
+            assert!(warning.contains("split into 5"));
+            assert!(warning.contains("3 of which"));
+            assert!(!warning.contains('{'));
+        }
+
+        assert_eq!(
+            share_warning("{threshold} of {shares} {shares}", 5, 3)?,
+            "3 of 5 5"
+        );
+        assert_eq!(share_warning("no placeholders", 5, 3)?, "no placeholders");
+        for bad in ["{share}", "{}", "{shares"] {
+            assert!(
+                matches!(
+                    share_warning(bad, 5, 3),
+                    Err(HsmError::BadShareWarning(_))
+                ),
+                "accepted: {}",
+                bad
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_initialize_share_review() -> Result<()> {
+        let hsm = MockHsm::new();
+        let out = TempDir::new()?;
+        let result = initialize(
+            &hsm,
+            &wrap(),
+            AUTH_DOMAINS,
+            out.path(),
+            &CeremonyOptions {
+                display: ShareDisplay::All,
+                ..opts()
+            },
+            SHARE_WARNING,
+        );
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<HsmError>(),
+            Some(HsmError::NotAllowed(_, CeremonyMode::Prod))
+        ));
+        // refused before the HSM was touched
+        assert!(hsm
+            .contains(DEFAULT_AUTHENTICATION_KEY_ID, Type::AuthenticationKey));
+        assert!(!hsm.contains(WRAP_ID, Type::WrapKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_provision() -> Result<()> {
+        let out = TempDir::new()?;
+        let wrap_key = vec![7; key_len(ALG)];
+        let broken = MockHsm::new();
+        broken.fail.set(Some("put_wrap_key"));
+        let clients = [
+            ("hsm-a".to_string(), MockHsm::new()),
+            ("hsm-b".to_string(), broken),
+            ("hsm-c".to_string(), MockHsm::new()),
+        ];
+
+        let mut prompted = Vec::new();
+        let statuses = provision_with_passwords(
+            &clients,
+            &wrap_key,
+            &wrap(),
+            AUTH_DOMAINS,
+            out.path(),
+            &opts(),
+            |name| {
+                prompted.push(name.to_string());
+                Ok(PASSWORD.to_string())
+            },
+        )?;
+        assert_eq!(prompted, ["hsm-a", "hsm-b", "hsm-c"]);
+
+        // a failure on one YubiHSM doesn't stop the others
+        let ok: Vec<bool> = statuses.iter().map(|s| s.result.is_ok()).collect();
+        assert_eq!(ok, [true, false, true]);
+        for (name, hsm) in [&clients[0], &clients[2]] {
+            assert_eq!(
+                hsm.secret(WRAP_ID, Type::WrapKey),
+                Some(wrap_key.clone())
+            );
+            assert!(hsm.contains(AUTH_ID, Type::AuthenticationKey));
+            assert!(!hsm.contains(
+                DEFAULT_AUTHENTICATION_KEY_ID,
+                Type::AuthenticationKey
+            ));
+            assert!(out
+                .path()
+                .join(name)
+                .join(auth_wrap_file(AUTH_ID, BackupFormat::Json))
+                .is_file());
+        }
+        assert_eq!(
+            statuses[0].result.as_ref().unwrap().fingerprint,
+            Some(backup::fingerprint(&wrap_key))
+        );
+
+        // the key must fit the wrap key algorithm
+        assert!(provision_with_passwords(
+            &clients,
+            &[0; 3],
+            &wrap(),
+            AUTH_DOMAINS,
+            out.path(),
+            &opts(),
+            |_| Ok(PASSWORD.to_string()),
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_setup_key_len() -> Result<()> {
+        // the wrap key is sized for its algorithm
+        let hsm = MockHsm::new();
+        let wrap = WrapKeyParams {
+            algorithm: wrap::Algorithm::Aes128Ccm,
+            ..wrap()
+        };
+        Fixture::new(TempDir::new()?.path())
+            .wrap(&wrap)
+            .setup(&hsm)?;
+        assert_eq!(hsm.secret(WRAP_ID, Type::WrapKey).unwrap().len(), 16);
+
+        let hsm = MockHsm::new();
+        let err = put_wrap_key(&hsm, &wrap, &[0; 32]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Aes128Ccm wrap key must be 16 bytes, got 32"
+        );
+
+        // a supplied key is checked before the YubiHSM is touched
+        let err = Fixture::new(TempDir::new()?.path())
+            .wrap(&wrap)
+            .initialize_with_key(&hsm, Zeroizing::new(vec![0; 32]))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::BadWrapKeyLen { found: 32, .. })
+        ));
+        assert!(!hsm.contains(WRAP_ID, Type::WrapKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrap_algorithm() -> Result<()> {
+        let hsm = MockHsm::new();
+        hsm.algorithms.replace(Some(vec![Algorithm::Wrap(ALG)]));
+        let aes192 = WrapKeyParams {
+            algorithm: wrap::Algorithm::Aes192Ccm,
+            ..wrap()
+        };
+        let setup = |hsm: &MockHsm, out_dir: &Path| {
+            Fixture::new(out_dir).wrap(&aes192).setup(hsm)
+        };
+
+        // an algorithm the YubiHSM doesn't support isn't put into it
+        let err = setup(&hsm, TempDir::new()?.path()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::UnsupportedWrapAlgorithm(
+                wrap::Algorithm::Aes192Ccm
+            ))
+        ));
+        assert!(!hsm.contains(WRAP_ID, Type::WrapKey));
+        assert!(hsm
+            .contains(DEFAULT_AUTHENTICATION_KEY_ID, Type::AuthenticationKey));
+
+        // one that it does is used for the wrap key and the manifest
+        let out_dir = TempDir::new()?;
+        let (shares, _) = setup(&MockHsm::new(), out_dir.path())?;
+        let manifest =
+            WrapBackup::load(&out_dir.path().join(WRAP_BACKUP_FILE))?;
+        assert_eq!(manifest.params()?.algorithm, wrap::Algorithm::Aes192Ccm);
+
+        // restore checks the algorithm before the shares are collected
+        let err = restore_with_shares(
+            &hsm,
+            &wrap(),
+            AUTH_DOMAINS,
+            Some(&out_dir.path().join(WRAP_BACKUP_FILE)),
+            CeremonyMode::Prod,
+            false,
+            Vec::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::UnsupportedWrapAlgorithm(_))
+        ));
+
+        let new_hsm = MockHsm::new();
+        restore_with_shares(
+            &new_hsm,
+            &wrap(),
+            AUTH_DOMAINS,
+            Some(&out_dir.path().join(WRAP_BACKUP_FILE)),
+            CeremonyMode::Prod,
+            false,
+            shares[..3].to_vec(),
+        )?;
+        assert_eq!(new_hsm.secret(ID, Type::WrapKey).unwrap().len(), 24);
+        Ok(())
+    }
+
+    #[test]
+    fn test_setup() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+
+        let (shares, report) = Fixture::new(out.path()).setup(&hsm)?;
+        assert_eq!(shares.len(), SHARES as usize);
+
+        // the report describes the new keys and the files written
+        let ids: Vec<Id> = report.objects.iter().map(|o| o.id).collect();
+        assert_eq!(ids, vec![WRAP_ID, AUTH_ID]);
+        for file in [
+            BackupFormat::Json.file_name(AUTH_LABEL).as_str(),
+            HSM_ATTEST_FILE,
+            WRAP_BACKUP_FILE,
+        ] {
+            assert!(report.files.contains(&out.path().join(file)));
+        }
+
+        // the default auth key has been replaced by ours
+        assert!(!hsm
+            .contains(DEFAULT_AUTHENTICATION_KEY_ID, Type::AuthenticationKey));
+        assert_eq!(
+            hsm.secret(AUTH_ID, Type::AuthenticationKey),
+            Some(
+                Key::derive_from_password(PASSWORD.as_bytes())
+                    .as_secret_slice()
+                    .to_vec()
+            )
+        );
+        assert!(out
+            .path()
+            .join(BackupFormat::Json.file_name(AUTH_LABEL))
+            .is_file());
+        assert_eq!(
+            fs::read(out.path().join(HSM_ATTEST_FILE))?,
+            HSM_ATTEST_CERT
+        );
+
+        // the manifest describes the wrap key
+        let wrap_key = hsm.secret(WRAP_ID, Type::WrapKey).unwrap();
+        let manifest = WrapBackup::load(&out.path().join(WRAP_BACKUP_FILE))?;
+        assert_eq!(manifest.params()?, wrap());
+        assert_eq!(manifest.fingerprint, backup::fingerprint(&wrap_key));
+        assert_eq!((manifest.shares, manifest.threshold), (SHARES, THRESHOLD));
+
+        // any THRESHOLD shares recover the wrap key
+        let recovered = shares::recover(
+            shares[SHARES as usize - THRESHOLD as usize..].to_vec(),
+        )
+        .unwrap();
+        assert_eq!(recovered, wrap_key);
+        Ok(())
+    }
+
+    #[test]
+    fn test_personalize_keeps_default_auth_on_failed_backup() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+
+        // no wrap key: exporting the backup of the new auth key fails
+        let res = personalize(
+            &hsm,
+            WRAP_ID,
+            AUTH_DOMAINS,
+            out.path(),
+            &opts(),
+            PASSWORD,
+        );
+        assert!(res.is_err());
+        assert!(hsm
+            .contains(DEFAULT_AUTHENTICATION_KEY_ID, Type::AuthenticationKey));
+        assert!(!out
+            .path()
+            .join(BackupFormat::Json.file_name(AUTH_LABEL))
+            .exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_personalize_attestation_required() -> Result<()> {
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        hsm.fail.set(Some("get_opaque"));
+
+        let out = TempDir::new()?;
+        let res = personalize(
+            &hsm,
+            WRAP_ID,
+            AUTH_DOMAINS,
+            out.path(),
+            &opts(),
+            PASSWORD,
+        );
+        assert!(res.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_auth() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+
+        let new_id = free_auth_id(&hsm, AUTH_ID)?;
+        assert_eq!(new_id, AUTH_ID + 1);
+        let password = "correct horse battery staple";
+        let report = rotate_auth(
+            &hsm,
+            AUTH_ID,
+            new_id,
+            WRAP_ID,
+            out.path(),
+            BackupFormat::Json,
+            password,
+        )?;
+
+        assert!(!hsm.contains(AUTH_ID, Type::AuthenticationKey));
+        hsm.authenticate(Credentials::new(
+            new_id,
+            Key::derive_from_password(password.as_bytes()),
+        ))?;
+        let backup =
+            out.path().join(auth_wrap_file(new_id, BackupFormat::Json));
+        assert!(backup.is_file());
+        let auth_key = |id| report::ReportObject {
+            id,
+            object_type: "AuthenticationKey".to_string(),
+            label: AUTH_LABEL.to_string(),
+        };
+        assert_eq!(report.objects, [auth_key(new_id)]);
+        assert_eq!(report.files, [backup]);
+        assert_eq!(report.deleted, [auth_key(AUTH_ID)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_auth_domains() -> Result<()> {
+        let auth_domains = Domain::DOM1 | Domain::DOM2;
+        assert!(check_domains(auth_domains, "key", Domain::DOM1).is_ok());
+        assert!(check_domains(auth_domains, "key", auth_domains).is_ok());
+        assert!(check_domains(auth_domains, "key", Domain::DOM3).is_err());
+        assert!(check_domains(auth_domains, "key", Domain::empty()).is_err());
+
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        let wrap = WrapKeyParams {
+            domains: auth_domains,
+            ..wrap()
+        };
+        Fixture::new(TempDir::new()?.path())
+            .wrap(&wrap)
+            .auth_domains(auth_domains)
+            .setup(&hsm)?;
+        let info = hsm.get_object_info(AUTH_ID, Type::AuthenticationKey)?;
+        assert_eq!(info.unwrap().domains, auth_domains);
+
+        // the spec puts the key in DOM1
+        let spec = key_spec(out.path())?;
+        assert!(Fixture::new(out.path())
+            .auth_domains(Domain::DOM2)
+            .generate(&hsm, &spec)
+            .is_err());
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+
+        // the new auth key keeps the domains of the old one
+        rotate_auth(
+            &hsm,
+            AUTH_ID,
+            AUTH_ID + 1,
+            WRAP_ID,
+            out.path(),
+            BackupFormat::Json,
+            PASSWORD,
+        )?;
+        let info = hsm.get_object_info(AUTH_ID + 1, Type::AuthenticationKey)?;
+        assert_eq!(info.unwrap().domains, auth_domains);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotate_auth_keeps_old_key_on_failure() -> Result<()> {
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        hsm.fail.set(Some("authenticate"));
+
+        let new_id = free_auth_id(&hsm, AUTH_ID)?;
+        let res = rotate_auth(
+            &hsm,
+            AUTH_ID,
+            new_id,
+            WRAP_ID,
+            TempDir::new()?.path(),
+            BackupFormat::Json,
+            "correct horse battery staple",
+        );
+        assert!(res.is_err());
+        assert!(hsm.contains(AUTH_ID, Type::AuthenticationKey));
+        assert!(!hsm.contains(new_id, Type::AuthenticationKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reconfigure_wrap_key() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        let (shares, _) = Fixture::new(out.path()).setup(&hsm)?;
+        let manifest = WrapBackup::load(&out.path().join(WRAP_BACKUP_FILE))?;
+        let backup = out.path().join(BackupFormat::Json.file_name(AUTH_LABEL));
+        let wrap_key = hsm.secret(WRAP_ID, Type::WrapKey);
+
+        let update = WrapKeyUpdate {
+            domains: Some(Domain::DOM1 | Domain::DOM2),
+            capabilities: Some(
+                Capability::EXPORT_WRAPPED | Capability::IMPORT_WRAPPED,
+            ),
+            ..Default::default()
+        };
+        let (new_manifest, report) = reconfigure(
+            &hsm,
+            &manifest,
+            &update,
+            &backup,
+            AUTH_DOMAINS,
+            CeremonyMode::Prod,
+            shares[..THRESHOLD as usize].to_vec(),
+        )?;
+        assert_eq!(report.objects[0].id, WRAP_ID);
+
+        // same key, new parameters
+        assert_eq!(hsm.secret(WRAP_ID, Type::WrapKey), wrap_key);
+        let info = hsm.get_object_info(WRAP_ID, Type::WrapKey)?.unwrap();
+        assert_eq!(info.domains, Domain::DOM1 | Domain::DOM2);
+        assert_eq!(
+            info.capabilities,
+            Capability::EXPORT_WRAPPED | Capability::IMPORT_WRAPPED
+        );
+        assert_eq!(info.delegated_capabilities, DELEGATED_CAPS);
+        assert_eq!(new_manifest.params()?, update.apply(&wrap()));
+        assert_eq!(new_manifest.fingerprint, manifest.fingerprint);
+
+        // a backup that the key doesn't decrypt leaves the key alone
+        let other = TempDir::new()?;
+        setup_with_secret(
+            &MockHsm::new(),
+            &wrap(),
+            AUTH_DOMAINS,
+            other.path(),
+            &opts(),
+            PASSWORD,
+            || Ok(Zeroizing::new(vec![9; key_len(ALG)])),
+        )?;
+        let other_backup =
+            other.path().join(BackupFormat::Json.file_name(AUTH_LABEL));
+        let err = reconfigure(
+            &hsm,
+            &new_manifest,
+            &WrapKeyUpdate::default(),
+            &other_backup,
+            AUTH_DOMAINS,
+            CeremonyMode::Prod,
+            shares[..THRESHOLD as usize].to_vec(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::BackupDecryptFail(_))
+        ));
+        let info = hsm.get_object_info(WRAP_ID, Type::WrapKey)?.unwrap();
+        assert_eq!(info.domains, Domain::DOM1 | Domain::DOM2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resplit() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        let (shares, _) = Fixture::new(out.path()).setup(&hsm)?;
+        let manifest = WrapBackup::load(&out.path().join(WRAP_BACKUP_FILE))?;
+
+        let (new_manifest, new_shares) = resplit(
+            &manifest,
+            shares[..THRESHOLD as usize].to_vec(),
+            7,
+            4,
+            None,
+            CeremonyMode::Prod,
+        )?;
+        assert_eq!((new_manifest.shares, new_manifest.threshold), (7, 4));
+        assert_eq!(new_manifest.fingerprint, manifest.fingerprint);
+        assert_eq!(
+            new_manifest.share_digests,
+            new_shares
+                .iter()
+                .map(|s| shares::digest(s))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(new_shares.len(), 7);
+
+        // the new shares recover the same wrap key as the old ones
+        let new_hsm = MockHsm::new();
+        restore_wrap_key(
+            &new_hsm,
+            &new_manifest.params()?,
+            Some(&new_manifest.fingerprint),
+            CeremonyMode::Prod,
+            false,
+            new_shares[3..].to_vec(),
+        )?;
+        assert_eq!(
+            new_hsm.secret(WRAP_ID, Type::WrapKey),
+            hsm.secret(WRAP_ID, Type::WrapKey)
+        );
+
+        // migrate the shares to another backend
+        let (_, sharks_shares) = resplit(
+            &new_manifest,
+            new_shares[..4].to_vec(),
+            5,
+            3,
+            Some(ShareBackend::Sharks),
+            CeremonyMode::Prod,
+        )?;
+        assert!(sharks_shares
+            .iter()
+            .all(|s| ShareBackend::detect(s) == ShareBackend::Sharks));
+        assert_eq!(
+            shares::recover(sharks_shares[2..].to_vec())?,
+            hsm.secret(WRAP_ID, Type::WrapKey).unwrap()
+        );
+
+        // shares for some other wrap key are rejected
+        let other_hsm = MockHsm::new();
+        other_hsm.get_pseudo_random(1)?;
+        let (other_shares, _) =
+            Fixture::new(TempDir::new()?.path()).setup(&other_hsm)?;
+        assert!(resplit(
+            &manifest,
+            other_shares,
+            7,
+            4,
+            None,
+            CeremonyMode::Prod
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_share() -> Result<()> {
+        let (shares, _) =
+            Fixture::new(TempDir::new()?.path()).setup(&MockHsm::new())?;
+        let entered = shares[..2].to_vec();
+
+        assert!(check_share(&entered, &shares[2]).is_ok());
+        // the same share, w/ or w/o surrounding whitespace
+        assert!(check_share(&entered, &shares[1]).is_err());
+        assert!(check_share(&entered, &format!(" {}\n", shares[0])).is_err());
+        // a different share w/ the index of a share already entered
+        let (_, data) = shares[2].split_at(4);
+        let reindexed = format!("{}-2-{}", THRESHOLD, data);
+        assert_eq!(shares::index(&reindexed), Some(2));
+        assert!(check_share(&entered, &reindexed).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_share() -> Result<()> {
+        let shares = ShareBackend::RustySecrets.split(3, 5, &[0x42; 32])?;
+        let base32 = ShareEncoding::Base32.encode(&shares[1])?;
+
+        let entered = vec![accept_share(&[], &[], None, &shares[0])?];
+        assert_eq!(entered[0], shares[0]);
+        // shares can't be entered in a mix of encodings
+        assert!(accept_share(
+            &entered,
+            &[],
+            Some(ShareEncoding::Plain),
+            &base32
+        )
+        .is_err());
+        assert_eq!(
+            accept_share(&entered, &[], Some(ShareEncoding::Base32), &base32)?,
+            shares[1]
+        );
+        // shares are checked for duplicates once decoded
+        let base32 = ShareEncoding::Base32.encode(&shares[0])?;
+        assert!(accept_share(
+            &entered,
+            &[],
+            Some(ShareEncoding::Base32),
+            &base32
+        )
+        .is_err());
+
+        // and against the check digests from the manifest, if any
+        let digests: Vec<String> =
+            shares[..4].iter().map(|s| shares::digest(s)).collect();
+        assert_eq!(accept_share(&[], &digests, None, &shares[3])?, shares[3]);
+        assert!(matches!(
+            accept_share(&[], &digests, None, &shares[4]),
+            Err(HsmError::UnknownShare(digest))
+                if digest == shares::digest(&shares[4])
+        ));
+        Ok(())
+    }
+
+    // the current SIGINT handler
+    fn sigint_handler() -> libc::sighandler_t {
+        // SAFETY: a null action only queries the handler
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            libc::sigaction(libc::SIGINT, std::ptr::null(), &mut action);
+            action.sa_sigaction
+        }
+    }
+
+    // the SIGINT handler is process wide so the test runs itself again in a
+    // child process w/ this set, where nothing else is running
+    const CLEAR_ON_INTERRUPT_CHILD: &str = "OKS_TEST_CLEAR_ON_INTERRUPT";
+
+    #[test]
+    fn test_clear_on_interrupt() -> Result<()> {
+        if env::var_os(CLEAR_ON_INTERRUPT_CHILD).is_some() {
+            let previous = sigint_handler();
+            {
+                let _guard = ClearOnInterrupt::install();
+                assert_eq!(
+                    sigint_handler(),
+                    clear_and_exit as extern "C" fn(libc::c_int)
+                        as libc::sighandler_t
+                );
+            }
+            assert_eq!(sigint_handler(), previous);
+
+            let _guard = ClearOnInterrupt::install();
+            // SAFETY: the handler exits the child
+            unsafe { libc::raise(libc::SIGINT) };
+            unreachable!("the SIGINT handler exits");
+        }
+
+        let output = std::process::Command::new(env::current_exe()?)
+            .args(["--exact", "tests::test_clear_on_interrupt"])
+            .env(CLEAR_ON_INTERRUPT_CHILD, "1")
+            .output()?;
+        assert_eq!(
+            output.status.code(),
+            Some(130),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(output.stdout.ends_with(CLEAR_SCREEN.as_bytes()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_shares_normalized() -> Result<()> {
+        let secret = [0x42; 32];
+        let shares = ShareBackend::RustySecrets.split(3, 5, &secret)?;
+
+        let mut lines = vec![
+            format!("  {}\r", shares[3]),
+            "\r".to_string(),
+            String::new(),
+            format!("\t{} \r\n", shares[0]),
+            "   ".to_string(),
+            format!("{}\r\n", shares[4]),
+        ]
+        .into_iter();
+        let entered = collect_shares(
+            3,
+            &[],
+            || {
+                lines
+                    .next()
+                    .map(|line| vec![line])
+                    .ok_or_else(|| anyhow::anyhow!("out of lines"))
+            },
+            || panic!("no wrapped shares"),
+        )?;
+
+        // blank lines aren't counted as shares
+        assert_eq!(
+            entered,
+            vec![shares[3].clone(), shares[0].clone(), shares[4].clone()]
+        );
+        assert_eq!(shares::recover(entered)?, secret);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_pasted_shares() -> Result<()> {
+        let secret = [0x42; 32];
+        let shares = ShareBackend::Sharks.split(3, 5, &secret)?;
+
+        // two shares pasted at once w/ a duplicate & blank lines, then the
+        // last one on its own
+        let mut blocks = vec![
+            vec![
+                format!("{}\r", shares[0]),
+                String::new(),
+                shares[0].clone(),
+                shares[2].clone(),
+                "  ".to_string(),
+            ],
+            vec![shares[4].clone()],
+        ]
+        .into_iter();
+        let mut prompts = 0;
+        let entered = collect_shares(
+            3,
+            &[],
+            || {
+                prompts += 1;
+                blocks.next().ok_or_else(|| anyhow::anyhow!("out of lines"))
+            },
+            || panic!("no wrapped shares"),
+        )?;
+        assert_eq!(prompts, 2);
+        assert_eq!(
+            entered,
+            vec![shares[0].clone(), shares[2].clone(), shares[4].clone()]
+        );
+
+        // all of the shares pasted at once, the lines past the last share
+        // required are ignored
+        let block = shares.to_vec();
+        let entered = collect_shares(
+            3,
+            &[],
+            || Ok(block.clone()),
+            || panic!("no wrapped shares"),
+        )?;
+        assert_eq!(entered, shares[..3].to_vec());
+        assert_eq!(shares::recover(entered)?, secret);
+        Ok(())
+    }
+
+    #[test]
+    fn test_collect_wrapped_shares() -> Result<()> {
+        let secret = [0x42; 32];
+        let shares = ShareBackend::Sharks.split(3, 5, &secret)?;
+
+        // wrapped and plain shares can be entered together, in any encoding
+        let mut lines = vec![
+            shares::wrap_share(&shares[1], "one")?,
+            ShareEncoding::Base32.encode(&shares[2])?,
+            // wrong passphrase
+            shares::wrap_share(&shares[3], "three")?,
+            shares::wrap_share(&shares[3], "three")?,
+        ]
+        .into_iter();
+        let mut passphrases = vec!["one", "wrong", "three"].into_iter();
+        let entered = collect_shares(
+            3,
+            &[],
+            || {
+                lines
+                    .next()
+                    .map(|line| vec![line])
+                    .ok_or_else(|| anyhow::anyhow!("out of lines"))
+            },
+            || {
+                passphrases
+                    .next()
+                    .map(String::from)
+                    .ok_or_else(|| anyhow::anyhow!("out of passphrases"))
+            },
+        )?;
+        assert_eq!(entered, shares[1..4].to_vec());
+        assert_eq!(shares::recover(entered)?, secret);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recover_fingerprint() -> Result<()> {
+        let secret = [0x42; 32];
+        let shares = ShareBackend::Sharks.split(3, 5, &secret)?;
+        let expected = backup::fingerprint(&secret);
+
+        // in the encoding the custodians recorded the shares in
+        let base32 = |i: usize| ShareEncoding::Base32.encode(&shares[i]);
+        let entered =
+            vec![base32(4)?, String::new(), format!(" {}\r", base32(1)?)];
+        // fewer than the threshold
+        let err = recover_fingerprint(entered.clone(), None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::BadShare(_))
+        ));
+        let entered = [entered, vec![base32(2)?]].concat();
+        assert_eq!(recover_fingerprint(entered.clone(), None)?, expected);
+
+        let duplicate = vec![shares[0].clone(), shares[0].clone()];
+        assert!(matches!(
+            recover_fingerprint(duplicate, None)
+                .unwrap_err()
+                .downcast_ref::<HsmError>(),
+            Some(HsmError::DuplicateShare(_))
+        ));
+
+        // w/ a manifest its digests & fingerprint are checked
+        let out = TempDir::new()?;
+        let path = out.path().join(WRAP_BACKUP_FILE);
+        let mut manifest = WrapBackup::new(&wrap(), &secret, 5, 3)?;
+        manifest.share_digests =
+            shares.iter().map(|share| shares::digest(share)).collect();
+        manifest.write(&path)?;
+        assert_eq!(
+            recover_fingerprint(entered.clone(), Some(&path))?,
+            expected
+        );
+        let mut other = manifest.clone();
+        other.share_digests.remove(4);
+        other.write(&path)?;
+        let err =
+            recover_fingerprint(entered.clone(), Some(&path)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::UnknownShare(_))
+        ));
+        other = WrapBackup::new(&wrap(), &[0x24; 32], 5, 3)?;
+        other.share_digests = manifest.share_digests;
+        other.write(&path)?;
+        let err = recover_fingerprint(entered, Some(&path)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::FingerprintMismatch { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_share_title() {
+        assert_eq!(share_title("3-2-abcd", 0, 5), "Share 2 of 5");
+        assert_eq!(share_title("garbage", 3, 5), "Share 4 of 5");
+    }
+
+    #[test]
+    fn test_read_back_printed() -> Result<()> {
+        let shares = ShareBackend::Sharks.split(2, 3, &[0x42; 32])?;
+        let print = SharePrint {
+            dir: PathBuf::from("/media/usb"),
+            ceremony: "test-20230301T170500Z".to_string(),
+            qr: false,
+        };
+
+        // a custodian is asked again until the digest matches, in any case
+        // and w/ any separators
+        let mut entered = vec![
+            shares::digest(&shares[0]).to_uppercase(),
+            shares::digest(&shares[2]),
+            format!(" {} ", shares::digest(&shares[1])),
+            shares::digest(&shares[2]),
+        ]
+        .into_iter();
+        let mut asked = Vec::new();
+        read_back_printed(&shares, &print, |n| {
+            asked.push(n);
+            Ok(entered.next().unwrap())
+        })?;
+        assert_eq!(asked, [1, 2, 2, 3]);
+
+        // running out of input doesn't confirm the share
+        let err = read_back_printed(&shares, &print, |_| {
+            Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+        });
+        assert!(err.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_share_pdfs() -> Result<()> {
+        let shares = ShareBackend::Sharks.split(3, 5, &[0x42; 32])?;
+        let parent = TempDir::new()?;
+        let public = parent.path().join("public");
+        let out_dir = parent.path().join("usb");
+        fs::create_dir(&public)?;
+        fs::create_dir(&out_dir)?;
+        let print = SharePrint {
+            dir: out_dir.clone(),
+            ceremony: "test-20230301T170500Z".to_string(),
+            qr: true,
+        };
+
+        let paths =
+            write_share_pdfs(&shares, ShareEncoding::Base32, false, &print)?;
+        assert_eq!(paths.len(), 5);
+        for (i, path) in paths.iter().enumerate() {
+            assert_eq!(*path, out_dir.join(format!("share-{}.pdf", i + 1)));
+            assert_eq!(fs::metadata(path)?.permissions().mode() & 0o777, 0o600);
+            let pdf = fs::read_to_string(path)?;
+            assert!(pdf.contains("(Ceremony: test-20230301T170500Z)"));
+            assert!(pdf.contains(&format!("(Share {} of 5)", i + 1)));
+            assert!(pdf.contains(&shares::digest(&shares[i])));
+            assert!(pdf.contains(" re\n"));
+        }
+        assert_eq!(fs::read_dir(&public)?.count(), 0);
+
+        // existing share files are never overwritten and the shares are
+        // never written w/ the public data, this is checked before the
+        // YubiHSM is touched
+        let hsm = MockHsm::new();
+        let init = |print: SharePrint| {
+            Fixture::new(&public)
+                .mode(CeremonyMode::Dev)
+                .display(ShareDisplay::Pdf(print))
+                .initialize_with_key(
+                    &hsm,
+                    Zeroizing::new(vec![0; key_len(ALG)]),
+                )
+                .unwrap_err()
+        };
+        let err = init(print.clone());
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::OutputExists(path)) if *path == paths[0]
+        ));
+        let link = parent.path().join("link");
+        std::os::unix::fs::symlink(&public, &link)?;
+        for dir in [
+            public.clone(),
+            public.join("shares"),
+            public.join("../public/shares"),
+            link.join("shares"),
+        ] {
+            let err = init(SharePrint {
+                dir: dir.clone(),
+                ..print.clone()
+            });
+            assert!(
+                matches!(
+                    err.downcast_ref::<HsmError>(),
+                    Some(HsmError::ShareDirInPublic { share_dir, .. })
+                        if *share_dir == dir
+                ),
+                "{}",
+                err
+            );
+        }
+        assert!(!hsm.contains(WRAP_ID, Type::WrapKey));
+
+        // only the share files are wiped, symlinks are refused
+        fs::write(out_dir.join("share-notes.pdf"), "not a share")?;
+        let wiped = wipe_share_pdfs(&out_dir)?;
+        assert_eq!(wiped, paths);
+        assert!(paths.iter().all(|path| !path.exists()));
+        assert!(out_dir.join("share-notes.pdf").exists());
+        let link = out_dir.join("share-1.pdf");
+        std::os::unix::fs::symlink(out_dir.join("share-notes.pdf"), &link)?;
+        let err = secure_wipe(&link).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::NotRegularFile(_))
+        ));
+        assert_eq!(fs::read(out_dir.join("share-notes.pdf"))?, b"not a share");
+        let err = secure_wipe(&out_dir).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::NotRegularFile(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_share_escrow() -> Result<()> {
+        use escrow::tests::{decrypt, key_pair};
+
+        let shares = ShareBackend::RustySecrets.split(3, 5, &[0x42; 32])?;
+        let out_dir = TempDir::new()?;
+        let keys = TempDir::new()?;
+        let rsa = ["-algorithm", "RSA", "-pkeyopt", "rsa_keygen_bits:2048"];
+        let (key, public) = key_pair(keys.path(), "custodian", &rsa)?;
+        // the same card for every custodian keeps the test fast
+        let recipients = vec![RecipientKey::load(&public)?; 5];
+
+        let paths = write_share_escrow(
+            &shares,
+            ShareEncoding::Base32,
+            false,
+            &recipients,
+            out_dir.path(),
+        )?;
+        assert_eq!(paths.len(), 5);
+        for (i, path) in paths.iter().enumerate() {
+            assert_eq!(
+                *path,
+                out_dir.path().join(format!("share-{}.enc", i + 1))
+            );
+            assert_eq!(
+                String::from_utf8(decrypt(&key, path)?)?,
+                ShareEncoding::Base32.encode(&shares[i])?
+            );
+        }
+
+        // a key for each share is required and existing share files are
+        // never overwritten, both are checked before the YubiHSM is touched
+        let hsm = MockHsm::new();
+        let init = |recipients: Vec<RecipientKey>| {
+            Fixture::new(out_dir.path())
+                .mode(CeremonyMode::Dev)
+                .display(ShareDisplay::Escrow(recipients))
+                .initialize_with_key(
+                    &hsm,
+                    Zeroizing::new(vec![0; key_len(ALG)]),
+                )
+                .unwrap_err()
+        };
+        let err = init(recipients[..4].to_vec());
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::RecipientCount {
+                expected: 5,
+                found: 4
+            })
+        ));
+        let err = init(recipients);
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::OutputExists(path)) if *path == paths[0]
+        ));
+        assert!(!hsm.contains(WRAP_ID, Type::WrapKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_shares_any_order() -> Result<()> {
+        let hsm = MockHsm::new();
+        let (shares, _) = Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+
+        let new_hsm = MockHsm::new();
+        restore_wrap_key(
+            &new_hsm,
+            &wrap(),
+            None,
+            CeremonyMode::Prod,
+            false,
+            vec![shares[4].clone(), shares[0].clone(), shares[2].clone()],
+        )?;
+        assert_eq!(
+            new_hsm.secret(WRAP_ID, Type::WrapKey),
+            hsm.secret(WRAP_ID, Type::WrapKey)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_wrap_key() -> Result<()> {
+        let hsm = MockHsm::new();
+        let (shares, _) = Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        let wrap_key = hsm.secret(WRAP_ID, Type::WrapKey);
+
+        let new_hsm = MockHsm::new();
+        let replace = check_wrap_slot(&new_hsm, ID, false)?;
+        assert!(!replace);
+        let report = restore_wrap_key(
+            &new_hsm,
+            &wrap(),
+            None,
+            CeremonyMode::Prod,
+            replace,
+            shares[..THRESHOLD as usize].to_vec(),
+        )?;
+        assert_eq!(new_hsm.secret(ID, Type::WrapKey), wrap_key);
+        assert_eq!(
+            report.fingerprint,
+            wrap_key.as_deref().map(backup::fingerprint)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_from_manifest() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        let wrap = WrapKeyParams {
+            domains: Domain::DOM1 | Domain::DOM2,
+            capabilities: Capability::EXPORT_WRAPPED
+                | Capability::IMPORT_WRAPPED,
+            delegated_capabilities: Capability::EXPORTABLE_UNDER_WRAP,
+            ..WrapKeyParams::with_label("backup-dev")?
+        };
+        let (shares, _) = Fixture::new(out.path())
+            .wrap(&wrap)
+            .mode(CeremonyMode::Dev)
+            .setup(&hsm)?;
+
+        let manifest = WrapBackup::load(&out.path().join(WRAP_BACKUP_FILE))?;
+        let digests: Vec<String> =
+            shares.iter().map(|s| shares::digest(s)).collect();
+        assert_eq!(manifest.share_digests, digests);
+        let new_hsm = MockHsm::new();
+        restore_wrap_key(
+            &new_hsm,
+            &manifest.params()?,
+            Some(&manifest.fingerprint),
+            CeremonyMode::Prod,
+            false,
+            shares[..manifest.threshold as usize].to_vec(),
+        )?;
+
+        // the wrap key is put w/ the same parameters it was created with
+        let info = new_hsm.get_object_info(wrap.id, Type::WrapKey)?.unwrap();
+        assert_eq!(info.label, wrap.label);
+        assert_eq!(info.domains, wrap.domains);
+        assert_eq!(info.capabilities, wrap.capabilities);
+        assert_eq!(info.delegated_capabilities, wrap.delegated_capabilities);
+        assert_eq!(info.algorithm, yubihsm::Algorithm::Wrap(wrap.algorithm));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_fingerprint_mismatch() -> Result<()> {
+        let out = TempDir::new()?;
+        Fixture::new(out.path()).setup(&MockHsm::new())?;
+        let manifest = WrapBackup::load(&out.path().join(WRAP_BACKUP_FILE))?;
+
+        // shares for a different wrap key
+        let hsm = MockHsm::new();
+        hsm.get_pseudo_random(1)?;
+        let (shares, _) = Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+
+        let new_hsm = MockHsm::new();
+        let res = restore_wrap_key(
+            &new_hsm,
+            &manifest.params()?,
+            Some(&manifest.fingerprint),
+            CeremonyMode::Prod,
+            false,
+            shares,
+        );
+        assert!(res.is_err());
+        assert!(!new_hsm.contains(ID, Type::WrapKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_with_shares() -> Result<()> {
+        let hsm = MockHsm::new();
+        let (shares, _) = Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        let restore = |hsm: &MockHsm, shares: Vec<String>| {
+            restore_with_shares(
+                hsm,
+                &wrap(),
+                AUTH_DOMAINS,
+                None,
+                CeremonyMode::Prod,
+                false,
+                shares,
+            )
+        };
+
+        // duplicates and too few shares are errors, not prompts
+        let new_hsm = MockHsm::new();
+        let dup = vec![shares[0].clone(), shares[0].clone(), shares[1].clone()];
+        assert!(restore(&new_hsm, dup).is_err());
+        assert!(restore(&new_hsm, shares[..2].to_vec()).is_err());
+        assert!(!new_hsm.contains(ID, Type::WrapKey));
+
+        // shares are normalized like the ones that are typed in
+        let pasted = vec![
+            format!("  {}\r", shares[3]),
+            String::new(),
+            shares[1].clone(),
+            shares[4].clone(),
+        ];
+        restore(&new_hsm, pasted)?;
+        assert_eq!(
+            new_hsm.secret(ID, Type::WrapKey),
+            hsm.secret(ID, Type::WrapKey)
+        );
+
+        // shares of a secret that isn't the length of the wrap key don't
+        // replace the existing wrap key
+        let short = ShareBackend::RustySecrets.split(3, 5, &[0x42; 16])?;
+        let e = restore_with_shares(
+            &new_hsm,
+            &wrap(),
+            AUTH_DOMAINS,
+            None,
+            CeremonyMode::Prod,
+            true,
+            short[..3].to_vec(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HsmError>(),
+            Some(HsmError::RecoveredKeyLen {
+                expected: 32,
+                found: 16,
+                ..
+            })
+        ));
+        assert_eq!(
+            new_hsm.secret(ID, Type::WrapKey),
+            hsm.secret(ID, Type::WrapKey)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_too_few_shares() -> Result<()> {
+        let hsm = MockHsm::new();
+        let (shares, _) = Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+
+        let new_hsm = MockHsm::new();
+        let res = restore_wrap_key(
+            &new_hsm,
+            &wrap(),
+            None,
+            CeremonyMode::Prod,
+            false,
+            shares[..THRESHOLD as usize - 1].to_vec(),
+        );
+        assert!(res.is_err());
+        assert!(!new_hsm.contains(ID, Type::WrapKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_wrap_slot_occupied() -> Result<()> {
+        let hsm = MockHsm::new();
+        let (shares, _) = Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+
+        assert!(check_wrap_slot(&hsm, ID, false).is_err());
+        assert!(check_wrap_slot(&hsm, ID, true)?);
+
+        // replacing the wrap key w/ the same key succeeds
+        restore_wrap_key(
+            &hsm,
+            &wrap(),
+            None,
+            CeremonyMode::Prod,
+            true,
+            shares,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+
+        let spec = key_spec(out.path())?;
+        let report = Fixture::new(out.path()).generate(&hsm, &spec)?;
+
+        assert!(hsm.contains(3, Type::AsymmetricKey));
+        assert!(out.path().join("rot-identity-a.wrap.json").is_file());
+        assert_eq!(
+            fs::read(out.path().join("rot-identity-a.attest.cert.pem"))?,
+            b"attestation for key 3"
+        );
+
+        // the report describes the new key and the files written
+        assert_eq!(report.objects.len(), 1);
+        assert_eq!(report.objects[0].id, 3);
+        assert_eq!(report.objects[0].label, "rot-identity-a");
+        assert_eq!(
+            report.files,
+            vec![
+                out.path().join("rot-identity-a.wrap.json"),
+                out.path().join("rot-identity-a.attest.cert.pem"),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_pem() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+
+        let spec = key_spec(out.path())?;
+        Fixture::new(out.path())
+            .format(BackupFormat::Pem)
+            .generate(&hsm, &spec)?;
+
+        let path = out.path().join("rot-identity-a.wrap.pem");
+        let pem = fs::read_to_string(&path)?;
+        // the metadata comes before the PEM block
+        assert!(pem.starts_with("Serial: "));
+        assert!(pem.contains("\n-----BEGIN YUBIHSM WRAP-----"));
+        assert!(backup::read_wrapped(&path).is_ok());
+        let metadata = backup::read_metadata(&path)?.unwrap();
+        assert_eq!(metadata.label, "rot-identity-a");
+        assert!(!out.path().join("rot-identity-a.wrap.json").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_delegated_capabilities() -> Result<()> {
+        let hsm = MockHsm::new();
+        put_wrap_key(&hsm, &wrap(), &vec![0; key_len(ALG)])?;
+        let dir = TempDir::new()?;
+        Fixture::new(dir.path())
+            .mode(CeremonyMode::Dev)
+            .format(BackupFormat::Pem)
+            .generate(&hsm, &key_spec(dir.path())?)?;
+        let backup = dir.path().join("rot-identity-a.wrap.pem");
+        let metadata = backup::read_metadata(&backup)?.unwrap();
+        assert_eq!(
+            metadata.capabilities,
+            Some(config::capability_names(Capability::all()))
+        );
+
+        // the same wrap key w/o sign-ecdsa in its delegated capabilities
+        // refuses the backup before importing it
+        hsm.delete_object(3, Type::AsymmetricKey)?;
+        hsm.delete_object(WRAP_ID, Type::WrapKey)?;
+        let params = WrapKeyParams {
+            delegated_capabilities: Capability::all() - Capability::SIGN_ECDSA,
+            ..wrap()
+        };
+        put_wrap_key(&hsm, &params, &vec![0; key_len(ALG)])?;
+        let failures = verify_backups(&hsm, WRAP_ID, dir.path())?;
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].reason.contains("missing: sign-ecdsa"));
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+
+        // backups w/o the capabilities in their metadata can't be checked
+        let pem = fs::read_to_string(&backup)?;
+        let start = pem.find("Capabilities: ").unwrap();
+        let end = start + pem[start..].find("\r\n").unwrap() + 2;
+        fs::write(&backup, format!("{}{}", &pem[..start], &pem[end..]))?;
+        assert_eq!(verify_backups(&hsm, WRAP_ID, dir.path())?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_backups() -> Result<()> {
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        let dir = TempDir::new()?;
+        let spec = key_spec(dir.path())?;
+        Fixture::new(dir.path()).generate(&hsm, &spec)?;
+        let backup = dir.path().join("rot-identity-a.wrap.json");
+        let secret = hsm.secret(3, Type::AsymmetricKey);
+
+        // the live key is left alone
+        let failures = verify_backups(&hsm, WRAP_ID, dir.path())?;
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].path, backup);
+        assert_eq!(hsm.secret(3, Type::AsymmetricKey), secret);
+
+        // w/o the live key the backup imports and is deleted again
+        hsm.delete_object(3, Type::AsymmetricKey)?;
+        assert_eq!(verify_backups(&hsm, WRAP_ID, dir.path())?, vec![]);
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+
+        // a backup that doesn't decrypt is reported
+        let bad = dir.path().join("bad.wrap.json");
+        fs::write(
+            &bad,
+            BackupFormat::Json.encode(
+                wrap::Message {
+                    nonce: wrap::Nonce([0; 13]),
+                    ciphertext: vec![0; 64],
+                },
+                None,
+            )?,
+        )?;
+        let failures = verify_backups(&hsm, WRAP_ID, dir.path())?;
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].path, bad);
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_wrap_id() -> Result<()> {
+        let hsm = MockHsm::new();
+        let wrap = WrapKeyParams { id: 9, ..wrap() };
+        put_wrap_key(&hsm, &wrap, &vec![0; key_len(ALG)])?;
+        let dir = TempDir::new()?;
+        let spec = key_spec(dir.path())?;
+
+        // there's no wrap key at the default id
+        let err = Fixture::new(dir.path()).generate(&hsm, &spec).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::NoWrapKey(WRAP_ID))
+        ));
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+
+        // the key is exported under the wrap key it's told to use & the
+        // backup is checked under it
+        let opts = CeremonyOptions {
+            wrap_id: wrap.id,
+            ..opts()
+        };
+        generate(&hsm, &spec, AUTH_DOMAINS, dir.path(), &opts)?;
+        hsm.delete_object(3, Type::AsymmetricKey)?;
+        assert_eq!(verify_backups(&hsm, wrap.id, dir.path())?, vec![]);
+        assert!(verify_backups(&hsm, WRAP_ID, dir.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_pubkeys() -> Result<()> {
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        let dir = TempDir::new()?;
+        let spec_path = key_spec(dir.path())?;
+        Fixture::new(dir.path()).generate(&hsm, &spec_path)?;
+        let specs = load_key_specs(&spec_path, false)?;
+
+        let out = TempDir::new()?;
+        let written =
+            export_pubkeys(&hsm, &specs, out.path(), CeremonyMode::Prod)?;
+        let key = out.path().join("rot-identity-a.pub.pem");
+        let bundle = out.path().join(PUBKEY_BUNDLE_FILE);
+        assert_eq!(written, [key.clone(), bundle.clone()]);
+
+        let pem = fs::read_to_string(&key)?;
+        assert!(pem.starts_with("Label: rot-identity-a\r\n"));
+        assert_eq!(
+            pem::parse(&pem)?.contents,
+            pubkey::spki_der(&hsm.get_public_key(3)?)?
+        );
+        assert_eq!(fs::read_to_string(&bundle)?, pem);
+
+        // the files from an earlier export aren't overwritten
+        let e = export_pubkeys(&hsm, &specs, out.path(), CeremonyMode::Prod)
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HsmError>(),
+            Some(HsmError::OutputExists(_))
+        ));
+
+        // a key that isn't the one described by the spec
+        let json = JSON_ECP384
+            .replace("Ecp384", "Rsa4096")
+            .replace("\"Identity\"", "\"DevelopmentCodeSigningCA\"");
+        let e = export_pubkeys(
+            &hsm,
+            &[KeySpec::from_str(&json)?],
+            TempDir::new()?.path(),
+            CeremonyMode::Prod,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HsmError>(),
+            Some(HsmError::KeyAlgorithmMismatch { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_auth_key() -> Result<()> {
+        let hsm = MockHsm::new();
+        let first = TempDir::new()?;
+        Fixture::new(first.path()).setup(&hsm)?;
+        fs::remove_file(
+            first
+                .path()
+                .join(auth_wrap_file(AUTH_ID, BackupFormat::Json)),
+        )?;
+
+        // the lost admin.json is written again from the auth key in place
+        let out = TempDir::new()?;
+        let path = backup_auth_key(
+            &hsm,
+            AUTH_ID,
+            WRAP_ID,
+            out.path(),
+            CeremonyMode::Prod,
+            BackupFormat::Json,
+        )?;
+        assert_eq!(
+            path,
+            out.path().join(auth_wrap_file(AUTH_ID, BackupFormat::Json))
+        );
+        assert!(hsm.contains(AUTH_ID, Type::AuthenticationKey));
+        let metadata = backup::read_metadata(&path)?.unwrap();
+        assert_eq!(metadata.serial, hsm::mock::SERIAL_NUMBER);
+        assert_eq!(metadata.id, AUTH_ID);
+        assert_eq!(metadata.object_type, "authentication-key");
+        assert_eq!(metadata.label, AUTH_LABEL);
+
+        // and it restores the auth key
+        let secret = hsm.secret(AUTH_ID, Type::AuthenticationKey);
+        hsm.delete_object(AUTH_ID, Type::AuthenticationKey)?;
+        hsm.import_wrapped(WRAP_ID, backup::read_wrapped(&path)?)?;
+        assert_eq!(hsm.secret(AUTH_ID, Type::AuthenticationKey), secret);
+
+        // an existing backup isn't overwritten in a prod ceremony
+        let e = backup_auth_key(
+            &hsm,
+            AUTH_ID,
+            WRAP_ID,
+            out.path(),
+            CeremonyMode::Prod,
+            BackupFormat::Json,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HsmError>(),
+            Some(HsmError::OutputExists(_))
+        ));
+
+        let e = backup_auth_key(
+            &hsm,
+            AUTH_ID + 1,
+            WRAP_ID,
+            TempDir::new()?.path(),
+            CeremonyMode::Prod,
+            BackupFormat::Json,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<HsmError>(),
+            Some(HsmError::NoSuchObject(_, Type::AuthenticationKey))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrap() -> Result<()> {
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        let dir = TempDir::new()?;
+        let spec = key_spec(dir.path())?;
+        Fixture::new(dir.path()).generate(&hsm, &spec)?;
+        let backup = dir.path().join("rot-identity-a.wrap.json");
+        let secret = hsm.secret(3, Type::AsymmetricKey);
+
+        // the new backup key
+        let new_wrap = WrapKeyParams {
+            id: WRAP_ID + 1,
+            ..wrap()
+        };
+        let new_key = vec![7; key_len(new_wrap.algorithm)];
+        put_wrap_key(&hsm, &new_wrap, &new_key)?;
+
+        // the key is imported from the old backup if it's not in the YubiHSM
+        hsm.delete_object(3, Type::AsymmetricKey)?;
+        let out = TempDir::new()?;
+        let rewrap = |backup| {
+            rewrap(
+                &hsm,
+                WRAP_ID,
+                new_wrap.id,
+                3,
+                Type::AsymmetricKey,
+                backup,
+                out.path(),
+                CeremonyMode::Dev,
+                BackupFormat::Json,
+            )
+        };
+        assert!(rewrap(None).is_err());
+        let path = rewrap(Some(&backup))?;
+        assert_eq!(
+            path,
+            out.path().join("asymmetric-key-3-rot-identity-a.json")
+        );
+        assert_eq!(hsm.secret(3, Type::AsymmetricKey), secret);
+
+        // the new backup opens w/ the new wrap key, and only w/ it
+        let other = MockHsm::new();
+        put_wrap_key(
+            &other,
+            &wrap(),
+            &hsm.secret(WRAP_ID, Type::WrapKey).unwrap(),
+        )?;
+        assert!(other
+            .import_wrapped(WRAP_ID, backup::read_wrapped(&path)?)
+            .is_err());
+        put_wrap_key(&other, &new_wrap, &new_key)?;
+        other.import_wrapped(new_wrap.id, backup::read_wrapped(&path)?)?;
+        assert_eq!(other.secret(3, Type::AsymmetricKey), secret);
+
+        // a live key isn't replaced by the backup
+        assert_eq!(rewrap(Some(&backup))?, path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_wrap() -> Result<()> {
+        let hsm = MockHsm::new();
+        let old = WrapKeyParams {
+            algorithm: wrap::Algorithm::Aes128Ccm,
+            ..wrap()
+        };
+        let backup_dir = TempDir::new()?;
+        let (shares, _) = Fixture::new(backup_dir.path())
+            .wrap(&old)
+            .backend(ShareBackend::Sharks)
+            .setup(&hsm)?;
+        let spec_dir = TempDir::new()?;
+        let spec = key_spec(spec_dir.path())?;
+        Fixture::new(backup_dir.path()).generate(&hsm, &spec)?;
+        let secret = hsm.secret(3, Type::AsymmetricKey);
+        // the key is imported from its backup
+        hsm.delete_object(3, Type::AsymmetricKey)?;
+        let manifest =
+            WrapBackup::load(&backup_dir.path().join(WRAP_BACKUP_FILE))?;
+        let old_key = hsm.secret(WRAP_ID, Type::WrapKey);
+        let wrap_keys = |hsm: &MockHsm| {
+            hsm.objects
+                .borrow()
+                .keys()
+                .filter(|(_, t)| *t == Type::WrapKey)
+                .count()
+        };
+
+        let migrate =
+            |out_dir: &Path,
+             shares: Vec<String>,
+             record: &dyn Fn(&[String]) -> Result<()>| {
+                migrate_wrap_with_shares(
+                    &hsm,
+                    &manifest,
+                    shares,
+                    wrap::Algorithm::Aes256Ccm,
+                    backup_dir.path(),
+                    out_dir,
+                    CeremonyMode::Prod,
+                    BackupFormat::Json,
+                    record,
+                )
+            };
+        let ok = |_: &[String]| Ok(());
+
+        // the new backups can't replace the old ones
+        let err =
+            migrate(backup_dir.path(), shares[..3].to_vec(), &ok).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::MigrateInPlace(_))
+        ));
+
+        // shares of another key change nothing
+        let other = ShareBackend::Sharks.split(3, 5, &[0x42; 16])?;
+        let scratch = TempDir::new()?;
+        let err =
+            migrate(scratch.path(), other[..3].to_vec(), &ok).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::FingerprintMismatch { .. })
+        ));
+        assert_eq!(wrap_keys(&hsm), 1);
+
+        // if the new shares aren't recorded the old wrap key stays
+        let scratch = TempDir::new()?;
+        let err = migrate(scratch.path(), shares[1..4].to_vec(), &|_| {
+            Err(HsmError::NotConfirmed("record the shares").into())
+        })
+        .unwrap_err();
+        assert!(err.downcast_ref::<HsmError>().is_some());
+        assert_eq!(wrap_keys(&hsm), 1);
+        assert_eq!(hsm.secret(WRAP_ID, Type::WrapKey), old_key);
+        // nor the new backups & manifest, or the imported key
+        assert_eq!(fs::read_dir(scratch.path())?.count(), 0);
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+
+        let out = TempDir::new()?;
+        let recorded = std::cell::RefCell::new(Vec::new());
+        let report = migrate(out.path(), shares[2..].to_vec(), &|shares| {
+            recorded.replace(shares.to_vec());
+            Ok(())
+        })?;
+        let new_shares = recorded.take();
+        assert_eq!(new_shares.len(), 5);
+        assert_eq!(wrap_keys(&hsm), 1);
+        let new_key = hsm.secret(WRAP_ID, Type::WrapKey).unwrap();
+        assert_eq!(new_key.len(), 32);
+        assert_eq!(hsm.secret(3, Type::AsymmetricKey), secret);
+
+        // the new manifest describes the new key, the old one is unchanged
+        let new_manifest =
+            WrapBackup::load(&out.path().join(WRAP_BACKUP_FILE))?;
+        assert_eq!(new_manifest.algorithm, config::OksWrapAlgorithm::Aes256Ccm);
+        assert_eq!(new_manifest.fingerprint, backup::fingerprint(&new_key));
+        assert_eq!(report.fingerprint, Some(new_manifest.fingerprint.clone()));
+        assert_eq!(shares::recover(new_shares[..3].to_vec())?, new_key);
+        assert_eq!(
+            WrapBackup::load(&backup_dir.path().join(WRAP_BACKUP_FILE))?,
+            manifest
+        );
+
+        // every object was exported under the new key
+        let other = MockHsm::new();
+        put_wrap_key(&other, &new_manifest.params()?, &new_key)?;
+        for name in [
+            "authentication-key-2-admin.json",
+            "asymmetric-key-3-rot-identity-a.json",
+        ] {
+            let path = out.path().join(name);
+            assert!(report.files.contains(&path));
+            other.import_wrapped(WRAP_ID, backup::read_wrapped(&path)?)?;
+        }
+        assert_eq!(other.secret(3, Type::AsymmetricKey), secret);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_all() -> Result<()> {
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        let dir = TempDir::new()?;
+        let spec = key_spec(dir.path())?;
+        Fixture::new(dir.path()).generate(&hsm, &spec)?;
+        hsm.put_authentication_key(
+            7,
+            Label::from_bytes(b"not/exportable")?,
+            Domain::DOM1,
+            Capability::empty(),
+            Capability::empty(),
+            authentication::Algorithm::default(),
+            Key::derive_from_password(b"password"),
+        )?;
+
+        let out = TempDir::new()?;
+        let written = backup_all(
+            &hsm,
+            WRAP_ID,
+            out.path(),
+            CeremonyMode::Prod,
+            BackupFormat::Json,
+        )?;
+        let mut names: Vec<String> = written
+            .iter()
+            .map(|p| p.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "asymmetric-key-3-rot-identity-a.json",
+                "authentication-key-2-admin.json",
+            ]
+        );
+        // neither is the wrap key, under itself
+        for skipped in [
+            "authentication-key-7-not_exportable.json",
+            "wrap-key-1-backup.json",
+        ] {
+            assert!(!out.path().join(skipped).exists());
+        }
+        // the backups are found like those written by `generate`
+        let mut written = written;
+        written.sort();
+        assert_eq!(backup_files(out.path())?, written);
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_batch() -> Result<()> {
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        let second = JSON_ECP384
+            .replace("\"id\": 3", "\"id\": 4")
+            .replace("rot-identity-a", "rot-identity-b");
+
+        // one file holding an array of specs
+        let out = TempDir::new()?;
+        let spec = out.path().join("keys.json");
+        fs::write(&spec, format!("[{}, {}]", JSON_ECP384, second))?;
+        Fixture::new(out.path())
+            .mode(CeremonyMode::Dev)
+            .generate(&hsm, &spec)?;
+        assert!(hsm.contains(3, Type::AsymmetricKey));
+        assert!(hsm.contains(4, Type::AsymmetricKey));
+        assert!(out.path().join("rot-identity-b.wrap.json").is_file());
+
+        // a directory of spec files
+        let specs = TempDir::new()?;
+        fs::write(specs.path().join("a.json"), JSON_ECP384)?;
+        fs::write(specs.path().join("b.json"), &second)?;
+        fs::write(specs.path().join("README"), "not a spec")?;
+        assert_eq!(load_key_specs(specs.path(), false)?.len(), 2);
+        // the names don't follow the <label>.keyspec.json convention
+        assert!(load_key_specs(specs.path(), true).is_err());
+        let named = TempDir::new()?;
+        fs::write(
+            named.path().join("rot-identity-a.keyspec.json"),
+            JSON_ECP384,
+        )?;
+        assert_eq!(load_key_specs(named.path(), true)?.len(), 1);
+
+        // duplicate ids are refused before any key is created
+        let dup = out.path().join("dup.json");
+        fs::write(&dup, format!("[{}, {}]", JSON_ECP384, JSON_ECP384))?;
+        let hsm = MockHsm::new();
+        assert!(Fixture::new(out.path())
+            .mode(CeremonyMode::Dev)
+            .generate(&hsm, &dup)
+            .is_err());
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_serial() -> Result<()> {
+        let hsm = MockHsm::new();
+        check_serial(&hsm, SerialNumber::from_str(hsm::mock::SERIAL_NUMBER)?)?;
+
+        let err = check_serial(&hsm, SerialNumber::from_str("0000000001")?)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::SerialMismatch { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_show_object() -> Result<()> {
+        let hsm = MockHsm::new();
+        hsm.generate_asymmetric_key(
+            4,
+            Label::from("show-me"),
+            Domain::DOM1 | Domain::DOM3,
+            Capability::SIGN_ECDSA | Capability::EXPORTABLE_UNDER_WRAP,
+            asymmetric::Algorithm::EcP384,
+        )?;
+
+        let shown = show_object(&hsm, 4, Type::AsymmetricKey)?;
+        assert!(shown.contains("Id: 4\n"));
+        assert!(shown.contains("Type: asymmetric-key\n"));
+        assert!(shown.contains("Label: show-me\n"));
+        assert!(shown.contains("Domains: [1, 3]\n"));
+        assert!(
+            shown.contains("Capabilities: exportable-under-wrap, sign-ecdsa\n")
+        );
+
+        let err = show_object(&hsm, 4, Type::WrapKey).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::NoSuchObject(4, Type::WrapKey))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ceremony_dir() -> Result<()> {
+        let out = TempDir::new()?;
+        // 2023-03-01 17:05:00 UTC
+        let start = OffsetDateTime::from_unix_timestamp(1677690300)?;
+        let id = ceremony_id("rot-keys", start)?;
+        assert_eq!(id, "rot-keys-20230301T170500Z");
+        assert!(ceremony_id("../rot-keys", start).is_err());
+        assert!(ceremony_dir(out.path(), "..").is_err());
+
+        // the directory is only created when asked for
+        let dir = ceremony_dir(&out.path().join("public"), &id)?;
+        assert_eq!(dir, out.path().join("public").join(&id));
+        assert!(!dir.exists());
+        assert!(!create_ceremony_dir(&dir, false)?);
+        assert!(dir.is_dir());
+
+        // an existing ceremony is only reused when forced
+        let err = create_ceremony_dir(&dir, false).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::CeremonyExists(path)) if path == &dir
+        ));
+        assert!(create_ceremony_dir(&dir, true)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_storage() -> Result<()> {
+        let hsm = MockHsm::new();
+        let storage = storage(&hsm)?;
+        assert_eq!(storage.used_records, 1);
+        assert_eq!(storage.free_records, 255);
+        assert_eq!(storage.used_bytes, 126);
+
+        let ecp384 = KeySpec::from_str(JSON_ECP384)?;
+        let rsa4k = KeySpec {
+            algorithm: asymmetric::Algorithm::Rsa4096,
+            ..KeySpec::from_str(JSON_ECP384)?
+        };
+        let rsa4k = [&rsa4k];
+        // an EC key fits in 2 pages, RSA-4096 needs 15
+        assert!(check_storage(&hsm.get_storage_info()?, &[&ecp384]).is_ok());
+        hsm.total_pages.set(Some(15));
+        let info = hsm.get_storage_info()?;
+        assert!(check_storage(&info, &rsa4k).is_err());
+        hsm.total_pages.set(Some(16));
+        let info = hsm.get_storage_info()?;
+        assert!(check_storage(&info, &rsa4k).is_ok());
+
+        // keys that don't fit are refused before any key is created
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        hsm.total_pages.set(Some(3));
+        let out = TempDir::new()?;
+        let spec = key_spec(out.path())?;
+        assert!(Fixture::new(out.path()).generate(&hsm, &spec).is_err());
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sign_report() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        Fixture::new(out.path()).setup(&hsm)?;
+        let spec = key_spec(out.path())?;
+        let report = Fixture::new(out.path()).generate(&hsm, &spec)?;
+
+        let ceremony = CeremonyReport::new(report)?;
+        let path = out.path().join("ceremony.json");
+        let written = sign_report(&hsm, 3, &ceremony, &path)?;
+        let sig_path = out.path().join("ceremony.json.sig");
+        assert_eq!(written.files, vec![path.clone(), sig_path.clone()]);
+
+        // the P-384 key signs the SHA-384 digest of the report as written
+        let json = fs::read(&path)?;
+        assert_eq!(json, ceremony.to_canonical_json()?);
+        let signature = fs::read(&sig_path)?;
+        assert_eq!(signature[..48], Sha384::digest(&json)[..]);
+        assert_eq!(
+            signature[48..],
+            hsm.secret(3, Type::AsymmetricKey).unwrap()
+        );
+
+        // only ECDSA keys sign reports
+        hsm.generate_asymmetric_key(
+            4,
+            Label::from("rsa"),
+            Domain::DOM1,
+            Capability::all(),
+            asymmetric::Algorithm::Rsa2048,
+        )?;
+        assert!(sign_report(&hsm, 4, &ceremony, &path).is_err());
+        assert!(sign_report(&hsm, 5, &ceremony, &path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_benchmark() -> Result<()> {
+        let hsm = MockHsm::new();
+        let latencies = benchmark(&hsm, AUTH_DOMAINS, 10)?;
+        let operations: Vec<_> = latencies
+            .iter()
+            .map(|l| (l.operation.as_str(), l.runs))
+            .collect();
+        assert_eq!(
+            operations,
+            [
+                ("get-pseudo-random", 10),
+                ("get-object-info", 10),
+                ("generate-asymmetric-key", 1)
+            ]
+        );
+        // the key is deleted
+        assert!(!hsm
+            .list_objects()?
+            .iter()
+            .any(|e| e.object_type == Type::AsymmetricKey));
+
+        hsm.fail.set(Some("get_pseudo_random"));
+        assert!(benchmark(&hsm, AUTH_DOMAINS, 10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_latency() {
+        let ms = Duration::from_millis;
+        let latency = Latency::from_samples(
+            "op",
+            (1..=100).rev().map(ms).collect::<Vec<_>>(),
+        );
+        assert_eq!(latency.runs, 100);
+        assert_eq!(latency.mean, Duration::from_micros(50_500));
+        assert_eq!(latency.median, ms(50));
+        assert_eq!(latency.p99, ms(99));
+
+        let latency = Latency::from_samples("op", vec![ms(7)]);
+        assert_eq!(
+            (latency.mean, latency.median, latency.p99),
+            (ms(7), ms(7), ms(7))
+        );
+        assert_eq!(
+            latency.to_string(),
+            "op: 1 runs, mean 7.0ms, median 7.0ms, p99 7.0ms"
+        );
+    }
+
+    #[test]
+    fn test_sign_keyspecs() -> Result<()> {
+        use cert::tests::{SIGNATURE, SIGNED_DATA, SIGNER_CERT};
+
+        let dir = TempDir::new()?;
+        let specs = dir.path().join("specs");
+        fs::create_dir(&specs)?;
+        let spec = specs.join("rot-identity-a.keyspec.json");
+        fs::write(&spec, JSON_ECP384)?;
+        let cert = dir.path().join("signer.cert.pem");
+        fs::write(&cert, SIGNER_CERT)?;
+
+        let hsm = MockHsm::new();
+        hsm.generate_asymmetric_key(
+            4,
+            Label::from("signer"),
+            Domain::DOM1,
+            Capability::all(),
+            asymmetric::Algorithm::EcP384,
+        )?;
+        let written = sign_keyspecs(&hsm, 4, &specs)?;
+        let sig = specs.join("rot-identity-a.keyspec.json.sig");
+        assert_eq!(written.files, vec![sig.clone()]);
+        let signature = fs::read(&sig)?;
+        assert_eq!(signature[..48], Sha384::digest(JSON_ECP384)[..]);
+
+        // the mock signature isn't made w/ the key in the cert
+        let err = verify_keyspecs(&specs, &cert).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::KeySpecSignature(path)) if *path == spec
+        ));
+        // and no key is generated from a spec that doesn't verify
+        let out = TempDir::new()?;
+        Fixture::new(out.path()).setup(&hsm)?;
+        let err = Fixture::new(out.path())
+            .verify_with(&cert)
+            .generate(&hsm, &specs)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::KeySpecSignature(path)) if *path == spec
+        ));
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+
+        // keys whose signatures can't be verified sign nothing
+        hsm.generate_asymmetric_key(
+            5,
+            Label::from("signer-k256"),
+            Domain::DOM1,
+            Capability::all(),
+            asymmetric::Algorithm::EcK256,
+        )?;
+        fs::remove_file(&sig)?;
+        let err = sign_keyspecs(&hsm, 5, &specs).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::BadSigningKey(5, _))
+        ));
+        assert!(!sig.exists());
+
+        // a spec signed w/ the key in the cert
+        fs::write(&spec, SIGNED_DATA)?;
+        fs::write(&sig, hex::decode(SIGNATURE)?)?;
+        verify_keyspecs(&specs, &cert)?;
+        verify_keyspecs(&spec, &cert)?;
+
+        fs::remove_file(&sig)?;
+        let err = verify_keyspecs(&specs, &cert).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::KeySpecUnsigned(path)) if *path == spec
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_no_wrap_key() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+
+        let spec = key_spec(out.path())?;
+        assert!(Fixture::new(out.path()).generate(&hsm, &spec).is_err());
+        assert!(!out.path().join("rot-identity-a.wrap.json").exists());
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+
+        // a wrap key that can't export is refused before any key is created
+        hsm.put_wrap_key(
+            WRAP_ID,
+            Label::from("no-export"),
+            DOMAIN,
+            CAPS - Capability::EXPORT_WRAPPED,
+            DELEGATED_CAPS,
+            ALG,
+            &vec![0; key_len(ALG)],
+        )?;
+        let err = Fixture::new(out.path()).generate(&hsm, &spec).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "wrap key 1 is missing capabilities: export-wrapped"
+        );
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_refuses_overwrite() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+
+        let spec = key_spec(out.path())?;
+        fs::write(out.path().join("rot-identity-a.wrap.json"), "backup")?;
+        assert!(Fixture::new(out.path()).generate(&hsm, &spec).is_err());
+        // the key must not be created if we can't back it up
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+
+        Fixture::new(out.path())
+            .mode(CeremonyMode::Dev)
+            .generate(&hsm, &spec)?;
+        assert!(hsm.contains(3, Type::AsymmetricKey));
+        Ok(())
+    }
+
+    // the key specs and the id registry in data/ are what the commands use
+    // by default, they must parse and agree
+    #[test]
+    fn test_data_key_specs() -> Result<()> {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("data");
+        let errors = check_key_specs(&data)?;
+        assert!(errors.is_empty(), "{:?}", errors);
+        let registry = data.join("registry").join("key-ids.json");
+        assert_eq!(check_key_ids(&data, &registry)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_spec() -> Result<()> {
+        let hsm = MockHsm::new();
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        assert_eq!(verify_spec(&hsm, &spec)?, vec![SpecMismatch::Missing(3)]);
+
+        hsm.generate_asymmetric_key(
+            spec.id,
+            spec.label.clone(),
+            spec.domain,
+            spec.capabilities,
+            spec.algorithm,
+        )?;
+        assert!(verify_spec(&hsm, &spec)?.is_empty());
+
+        // a key created w/ other parameters than those in the spec
+        hsm.delete_object(spec.id, Type::AsymmetricKey)?;
+        hsm.generate_asymmetric_key(
+            spec.id,
+            "other".into(),
+            Domain::DOM2,
+            Capability::all() - Capability::SIGN_ECDSA,
+            asymmetric::Algorithm::Rsa4096,
+        )?;
+        let report = verify_spec(&hsm, &spec)?;
+        assert_eq!(
+            report,
+            vec![
+                SpecMismatch::Label {
+                    expected: "rot-identity-a".to_string(),
+                    found: "other".to_string(),
+                },
+                SpecMismatch::Domains {
+                    expected: vec![1],
+                    found: vec![2],
+                },
+                SpecMismatch::Capabilities {
+                    missing: vec!["sign-ecdsa".to_string()],
+                    unexpected: vec![],
+                },
+                SpecMismatch::Algorithm {
+                    expected: asymmetric::Algorithm::EcP384,
+                    found: Algorithm::Asymmetric(
+                        asymmetric::Algorithm::Rsa4096
+                    ),
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_hsms() -> Result<()> {
+        let (a, b) = (MockHsm::new(), MockHsm::new());
+        assert!(compare_hsms(&a, &b)?.is_empty());
+
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        for hsm in [&a, &b] {
+            hsm.generate_asymmetric_key(
+                spec.id,
+                spec.label.clone(),
+                spec.domain,
+                spec.capabilities,
+                spec.algorithm,
+            )?;
+        }
+        assert!(compare_hsms(&a, &b)?.is_empty());
+
+        // a key that drifted and keys on only one side
+        a.generate_asymmetric_key(
+            7,
+            "primary-only".into(),
+            Domain::DOM1,
+            Capability::SIGN_ECDSA,
+            asymmetric::Algorithm::EcP384,
+        )?;
+        b.delete_object(spec.id, Type::AsymmetricKey)?;
+        b.generate_asymmetric_key(
+            spec.id,
+            spec.label.clone(),
+            Domain::DOM2,
+            spec.capabilities - Capability::SIGN_ECDSA,
+            asymmetric::Algorithm::Rsa4096,
+        )?;
+        b.generate_asymmetric_key(
+            9,
+            "backup-only".into(),
+            Domain::DOM1,
+            Capability::SIGN_ECDSA,
+            asymmetric::Algorithm::EcP384,
+        )?;
+        let diffs = compare_hsms(&a, &b)?;
+        assert_eq!(
+            diffs,
+            vec![
+                ObjectDiff::Domains {
+                    id: spec.id,
+                    object_type: Type::AsymmetricKey,
+                    a: vec![1],
+                    b: vec![2],
+                },
+                ObjectDiff::Capabilities {
+                    id: spec.id,
+                    object_type: Type::AsymmetricKey,
+                    only_a: vec!["sign-ecdsa".to_string()],
+                    only_b: vec![],
+                },
+                ObjectDiff::Algorithm {
+                    id: spec.id,
+                    object_type: Type::AsymmetricKey,
+                    a: Algorithm::Asymmetric(asymmetric::Algorithm::EcP384),
+                    b: Algorithm::Asymmetric(asymmetric::Algorithm::Rsa4096),
+                },
+                ObjectDiff::OnlyInA {
+                    id: 7,
+                    object_type: Type::AsymmetricKey,
+                    label: "primary-only".to_string(),
+                },
+                ObjectDiff::OnlyInB {
+                    id: 9,
+                    object_type: Type::AsymmetricKey,
+                    label: "backup-only".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            diffs[3].to_string(),
+            "asymmetric-key 7 \"primary-only\" is only in the first YubiHSM"
+        );
+        Ok(())
     }
 
-    passwd_to_env("OKM_HSM_PKCS11_AUTH")?;
+    #[test]
+    fn test_generate_bad_out_dir() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
 
-    // get canonical path to CSR before chdir into CA dir
-    let csr = fs::canonicalize(csr)?;
-    debug!("canonical CSR: {}", csr.display());
-    let publish = fs::canonicalize(publish)?;
-    debug!("canonical publish: {}", publish.display());
+        let spec = key_spec(out.path())?;
+        let file = out.path().join("not-a-dir");
+        fs::write(&file, "")?;
+        for bad in [file, out.path().join("missing")] {
+            let err = Fixture::new(&bad).generate(&hsm, &spec).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<HsmError>(),
+                Some(HsmError::BadOutDir { path, .. }) if path == &bad
+            ));
+        }
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
+        Ok(())
+    }
 
-    // pushd into ca dir based on spec file
-    let pwd = std::env::current_dir()?;
-    debug!("got current directory: {:?}", pwd);
+    #[test]
+    fn test_generate_non_exportable() -> Result<()> {
+        let out = TempDir::new()?;
+        // a key that's never exported doesn't need a wrap key
+        let hsm = MockHsm::new();
 
-    let ca_dir = state.join(spec.label.to_string());
-    std::env::set_current_dir(&ca_dir)?;
-    debug!("setting current directory: {}", ca_dir.display());
+        let spec = out.path().join("key.spec");
+        fs::write(
+            &spec,
+            JSON_ECP384.replace(r#""All""#, r#""NonExportable""#),
+        )?;
+        let report = Fixture::new(out.path()).generate(&hsm, &spec)?;
 
-    // start connector
-    debug!("starting connector");
-    let mut connector = Command::new("yubihsm-connector").spawn()?;
+        assert!(hsm.contains(3, Type::AsymmetricKey));
+        assert!(!out.path().join("rot-identity-a.wrap.json").exists());
+        assert_eq!(
+            report.files,
+            vec![out.path().join("rot-identity-a.attest.cert.pem")]
+        );
+        assert_eq!(report.no_backup, vec!["rot-identity-a"]);
+        Ok(())
+    }
 
-    debug!("connector started");
-    std::thread::sleep(std::time::Duration::from_millis(1000));
+    #[test]
+    fn test_generate_exportable_signing_key() -> Result<()> {
+        let out = TempDir::new()?;
+        let spec = out.path().join("key.spec");
+        fs::write(
+            &spec,
+            JSON_ECP384.replace(r#""Identity""#, r#""DevelopmentCodeSigning""#),
+        )?;
+        let generate = |hsm: &MockHsm, mode| {
+            Fixture::new(out.path()).mode(mode).generate(hsm, &spec)
+        };
 
-    // cert file name takes prefix from CSR file name, appends ".cert.pem"
-    debug!("canonical csr: {}", csr.display());
-    let csr_filename = csr
-        .file_name()
-        .unwrap()
-        .to_os_string()
-        .into_string()
-        .unwrap();
-    let csr_prefix = match csr_filename.find('.') {
-        Some(i) => csr_filename[..i].to_string(),
-        None => csr_filename,
-    };
-    let cert = publish.join(format!("{}.cert.pem", csr_prefix));
+        // refused in prod before anything is created
+        let hsm = MockHsm::new();
+        let err = generate(&hsm, CeremonyMode::Prod).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<config::ConfigError>(),
+            Some(config::ConfigError::ExportableSigningKey { .. })
+        ));
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
 
-    // execute CA command
-    let mut cmd = Command::new("openssl");
-    cmd.arg("ca")
-        .arg("-batch")
-        .arg("-config")
-        .arg("openssl.cnf")
-        .arg("-engine")
-        .arg("pkcs11")
-        .arg("-keyform")
-        .arg("engine")
-        .arg("-keyfile")
-        .arg(format!("0:{:#04}", spec.id))
-        .arg("-extensions")
-        .arg(spec.purpose.to_string())
-        .arg("-passin")
-        .arg("env:OKM_HSM_PKCS11_AUTH")
-        .arg("-in")
-        .arg(&csr)
-        .arg("-out")
-        .arg(&cert);
+        // a dev ceremony only warns
+        let hsm = MockHsm::new();
+        put_wrap_key(&hsm, &wrap(), &vec![0; key_len(ALG)])?;
+        generate(&hsm, CeremonyMode::Dev)?;
+        assert!(hsm.contains(3, Type::AsymmetricKey));
+        Ok(())
+    }
 
-    info!("executing command: \"{:#?}\"", cmd);
-    let output = cmd.output()?;
+    #[test]
+    fn test_generate_per_key_layout() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        put_wrap_key(&hsm, &wrap(), &vec![0; key_len(ALG)])?;
+        let spec = out.path().join("key.spec");
+        fs::write(
+            &spec,
+            JSON_ECP384.replacen('{', "{\"ca_dir_name\": \"identity-ca\",", 1),
+        )?;
+        Fixture::new(out.path())
+            .mode(CeremonyMode::Dev)
+            .layout(OutputLayout::PerKey)
+            .generate(&hsm, &spec)?;
+        // the files for the key are in the directory named like its CA
+        // directory
+        let key_dir = out.path().join("identity-ca");
+        let backup = key_dir.join("rot-identity-a.wrap.json");
+        assert!(backup.is_file());
+        assert!(key_dir.join("rot-identity-a.attest.cert.pem").is_file());
+        assert!(!out.path().join("rot-identity-a.wrap.json").exists());
 
-    if !output.status.success() {
-        warn!("command failed with status: {}", output.status);
-        warn!("stderr: \"{}\"", String::from_utf8_lossy(&output.stderr));
-        connector.kill()?;
-        return Err(HsmError::CertGenFail.into());
+        // the backups in the key directories are found
+        hsm.delete_object(3, Type::AsymmetricKey)?;
+        assert_eq!(verify_backups(&hsm, WRAP_ID, out.path())?, vec![]);
+        assert_eq!(backup_files(out.path())?, vec![backup]);
+        Ok(())
     }
 
-    // kill connector
-    connector.kill()?;
+    #[test]
+    fn test_generate_in_domain() -> Result<()> {
+        let out = TempDir::new()?;
+        let spec = key_spec(out.path())?;
+        let hsm = MockHsm::new();
+        put_wrap_key(&hsm, &wrap(), &vec![0; key_len(ALG)])?;
 
-    std::env::set_current_dir(pwd)?;
+        // the scratch domain must be one the auth key can create objects in
+        let err = generate_in_domain(
+            &hsm,
+            &spec,
+            Domain::DOM16,
+            Domain::DOM1,
+            out.path(),
+            &CeremonyOptions {
+                mode: CeremonyMode::Dev,
+                ..opts()
+            },
+        )
+        .unwrap_err();
+        assert!(err.downcast_ref::<HsmError>().is_some());
+        assert!(!hsm.contains(3, Type::AsymmetricKey));
 
-    Ok(())
-}
+        generate_in_domain(
+            &hsm,
+            &spec,
+            Domain::DOM16,
+            AUTH_DOMAINS,
+            out.path(),
+            &CeremonyOptions {
+                mode: CeremonyMode::Dev,
+                ..opts()
+            },
+        )?;
+        let info = hsm.get_object_info(3, Type::AsymmetricKey)?.unwrap();
+        assert_eq!(info.domains, Domain::DOM16);
+        Ok(())
+    }
 
-/// Create the directory structure and initial files expected by the `openssl ca` tool.
-fn bootstrap_ca(key_spec: &KeySpec) -> Result<()> {
-    // create directories expected by `openssl ca`: crl, newcerts
-    for dir in ["crl", "newcerts"] {
-        debug!("creating directory: {}?", dir);
-        fs::create_dir(dir)?;
+    #[test]
+    fn test_export_keyspecs() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
+        put_wrap_key(&hsm, &wrap(), &vec![0; key_len(ALG)])?;
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        Fixture::new(out.path())
+            .mode(CeremonyMode::Dev)
+            .generate(&hsm, &key_spec(out.path())?)?;
+        hsm.generate_asymmetric_key(
+            4,
+            Label::from("other-domain"),
+            Domain::DOM2,
+            Capability::SIGN_ECDSA,
+            asymmetric::Algorithm::EcP256,
+        )?;
+
+        let specs = TempDir::new()?;
+        let paths = export_keyspecs(&hsm, specs.path())?;
+        assert_eq!(
+            paths,
+            [
+                specs.path().join("rot-identity-a.keyspec.json"),
+                specs.path().join("other-domain.keyspec.json"),
+            ]
+        );
+
+        // the spec doesn't parse until the placeholders are filled in
+        let json = fs::read_to_string(&paths[0])?;
+        assert!(KeySpec::from_str(&json).is_err());
+        let json = json
+            .replace(
+                "TODO: common name isn't recorded in the YubiHSM",
+                &spec.common_name,
+            )
+            .replace(
+                r#""TODO: hash isn't recorded in the YubiHSM""#,
+                r#""Sha384""#,
+            )
+            .replace(
+                r#""TODO: purpose isn't recorded in the YubiHSM""#,
+                r#""Identity""#,
+            );
+        let exported = KeySpec::from_str(&json)?;
+        assert_eq!(exported.id, spec.id);
+        assert_eq!(exported.label, spec.label);
+        assert_eq!(exported.capabilities, spec.capabilities);
+        assert_eq!(exported.domain, spec.domain);
+        assert_eq!(exported.algorithm, spec.algorithm);
+
+        let json = fs::read_to_string(&paths[1])?;
+        assert!(json.contains("TODO: domains [2] can't be expressed"));
+        assert!(json.contains("TODO: algorithm EcP256 isn't allowed"));
+
+        // existing specs are never overwritten
+        let err = export_keyspecs(&hsm, specs.path()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::OutputExists(_))
+        ));
+        Ok(())
     }
 
-    // the 'private' directory is a special case w/ restricted permissions
-    let priv_dir = "private";
-    debug!("creating directory: {}?", priv_dir);
-    fs::create_dir(priv_dir)?;
-    let perms = Permissions::from_mode(0o700);
-    debug!(
-        "setting permissions on directory {} to {:#?}",
-        priv_dir, perms
-    );
-    fs::set_permissions(priv_dir, perms)?;
+    #[test]
+    fn test_resume_generate() -> Result<()> {
+        let hsm = MockHsm::new();
+        let setup_dir = TempDir::new()?;
+        Fixture::new(setup_dir.path()).setup(&hsm)?;
+        let spec = |id: Id, label: &str| {
+            JSON_ECP384
+                .replace("\"id\": 3", &format!("\"id\": {}", id))
+                .replace("rot-identity-a", label)
+        };
+        let out = TempDir::new()?;
+        let batch = out.path().join("keys.json");
+        fs::write(
+            &batch,
+            format!(
+                "[{}, {}, {}]",
+                spec(3, "key-a"),
+                spec(4, "key-b"),
+                spec(5, "key-c")
+            ),
+        )?;
+        let generate = |resume| {
+            let generate = if resume { resume_generate } else { generate };
+            generate(&hsm, &batch, AUTH_DOMAINS, out.path(), &opts())
+        };
 
-    // touch 'index.txt' file
-    use std::fs::OpenOptions;
-    let index = "index.txt";
-    debug!("touching file {}", index);
-    OpenOptions::new().create(true).write(true).open(index)?;
+        // the ceremony was interrupted after creating the second key
+        generate_key(
+            &hsm,
+            &KeySpec::from_str(&spec(3, "key-a"))?,
+            out.path(),
+            &opts(),
+            &mut Report::default(),
+        )?;
+        let key_a = fs::read(out.path().join("key-a.wrap.json"))?;
+        hsm.generate_asymmetric_key(
+            4,
+            Label::from("key-b"),
+            Domain::DOM1,
+            Capability::all(),
+            asymmetric::Algorithm::EcP384,
+        )?;
 
-    // write initial serial number to 'serial' (echo 1000 > serial)
-    let serial = "serial";
-    let sn = 1000u32;
-    debug!(
-        "setting initial serial number to \"{}\" in file \"{}\"",
-        sn, serial
-    );
-    fs::write(serial, sn.to_string())?;
+        // running it again collides w/ the first key
+        assert!(generate(false).is_err());
 
-    // create & write out an openssl.cnf
-    fs::write(
-        "openssl.cnf",
-        format!(openssl_cnf_fmt!(), key = key_spec.id, hash = key_spec.hash),
-    )?;
+        let report = generate(true)?;
+        // the first key is untouched, the second is backed up and the
+        // third is generated
+        assert_eq!(fs::read(out.path().join("key-a.wrap.json"))?, key_a);
+        for label in ["key-b", "key-c"] {
+            let backup = out.path().join(format!("{}.wrap.json", label));
+            assert!(backup.is_file());
+            assert!(report.files.contains(&backup));
+        }
+        assert!(hsm.contains(5, Type::AsymmetricKey));
 
-    Ok(())
-}
+        // resuming again does nothing
+        let report = generate(true)?;
+        assert!(report.files.is_empty());
 
-// consts for our authentication credential
-const AUTH_DOMAINS: Domain = Domain::all();
-const AUTH_CAPS: Capability = Capability::all();
-const AUTH_DELEGATED: Capability = Capability::all();
-const AUTH_ID: Id = 2;
-const AUTH_LABEL: &str = "admin";
+        // a backup w/o metadata isn't taken as written, it's exported again
+        // & that collides w/ the file
+        let key_a_path = out.path().join("key-a.wrap.json");
+        let mut json: serde_json::Value = serde_json::from_slice(&key_a)?;
+        json.as_object_mut().unwrap().remove("metadata").unwrap();
+        fs::write(&key_a_path, json.to_string())?;
+        let err = generate(true).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::OutputExists(path)) if *path == key_a_path
+        ));
+        fs::write(&key_a_path, &key_a)?;
 
-/// This function prompts the user to enter M of the N backup shares. It
-/// uses these shares to reconstitute the wrap key. This wrap key can then
-/// be used to restore previously backed up / export wrapped keys.
-pub fn restore(client: &Client) -> Result<()> {
-    let mut shares: Vec<String> = Vec::new();
+        // an existing key that doesn't match its spec is refused
+        hsm.delete_object(5, Type::AsymmetricKey)?;
+        hsm.generate_asymmetric_key(
+            5,
+            Label::from("other"),
+            Domain::DOM1,
+            Capability::all(),
+            asymmetric::Algorithm::EcP384,
+        )?;
+        let err = generate(true).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::ResumeMismatch { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_id_mismatch() -> Result<()> {
+        let out = TempDir::new()?;
+        let hsm = MockHsm::new();
 
-    for i in 1..=THRESHOLD {
-        println!("Enter share[{}]: ", i);
-        shares.push(io::stdin().lines().next().unwrap().unwrap());
+        // id 0 has the YubiHSM pick the id
+        let spec = KeySpec {
+            id: 0,
+            ..KeySpec::from_str(JSON_ECP384)?
+        };
+        let mut report = Report::default();
+        let err = generate_key(
+            &hsm,
+            &spec,
+            out.path(),
+            &CeremonyOptions {
+                mode: CeremonyMode::Dev,
+                ..opts()
+            },
+            &mut report,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::IdMismatch {
+                expected: 0,
+                found: 1,
+                ..
+            })
+        ));
+        // nothing is exported for the misplaced key
+        assert!(hsm.contains(1, Type::AsymmetricKey));
+        assert!(report.files.is_empty());
+        Ok(())
     }
 
-    for (i, share) in shares.iter().enumerate() {
-        println!("share[{}]: {}", i, share);
+    #[test]
+    fn test_generate_attestation_failure() -> Result<()> {
+        let hsm = MockHsm::new();
+        Fixture::new(TempDir::new()?.path()).setup(&hsm)?;
+        hsm.fail.set(Some("sign_attestation_certificate"));
+
+        let out = TempDir::new()?;
+        let spec = key_spec(out.path())?;
+        assert!(Fixture::new(out.path()).generate(&hsm, &spec).is_err());
+
+        let out = TempDir::new()?;
+        let spec = key_spec(out.path())?;
+        hsm.delete_object(3, Type::AsymmetricKey)?;
+        Fixture::new(out.path())
+            .mode(CeremonyMode::Dev)
+            .generate(&hsm, &spec)?;
+        assert!(!out.path().join("rot-identity-a.attest.cert.pem").exists());
+        Ok(())
     }
 
-    let wrap_key =
-        rusty_secrets::recover_secret(shares).unwrap_or_else(|err| {
-            println!("Unable to recover key: {}", err);
-            std::process::exit(1);
+    // serve one request w/ the provided response, returning the address
+    fn serve_once(response: &'static str) -> Result<String> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?.to_string();
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(response.as_bytes());
+            }
         });
+        Ok(addr)
+    }
 
-    debug!("restored wrap key: {}", wrap_key.encode_hex::<String>());
+    #[test]
+    fn test_connector_status() -> Result<()> {
+        let addr = serve_once(
+            "HTTP/1.1 200 OK\r\n\r\nstatus=OK\nserial=*\nversion=3.0.4\n",
+        )?;
+        assert_eq!(
+            connector_status(&addr)?,
+            ConnectorStatus::Running(Some("3.0.4".to_string()))
+        );
 
-    // put restored wrap key the YubiHSM as an Aes256Ccm wrap key
-    let id = client
-        .put_wrap_key(
-            ID,
-            Label::from_bytes(LABEL.as_bytes())?,
-            DOMAIN,
-            CAPS,
-            DELEGATED_CAPS,
-            ALG,
-            wrap_key,
-        )
-        .with_context(|| {
+        let addr = serve_once("HTTP/1.1 404 Not Found\r\n\r\n")?;
+        assert_eq!(connector_status(&addr)?, ConnectorStatus::Other);
+
+        // nothing listens on the port of a listener we've dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        assert_eq!(connector_status(&addr)?, ConnectorStatus::Absent);
+        Ok(())
+    }
+
+    #[test]
+    fn test_version_report() -> Result<()> {
+        let hsm = MockHsm::new();
+        let addr = serve_once(
+            "HTTP/1.1 200 OK\r\n\r\nstatus=OK\nserial=*\nversion=3.0.4\n",
+        )?;
+        let report = version_report_at(&hsm, &addr)?;
+        assert_eq!(
+            report.connector,
+            ConnectorStatus::Running(Some("3.0.4".to_string()))
+        );
+        assert_eq!(report.firmware, hsm::mock::FIRMWARE_VERSION);
+        assert_eq!(
+            report.to_string(),
             format!(
-                "Failed to put wrap key into YubiHSM domains {:?} with id {}",
-                DOMAIN, ID
+                "oks-util: {}\n\
+                yubihsm crate: {}\n\
+                yubihsm-connector: 3.0.4\n\
+                YubiHSM firmware: 2.4.0\n\
+                YubiHSM serial number: 0012345678",
+                env!("CARGO_PKG_VERSION"),
+                YUBIHSM_CRATE_VERSION
             )
-        })?;
-    info!("wrap id: {}", id);
+        );
 
-    Ok(())
-}
+        // the crate version is the one resolved for the requirement in
+        // Cargo.toml, a 0.x.y requirement allows any 0.x patch release
+        let requirement = include_str!("../Cargo.toml")
+            .lines()
+            .find_map(|l| l.strip_prefix("yubihsm = { version = \""))
+            .and_then(|l| l.split('"').next())
+            .unwrap();
+        let (minor, _) = requirement.rsplit_once('.').unwrap();
+        assert!(
+            YUBIHSM_CRATE_VERSION.starts_with(&format!("{}.", minor)),
+            "{} doesn't match {}",
+            YUBIHSM_CRATE_VERSION,
+            requirement
+        );
 
-/// Initialize a new YubiHSM 2 by creating:
-/// - a new wap key for backup
-/// - a new auth key derived from a user supplied password
-/// This new auth key is backed up / exported under wrap using the new wrap
-/// key. This backup is written to the provided directory path. Finally this
-/// function removes the default authentication credentials.
-pub fn initialize(client: &Client, out_dir: &Path) -> Result<()> {
-    // get 32 bytes from YubiHSM PRNG
-    // TODO: zeroize
-    let wrap_key = client.get_pseudo_random(KEY_LEN)?;
-    info!("got {} bytes from YubiHSM PRNG", KEY_LEN);
-    debug!("got wrap key: {}", wrap_key.encode_hex::<String>());
-
-    // put 32 random bytes into the YubiHSM as an Aes256Ccm wrap key
-    let id = client
-        .put_wrap_key::<Vec<u8>>(
-            ID,
-            Label::from_bytes(LABEL.as_bytes())?,
-            DOMAIN,
-            CAPS,
-            DELEGATED_CAPS,
-            ALG,
-            wrap_key.clone(),
-        )
-        .with_context(|| {
+        // nothing listens on the port of a listener we've dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        let report = version_report_at(&hsm, &addr)?;
+        assert_eq!(report.connector, ConnectorStatus::Absent);
+        assert!(report
+            .to_string()
+            .contains("yubihsm-connector: not running\n"));
+
+        // something other than a connector is told apart
+        let addr = serve_once("HTTP/1.1 404 Not Found\r\n\r\n")?;
+        let report = version_report_at(&hsm, &addr)?;
+        assert_eq!(report.connector, ConnectorStatus::Other);
+        assert!(report
+            .to_string()
+            .contains("not running, something else is listening"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cnf_with_san() -> Result<()> {
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        let cnf = |spec: &KeySpec| {
+            cnf_with_san(
+                format!(
+                    openssl_cnf_fmt!(),
+                    key = 3,
+                    hash = Hash::Sha384.openssl_name(),
+                    module = "/usr/lib/pkcs11/yubihsm_pkcs11.so",
+                    debug = "",
+                    connector = CONNECTOR_ADDR,
+                ),
+                spec,
+            )
+        };
+        assert!(!cnf(&spec).contains("subjectAltName"));
+        assert_eq!(extensions(&spec, false), "v3_identity");
+
+        let json = JSON_ECP384.replace(
+            "\"purpose\"",
+            r#""san": ["DNS:a.oxide.computer", "DNS:b.oxide.computer",
+                "URI:urn:oxide:rot"],
+            "purpose""#,
+        );
+        let spec = KeySpec::from_str(&json)?;
+        let cnf = cnf(&spec);
+        assert!(cnf.ends_with(
+            "
+[ v3_identity_san ]
+subjectKeyIdentifier        = hash
+authorityKeyIdentifier      = keyid:always,issuer
+basicConstraints            = critical,CA:true
+keyUsage                    = critical, keyCertSign, cRLSign
+subjectAltName              = @v3_identity_alt_names
+
+[ v3_identity_alt_names ]
+DNS.1                       = a.oxide.computer
+DNS.2                       = b.oxide.computer
+URI.1                       = urn:oxide:rot
+"
+        ));
+
+        // the self signed CA cert only gets SANs if asked for
+        assert_eq!(extensions(&spec, false), "v3_identity_san");
+        assert_eq!(extensions(&spec, true), "v3_identity");
+        let spec = KeySpec {
+            san_in_ca_cert: true,
+            ..spec
+        };
+        assert_eq!(extensions(&spec, true), "v3_identity_san");
+        Ok(())
+    }
+
+    #[test]
+    fn test_cnf_with_key_usage() -> Result<()> {
+        let cnf = |spec: &KeySpec| {
+            cnf_with_san(
+                cnf_with_key_usage(
+                    format!(
+                        openssl_cnf_fmt!(),
+                        key = 3,
+                        hash = Hash::Sha384.openssl_name(),
+                        module = "/usr/lib/pkcs11/yubihsm_pkcs11.so",
+                        debug = "",
+                        connector = CONNECTOR_ADDR,
+                    ),
+                    spec,
+                ),
+                spec,
+            )
+        };
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        let default = cnf(&spec);
+        assert!(default.contains(
+            "[ v3_identity ]
+subjectKeyIdentifier        = hash
+authorityKeyIdentifier      = keyid:always,issuer
+basicConstraints            = critical,CA:true
+keyUsage                    = critical, keyCertSign, cRLSign
+"
+        ));
+
+        let json = JSON_ECP384.replace(
+            "\"purpose\"",
+            r#""san": ["DNS:a.oxide.computer"],
+            "key_usage": ["keyCertSign"],
+            "purpose""#,
+        );
+        let spec = KeySpec::from_str(&json)?;
+        let cnf = cnf(&spec);
+        let usage = "keyUsage                    = critical, keyCertSign\n";
+        // both the section for the purpose and the copy w/ the SANs, the
+        // sections for other purposes are left alone
+        assert_eq!(cnf.matches(usage).count(), 2);
+        assert!(cnf.contains(
+            "[ v3_identity_san ]
+subjectKeyIdentifier        = hash
+authorityKeyIdentifier      = keyid:always,issuer
+basicConstraints            = critical,CA:true
+keyUsage                    = critical, keyCertSign
+"
+        ));
+        assert_eq!(
+            cnf.matches("critical, keyCertSign, cRLSign").count(),
+            default.matches("critical, keyCertSign, cRLSign").count() - 1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_cnf_with_extended_key_usage() -> Result<()> {
+        let cnf = |spec: &KeySpec| {
+            cnf_with_san(
+                cnf_with_extended_key_usage(
+                    format!(
+                        openssl_cnf_fmt!(),
+                        key = 3,
+                        hash = Hash::Sha384.openssl_name(),
+                        module = "/usr/lib/pkcs11/yubihsm_pkcs11.so",
+                        debug = "",
+                        connector = CONNECTOR_ADDR,
+                    ),
+                    spec,
+                ),
+                spec,
+            )
+        };
+        let json = JSON_ECP384.replace(
+            "\"purpose\"",
+            r#""san": ["DNS:a.oxide.computer"],
+            "extended_key_usage": ["1.3.6.1.4.1.57551.2"],
+            "purpose""#,
+        );
+        let spec = KeySpec::from_str(&json)?;
+        let cnf = cnf(&spec);
+        let usage =
+            "extendedKeyUsage            = clientAuth, 1.3.6.1.4.1.57551.2\n";
+        // both the section for the purpose and the copy w/ the SANs
+        assert_eq!(cnf.matches("extendedKeyUsage").count(), 2);
+        assert_eq!(cnf.matches(usage).count(), 2);
+        // the one already in the section is replaced
+        assert_eq!(cnf_with_extended_key_usage(cnf.clone(), &spec), cnf);
+        // & a section w/o a keyUsage gets it at the end
+        let no_usage = "[ v3_identity ]\nbasicConstraints = CA:true\n\n[ x ]\n";
+        assert_eq!(
+            cnf_with_extended_key_usage(no_usage.to_string(), &spec),
             format!(
-                "Failed to put wrap key into YubiHSM domains {:?} with id {}",
-                DOMAIN, ID
+                "[ v3_identity ]\nbasicConstraints = CA:true\n{}\n[ x ]\n",
+                usage
             )
-        })?;
-    debug!("wrap id: {}", id);
-    // Future commands assume that our wrap key has id 1. If we got a wrap
-    // key with any other id the HSM isn't in the state we think it is.
-    assert_eq!(id, WRAP_ID);
+        );
+        assert_eq!(
+            cnf_with_extended_key_usage("[ v3_identity ]\n".to_string(), &spec),
+            format!("[ v3_identity ]\n{}", usage)
+        );
 
-    // do the stuff from replace-auth.sh
-    personalize(client, WRAP_ID, out_dir)?;
+        // issue a cert w/ the section for the purpose, w/ a software key and
+        // w/o the pkcs11 engine
+        let dir = TempDir::new()?;
+        let cnf_path = dir.path().join("openssl.cnf");
+        let cnf: String = cnf
+            .lines()
+            .filter(|l| !l.starts_with("openssl_conf"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        fs::write(&cnf_path, cnf)?;
+        let cert = dir.path().join("cert.pem");
+        let output = Command::new("openssl")
+            .args(["req", "-x509", "-new", "-nodes", "-newkey", "ec"])
+            .args(["-pkeyopt", "ec_paramgen_curve:P-384", "-subj", "/CN=test"])
+            .args(["-extensions", "v3_identity", "-config"])
+            .arg(&cnf_path)
+            .arg("-keyout")
+            .arg(dir.path().join("key.pem"))
+            .arg("-out")
+            .arg(&cert)
+            .output()?;
+        check_openssl("req -x509", &output)?;
 
-    let shares = rusty_secrets::generate_shares(THRESHOLD, SHARES, &wrap_key)
-        .with_context(|| {
-        format!(
-            "Failed to split secret into {} shares with threashold {}",
-            SHARES, THRESHOLD
-        )
-    })?;
+        let pem = fs::read(&cert)?;
+        let (_, pem) = x509_parser::pem::parse_x509_pem(&pem)?;
+        let cert = pem.parse_x509()?;
+        let eku = cert
+            .extended_key_usage()?
+            .ok_or_else(|| anyhow::anyhow!("no extendedKeyUsage"))?
+            .value;
+        assert!(eku.client_auth && !eku.code_signing);
+        let other: Vec<String> =
+            eku.other.iter().map(|oid| oid.to_id_string()).collect();
+        assert_eq!(other, ["1.3.6.1.4.1.57551.2"]);
+        Ok(())
+    }
 
-    println!(
-        "WARNING: The wrap / backup key has been created and stored in the\n\
-        YubiHSM. It will now be split into {} key shares. The operator must\n\
-        record these shares as they're displayed. Failure to do so will\n\
-        result in the inability to reconstruct this key and restore\n\
-        backups.\n\n\
-        Press enter to begin the key share recording process ...",
-        SHARES
-    );
+    #[test]
+    fn test_cnf_with_policy() -> Result<()> {
+        let cnf = format!(
+            openssl_cnf_fmt!(),
+            key = 3,
+            hash = Hash::Sha384.openssl_name(),
+            module = "/usr/lib/pkcs11/yubihsm_pkcs11.so",
+            debug = "",
+            connector = CONNECTOR_ADDR,
+        );
 
-    wait_for_line();
-    clear_screen();
+        // identity certs must name the organization
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        let policy = cnf_with_policy(cnf.clone(), &spec);
+        assert!(policy.contains(
+            "[ policy_match ]
+countryName                 = optional
+stateOrProvinceName         = optional
+organizationName            = supplied
+organizationalUnitName      = optional
+commonName                  = supplied
+emailAddress                = optional
 
-    for (i, share) in shares.iter().enumerate() {
-        let share_num = i + 1;
-        println!(
-            "When key custodian {share} is steated, press enter to display \
-            share {share}",
-            share = share_num
+[ req ]
+"
+        ));
+        assert_eq!(
+            policy.replace("organizationName            = supplied", ""),
+            cnf.replace("organizationName            = optional", "")
         );
-        wait_for_line();
 
-        // Can we generate a QR code, photograph it & then recover the key by
-        // reading them back through the camera?
-        println!("\n{}\n", share);
-        println!("When you are done recording this key share, press enter");
-        wait_for_line();
-        clear_screen();
+        let json = JSON_ECP384.replace(
+            "\"purpose\"",
+            r#""policy": {"countryName": "match"},
+            "purpose""#,
+        );
+        let spec = KeySpec::from_str(&json)?;
+        let policy = cnf_with_policy(cnf, &spec);
+        assert!(policy.contains("countryName                 = match\n"));
+        assert_eq!(policy.matches("countryName").count(), 1);
+        Ok(())
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_bootstrap_ca_rerun() -> Result<()> {
+        let state = TempDir::new()?;
+        let ca_dir = state.path().join("rot-identity-a");
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        let module = Pkcs11Module {
+            path: "/usr/lib/pkcs11/yubihsm_pkcs11.so".into(),
+            debug: false,
+        };
 
-// create a new auth key, remove the default auth key, then export the new
-// auth key under the wrap key with the provided id
-fn personalize(client: &Client, wrap_id: Id, out_dir: &Path) -> Result<()> {
-    debug!(
-        "personalizing with wrap key {} and out_dir {}",
-        wrap_id,
-        out_dir.display()
-    );
-    // get a new password from the user
-    let mut password = loop {
-        let password = rpassword::prompt_password(PASSWD_PROMPT).unwrap();
-        let mut password2 = rpassword::prompt_password(PASSWD_PROMPT2).unwrap();
-        if password != password2 {
-            error!("the passwords entered do not match");
-        } else {
-            password2.zeroize();
-            break password;
+        let initial = CaSerial::from_str(INITIAL_CA_SERIAL)?;
+        bootstrap_ca(&ca_dir, &spec, JSON_ECP384, &initial, &module, None)?;
+        for file in ["index.txt", "serial", "openssl.cnf", "key.spec"] {
+            assert!(ca_dir.join(file).is_file());
         }
-    };
-    debug!("got the same password twice: {}", password);
 
-    // not compatible with Zeroizing wrapper
-    let auth_key = Key::derive_from_password(password.as_bytes());
+        // a partial failure (e.g. in `openssl req`) leaves the directory
+        // behind, running again picks up where we left off
+        fs::remove_file(ca_dir.join("openssl.cnf"))?;
+        bootstrap_ca(&ca_dir, &spec, JSON_ECP384, &initial, &module, None)?;
+        assert!(ca_dir.join("openssl.cnf").is_file());
+        assert_eq!(fs::read_to_string(ca_dir.join("serial"))?, "1000");
 
-    debug!("putting new auth key from provided password");
-    // create a new auth key
-    client.put_authentication_key(
-        AUTH_ID,
-        AUTH_LABEL.into(),
-        AUTH_DOMAINS,
-        AUTH_CAPS,
-        AUTH_DELEGATED,
-        authentication::Algorithm::default(), // can't be used in const
-        auth_key,
-    )?;
+        // but the state of a different CA isn't reused
+        let other = JSON_ECP384.replace("\"id\": 3", "\"id\": 4");
+        let other_spec = KeySpec::from_str(&other)?;
+        assert!(bootstrap_ca(
+            &ca_dir,
+            &other_spec,
+            &other,
+            &initial,
+            &module,
+            None
+        )
+        .is_err());
+        fs::write(ca_dir.join("ca.cert.pem"), "cert")?;
+        let err =
+            bootstrap_ca(&ca_dir, &spec, JSON_ECP384, &initial, &module, None)
+                .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<HsmError>(),
+            Some(HsmError::ConflictingCa(path)) if path.ends_with("ca.cert.pem")
+        ));
+        Ok(())
+    }
 
-    debug!("deleting default auth key");
-    client.delete_object(
-        DEFAULT_AUTHENTICATION_KEY_ID,
-        Type::AuthenticationKey,
-    )?;
+    #[test]
+    fn test_openssl_cnf() -> Result<()> {
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        let module = Pkcs11Module {
+            path: "/usr/lib/pkcs11/yubihsm_pkcs11.so".into(),
+            debug: false,
+        };
 
-    debug!("exporting new auth key under wrap-key w/ id: {}", wrap_id);
-    let msg =
-        client.export_wrapped(wrap_id, Type::AuthenticationKey, AUTH_ID)?;
+        // w/o a template it's the built-in one
+        let builtin = openssl_cnf(None, &spec, &module)?;
+        assert_eq!(
+            builtin,
+            format!(
+                openssl_cnf_fmt!(),
+                key = spec.id,
+                hash = spec.hash.openssl_name(),
+                module = module.path.display(),
+                debug = "",
+                connector = CONNECTOR_ADDR,
+            )
+        );
+
+        // the built-in template is a template like any other
+        assert_eq!(
+            openssl_cnf(Some(builtin_cnf_template()), &spec, &module)?,
+            builtin
+        );
 
-    // include additional metadata (enough to reconstruct current state)?
-    let msg_json = serde_json::to_string(&msg)?;
+        // openssl's own `${var}` is left alone
+        let sections = "
+[ v3_identity ]
+keyUsage = critical, keyCertSign
 
-    debug!("msg_json: {:#?}", msg_json);
+[ policy_match ]
+commonName = supplied
+";
+        let template = [
+            "\
+[ CA_default ]
+dir = /srv/{label}
+certificate = ${dir}/ca.cert.pem
+private_key = 0:{key}
+default_md = {hash}
+# {common_name}
+MODULE_PATH = {module}
+INIT_ARGS = connector=http://{connector}{debug}
+",
+            sections,
+        ]
+        .concat();
+        let debug = Pkcs11Module {
+            debug: true,
+            ..module.clone()
+        };
+        let cnf = openssl_cnf(Some(&template), &spec, &debug)?;
+        assert_eq!(
+            cnf,
+            format!(
+                "\
+[ CA_default ]
+dir = /srv/rot-identity-a
+certificate = ${{dir}}/ca.cert.pem
+private_key = 0:0003
+default_md = sha384
+# {}
+MODULE_PATH = /usr/lib/pkcs11/yubihsm_pkcs11.so
+INIT_ARGS = connector=http://{} debug
+{}",
+                spec.common_name, CONNECTOR_ADDR, sections
+            )
+        );
 
-    // we need to append a name for our file
-    let mut auth_wrap_path = out_dir.to_path_buf();
-    auth_wrap_path.push(format!("{}.wrap.json", AUTH_LABEL));
-    debug!("writing to: {}", auth_wrap_path.display());
-    fs::write(&auth_wrap_path, msg_json)?;
+        // a template the CA can't use is refused
+        for (bad, reason) in [
+            (template.replace("{key}", "3"), "missing placeholder {key}"),
+            (
+                format!("{}{{keyid}}", template),
+                "unknown placeholder {keyid}",
+            ),
+            (
+                template.replace("{hash}", "{hash:>8}"),
+                "unsupported format spec {hash:>8}",
+            ),
+            (format!("{}{{hash", template), "{hash"),
+            (
+                template.replace("v3_identity", "v3_other"),
+                "missing section [ v3_identity ]",
+            ),
+            (
+                template.replace("keyUsage", "# keyUsage"),
+                "no keyUsage in section [ v3_identity ]",
+            ),
+            (
+                template.replace("[ policy_match ]", "[ policy ]"),
+                "missing section [ policy_match ]",
+            ),
+        ] {
+            let err = openssl_cnf(Some(&bad), &spec, &module);
+            assert!(
+                matches!(&err, Err(HsmError::BadCnfTemplate(r)) if r == reason),
+                "{:?}",
+                err
+            );
+        }
+        Ok(())
+    }
 
-    // dump cert for default attesation key in hsm
-    debug!("extracting attestation certificate");
-    let attest_cert = client.get_opaque(0)?;
-    let mut attest_path = out_dir.to_path_buf();
-    attest_path.push("hsm.attest.cert.pem");
+    #[test]
+    fn test_ca_serial() -> Result<()> {
+        assert_eq!(CaSerial::from_str("1000")?.to_string(), "1000");
+        assert_eq!(CaSerial::from_str("abc")?.to_string(), "0ABC");
+        assert_eq!(CaSerial::from_str("000001")?.to_string(), "01");
+        for bad in ["", "0", "000", "12g4", "-1000", "0x1000"] {
+            assert!(CaSerial::from_str(bad).is_err(), "accepted: {}", bad);
+        }
 
-    debug!("writing attestation cert to: {}", attest_path.display());
-    fs::write(&attest_path, attest_cert)?;
+        // the largest serial allowed is 20 octets, far more than a u128
+        let max = "F".repeat(CA_SERIAL_MAX_LEN);
+        assert_eq!(CaSerial::from_str(&max)?.to_string(), max);
+        assert!(CaSerial::from_str(&format!("1{}", max)).is_err());
 
-    password.zeroize();
+        let state = TempDir::new()?;
+        let ca_dir = state.path().join("rot-identity-a");
+        let spec = KeySpec::from_str(JSON_ECP384)?;
+        let large =
+            CaSerial::from_str("1234567890abcdef1234567890abcdef12345")?;
+        bootstrap_ca(
+            &ca_dir,
+            &spec,
+            JSON_ECP384,
+            &large,
+            &Pkcs11Module {
+                path: "/usr/lib/pkcs11/yubihsm_pkcs11.so".into(),
+                debug: false,
+            },
+            None,
+        )?;
+        assert_eq!(
+            fs::read_to_string(ca_dir.join("serial"))?,
+            "01234567890ABCDEF1234567890ABCDEF12345"
+        );
+        Ok(())
+    }
 
-    Ok(())
-}
+    #[test]
+    fn test_check_openssl() -> Result<()> {
+        let output = Command::new("sh")
+            .args(["-c", "echo out; echo 'bad engine' >&2; exit 3"])
+            .output()?;
+        let err = check_openssl("req", &output).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "openssl req failed w/ exit status: 3: bad engine"
+        );
+        assert!(matches!(
+            err,
+            HsmError::OpensslFailed { stage: "req", stdout, stderr, .. }
+                if stdout == "out\n" && stderr == "bad engine\n"
+        ));
 
-/// This "clears" the screen using terminal control characters. If your
-/// terminal has a scroll bar that can be used to scroll back to previous
-/// screens that had been "cleared".
-fn clear_screen() {
-    print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
-}
+        let output = Command::new("true").output()?;
+        assert!(check_openssl("req", &output).is_ok());
+        Ok(())
+    }
 
-/// This function is used when displaying key shares as a way for the user to
-/// control progression through the key shares displayed in the terminal.
-fn wait_for_line() {
-    let _ = io::stdin().lines().next().unwrap().unwrap();
+    #[test]
+    fn test_private_key_id() {
+        let cnf = format!(
+            openssl_cnf_fmt!(),
+            key = 3,
+            hash = Hash::Sha384.openssl_name(),
+            module = "/opt/yubihsm/yubihsm_pkcs11.so",
+            debug = pkcs11_init_debug(false),
+            connector = CONNECTOR_ADDR,
+        );
+        assert_eq!(private_key_id(&cnf), Some(3));
+        assert!(cnf.contains(
+            "MODULE_PATH                 = /opt/yubihsm/yubihsm_pkcs11.so\n"
+        ));
+        assert!(cnf.contains("= connector=http://127.0.0.1:12345\n"));
+
+        let cnf = format!(
+            openssl_cnf_fmt!(),
+            key = 3,
+            hash = Hash::Sha384.openssl_name(),
+            module = "/opt/yubihsm/yubihsm_pkcs11.so",
+            debug = pkcs11_init_debug(true),
+            connector = CONNECTOR_ADDR,
+        );
+        assert!(cnf.contains("= connector=http://127.0.0.1:12345 debug\n"));
+    }
+
+    #[test]
+    fn test_cnf_sha512() {
+        let cnf = format!(
+            openssl_cnf_fmt!(),
+            key = 3,
+            hash = Hash::Sha512.openssl_name(),
+            module = "/opt/yubihsm/yubihsm_pkcs11.so",
+            debug = "",
+            connector = CONNECTOR_ADDR,
+        );
+        assert_eq!(
+            cnf.matches("\ndefault_md                  = sha512\n")
+                .count(),
+            2
+        );
+    }
 }
@@ -0,0 +1,287 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Logging for the operator and for the audit record. The console gets
+//! the usual `env_logger` output at the level the operator asked for. The
+//! log file, if any, gets every record at debug level w/ a timestamp so
+//! that it can be archived w/ the other outputs of the ceremony.
+//!
+//! Secrets are only ever logged w/ `SECRET_TARGET` as the target, and only
+//! when the ceremony mode doesn't redact them (see `CeremonyMode::redact`).
+//! Records w/ this target go to the console only, they're never written to
+//! the log file.
+//!
+//! The ceremony transcript, if any, is the record of what the operators saw
+//! and did: the prompts they answered and what they confirmed. It's meant to
+//! be published so it only gets the records w/ `TRANSCRIPT_TARGET` as the
+//! target. These must never include shares, passwords or key material.
+
+use anyhow::Result;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use time::OffsetDateTime;
+
+/// Target for log records that may contain secret material, e.g.
+/// `debug!(target: SECRET_TARGET, "wrap key: {}", mode.redact(&key))`.
+pub const SECRET_TARGET: &str = "oks_util::secret";
+
+/// Target for log records that go in the ceremony transcript, e.g.
+/// `debug!(target: TRANSCRIPT_TARGET, "key custodian 2 recorded their share")`.
+pub const TRANSCRIPT_TARGET: &str = "oks_util::transcript";
+
+/// Level of the records written to the log file.
+pub const FILE_LEVEL: LevelFilter = LevelFilter::Debug;
+
+/// A logger that writes each record to the console logger and, if it's at
+/// or below `FILE_LEVEL` and may not contain secrets, to the log file. Records
+/// for the ceremony transcript are also written to the transcript.
+pub struct TeeLogger {
+    console: env_logger::Logger,
+    file: Option<Mutex<File>>,
+    transcript: Option<Mutex<File>>,
+}
+
+impl TeeLogger {
+    pub fn new(
+        console: env_logger::Logger,
+        file: Option<File>,
+        transcript: Option<File>,
+    ) -> Self {
+        TeeLogger {
+            console,
+            file: file.map(Mutex::new),
+            transcript: transcript.map(Mutex::new),
+        }
+    }
+
+    fn to_file(&self, metadata: &Metadata) -> bool {
+        self.file.is_some()
+            && metadata.level() <= FILE_LEVEL
+            && metadata.target() != SECRET_TARGET
+    }
+
+    fn to_transcript(&self, metadata: &Metadata) -> bool {
+        self.transcript.is_some() && metadata.target() == TRANSCRIPT_TARGET
+    }
+
+    /// The most verbose level any of the loggers wants.
+    pub fn max_level(&self) -> LevelFilter {
+        if self.file.is_some() || self.transcript.is_some() {
+            self.console.filter().max(FILE_LEVEL)
+        } else {
+            self.console.filter()
+        }
+    }
+}
+
+// append a line to a log file: a failed write can't be logged, and mustn't
+// abort the ceremony half way through
+fn append(file: &Mutex<File>, line: std::fmt::Arguments) {
+    if let Ok(mut file) = file.lock() {
+        let _ = writeln!(
+            file,
+            "{} {}",
+            crate::utc_timestamp(OffsetDateTime::now_utc()),
+            line
+        );
+    }
+}
+
+impl Log for TeeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.console.enabled(metadata)
+            || self.to_file(metadata)
+            || self.to_transcript(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.console.matches(record) {
+            self.console.log(record);
+        }
+        if let Some(file) = &self.file {
+            if self.to_file(record.metadata()) {
+                append(
+                    file,
+                    format_args!(
+                        "{:<5} {}: {}",
+                        record.level(),
+                        record.target(),
+                        record.args()
+                    ),
+                );
+            }
+        }
+        if let Some(transcript) = &self.transcript {
+            if self.to_transcript(record.metadata()) {
+                append(transcript, *record.args());
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        for file in self.file.iter().chain(&self.transcript) {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+/// The files written by the logger set up by `init`.
+#[derive(Debug, Default)]
+pub struct LogFiles {
+    pub log: Option<PathBuf>,
+    pub transcript: Option<PathBuf>,
+}
+
+// create a new file in `dir` named w/ the prefix & the current UTC time
+fn create(dir: &Path, prefix: &str, ext: &str) -> Result<(File, PathBuf)> {
+    let path = dir.join(format!(
+        "{}-{}.{}",
+        prefix,
+        crate::utc_timestamp(OffsetDateTime::now_utc()),
+        ext
+    ));
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+
+    Ok((file, path))
+}
+
+/// Set up logging to the console at `level`, refined by `RUST_LOG`. If
+/// `log_dir` is provided the records are also written to a new file in
+/// it named for the current UTC time, e.g. `oks-20230301T170500Z.log`.
+/// If `transcript_dir` is provided the ceremony transcript is written to
+/// a new file in it, e.g. `transcript-20230301T170500Z.txt`. The paths of
+/// the files are returned.
+pub fn init(
+    level: LevelFilter,
+    log_dir: Option<&Path>,
+    transcript_dir: Option<&Path>,
+) -> Result<LogFiles> {
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.filter(None, level);
+
+    if log_dir.is_none() && transcript_dir.is_none() {
+        builder.init();
+        return Ok(LogFiles::default());
+    }
+
+    let (file, log) = log_dir
+        .map(|dir| create(dir, "oks", "log"))
+        .transpose()?
+        .unzip();
+    let (transcript, transcript_path) = transcript_dir
+        .map(|dir| create(dir, "transcript", "txt"))
+        .transpose()?
+        .unzip();
+    let logger = TeeLogger::new(builder.build(), file, transcript);
+    log::set_max_level(logger.max_level());
+    log::set_boxed_logger(Box::new(logger))?;
+
+    Ok(LogFiles {
+        log,
+        transcript: transcript_path,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_tee_logger() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("test.log");
+        let console = env_logger::Builder::new()
+            .filter(None, LevelFilter::Info)
+            .build();
+        let logger = TeeLogger::new(console, Some(File::create(&path)?), None);
+        assert_eq!(logger.max_level(), LevelFilter::Debug);
+
+        let log = |level, target, msg: &str| {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(format_args!("{}", msg))
+                    .build(),
+            )
+        };
+        log(Level::Info, "oks_util", "starting ceremony");
+        log(Level::Debug, "oks_util", "more detail");
+        log(Level::Trace, "oks_util", "too much detail");
+        log(Level::Debug, SECRET_TARGET, "wrap key: 0123");
+        logger.flush();
+
+        // debug records go to the file even though the console is at info,
+        // secrets never do
+        let logged = fs::read_to_string(&path)?;
+        let lines: Vec<&str> = logged.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" INFO  oks_util: starting ceremony"));
+        assert!(lines[1].ends_with(" DEBUG oks_util: more detail"));
+        assert!(!logged.contains("0123"));
+
+        let secret = Metadata::builder()
+            .level(Level::Debug)
+            .target(SECRET_TARGET)
+            .build();
+        assert!(!logger.enabled(&secret));
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcript() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("transcript.txt");
+        let console = env_logger::Builder::new()
+            .filter(None, LevelFilter::Warn)
+            .build();
+        let logger = TeeLogger::new(console, None, Some(File::create(&path)?));
+
+        let log = |level, target, msg: &str| {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(format_args!("{}", msg))
+                    .build(),
+            )
+        };
+        log(Level::Info, "oks_util", "starting ceremony");
+        log(
+            Level::Debug,
+            TRANSCRIPT_TARGET,
+            "key custodian 1 recorded their share",
+        );
+        log(Level::Debug, SECRET_TARGET, "wrap key: 0123");
+        logger.flush();
+
+        // only the transcript records are written, w/o the level or target
+        let transcript = fs::read_to_string(&path)?;
+        let lines: Vec<&str> = transcript.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with("Z key custodian 1 recorded their share"));
+
+        let record = Metadata::builder()
+            .level(Level::Debug)
+            .target(TRANSCRIPT_TARGET)
+            .build();
+        assert!(logger.enabled(&record));
+        assert_eq!(logger.max_level(), LevelFilter::Debug);
+        Ok(())
+    }
+}
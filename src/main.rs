@@ -2,12 +2,34 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
-use env_logger::Builder;
-use log::LevelFilter;
-use std::path::PathBuf;
-use yubihsm::{Client, Connector, Credentials, UsbConfig};
+use log::{info, warn, LevelFilter};
+use oks_util::{
+    backup::BackupFormat,
+    config::{
+        self, CeremonyMode, KeySpec, OksWrapAlgorithm, OutputLayout,
+        SummaryFormat,
+    },
+    escrow::RecipientKey,
+    logging,
+    report::{CeremonyReport, Output, Report},
+    shares::{ShareBackend, ShareEncoding},
+    CaSerial, CeremonyOptions, Confirm, Pkcs11Module, ShareDisplay, SharePrint,
+    WrapKeyParams, WrapKeyUpdate,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use time::OffsetDateTime;
+use yubihsm::{
+    device::SerialNumber, object::Type, Client, Connector, Credentials, Domain,
+    UsbConfig,
+};
+use zeroize::Zeroizing;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -21,6 +43,39 @@ struct Args {
     #[clap(long, env, default_value = "oks-publish")]
     public: PathBuf,
 
+    /// Write public data for this run to its own directory under the
+    /// public directory, named for the ceremony and the current UTC time
+    #[clap(long, env)]
+    ceremony: Option<String>,
+
+    /// Write public data to the directory for a ceremony id under the
+    /// public directory, e.g. for the next step of a ceremony. The
+    /// directory is created if it doesn't exist, one that exists requires
+    /// --reuse-ceremony
+    #[clap(long, env, conflicts_with = "ceremony")]
+    ceremony_id: Option<String>,
+
+    /// Reuse the directory for a ceremony that exists
+    #[clap(long)]
+    reuse_ceremony: bool,
+
+    /// Also write the log, w/ debug detail, to a file named for the
+    /// current time in the directory for public data. Secrets are never
+    /// written to it
+    #[clap(long, env)]
+    log_file: bool,
+
+    /// Also write a transcript of the ceremony to a file named for the
+    /// current time in the directory for public data: the prompts the
+    /// operators answered and what they confirmed. Shares, passwords and
+    /// key material are never written to it, it's safe to publish
+    #[clap(long, env)]
+    transcript: bool,
+
+    /// Ceremony mode: safety checks are relaxed in "dev" ceremonies
+    #[clap(long, env, default_value = "prod")]
+    mode: CeremonyMode,
+
     /// subcommands
     #[command(subcommand)]
     command: Command,
@@ -37,19 +92,154 @@ enum Command {
         #[clap(long, env, default_value = "oks-state")]
         state: PathBuf,
 
+        /// Path to the YubiHSM pkcs11 module used by openssl, defaults to
+        /// the first of the usual install locations for the platform that
+        /// exists
+        #[clap(long, env)]
+        pkcs11_module: Option<PathBuf>,
+
+        /// Enable debug output from the pkcs11 module, this is verbose and
+        /// is meant for troubleshooting only
+        #[clap(long, env)]
+        pkcs11_debug: bool,
+
         #[command(subcommand)]
         command: CaCommand,
     },
     Hsm {
+        /// Label for the wrap key created by initialize and restore
+        #[clap(long, env, default_value = oks_util::DEFAULT_WRAP_LABEL)]
+        wrap_label: String,
+
+        /// Algorithm of the wrap key created by initialize and restore:
+        /// "aes128-ccm", "aes192-ccm" or "aes256-ccm". The YubiHSM must
+        /// support it. Restore from a manifest uses the algorithm from the
+        /// manifest
+        #[clap(long, env, default_value = "aes256-ccm")]
+        wrap_algorithm: OksWrapAlgorithm,
+
+        /// Abort the ceremony, clearing the screen, if an interactive
+        /// prompt gets no response within this many seconds
+        #[clap(long, env)]
+        prompt_timeout: Option<u64>,
+
+        /// Format for keys exported under wrap: "json" or "pem"
+        #[clap(long, env, default_value = "json")]
+        backup_format: BackupFormat,
+
+        /// Id of the auth key to authenticate with, defaults to the id of
+        /// the auth key created by initialize
+        #[clap(long, env)]
+        auth_id: Option<u16>,
+
+        /// Comma separated domains (1 - 16) of the auth key created by
+        /// initialize, defaults to all domains. Objects created or restored
+        /// must be in a subset of these
+        #[clap(long, env, value_delimiter = ',')]
+        auth_domains: Vec<usize>,
+
+        /// Secret sharing implementation used to split the wrap key:
+        /// "rusty-secrets" or "sharks". Initialize defaults to
+        /// rusty-secrets, refresh-shares to the backend of the old shares
+        #[clap(long, env)]
+        share_backend: Option<ShareBackend>,
+
+        /// Encoding the shares are displayed in: "plain" or "base32". Shares
+        /// in any encoding are accepted when they're entered
+        #[clap(long, env, default_value = "plain")]
+        share_encoding: ShareEncoding,
+
+        /// Don't prompt for confirmation of irreversible steps like deleting
+        /// the default auth key, for non-interactive use only
+        #[clap(long)]
+        assume_yes: bool,
+
+        /// Write a JSON description of the outcome (objects created, files
+        /// written, status) to this file, or to stdout if "-". Stdout can't
+        /// be used by the commands that prompt or print to it
+        #[clap(long, env)]
+        json: Option<Output>,
+
+        /// Serial number of the YubiHSM to use. When several are connected
+        /// this one is opened, and the command is aborted if the YubiHSM
+        /// reports a different serial number
+        #[clap(long, env)]
+        serial: Option<SerialNumber>,
+
         #[command(subcommand)]
         command: HsmCommand,
     },
+    /// Check that secret sharing round trips on this build before a
+    /// ceremony, no YubiHSM required
+    Selftest,
+    /// Recover the wrap key from the shares in a file and print its
+    /// fingerprint, to compare w/ the one recorded at initialization, no
+    /// YubiHSM required
+    RecoverFingerprint {
+        /// File holding the shares, one per line
+        #[clap(long)]
+        shares_file: PathBuf,
+
+        /// Wrap key manifest written by initialize, the shares are checked
+        /// against its share digests & threshold and the fingerprint
+        /// against its fingerprint
+        #[clap(long, env)]
+        manifest: Option<PathBuf>,
+    },
+    /// Print the label, common name, purpose and algorithm of each key
+    /// spec for review against the RFD, no YubiHSM required
+    SummarizeKeySpecs {
+        /// Key spec file or a directory of them
+        #[clap(long, env, default_value = "data")]
+        key_spec: PathBuf,
+
+        /// Output format: "csv" or "json"
+        #[clap(long, default_value = "csv")]
+        format: SummaryFormat,
+    },
+    /// Parse each key spec, reporting every one that's malformed, no
+    /// YubiHSM required
+    CheckKeySpecs {
+        /// Key spec file or a directory of them
+        #[clap(long, env, default_value = "data")]
+        key_spec: PathBuf,
+    },
+    /// Check that each key spec uses the id registered for its label, no
+    /// YubiHSM required
+    CheckKeyIds {
+        /// Key spec file or a directory of them
+        #[clap(long, env, default_value = "data")]
+        key_spec: PathBuf,
+
+        /// JSON file mapping each key label to its id
+        #[clap(long, env, default_value = "data/registry/key-ids.json")]
+        id_registry: PathBuf,
+    },
+    /// Overwrite and remove the key share PDFs written by hsm initialize
+    /// --print-shares once they've been printed, no YubiHSM required. This
+    /// is best effort, the media must still be wiped or destroyed
+    WipeShares {
+        /// Directory the share-<n>.pdf files were written to
+        #[clap(long)]
+        dir: PathBuf,
+    },
 }
 
 #[derive(Subcommand, Debug, PartialEq)]
 enum CaCommand {
     /// Initialize an OpenSSL CA for the given key.
-    Initialize,
+    Initialize {
+        /// Serial number, in hex, of the first cert issued by the CA
+        #[clap(long, env, default_value = oks_util::INITIAL_CA_SERIAL)]
+        initial_serial: CaSerial,
+
+        /// openssl.cnf template to use in place of the built-in one, it
+        /// must have the {key}, {hash}, {module} & {connector} placeholders,
+        /// the section for the purpose of the key spec & [ policy_match ],
+        /// see cnf-template for the built-in one
+        #[clap(long, env)]
+        openssl_cnf: Option<PathBuf>,
+    },
 
     /// Use the CA associated with the provided key spec to sign the
     /// provided CSR.
@@ -57,19 +247,622 @@ enum CaCommand {
         #[clap(long, env, default_value = "data/p384-sha384.csr.pem")]
         csr: PathBuf,
     },
+
+    /// Check that the CA associated with the provided key spec is
+    /// consistent with the key spec.
+    Verify,
+
+    /// Check that the certs of the CAs in the CA state directory, or in
+    /// the provided directory of CA directories, link up.
+    VerifyTree {
+        /// Directory of CA directories, defaults to the CA state directory
+        #[clap(long, env)]
+        root: Option<PathBuf>,
+    },
+
+    /// Print the certificate(s) of the CA associated with the provided key
+    /// spec, or those in the provided PEM file, as text.
+    Show {
+        /// PEM file holding a cert or cert chain, defaults to the CA cert
+        #[clap(long, env)]
+        cert: Option<PathBuf>,
+    },
+
+    /// Print the built-in openssl.cnf template, a starting point for the
+    /// template passed to initialize w/ --openssl-cnf.
+    CnfTemplate,
 }
 
 #[derive(Subcommand, Debug, PartialEq)]
 enum HsmCommand {
     /// Generate keys in YubiHSM from specification.
     Generate {
+        /// Key spec file, a file holding a JSON array of key specs, or a
+        /// directory of key spec files
         #[clap(long, env, default_value = "data/key-request-rsa4k.json")]
         key_spec: PathBuf,
+
+        /// Require each key spec file to be named <label>.keyspec.json
+        #[clap(long)]
+        check_names: bool,
+
+        /// Comma separated domains (1 - 16) to create the keys in, in place
+        /// of those from the key specs, e.g. a scratch domain to rehearse a
+        /// ceremony. Keys can't be moved out of it later
+        #[clap(long, env, value_delimiter = ',')]
+        domains: Vec<usize>,
+
+        /// Resume an interrupted batch w/ the same key specs and output
+        /// directory: keys that exist and match their spec are finished or
+        /// skipped, the rest are generated
+        #[clap(long, conflicts_with = "domains")]
+        resume: bool,
+
+        /// Where the backup and attestation for each key are written:
+        /// "flat" for all in the directory for public data, or "per-key"
+        /// for each key's in a subdirectory named for its label, like the
+        /// CA directories
+        #[clap(long, default_value = "flat")]
+        layout: OutputLayout,
+
+        /// Refuse to generate keys unless each key spec file has a valid
+        /// signature, made w/ sign-keyspecs, from the key in this cert
+        #[clap(long, env)]
+        verify_with: Option<PathBuf>,
     },
     /// Initialize the YubiHSM for use in the OKS.
-    Initialize,
+    Initialize {
+        /// Display all key shares at once w/o clearing the screen between
+        /// them. INSECURE: only allowed in dev ceremonies
+        #[clap(long)]
+        review_shares: bool,
+
+        /// Offer each key custodian to encrypt their share under a
+        /// passphrase, the passphrase is required to restore from the share
+        #[clap(long)]
+        share_passphrases: bool,
+
+        /// Use the wrap key from this file, hex encoded, instead of one from
+        /// the YubiHSM PRNG, e.g. to share a wrap key between YubiHSMs
+        #[clap(long)]
+        wrap_key_file: Option<PathBuf>,
+
+        /// Display the instructions in this file to the key custodians
+        /// before the shares are recorded, in place of the default ones.
+        /// {shares} and {threshold} are replaced w/ the number of shares and
+        /// the number required to reconstruct the wrap key
+        #[clap(long, env)]
+        share_warning: Option<PathBuf>,
+
+        /// Display the key shares in a full screen terminal UI that shows
+        /// the progress of the ceremony
+        #[cfg(feature = "tui")]
+        #[clap(
+            long,
+            conflicts_with_all = ["review_shares", "print_shares", "escrow_to"]
+        )]
+        tui: bool,
+
+        /// Write each key share to its own PDF page, share-<n>.pdf in
+        /// --share-dir, for printing instead of displaying them. Each page
+        /// has the id of the ceremony, so this requires --ceremony or
+        /// --ceremony-id. ONLY for air gapped printing: print the files
+        /// immediately and then wipe the media they were written to
+        #[clap(long, conflicts_with = "review_shares", requires = "share_dir")]
+        print_shares: bool,
+
+        /// Directory on removable media the key share PDFs are written to,
+        /// it can't be in the directory for public data
+        #[clap(long, requires = "print_shares")]
+        share_dir: Option<PathBuf>,
+
+        /// Also print each key share as a QR code on its PDF page
+        #[clap(long, requires = "print_shares")]
+        share_qr: bool,
+
+        /// Comma separated PEM files w/ the RSA public key of each key
+        /// custodian's smartcard, one per share in order. Each share is
+        /// encrypted to its custodian's key and written to share-<n>.enc in
+        /// the directory for public data instead of being displayed
+        #[clap(
+            long,
+            value_delimiter = ',',
+            conflicts_with_all = ["review_shares", "print_shares"]
+        )]
+        escrow_to: Vec<PathBuf>,
+    },
     /// Restore a previously split aes256-ccm-wrap key
-    Restore,
+    Restore {
+        /// Replace an existing wrap key with the restored one
+        #[clap(long)]
+        force: bool,
+
+        /// Wrap key manifest written by initialize, the wrap key is
+        /// restored with the parameters from this file
+        #[clap(long, env)]
+        manifest: Option<PathBuf>,
+
+        /// Read the shares from this file, one per line, in place of
+        /// prompting for them, e.g. to test the restore path w/o a TTY
+        #[clap(long)]
+        shares_file: Option<PathBuf>,
+    },
+    /// Replace the admin auth key with one derived from a new password
+    RotateAuth,
+    /// Export the auth key under the wrap key again, e.g. when admin.json
+    /// is lost. Nothing in the YubiHSM is changed.
+    BackupAuth,
+    /// Export every object that's exportable under wrap
+    BackupAll,
+    /// Export an object under another wrap key, e.g. when the backup key
+    /// is rotated
+    Rewrap {
+        /// Id of the object.
+        id: u16,
+
+        /// Type of the object, e.g. asymmetric-key or wrap-key.
+        #[clap(
+            long,
+            default_value = "asymmetric-key",
+            value_parser = parse_object_type
+        )]
+        object_type: Type,
+
+        /// Id of the wrap key to export the object under
+        #[clap(long)]
+        dst_wrap_id: u16,
+
+        /// Backup of the object made under the wrap key created by
+        /// initialize, imported if the object isn't in the YubiHSM
+        #[clap(long)]
+        backup: Option<PathBuf>,
+    },
+    /// Split the wrap key into a new set of shares. The old shares remain
+    /// valid.
+    RefreshShares {
+        /// Wrap key manifest written by initialize, updated to describe the
+        /// new shares
+        #[clap(long, env)]
+        manifest: PathBuf,
+
+        /// Number of new shares, defaults to the number in the manifest
+        #[clap(long)]
+        shares: Option<u8>,
+
+        /// Number of new shares required to reconstruct the wrap key,
+        /// defaults to the threshold in the manifest
+        #[clap(long)]
+        threshold: Option<u8>,
+
+        /// Display the instructions in this file to the key custodians
+        /// before the new shares are recorded, in place of the default
+        /// ones, see initialize
+        #[clap(long, env)]
+        share_warning: Option<PathBuf>,
+    },
+    /// Change the domains and / or capabilities of the wrap key w/o
+    /// changing the key. The wrap key is reconstituted from the key shares.
+    ReconfigureWrap {
+        /// Wrap key manifest written by initialize, updated to describe the
+        /// new parameters
+        #[clap(long, env)]
+        manifest: PathBuf,
+
+        /// An existing backup that the wrap key must decrypt before it's
+        /// replaced
+        #[clap(long)]
+        backup: PathBuf,
+
+        /// New domains (1 - 16) of the wrap key
+        #[clap(long, value_delimiter = ',')]
+        domains: Vec<usize>,
+
+        /// New capabilities of the wrap key
+        #[clap(long, value_delimiter = ',')]
+        capabilities: Vec<String>,
+
+        /// New delegated capabilities of the wrap key
+        #[clap(long, value_delimiter = ',')]
+        delegated_capabilities: Vec<String>,
+    },
+    /// Migrate the wrap key and every backup made under it to a new wrap
+    /// key w/ another algorithm. The old wrap key is reconstituted from the
+    /// key shares and the new one is split into new shares. The new backups
+    /// and manifest are written to the directory for public data, the old
+    /// ones remain valid.
+    MigrateWrap {
+        /// Wrap key manifest written by initialize for the old wrap key
+        #[clap(long, env)]
+        manifest: PathBuf,
+
+        /// Directory of the backups made under the old wrap key
+        #[clap(long, env)]
+        backup_dir: PathBuf,
+
+        /// Algorithm of the new wrap key: "aes128-ccm", "aes192-ccm" or
+        /// "aes256-ccm"
+        #[clap(long)]
+        to: OksWrapAlgorithm,
+
+        /// Display the instructions in this file to the key custodians
+        /// before the shares of the new wrap key are recorded, in place of
+        /// the default ones, see initialize
+        #[clap(long, env)]
+        share_warning: Option<PathBuf>,
+    },
+    /// Show the free and used storage in the YubiHSM.
+    Storage,
+    /// Show the versions of this tool, the yubihsm crate and the
+    /// yubihsm-connector, and the firmware version and serial number of
+    /// the YubiHSM.
+    Version,
+    /// Time cheap operations and a key generation w/ the YubiHSM to check
+    /// that it's responsive before a ceremony. The key is deleted.
+    Benchmark {
+        /// How many times each cheap operation is run
+        #[clap(long, default_value_t = 100)]
+        runs: usize,
+    },
+    /// Show the info for a single object in the YubiHSM.
+    ShowObject {
+        /// Id of the object.
+        id: u16,
+
+        /// Type of the object, e.g. asymmetric-key or wrap-key.
+        #[clap(
+            long,
+            default_value = "asymmetric-key",
+            value_parser = parse_object_type
+        )]
+        object_type: Type,
+    },
+    /// Sign a report written w/ --json, along w/ the digests of the files
+    /// it lists, w/ an ECDSA key in the YubiHSM.
+    SignReport {
+        /// Report written by a previous command w/ --json
+        #[clap(long, env)]
+        report: PathBuf,
+
+        /// Id of the key used to sign the report
+        #[clap(long)]
+        key_id: u16,
+
+        /// Where the signed report is written, the signature is written
+        /// alongside it w/ a .sig extension
+        #[clap(long)]
+        out: PathBuf,
+    },
+    /// Sign each key spec file w/ an ECDSA key in the YubiHSM so that
+    /// generate can check them w/ --verify-with.
+    SignKeyspecs {
+        /// Key spec file or directory of key spec files, the signature for
+        /// each is written alongside it w/ a .sig extension
+        #[clap(long, env)]
+        key_spec: PathBuf,
+
+        /// Id of the key used to sign the key specs
+        #[clap(long)]
+        key_id: u16,
+    },
+    /// Put the same wrap key into several YubiHSMs in their default state
+    /// and personalize each w/ its own auth key, so that any of them can
+    /// restore the others' backups.
+    Provision {
+        /// Comma separated serial numbers of the YubiHSMs to provision
+        #[clap(long, required = true, value_delimiter = ',')]
+        serials: Vec<String>,
+
+        /// Use the wrap key from this file, hex encoded
+        #[clap(long)]
+        wrap_key_file: Option<PathBuf>,
+
+        /// Recover the wrap key from the key shares, checked against the
+        /// fingerprint in this manifest
+        #[clap(long, required_unless_present = "wrap_key_file")]
+        manifest: Option<PathBuf>,
+    },
+    /// Check that each backup in a directory still imports under the wrap
+    /// key. Run this on a YubiHSM w/o the objects backed up.
+    VerifyBackups {
+        /// Directory holding the *.wrap.json / *.wrap.pem backups
+        #[clap(long)]
+        backup_dir: PathBuf,
+    },
+    /// Check that the keys in the YubiHSM match the key spec(s) they were
+    /// generated from.
+    VerifySpec {
+        /// Key spec file or a file holding a JSON array of key specs
+        #[clap(long, env, default_value = "data/key-request-rsa4k.json")]
+        key_spec: PathBuf,
+    },
+    /// Write the public key of each key generated from the key spec(s) to
+    /// its own PEM file and to a single bundle for the relying parties.
+    ExportPubkeys {
+        /// Key spec file, a file holding a JSON array of key specs, or a
+        /// directory of key spec files
+        #[clap(long, env, default_value = "data/key-request-rsa4k.json")]
+        key_spec: PathBuf,
+    },
+    /// Write a key spec for each asymmetric key in the YubiHSM to the
+    /// directory for public data, e.g. to bootstrap the specs for a
+    /// YubiHSM provisioned before this tool. Fields the YubiHSM doesn't
+    /// record are left as TODO placeholders to fill in by hand.
+    ExportKeyspecs,
+    /// Compare the objects in the YubiHSM w/ those in another, e.g. to
+    /// check that a backup YubiHSM mirrors the primary. The same auth key
+    /// id is used for both, the operator is prompted for its password on
+    /// the other YubiHSM.
+    Compare {
+        /// Serial number of the other YubiHSM
+        other: String,
+    },
+}
+
+impl Command {
+    // whether the command writes to the directory for public data
+    fn writes_public(&self) -> bool {
+        match self {
+            Command::Ca { command, .. } => matches!(
+                command,
+                CaCommand::Initialize { .. } | CaCommand::Sign { .. }
+            ),
+            Command::Hsm { command, .. } => matches!(
+                command,
+                HsmCommand::Generate { .. }
+                    | HsmCommand::Initialize { .. }
+                    | HsmCommand::RotateAuth
+                    | HsmCommand::BackupAuth
+                    | HsmCommand::BackupAll
+                    | HsmCommand::Rewrap { .. }
+                    | HsmCommand::MigrateWrap { .. }
+                    | HsmCommand::Provision { .. }
+                    | HsmCommand::ExportKeyspecs
+                    | HsmCommand::ExportPubkeys { .. }
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl HsmCommand {
+    // whether the command prompts the operator, displays key shares or
+    // prints its results on stdout, a report written there w/ --json -
+    // would be mixed in w/ them
+    fn uses_stdout(&self) -> bool {
+        match self {
+            HsmCommand::Restore { shares_file, .. } => shares_file.is_none(),
+            HsmCommand::Initialize { .. }
+            | HsmCommand::Provision { .. }
+            | HsmCommand::RefreshShares { .. }
+            | HsmCommand::ReconfigureWrap { .. }
+            | HsmCommand::MigrateWrap { .. }
+            | HsmCommand::Benchmark { .. }
+            | HsmCommand::Version
+            | HsmCommand::Storage
+            | HsmCommand::ShowObject { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+// compare each spec in the file w/ the key in the YubiHSM, failing if any
+// differ
+fn verify_specs(client: &Client, key_spec: &Path) -> Result<Report> {
+    let specs = KeySpec::many_from_str(&fs::read_to_string(key_spec)?)?;
+    let mut mismatches = 0;
+    for spec in &specs {
+        let report = oks_util::verify_spec(client, spec)?;
+        if report.is_empty() {
+            info!("key {} matches its spec", spec.label);
+        }
+        for mismatch in &report {
+            warn!("key {}: {}", spec.label, mismatch);
+        }
+        mismatches += report.len();
+    }
+    if mismatches > 0 {
+        bail!(
+            "found {} mismatches between {} and the YubiHSM",
+            mismatches,
+            key_spec.display()
+        );
+    }
+
+    Ok(Report::default())
+}
+
+// connect to the other YubiHSM and compare its objects w/ those in the
+// first, failing if they differ
+fn compare(client: &Client, other: &str, auth_id: u16) -> Result<Report> {
+    let number = SerialNumber::from_str(other)?;
+    let config = UsbConfig {
+        serial: Some(number),
+        timeout_ms: TIMEOUT_MS,
+    };
+    let passwd = Zeroizing::new(rpassword::prompt_password(format!(
+        "Enter password for YubiHSM {}: ",
+        other
+    ))?);
+    let credentials = Credentials::from_password(auth_id, passwd.as_bytes());
+    let other_client =
+        Client::open(Connector::usb(&config), credentials, true)?;
+    oks_util::check_serial(&other_client, number)?;
+
+    let diffs = oks_util::compare_hsms(client, &other_client)?;
+    if diffs.is_empty() {
+        info!("the objects in YubiHSM {} match", other);
+    }
+    for diff in &diffs {
+        warn!("{}", diff);
+    }
+    if !diffs.is_empty() {
+        bail!("found {} differences w/ YubiHSM {}", diffs.len(), other);
+    }
+
+    Ok(Report::default())
+}
+
+// import each backup in the directory, failing if any don't
+fn verify_backups(
+    client: &Client,
+    wrap_id: u16,
+    backup_dir: &Path,
+) -> Result<Report> {
+    let failures = oks_util::verify_backups(client, wrap_id, backup_dir)?;
+    for failure in &failures {
+        warn!("backup failed to import: {}", failure);
+    }
+    if !failures.is_empty() {
+        bail!(
+            "{} backups in {} failed to import",
+            failures.len(),
+            backup_dir.display()
+        );
+    }
+
+    Ok(Report::default())
+}
+
+// connect to each YubiHSM w/ the default auth key and provision it, failing
+// if any of them fail
+fn provision(
+    serials: &[String],
+    wrap_key: Zeroizing<Vec<u8>>,
+    wrap: &WrapKeyParams,
+    auth_domains: Domain,
+    out_dir: &Path,
+    opts: &CeremonyOptions,
+) -> Result<Report> {
+    let mut clients = Vec::new();
+    for serial in serials {
+        let number = SerialNumber::from_str(serial)?;
+        let config = UsbConfig {
+            serial: Some(number),
+            timeout_ms: TIMEOUT_MS,
+        };
+        let client = Client::open(
+            Connector::usb(&config),
+            Credentials::default(),
+            true,
+        )?;
+        oks_util::check_serial(&client, number)?;
+        clients.push((serial.clone(), client));
+    }
+
+    let statuses = oks_util::provision(
+        &clients,
+        wrap_key,
+        wrap,
+        auth_domains,
+        out_dir,
+        opts,
+    )?;
+    let mut report = Report::default();
+    let mut failed = 0;
+    for status in statuses {
+        match status.result {
+            Ok(hsm_report) => {
+                info!("{}: ok", status.name);
+                report.merge(hsm_report);
+            }
+            Err(e) => {
+                warn!("{}: {:#}", status.name, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        bail!(
+            "failed to provision {} of {} YubiHSMs",
+            failed,
+            serials.len()
+        );
+    }
+
+    Ok(report)
+}
+
+// clap needs an error that can be displayed, FromStr for Type returns ()
+fn parse_object_type(s: &str) -> Result<Type, String> {
+    Type::from_str(s).map_err(|_| format!("unknown object type: {}", s))
+}
+
+// read the shares from the file at `path`, one per line
+fn read_shares(path: &Path) -> Result<Vec<String>> {
+    let shares = Zeroizing::new(fs::read_to_string(path)?);
+    Ok(shares.lines().map(String::from).collect())
+}
+
+// read the instructions for the key custodians from the file at `path`, or
+// use the default ones
+fn read_share_warning(path: Option<PathBuf>, default: &str) -> Result<String> {
+    match path {
+        Some(path) => fs::read_to_string(&path).with_context(|| {
+            format!("Failed to read share warning: {}", path.display())
+        }),
+        None => Ok(default.to_string()),
+    }
+}
+
+// read a hex encoded wrap key from the file at `path`
+fn read_wrap_key(path: &Path) -> Result<Zeroizing<Vec<u8>>> {
+    let hex = Zeroizing::new(fs::read_to_string(path)?);
+    Ok(Zeroizing::new(hex::decode(hex.trim())?))
+}
+
+// how the key shares are displayed by initialize, the recipient keys for
+// escrow are checked here before anything is done w/ the YubiHSM
+fn share_display(
+    review_shares: bool,
+    print: Option<SharePrint>,
+    escrow_to: &[PathBuf],
+    #[cfg(feature = "tui")] tui: bool,
+) -> Result<ShareDisplay> {
+    if let Some(print) = print {
+        return Ok(ShareDisplay::Pdf(print));
+    }
+    if !escrow_to.is_empty() {
+        let recipients = escrow_to
+            .iter()
+            .map(|path| RecipientKey::load(path))
+            .collect::<Result<_>>()?;
+        return Ok(ShareDisplay::Escrow(recipients));
+    }
+    #[cfg(feature = "tui")]
+    if tui {
+        return Ok(ShareDisplay::Tui);
+    }
+    if review_shares {
+        Ok(ShareDisplay::All)
+    } else {
+        Ok(ShareDisplay::OneAtATime)
+    }
+}
+
+// the wrap key parameters to change, empty lists are left unchanged
+fn wrap_key_update(
+    domains: &[usize],
+    capabilities: &[String],
+    delegated_capabilities: &[String],
+) -> Result<WrapKeyUpdate> {
+    let capabilities_from_names = |names: &[String]| {
+        if names.is_empty() {
+            Ok(None)
+        } else {
+            config::capabilities_from_names(names).map(Some)
+        }
+    };
+    Ok(WrapKeyUpdate {
+        domains: if domains.is_empty() {
+            None
+        } else {
+            Some(config::domains_from_numbers(domains)?)
+        },
+        capabilities: capabilities_from_names(capabilities)?,
+        delegated_capabilities: capabilities_from_names(
+            delegated_capabilities,
+        )?,
+    })
 }
 
 // 2 minute to support RSA4K key generation
@@ -78,65 +871,666 @@ const TIMEOUT_MS: u64 = 120000;
 fn main() -> Result<()> {
     let args = Args::parse();
 
-    let mut builder = Builder::from_default_env();
+    // the log file goes w/ the public data so that's sorted out first, the
+    // directory is only created if something is written to it
+    let ceremony = match (args.ceremony, args.ceremony_id) {
+        (Some(name), _) => {
+            Some(oks_util::ceremony_id(&name, OffsetDateTime::now_utc())?)
+        }
+        (None, id) => id,
+    };
+    let public = match &ceremony {
+        Some(id) => oks_util::ceremony_dir(&args.public, id)?,
+        None => args.public.clone(),
+    };
+    let log_to_public = args.log_file || args.transcript;
+    let reused = match &ceremony {
+        Some(_) if log_to_public || args.command.writes_public() => {
+            Some(oks_util::create_ceremony_dir(&public, args.reuse_ceremony)?)
+        }
+        _ => None,
+    };
 
     let level = if args.verbose {
         LevelFilter::Debug
     } else {
         LevelFilter::Info
     };
-    builder.filter(None, level).init();
+    if log_to_public {
+        fs::create_dir_all(&public)?;
+    }
+    let log_files = logging::init(
+        level,
+        args.log_file.then_some(public.as_path()),
+        args.transcript.then_some(public.as_path()),
+    )?;
+    match reused {
+        Some(false) => {
+            info!("created ceremony directory: {}", public.display())
+        }
+        Some(true) => warn!("reusing ceremony directory: {}", public.display()),
+        None => (),
+    }
+    if let Some(log_file) = log_files.log {
+        info!("writing log to: {}", log_file.display());
+    }
+    if let Some(transcript) = log_files.transcript {
+        info!("writing transcript to: {}", transcript.display());
+    }
+
+    if args.mode != CeremonyMode::Prod {
+        warn!(
+            "running {} ceremony: production safety checks are relaxed",
+            args.mode
+        );
+    }
 
     match args.command {
         Command::Ca {
             command,
             key_spec,
             state,
+            pkcs11_module,
+            pkcs11_debug,
         } => match command {
-            CaCommand::Initialize => {
-                oks_util::ca_init(&key_spec, &state, &args.public)
-            }
+            CaCommand::Initialize {
+                initial_serial,
+                openssl_cnf,
+            } => oks_util::ca_init(
+                &key_spec,
+                &state,
+                &public,
+                args.mode,
+                &initial_serial,
+                &Pkcs11Module {
+                    path: pkcs11_module
+                        .unwrap_or_else(oks_util::default_pkcs11_module),
+                    debug: pkcs11_debug,
+                },
+                openssl_cnf.as_deref(),
+            ),
             CaCommand::Sign { csr } => {
-                oks_util::ca_sign(&key_spec, &csr, &state, &args.public)
+                oks_util::ca_sign(&key_spec, &csr, &state, &public, args.mode)
+            }
+            CaCommand::Verify => {
+                let spec = KeySpec::from_str(&fs::read_to_string(key_spec)?)?;
+                let ca_dir = spec.ca_dir(&state);
+                let report = oks_util::verify_ca(&ca_dir)?;
+                if report.is_empty() {
+                    info!("CA in {} is consistent", ca_dir.display());
+                    Ok(())
+                } else {
+                    for inconsistency in &report {
+                        warn!("{}", inconsistency);
+                    }
+                    bail!(
+                        "found {} inconsistencies in CA {}",
+                        report.len(),
+                        ca_dir.display()
+                    );
+                }
+            }
+            CaCommand::VerifyTree { root } => {
+                let root = root.unwrap_or(state);
+                let tree = oks_util::cert::verify_tree(&root)?;
+                print!("{}", tree);
+                if tree.broken.is_empty() {
+                    info!("CA tree in {} is consistent", root.display());
+                    Ok(())
+                } else {
+                    for link in &tree.broken {
+                        warn!("{}", link);
+                    }
+                    bail!(
+                        "found {} broken links in CA tree {}",
+                        tree.broken.len(),
+                        root.display()
+                    );
+                }
+            }
+            CaCommand::Show { cert } => {
+                let cert = match cert {
+                    Some(cert) => cert,
+                    None => {
+                        let spec =
+                            KeySpec::from_str(&fs::read_to_string(key_spec)?)?;
+                        spec.ca_dir(&state).join("ca.cert.pem")
+                    }
+                };
+                oks_util::cert::show_cert(&cert)
+            }
+            CaCommand::CnfTemplate => {
+                print!("{}", oks_util::builtin_cnf_template());
+                Ok(())
             }
         },
-        Command::Hsm { command } => {
+        Command::Hsm {
+            command,
+            wrap_label,
+            wrap_algorithm,
+            prompt_timeout,
+            backup_format,
+            auth_id,
+            auth_domains,
+            share_backend,
+            share_encoding,
+            assume_yes,
+            json,
+            serial,
+        } => {
+            let wrap = WrapKeyParams {
+                algorithm: wrap_algorithm.into(),
+                ..WrapKeyParams::with_label(&wrap_label)?
+            };
+            let auth_domains = if auth_domains.is_empty() {
+                oks_util::AUTH_DOMAINS
+            } else {
+                config::domains_from_numbers(&auth_domains)?
+            };
+            let timeout = prompt_timeout.map(Duration::from_secs);
+            let opts = CeremonyOptions {
+                mode: args.mode,
+                format: backup_format,
+                backend: share_backend.unwrap_or_default(),
+                encoding: share_encoding,
+                timeout,
+                wrap_id: wrap.id,
+                confirm: if assume_yes {
+                    Confirm::Auto
+                } else {
+                    Confirm::Prompt(timeout)
+                },
+                ..Default::default()
+            };
+            if json == Some(Output::Stdout) && command.uses_stdout() {
+                bail!(
+                    "--json - can't be used w/ this command, it prompts or \
+                    prints to stdout: write the report to a file"
+                );
+            }
+
+            // provision connects to each of the YubiHSMs itself
+            if let HsmCommand::Provision {
+                serials,
+                wrap_key_file,
+                manifest,
+            } = &command
+            {
+                let result = (|| {
+                    let wrap_key = match (wrap_key_file, manifest) {
+                        (Some(path), _) => read_wrap_key(path)?,
+                        (None, Some(manifest)) => {
+                            oks_util::recover_wrap_key(manifest, timeout)?
+                        }
+                        (None, None) => unreachable!("required by clap"),
+                    };
+                    provision(
+                        serials,
+                        wrap_key,
+                        &wrap,
+                        auth_domains,
+                        &public,
+                        &opts,
+                    )
+                })();
+                if let Some(json) = json {
+                    json.emit("provision", &result)?;
+                }
+                return result.map(|_| ());
+            }
+
             // For 'initialize' subcommand we assume the YubiHSM is in its
             // default state: auth key id is 1, password is 'password'.
             // Any other HSM subcommand we assume:
             // - the auth id is 2 which is the id of the auth key created
-            //   during initialization
+            //   during initialization, unless overridden w/ --auth-id
             // - the user will be prompted for a password
             let passwd = match command {
-                HsmCommand::Initialize => "password".to_string(),
+                HsmCommand::Initialize { .. } => "password".to_string(),
                 _ => rpassword::prompt_password("Enter YubiHSM Password: ")
                     .unwrap(),
             };
             let auth_id = match command {
-                HsmCommand::Initialize => 1, // default auth key id for YubiHSM
-                _ => 2, // auth key id we create in initialize
+                // default auth key id for YubiHSM
+                HsmCommand::Initialize { .. } => 1,
+                // auth key id we create in initialize
+                _ => auth_id.unwrap_or(2),
             };
 
             let config = UsbConfig {
-                serial: None,
+                serial,
                 timeout_ms: TIMEOUT_MS,
             };
             let connector = Connector::usb(&config);
             // this will only work if the default auth key is still available
-            // the next step in our process must be: replace the default auth key
+            // the next step in our process must be: replace the default auth
+            // key
             let credentials =
                 Credentials::from_password(auth_id, passwd.as_bytes());
             let client = Client::open(connector, credentials, true)?;
+            if let Some(serial) = serial {
+                oks_util::check_serial(&client, serial)?;
+            }
 
-            match command {
-                HsmCommand::Initialize => {
-                    oks_util::initialize(&client, &args.public)
+            let (name, result) = match command {
+                HsmCommand::Initialize {
+                    review_shares,
+                    share_passphrases,
+                    wrap_key_file,
+                    share_warning,
+                    #[cfg(feature = "tui")]
+                    tui,
+                    print_shares,
+                    share_dir,
+                    share_qr,
+                    escrow_to,
+                } => {
+                    let warning = read_share_warning(
+                        share_warning,
+                        oks_util::SHARE_WARNING,
+                    )?;
+                    let print = match (print_shares, share_dir) {
+                        (true, Some(dir)) => {
+                            // the ceremony directory is checked by
+                            // initialize, the rest of the public data here
+                            oks_util::check_share_dir(&dir, &args.public)?;
+                            let ceremony = ceremony.context(
+                                "--print-shares requires --ceremony or \
+                                --ceremony-id, its id is printed on each page",
+                            )?;
+                            Some(SharePrint {
+                                dir,
+                                ceremony,
+                                qr: share_qr,
+                            })
+                        }
+                        _ => None,
+                    };
+                    let display = share_display(
+                        review_shares,
+                        print,
+                        &escrow_to,
+                        #[cfg(feature = "tui")]
+                        tui,
+                    )?;
+                    let opts = CeremonyOptions {
+                        display,
+                        passphrases: share_passphrases,
+                        ..opts
+                    };
+                    let result = match wrap_key_file {
+                        Some(path) => read_wrap_key(&path).and_then(|key| {
+                            oks_util::initialize_with_key(
+                                &client,
+                                key,
+                                &wrap,
+                                auth_domains,
+                                &public,
+                                &opts,
+                                &warning,
+                            )
+                        }),
+                        None => oks_util::initialize(
+                            &client,
+                            &wrap,
+                            auth_domains,
+                            &public,
+                            &opts,
+                            &warning,
+                        ),
+                    };
+                    ("initialize", result)
+                }
+                HsmCommand::Generate {
+                    key_spec,
+                    check_names,
+                    domains,
+                    resume,
+                    layout,
+                    verify_with,
+                } => {
+                    let opts = CeremonyOptions {
+                        layout,
+                        check_names,
+                        verify_with,
+                        ..opts
+                    };
+                    (
+                        "generate",
+                        if resume {
+                            oks_util::resume_generate(
+                                &client,
+                                &key_spec,
+                                auth_domains,
+                                &public,
+                                &opts,
+                            )
+                        } else if domains.is_empty() {
+                            oks_util::generate(
+                                &client,
+                                &key_spec,
+                                auth_domains,
+                                &public,
+                                &opts,
+                            )
+                        } else {
+                            config::domains_from_numbers(&domains)
+                                .map_err(anyhow::Error::from)
+                                .and_then(|domain| {
+                                    oks_util::generate_in_domain(
+                                        &client,
+                                        &key_spec,
+                                        domain,
+                                        auth_domains,
+                                        &public,
+                                        &opts,
+                                    )
+                                })
+                        },
+                    )
                 }
-                HsmCommand::Generate { key_spec } => {
-                    oks_util::generate(&client, &key_spec, &args.public)
+                HsmCommand::Restore {
+                    force,
+                    manifest,
+                    shares_file,
+                } => (
+                    "restore",
+                    match shares_file {
+                        Some(path) => read_shares(&path).and_then(|shares| {
+                            oks_util::restore_with_shares(
+                                &client,
+                                &wrap,
+                                auth_domains,
+                                manifest.as_deref(),
+                                args.mode,
+                                force,
+                                shares,
+                            )
+                        }),
+                        None => oks_util::restore(
+                            &client,
+                            &wrap,
+                            auth_domains,
+                            manifest.as_deref(),
+                            args.mode,
+                            force,
+                            timeout,
+                        ),
+                    },
+                ),
+                HsmCommand::RotateAuth => (
+                    "rotate-auth",
+                    oks_util::rotate_auth_key(
+                        &client,
+                        auth_id,
+                        wrap.id,
+                        &public,
+                        args.mode,
+                        backup_format,
+                    ),
+                ),
+                HsmCommand::BackupAuth => (
+                    "backup-auth",
+                    oks_util::backup_auth_key(
+                        &client,
+                        auth_id,
+                        wrap.id,
+                        &public,
+                        args.mode,
+                        backup_format,
+                    )
+                    .map(|path| Report {
+                        files: vec![path],
+                        ..Default::default()
+                    }),
+                ),
+                HsmCommand::BackupAll => (
+                    "backup-all",
+                    oks_util::backup_all(
+                        &client,
+                        wrap.id,
+                        &public,
+                        args.mode,
+                        backup_format,
+                    )
+                    .map(|files| Report {
+                        files,
+                        ..Default::default()
+                    }),
+                ),
+                HsmCommand::Rewrap {
+                    id,
+                    object_type,
+                    dst_wrap_id,
+                    backup,
+                } => (
+                    "rewrap",
+                    oks_util::rewrap(
+                        &client,
+                        wrap.id,
+                        dst_wrap_id,
+                        id,
+                        object_type,
+                        backup.as_deref(),
+                        &public,
+                        args.mode,
+                        backup_format,
+                    )
+                    .map(|path| Report {
+                        files: vec![path],
+                        ..Default::default()
+                    }),
+                ),
+                HsmCommand::RefreshShares {
+                    manifest,
+                    shares,
+                    threshold,
+                    share_warning,
+                } => (
+                    "refresh-shares",
+                    oks_util::refresh_shares(
+                        &client,
+                        &manifest,
+                        shares,
+                        threshold,
+                        share_backend,
+                        share_encoding,
+                        args.mode,
+                        timeout,
+                        &read_share_warning(
+                            share_warning,
+                            oks_util::REFRESH_SHARE_WARNING,
+                        )?,
+                    ),
+                ),
+                HsmCommand::MigrateWrap {
+                    manifest,
+                    backup_dir,
+                    to,
+                    share_warning,
+                } => (
+                    "migrate-wrap",
+                    oks_util::migrate_wrap(
+                        &client,
+                        &manifest,
+                        to.into(),
+                        &backup_dir,
+                        &public,
+                        &opts,
+                        &read_share_warning(
+                            share_warning,
+                            oks_util::MIGRATE_SHARE_WARNING,
+                        )?,
+                    ),
+                ),
+                HsmCommand::ReconfigureWrap {
+                    manifest,
+                    backup,
+                    domains,
+                    capabilities,
+                    delegated_capabilities,
+                } => (
+                    "reconfigure-wrap",
+                    wrap_key_update(
+                        &domains,
+                        &capabilities,
+                        &delegated_capabilities,
+                    )
+                    .and_then(|update| {
+                        oks_util::reconfigure_wrap_key(
+                            &client,
+                            &manifest,
+                            &update,
+                            &backup,
+                            auth_domains,
+                            args.mode,
+                            timeout,
+                        )
+                    }),
+                ),
+                HsmCommand::SignReport {
+                    report,
+                    key_id,
+                    out,
+                } => (
+                    "sign-report",
+                    CeremonyReport::load(&report).and_then(|report| {
+                        oks_util::sign_report(&client, key_id, &report, &out)
+                    }),
+                ),
+                HsmCommand::SignKeyspecs { key_spec, key_id } => (
+                    "sign-keyspecs",
+                    oks_util::sign_keyspecs(&client, key_id, &key_spec),
+                ),
+                HsmCommand::Benchmark { runs } => (
+                    "benchmark",
+                    oks_util::benchmark(&client, auth_domains, runs).map(
+                        |latencies| {
+                            for latency in latencies {
+                                println!("{}", latency);
+                            }
+                            Report::default()
+                        },
+                    ),
+                ),
+                HsmCommand::Version => (
+                    "version",
+                    oks_util::version_report(&client).map(|report| {
+                        println!("{}", report);
+                        Report::default()
+                    }),
+                ),
+                HsmCommand::Storage => (
+                    "storage",
+                    oks_util::storage(&client).map(|storage| {
+                        println!("{}", storage);
+                        Report::default()
+                    }),
+                ),
+                HsmCommand::ShowObject { id, object_type } => (
+                    "show-object",
+                    oks_util::show_object(&client, id, object_type).map(
+                        |info| {
+                            println!("{}", info);
+                            Report::default()
+                        },
+                    ),
+                ),
+                HsmCommand::Provision { .. } => {
+                    unreachable!("provision is handled above")
                 }
-                HsmCommand::Restore => oks_util::restore(&client),
+                HsmCommand::VerifyBackups { backup_dir } => (
+                    "verify-backups",
+                    verify_backups(&client, wrap.id, &backup_dir),
+                ),
+                HsmCommand::VerifySpec { key_spec } => {
+                    ("verify-spec", verify_specs(&client, &key_spec))
+                }
+                HsmCommand::Compare { other } => {
+                    ("compare", compare(&client, &other, auth_id))
+                }
+                HsmCommand::ExportKeyspecs => (
+                    "export-keyspecs",
+                    oks_util::export_keyspecs(&client, &public).map(|files| {
+                        Report {
+                            files,
+                            ..Default::default()
+                        }
+                    }),
+                ),
+                HsmCommand::ExportPubkeys { key_spec } => (
+                    "export-pubkeys",
+                    oks_util::load_key_specs(&key_spec, false)
+                        .and_then(|specs| {
+                            oks_util::export_pubkeys(
+                                &client, &specs, &public, args.mode,
+                            )
+                        })
+                        .map(|files| Report {
+                            files,
+                            ..Default::default()
+                        }),
+                ),
+            };
+
+            if let Some(json) = json {
+                json.emit(name, &result)?;
+            }
+            result.map(|_| ())
+        }
+        Command::Selftest => oks_util::selftest(),
+        Command::RecoverFingerprint {
+            shares_file,
+            manifest,
+        } => {
+            let shares = read_shares(&shares_file)?;
+            println!(
+                "{}",
+                oks_util::recover_fingerprint(shares, manifest.as_deref())?
+            );
+            Ok(())
+        }
+        Command::SummarizeKeySpecs { key_spec, format } => {
+            print!("{}", oks_util::summarize_key_specs(&key_spec, format)?);
+            Ok(())
+        }
+        Command::CheckKeySpecs { key_spec } => {
+            let errors = oks_util::check_key_specs(&key_spec)?;
+            let count = errors.len();
+            for (path, e) in errors {
+                warn!("{}: {:#}", path.display(), anyhow::Error::from(e));
+            }
+            if count > 0 {
+                bail!("{} key spec files are malformed", count);
+            }
+            info!("every key spec in {} parses", key_spec.display());
+            Ok(())
+        }
+        Command::CheckKeyIds {
+            key_spec,
+            id_registry,
+        } => {
+            let mismatches = oks_util::check_key_ids(&key_spec, &id_registry)?;
+            for mismatch in &mismatches {
+                warn!("{}", mismatch);
+            }
+            if !mismatches.is_empty() {
+                bail!(
+                    "found {} key specs in {} that don't match {}",
+                    mismatches.len(),
+                    key_spec.display(),
+                    id_registry.display()
+                );
+            }
+            info!("every key spec matches the id registry");
+            Ok(())
+        }
+        Command::WipeShares { dir } => {
+            let wiped = oks_util::wipe_share_pdfs(&dir)?;
+            if wiped.is_empty() {
+                warn!("no key share files in {}", dir.display());
             }
+            Ok(())
         }
     }
 }
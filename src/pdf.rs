@@ -0,0 +1,230 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Render a key share as a single page PDF for printing, so that each key
+//! custodian can be handed their share on paper in a tamper evident
+//! envelope. The PDF is written by hand: one US letter page of text in the
+//! Courier base font, which every PDF reader has, so there's nothing to
+//! embed. The share can also be printed as a QR code below the text, drawn
+//! as filled squares.
+//!
+//! A PDF of a share is as sensitive as the share. They're only for air
+//! gapped printing workflows where the media they're written to is printed
+//! from immediately and then wiped.
+
+use qrcodegen::{QrCode, QrCodeEcc};
+use std::fmt::Write;
+
+// US letter in points w/ a 1 inch margin
+const PAGE_WIDTH: usize = 612;
+const PAGE_HEIGHT: usize = 792;
+const MARGIN: usize = 72;
+
+// Courier is 0.6 em wide so 12 point text fits 65 characters in the
+// margins, the shares are broken into lines of this many characters
+const FONT_SIZE: usize = 12;
+const LEADING: usize = 16;
+const LINE_LEN: usize = 64;
+
+// width in points of a module of the QR code, about 1 mm. The page margins
+// are wider than the quiet zone a QR code needs around it.
+const QR_MODULE: usize = 3;
+
+/// The text printed on the page for a key share.
+#[derive(Debug)]
+pub struct SharePage<'a> {
+    /// Id of the ceremony the share was created in.
+    pub ceremony: &'a str,
+    /// The ordinal of the share, e.g. "Share 2 of 5".
+    pub title: &'a str,
+    /// The share as it's displayed to the custodian.
+    pub share: &'a str,
+    /// The check digest of the share, see `shares::digest`.
+    pub digest: &'a str,
+    /// Also print the share as a QR code.
+    pub qr: bool,
+}
+
+// escape the characters that are special in a PDF string literal
+fn escape(text: &str) -> String {
+    text.chars().fold(String::new(), |mut escaped, c| {
+        if matches!(c, '\\' | '(' | ')') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        escaped
+    })
+}
+
+// the lines of text on the page, the share is broken into lines that fit
+// in the margins
+fn lines(page: &SharePage) -> Vec<String> {
+    let mut lines = vec![
+        format!("Ceremony: {}", page.ceremony),
+        page.title.to_string(),
+        String::new(),
+    ];
+    let share: Vec<char> = page.share.chars().collect();
+    lines.extend(share.chunks(LINE_LEN).map(String::from_iter));
+    lines.extend([
+        String::new(),
+        format!("Check digest: {}", page.digest),
+        String::new(),
+        "Keep this page sealed until the share is needed.".to_string(),
+    ]);
+    lines
+}
+
+/// Render the page for a key share as a PDF.
+pub fn share_page(page: &SharePage) -> Vec<u8> {
+    let mut content = format!(
+        "BT\n/F1 {} Tf\n{} TL\n{} {} Td\n",
+        FONT_SIZE,
+        LEADING,
+        MARGIN,
+        PAGE_HEIGHT - MARGIN
+    );
+    let lines = lines(page);
+    for line in &lines {
+        let _ = writeln!(content, "({}) Tj T*", escape(line));
+    }
+    content.push_str("ET\n");
+    if page.qr {
+        // a share is a few hundred bytes at most, far less than a QR code
+        // holds
+        let qr = QrCode::encode_text(page.share, QrCodeEcc::Medium)
+            .expect("a key share fits in a QR code");
+        // the code is drawn a line below the text, w/ y up from the bottom
+        // of the page
+        let top = PAGE_HEIGHT - MARGIN - LEADING * (lines.len() + 1);
+        let size = qr.size();
+        content.push_str("0 g\n");
+        for y in 0..size {
+            for x in (0..size).filter(|&x| qr.get_module(x, y)) {
+                let _ = writeln!(
+                    content,
+                    "{} {} {} {} re",
+                    MARGIN + x as usize * QR_MODULE,
+                    top - (y as usize + 1) * QR_MODULE,
+                    QR_MODULE,
+                    QR_MODULE
+                );
+            }
+        }
+        content.push_str("f\n");
+    }
+
+    let objects = [
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(),
+        format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+            /Resources << /Font << /F1 4 0 R >> >> /Contents 5 0 R >>",
+            PAGE_WIDTH, PAGE_HEIGHT
+        ),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Courier >>".to_string(),
+        format!(
+            "<< /Length {} >>\nstream\n{}endstream",
+            content.len(),
+            content
+        ),
+    ];
+
+    // the cross reference table has the byte offset of each object
+    let mut pdf = "%PDF-1.4\n".to_string();
+    let mut offsets = Vec::new();
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        let _ = write!(pdf, "{} 0 obj\n{}\nendobj\n", i + 1, object);
+    }
+    let xref = pdf.len();
+    let _ = write!(pdf, "xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+    for offset in offsets {
+        let _ = writeln!(pdf, "{:010} 00000 n ", offset);
+    }
+    let _ = write!(
+        pdf,
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+        objects.len() + 1,
+        xref
+    );
+
+    pdf.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_page() {
+        let share = "0".repeat(LINE_LEN) + "1(2)\\3";
+        let pdf = share_page(&SharePage {
+            ceremony: "oks-20230301T170500Z",
+            title: "Share 2 of 5",
+            share: &share,
+            digest: "abcd-1234",
+            qr: false,
+        });
+        let pdf = String::from_utf8(pdf).unwrap();
+        assert!(pdf.starts_with("%PDF-1.4\n"));
+        assert!(pdf.ends_with("%%EOF\n"));
+        assert!(pdf.contains("(Ceremony: oks-20230301T170500Z) Tj T*"));
+        assert!(pdf.contains("(Check digest: abcd-1234) Tj T*"));
+
+        // the share is broken into lines w/ the special characters escaped
+        assert!(pdf.contains(&format!("({}) Tj T*", "0".repeat(LINE_LEN))));
+        assert!(pdf.contains("(1\\(2\\)\\\\3) Tj T*"));
+
+        // each entry in the cross reference table is the offset of its
+        // object, and startxref is the offset of the table
+        let xref = pdf.find("xref\n").unwrap();
+        let start: usize = pdf
+            .rsplit("startxref\n")
+            .next()
+            .and_then(|s| s.lines().next())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(start, xref);
+        for (i, entry) in pdf[xref..].lines().skip(3).take(5).enumerate() {
+            assert_eq!(entry.len(), 19);
+            let offset: usize = entry[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(&format!("{} 0 obj\n", i + 1)));
+        }
+        assert!(!pdf.contains(" re\n"));
+    }
+
+    #[test]
+    fn test_share_page_qr() {
+        let share = "a".repeat(300);
+        let page = SharePage {
+            ceremony: "oks-20230301T170500Z",
+            title: "Share 2 of 5",
+            share: &share,
+            digest: "abcd-1234",
+            qr: true,
+        };
+        let pdf = String::from_utf8(share_page(&page)).unwrap();
+
+        // a square is drawn for each dark module, all of them between the
+        // text and the bottom margin
+        let qr = QrCode::encode_text(&share, QrCodeEcc::Medium).unwrap();
+        let dark = (0..qr.size())
+            .flat_map(|y| (0..qr.size()).map(move |x| (x, y)))
+            .filter(|&(x, y)| qr.get_module(x, y))
+            .count();
+        let squares: Vec<Vec<usize>> = pdf
+            .lines()
+            .filter_map(|line| line.strip_suffix(" re"))
+            .map(|line| line.split(' ').map(|n| n.parse().unwrap()).collect())
+            .collect();
+        assert_eq!(squares.len(), dark);
+        let text_bottom = PAGE_HEIGHT - MARGIN - LEADING * lines(&page).len();
+        for square in squares {
+            assert!(square[0] >= MARGIN && square[0] < PAGE_WIDTH - MARGIN);
+            assert!(square[1] >= MARGIN && square[1] < text_bottom);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Encode the public keys returned by the YubiHSM as PKCS#8 / X.509
+//! SubjectPublicKeyInfo (RFC 5280 section 4.1.2.7), the `PUBLIC KEY` PEM
+//! that openssl and most other tools expect. The YubiHSM returns the raw
+//! key: the modulus for RSA, the X and Y coordinates for ECC and the
+//! compressed point for Ed25519.
+
+use anyhow::Result;
+use pem::Pem;
+use yubihsm::asymmetric::{self, PublicKey};
+
+use crate::HsmError;
+
+/// Tag used in the PEM encapsulation boundaries of public keys.
+pub const PEM_TAG: &str = "PUBLIC KEY";
+
+// the DER encoded OIDs of the algorithms and curves we encode
+const RSA_ENCRYPTION: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01,
+];
+const EC_PUBLIC_KEY: &[u8] =
+    &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const PRIME256V1: &[u8] =
+    &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const SECP384R1: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22];
+const SECP521R1: &[u8] = &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x23];
+const ED25519: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+const NULL: &[u8] = &[0x05, 0x00];
+
+// the public exponent of every RSA key generated by the YubiHSM
+const RSA_EXPONENT: &[u8] = &[0x01, 0x00, 0x01];
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+
+// encode a DER tag, length and value
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let len = value.len().to_be_bytes();
+    let skip = len.iter().take_while(|b| **b == 0).count();
+    let mut der = vec![tag];
+    if value.len() < 0x80 {
+        der.push(value.len() as u8);
+    } else {
+        der.push(0x80 | (len.len() - skip) as u8);
+        der.extend_from_slice(&len[skip..]);
+    }
+    der.extend_from_slice(value);
+    der
+}
+
+// encode an unsigned big endian integer, a leading zero keeps it positive
+fn integer(value: &[u8]) -> Vec<u8> {
+    match value.first() {
+        Some(b) if b & 0x80 != 0 => tlv(INTEGER, &[&[0], value].concat()),
+        _ => tlv(INTEGER, value),
+    }
+}
+
+// a BIT STRING w/ no unused bits
+fn bit_string(value: &[u8]) -> Vec<u8> {
+    tlv(BIT_STRING, &[&[0], value].concat())
+}
+
+/// DER encode the public key as a SubjectPublicKeyInfo.
+pub fn spki_der(key: &PublicKey) -> Result<Vec<u8>> {
+    let len = key.algorithm.key_len();
+    let (algorithm, key_bits) = match key.algorithm {
+        asymmetric::Algorithm::Rsa2048
+        | asymmetric::Algorithm::Rsa3072
+        | asymmetric::Algorithm::Rsa4096 => (
+            [RSA_ENCRYPTION, NULL].concat(),
+            tlv(
+                SEQUENCE,
+                &[integer(&key.bytes), integer(RSA_EXPONENT)].concat(),
+            ),
+        ),
+        asymmetric::Algorithm::EcP256 => (
+            [EC_PUBLIC_KEY, PRIME256V1].concat(),
+            [&[0x04], key.as_slice()].concat(),
+        ),
+        asymmetric::Algorithm::EcP384 => (
+            [EC_PUBLIC_KEY, SECP384R1].concat(),
+            [&[0x04], key.as_slice()].concat(),
+        ),
+        asymmetric::Algorithm::EcP521 => (
+            [EC_PUBLIC_KEY, SECP521R1].concat(),
+            [&[0x04], key.as_slice()].concat(),
+        ),
+        asymmetric::Algorithm::Ed25519 => (ED25519.to_vec(), key.bytes.clone()),
+        algorithm => return Err(HsmError::BadPublicKey(algorithm).into()),
+    };
+    // the uncompressed point has both coordinates
+    let expected = match key.algorithm {
+        asymmetric::Algorithm::EcP256
+        | asymmetric::Algorithm::EcP384
+        | asymmetric::Algorithm::EcP521 => 2 * len,
+        _ => len,
+    };
+    if key.len() != expected {
+        return Err(HsmError::BadPublicKey(key.algorithm).into());
+    }
+
+    Ok(tlv(
+        SEQUENCE,
+        &[tlv(SEQUENCE, &algorithm), bit_string(&key_bits)].concat(),
+    ))
+}
+
+/// PEM encode the public key as a SubjectPublicKeyInfo w/ the label of the
+/// key in the explanatory text before the PEM block, see RFC 7468 section
+/// 5.2. The lines end in CRLF like those written by `pem::encode`.
+pub fn spki_pem(key: &PublicKey, label: &str) -> Result<String> {
+    Ok(format!("Label: {}\r\n", label)
+        + &pem::encode(&Pem {
+            tag: PEM_TAG.to_string(),
+            contents: spki_der(key)?,
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_parser::{
+        prelude::FromDer, public_key::PublicKey as ParsedKey,
+        x509::SubjectPublicKeyInfo,
+    };
+
+    #[test]
+    fn test_spki_der() -> Result<()> {
+        let rsa = PublicKey {
+            algorithm: asymmetric::Algorithm::Rsa4096,
+            bytes: vec![0xc5; 512],
+        };
+        let der = spki_der(&rsa)?;
+        let (rest, spki) = SubjectPublicKeyInfo::from_der(&der)?;
+        assert!(rest.is_empty());
+        match spki.parsed()? {
+            ParsedKey::RSA(key) => {
+                assert_eq!(key.key_size(), 4096);
+                assert_eq!(key.try_exponent()?, 65537);
+            }
+            _ => panic!("not an RSA key"),
+        }
+
+        let ec = PublicKey {
+            algorithm: asymmetric::Algorithm::EcP384,
+            bytes: vec![0x17; 96],
+        };
+        let der = spki_der(&ec)?;
+        let (_, spki) = SubjectPublicKeyInfo::from_der(&der)?;
+        match spki.parsed()? {
+            ParsedKey::EC(point) => {
+                assert_eq!(point.key_size(), 384);
+                assert_eq!(point.data()[0], 0x04);
+            }
+            _ => panic!("not an EC key"),
+        }
+        assert_eq!(
+            spki.algorithm.parameters.unwrap().as_bytes(),
+            &SECP384R1[2..]
+        );
+
+        let short = PublicKey {
+            algorithm: asymmetric::Algorithm::EcP384,
+            bytes: vec![0x17; 48],
+        };
+        assert!(spki_der(&short).is_err());
+
+        let pem = spki_pem(&ec, "rot-identity-a")?;
+        assert!(
+            pem.starts_with("Label: rot-identity-a\r\n-----BEGIN PUBLIC KEY")
+        );
+        assert_eq!(pem::parse(&pem)?.contents, der);
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{Context, Result};
+use hex::ToHex;
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use yubihsm::object::{Id, Label, Type};
+
+/// An object created, or deleted, in the YubiHSM by a ceremony step.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ReportObject {
+    pub id: Id,
+    #[serde(rename = "type")]
+    pub object_type: String,
+    pub label: String,
+}
+
+/// How long an operation in a ceremony step took, e.g. generating a key.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Timing {
+    pub operation: String,
+    pub millis: u64,
+}
+
+/// Run `f`, logging how long it took at info level. This is for planning
+/// ceremonies and spotting a slow YubiHSM, the outcome of `f` is returned
+/// as is.
+pub fn timed<T>(operation: &str, f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    info!("{} took {:.3}s", operation, elapsed.as_secs_f64());
+
+    (result, elapsed)
+}
+
+/// Machine readable description of the outcome of a ceremony step: the
+/// objects created and deleted in the YubiHSM, the files written, the
+/// labels of keys created w/o a backup and the fingerprint of the wrap key
+/// (if any).
+/// This is separate from the log and the prompts displayed to the
+/// operator so that the outcome can be checked by a script w/o scraping
+/// log lines.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Report {
+    #[serde(default)]
+    pub objects: Vec<ReportObject>,
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<ReportObject>,
+    /// Labels of the keys that can't be exported under wrap: these exist
+    /// only in the YubiHSM and can't be recovered if it's lost.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_backup: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub timings: Vec<Timing>,
+}
+
+impl Report {
+    pub fn add_object(&mut self, id: Id, object_type: Type, label: &Label) {
+        self.objects.push(ReportObject {
+            id,
+            object_type: format!("{:?}", object_type),
+            label: label.to_string(),
+        });
+    }
+
+    pub fn add_file(&mut self, path: &Path) {
+        self.files.push(path.to_path_buf());
+    }
+
+    pub fn add_deleted(&mut self, id: Id, object_type: Type, label: &Label) {
+        self.deleted.push(ReportObject {
+            id,
+            object_type: format!("{:?}", object_type),
+            label: label.to_string(),
+        });
+    }
+
+    pub fn add_no_backup(&mut self, label: &Label) {
+        self.no_backup.push(label.to_string());
+    }
+
+    /// Run `f` and record how long it took, see `timed`.
+    pub fn time<T>(&mut self, operation: &str, f: impl FnOnce() -> T) -> T {
+        let (result, elapsed) = timed(operation, f);
+        self.timings.push(Timing {
+            operation: operation.to_string(),
+            millis: elapsed.as_millis().try_into().unwrap_or(u64::MAX),
+        });
+
+        result
+    }
+
+    /// Append the objects, files, deleted objects, keys w/o backup and
+    /// timings from another report.
+    pub fn merge(&mut self, other: Report) {
+        self.objects.extend(other.objects);
+        self.files.extend(other.files);
+        self.deleted.extend(other.deleted);
+        self.no_backup.extend(other.no_backup);
+        self.timings.extend(other.timings);
+        if other.fingerprint.is_some() {
+            self.fingerprint = other.fingerprint;
+        }
+    }
+}
+
+/// The summary of a ceremony that's signed by a key in the YubiHSM: the
+/// report of the ceremony steps and the SHA-256 digest of each file they
+/// wrote, e.g. the certs.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CeremonyReport {
+    #[serde(flatten)]
+    pub report: Report,
+    pub digests: BTreeMap<PathBuf, String>,
+}
+
+impl CeremonyReport {
+    /// Create the summary of the provided report, reading each file it
+    /// lists to compute its digest.
+    pub fn new(report: Report) -> Result<Self> {
+        let mut digests = BTreeMap::new();
+        for path in &report.files {
+            let data = fs::read(path).with_context(|| {
+                format!("Failed to read file for digest: {}", path.display())
+            })?;
+            digests.insert(path.clone(), Sha256::digest(data).encode_hex());
+        }
+
+        Ok(CeremonyReport { report, digests })
+    }
+
+    /// Load a report written by `Output::emit` and create its summary.
+    pub fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path).with_context(|| {
+            format!("Failed to read report: {}", path.display())
+        })?;
+        Self::new(serde_json::from_str(&json)?)
+    }
+
+    /// The canonical serialization of the report: compact JSON w/ the
+    /// fields in declaration order and the digests sorted by path. This is
+    /// what gets signed.
+    pub fn to_canonical_json(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Error,
+}
+
+// the document written to the `Output`
+#[derive(Serialize)]
+struct Document<'a> {
+    command: &'a str,
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    report: Option<&'a Report>,
+}
+
+/// Where the machine readable result of a ceremony step is written: either
+/// stdout (`-`) or a file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Output {
+    Stdout,
+    File(PathBuf),
+}
+
+impl Output {
+    /// Write the result of the command w/ the provided name as a JSON
+    /// document. A failed command produces a document w/ the error in
+    /// place of the report.
+    pub fn emit(&self, command: &str, result: &Result<Report>) -> Result<()> {
+        let document = match result {
+            Ok(report) => Document {
+                command,
+                status: Status::Ok,
+                error: None,
+                report: Some(report),
+            },
+            Err(e) => Document {
+                command,
+                status: Status::Error,
+                error: Some(format!("{:#}", e)),
+                report: None,
+            },
+        };
+        let mut json = serde_json::to_string_pretty(&document)?;
+        json.push('\n');
+
+        match self {
+            Output::Stdout => io::stdout().write_all(json.as_bytes())?,
+            Output::File(path) => fs::write(path, json).with_context(|| {
+                format!("Failed to write result: {}", path.display())
+            })?,
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Output {
+    type Err = std::convert::Infallible;
+
+    fn from_str(output: &str) -> Result<Self, Self::Err> {
+        Ok(match output {
+            "-" => Output::Stdout,
+            path => Output::File(PathBuf::from(path)),
+        })
+    }
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::Stdout => write!(f, "-"),
+            Output::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HsmError;
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_emit() -> Result<()> {
+        let dir = TempDir::new()?;
+        let path = dir.path().join("result.json");
+        let output = Output::from_str(path.to_str().unwrap())?;
+        assert_eq!(output, Output::File(path.clone()));
+
+        let mut report = Report::default();
+        report.add_object(3, Type::AsymmetricKey, &Label::from("key"));
+        report.add_file(Path::new("key.wrap.json"));
+        output.emit("generate", &Ok(report))?;
+
+        let json: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        assert_eq!(json["command"], "generate");
+        assert_eq!(json["status"], "ok");
+        assert_eq!(json["objects"][0]["id"], 3);
+        assert_eq!(json["objects"][0]["type"], "AsymmetricKey");
+        assert_eq!(json["files"][0], "key.wrap.json");
+        assert!(json.get("error").is_none());
+        assert!(json.get("fingerprint").is_none());
+        assert!(json.get("no_backup").is_none());
+        assert!(json.get("timings").is_none());
+
+        output.emit("restore", &Err(HsmError::UnsupportedCertKey.into()))?;
+        let json: Value = serde_json::from_str(&fs::read_to_string(&path)?)?;
+        assert_eq!(json["status"], "error");
+        assert_eq!(json["error"], "cert public key isn't a P-256 or P-384 key");
+        assert!(json.get("objects").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ceremony_report() -> Result<()> {
+        let dir = TempDir::new()?;
+        let cert = dir.path().join("ca.cert.pem");
+        fs::write(&cert, "cert")?;
+        let mut report = Report::default();
+        report.add_object(3, Type::AsymmetricKey, &Label::from("key"));
+        report.add_file(&cert);
+
+        // a report emitted as JSON can be loaded again
+        let path = dir.path().join("result.json");
+        Output::File(path.clone()).emit("generate", &Ok(report.clone()))?;
+        let ceremony = CeremonyReport::load(&path)?;
+        assert_eq!(ceremony.report, report);
+        assert_eq!(
+            ceremony.digests[&cert],
+            // sha256sum of "cert"
+            "06298432e8066b29e2223bcc23aa9504b56ae508fabf3435508869b9c3190e22"
+        );
+
+        let json = ceremony.to_canonical_json()?;
+        assert_eq!(json, CeremonyReport::new(report)?.to_canonical_json()?);
+        assert!(!json.contains(&b'\n'));
+
+        // every file in the report must be readable
+        fs::remove_file(&cert)?;
+        assert!(CeremonyReport::load(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_time() -> Result<()> {
+        let mut report = Report::default();
+        let result = report.time("sleep", || {
+            std::thread::sleep(Duration::from_millis(10));
+            42
+        });
+        assert_eq!(result, 42);
+        assert_eq!(report.timings.len(), 1);
+        assert_eq!(report.timings[0].operation, "sleep");
+        assert!(report.timings[0].millis >= 10);
+
+        let json: Value = serde_json::to_value(&report)?;
+        assert_eq!(json["timings"][0]["operation"], "sleep");
+        Ok(())
+    }
+}
@@ -0,0 +1,701 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use anyhow::{bail, Context, Result};
+use argon2::Argon2;
+use base32::Alphabet::Crockford;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use log::debug;
+use sha2::{Digest, Sha256};
+use sharks::{Share, Sharks};
+use std::{fmt, str::FromStr};
+
+use crate::{config::ConfigError, HsmError};
+
+/// Tag prefixed to shares produced by the `sharks` backend. These shares are
+/// formatted as "sharks1-threshold-index-data" where data is the hex encoded
+/// share. Shares produced by `rusty_secrets` are untagged and formatted as
+/// "threshold-index-data".
+const SHARKS_TAG: &str = "sharks1";
+
+/// Prefix of shares in the `ShareEncoding::Base32` encoding.
+const BASE32_PREFIX: &str = "B32";
+
+/// Check symbols for Crockford's Base32, the values 32 through 36 have
+/// their own symbols.
+const CHECK_SYMBOLS: &[u8] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ*~$=U";
+
+/// Number of characters in each group of a Base32 encoded share.
+const BASE32_GROUP: usize = 4;
+
+/// Prefix of a share encrypted under a key custodian's passphrase, see
+/// `wrap_share`. The number is the version of the format so that it can
+/// change w/o breaking existing shares.
+const WRAPPED_PREFIX: &str = "W1";
+
+/// Length of the Argon2id salt and the XChaCha20-Poly1305 nonce in a wrapped
+/// share.
+const WRAP_SALT_LEN: usize = 16;
+const WRAP_NONCE_LEN: usize = 24;
+
+/// The Shamir secret sharing implementations used to split the wrap key.
+/// Shares are tagged w/ the backend that produced them so that the secret
+/// can be recovered w/o knowing which backend was used.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ShareBackend {
+    /// `rusty_secrets`, this crate has been abandoned but it's what all
+    /// existing shares were created with.
+    #[default]
+    RustySecrets,
+    /// `sharks`
+    Sharks,
+}
+
+impl ShareBackend {
+    /// Get the backend that produced the provided share.
+    pub fn detect(share: &str) -> Self {
+        if share.trim().starts_with(&format!("{}-", SHARKS_TAG)) {
+            ShareBackend::Sharks
+        } else {
+            ShareBackend::RustySecrets
+        }
+    }
+
+    /// Split the secret into `count` shares, `threshold` of which are
+    /// required to recover it.
+    pub fn split(
+        &self,
+        threshold: u8,
+        count: u8,
+        secret: &[u8],
+    ) -> Result<Vec<String>> {
+        let context = || {
+            format!(
+                "Failed to split secret into {} shares with threashold {}",
+                count, threshold
+            )
+        };
+        match self {
+            ShareBackend::RustySecrets => rusty_secrets::generate_shares(
+                threshold,
+                count,
+                &secret.to_vec(),
+            )
+            .with_context(context),
+            ShareBackend::Sharks => {
+                if threshold == 0 || threshold > count {
+                    bail!("{}: invalid threshold", context());
+                }
+                Ok(Sharks(threshold)
+                    .dealer(secret)
+                    .take(count as usize)
+                    .map(|share| {
+                        format!(
+                            "{}-{}-{}-{}",
+                            SHARKS_TAG,
+                            threshold,
+                            share.x.0,
+                            hex::encode(Vec::from(&share)),
+                        )
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+impl FromStr for ShareBackend {
+    type Err = ConfigError;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "rusty-secrets" => Ok(ShareBackend::RustySecrets),
+            "sharks" => Ok(ShareBackend::Sharks),
+            _ => Err(ConfigError::BadShareBackend),
+        }
+    }
+}
+
+impl fmt::Display for ShareBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShareBackend::RustySecrets => write!(f, "rusty-secrets"),
+            ShareBackend::Sharks => write!(f, "sharks"),
+        }
+    }
+}
+
+/// The encodings that shares are displayed to the key custodians in. Shares
+/// in any encoding other than `Plain` are prefixed so that the encoding can
+/// be detected when they're entered.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ShareEncoding {
+    /// The share as produced by the backend.
+    #[default]
+    Plain,
+    /// Crockford's Base32 w/ a check symbol, split into groups of 4
+    /// characters, e.g. "B32-05RP-...-7". The encoding is case insensitive
+    /// and the letters I, L and O are read as 1, 1 and 0.
+    Base32,
+}
+
+impl ShareEncoding {
+    /// Get the encoding of a share entered by a key custodian.
+    pub fn detect(share: &str) -> Self {
+        let prefix = share.trim().get(..BASE32_PREFIX.len() + 1);
+        match prefix {
+            Some(prefix)
+                if prefix
+                    .eq_ignore_ascii_case(&format!("{}-", BASE32_PREFIX)) =>
+            {
+                ShareEncoding::Base32
+            }
+            _ => ShareEncoding::Plain,
+        }
+    }
+
+    /// Encode a share produced by either backend.
+    pub fn encode(&self, share: &str) -> Result<String, HsmError> {
+        match self {
+            ShareEncoding::Plain => Ok(share.to_string()),
+            ShareEncoding::Base32 => {
+                let bytes = share_to_bytes(share)?;
+                let mut encoded = base32::encode(Crockford, &bytes);
+                encoded.push(check_symbol(&bytes));
+
+                let groups: Vec<&str> = encoded
+                    .as_bytes()
+                    .chunks(BASE32_GROUP)
+                    .map(|g| std::str::from_utf8(g).unwrap_or_default())
+                    .collect();
+                Ok(format!("{}-{}", BASE32_PREFIX, groups.join("-")))
+            }
+        }
+    }
+}
+
+impl FromStr for ShareEncoding {
+    type Err = ConfigError;
+
+    fn from_str(encoding: &str) -> Result<Self, Self::Err> {
+        match encoding {
+            "plain" => Ok(ShareEncoding::Plain),
+            "base32" => Ok(ShareEncoding::Base32),
+            _ => Err(ConfigError::BadShareEncoding),
+        }
+    }
+}
+
+impl fmt::Display for ShareEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShareEncoding::Plain => write!(f, "plain"),
+            ShareEncoding::Base32 => write!(f, "base32"),
+        }
+    }
+}
+
+/// Decode a share in any `ShareEncoding` into the form produced by its
+/// backend.
+pub fn decode(share: &str) -> Result<String, HsmError> {
+    match ShareEncoding::detect(share) {
+        ShareEncoding::Plain => Ok(share.trim().to_string()),
+        ShareEncoding::Base32 => {
+            let bad = |reason: &str| {
+                HsmError::BadShare(format!("{}: {}", reason, share.trim()))
+            };
+            let mut chars: Vec<u8> = share.trim()[BASE32_PREFIX.len()..]
+                .bytes()
+                .filter(|c| *c != b'-' && !c.is_ascii_whitespace())
+                .map(|c| match c.to_ascii_uppercase() {
+                    b'I' | b'L' => b'1',
+                    b'O' => b'0',
+                    c => c,
+                })
+                .collect();
+            let check = chars.pop().ok_or_else(|| bad("empty share"))?;
+            let chars = String::from_utf8(chars)
+                .map_err(|_| bad("invalid character"))?;
+            let bytes = base32::decode(Crockford, &chars)
+                .ok_or_else(|| bad("invalid Base32"))?;
+            if check_symbol(&bytes) != check as char {
+                return Err(bad("check symbol mismatch"));
+            }
+
+            bytes_to_share(&bytes).ok_or_else(|| bad("malformed share"))
+        }
+    }
+}
+
+// The check symbol for the provided bytes: the bytes as a big endian
+// integer modulo 37.
+fn check_symbol(bytes: &[u8]) -> char {
+    let value = bytes
+        .iter()
+        .fold(0usize, |acc, b| (acc * 256 + *b as usize) % 37);
+
+    CHECK_SYMBOLS[value] as char
+}
+
+// Pack a share into bytes for encoding: the backend (0 for rusty_secrets,
+// 1 for sharks), the threshold and the index, followed by the share data.
+fn share_to_bytes(share: &str) -> Result<Vec<u8>, HsmError> {
+    let bad = || HsmError::BadShare(share.to_string());
+    let parts: Vec<&str> = share.trim().split('-').collect();
+    let (tag, threshold, index, data) = match parts.as_slice() {
+        [k, i, data] => (
+            0,
+            k,
+            i,
+            base64::decode_config(data, base64::STANDARD_NO_PAD)
+                .map_err(|_| bad())?,
+        ),
+        [SHARKS_TAG, k, i, data] => {
+            // the share data starts w/ the index, so we drop it
+            let data = hex::decode(data).map_err(|_| bad())?;
+            (1, k, i, data.get(1..).ok_or_else(bad)?.to_vec())
+        }
+        _ => return Err(bad()),
+    };
+    let threshold: u8 = threshold.parse().map_err(|_| bad())?;
+    let index: u8 = index.parse().map_err(|_| bad())?;
+
+    Ok([&[tag, threshold, index], data.as_slice()].concat())
+}
+
+// the inverse of `share_to_bytes`
+fn bytes_to_share(bytes: &[u8]) -> Option<String> {
+    if bytes.len() < 3 {
+        return None;
+    }
+    let (header, data) = bytes.split_at(3);
+    let (threshold, index) = (header[1], header[2]);
+    match header[0] {
+        0 => Some(format!(
+            "{}-{}-{}",
+            threshold,
+            index,
+            base64::encode_config(data, base64::STANDARD_NO_PAD)
+        )),
+        1 => Some(format!(
+            "{}-{}-{}-{}",
+            SHARKS_TAG,
+            threshold,
+            index,
+            hex::encode([&[index], data].concat())
+        )),
+        _ => None,
+    }
+}
+
+/// Whether the share is encrypted under a passphrase, see `wrap_share`.
+/// Wrapped and plain shares can be entered together.
+pub fn is_wrapped(share: &str) -> bool {
+    share
+        .trim()
+        .get(..WRAPPED_PREFIX.len() + 1)
+        .is_some_and(|prefix| {
+            prefix.eq_ignore_ascii_case(&format!("{}-", WRAPPED_PREFIX))
+        })
+}
+
+// the key used to wrap a share, derived from the passphrase w/ Argon2id
+fn wrap_cipher(
+    passphrase: &str,
+    salt: &[u8],
+) -> Result<XChaCha20Poly1305, HsmError> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| HsmError::BadShare(format!("key derivation: {}", e)))?;
+
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+/// Encrypt a share under a key custodian's passphrase so that the recorded
+/// share is useless w/o it. The share is encrypted w/ XChaCha20-Poly1305
+/// under a key derived from the passphrase w/ Argon2id. The salt, nonce and
+/// ciphertext are encoded in Crockford's Base32 in groups of 4 characters
+/// behind the `W1` version tag, e.g. "W1-8G2K-...".
+pub fn wrap_share(share: &str, passphrase: &str) -> Result<String, HsmError> {
+    let salt: [u8; WRAP_SALT_LEN] = rand::random();
+    let nonce: [u8; WRAP_NONCE_LEN] = rand::random();
+    let payload = Payload {
+        msg: share.trim().as_bytes(),
+        aad: WRAPPED_PREFIX.as_bytes(),
+    };
+    let ciphertext = wrap_cipher(passphrase, &salt)?
+        .encrypt(XNonce::from_slice(&nonce), payload)
+        .map_err(|_| HsmError::BadShare("encryption failed".to_string()))?;
+
+    let encoded =
+        base32::encode(Crockford, &[&salt[..], &nonce, &ciphertext].concat());
+    let groups: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(BASE32_GROUP)
+        .map(|g| std::str::from_utf8(g).unwrap_or_default())
+        .collect();
+    Ok(format!("{}-{}", WRAPPED_PREFIX, groups.join("-")))
+}
+
+/// Decrypt a share produced by `wrap_share` w/ the key custodian's
+/// passphrase. A wrong passphrase or a miscopied share fail to decrypt.
+pub fn unwrap_share(share: &str, passphrase: &str) -> Result<String, HsmError> {
+    let bad =
+        |reason: &str| HsmError::BadShare(format!("wrapped share: {}", reason));
+    if !is_wrapped(share) {
+        return Err(bad("missing version tag"));
+    }
+    let chars: String = share.trim()[WRAPPED_PREFIX.len()..]
+        .chars()
+        .filter(|c| *c != '-' && !c.is_ascii_whitespace())
+        .collect();
+    let bytes = base32::decode(Crockford, &chars)
+        .ok_or_else(|| bad("invalid Base32"))?;
+    if bytes.len() < WRAP_SALT_LEN + WRAP_NONCE_LEN {
+        return Err(bad("too short"));
+    }
+    let (salt, rest) = bytes.split_at(WRAP_SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(WRAP_NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: WRAPPED_PREFIX.as_bytes(),
+    };
+    let plaintext = wrap_cipher(passphrase, salt)?
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| bad("wrong passphrase or miscopied share"))?;
+
+    String::from_utf8(plaintext).map_err(|_| bad("malformed share"))
+}
+
+/// Number of bytes of the SHA-256 digest of a share in its check digest.
+const DIGEST_LEN: usize = 4;
+
+/// The check digest for a decoded share: the first `DIGEST_LEN` bytes of
+/// its SHA-256 digest, hex encoded. This is displayed w/ the share and read
+/// back by the key custodian to catch a share that was mis-recorded. It's
+/// recorded in the manifest so that a share can be checked at restore time,
+/// the digest is far too short to help recover the share.
+pub fn digest(share: &str) -> String {
+    hex::encode(&Sha256::digest(share.trim().as_bytes())[..DIGEST_LEN])
+}
+
+/// Compare a check digest entered by a key custodian to the expected one,
+/// ignoring case, whitespace and dashes.
+pub fn digest_matches(expected: &str, entered: &str) -> bool {
+    let normalize = |digest: &str| {
+        digest
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .collect::<String>()
+            .to_ascii_lowercase()
+    };
+    normalize(expected) == normalize(entered)
+}
+
+/// Get the index of a share produced by either backend.
+pub fn index(share: &str) -> Option<u8> {
+    let field = match ShareBackend::detect(share) {
+        ShareBackend::RustySecrets => 1,
+        ShareBackend::Sharks => 2,
+    };
+
+    share.trim().split('-').nth(field)?.parse().ok()
+}
+
+/// Recover the secret from the provided shares. All shares must have been
+/// produced by the same backend.
+pub fn recover(shares: Vec<String>) -> Result<Vec<u8>, HsmError> {
+    let backend = match shares.first() {
+        Some(share) => ShareBackend::detect(share),
+        None => return Err(HsmError::RecoverFail("no shares".to_string())),
+    };
+    if shares.iter().any(|s| ShareBackend::detect(s) != backend) {
+        return Err(HsmError::RecoverFail(
+            "shares were produced by different backends".to_string(),
+        ));
+    }
+
+    match backend {
+        ShareBackend::RustySecrets => rusty_secrets::recover_secret(shares)
+            .map_err(|e| HsmError::RecoverFail(e.to_string())),
+        ShareBackend::Sharks => {
+            let mut threshold = None;
+            let mut parsed = Vec::new();
+            for share in &shares {
+                let (k, share) = parse_sharks(share)?;
+                match threshold {
+                    Some(threshold) if threshold != k => {
+                        return Err(HsmError::RecoverFail(
+                            "shares have different thresholds".to_string(),
+                        ))
+                    }
+                    _ => threshold = Some(k),
+                }
+                parsed.push(share);
+            }
+            // threshold is always set: there's at least one share
+            Sharks(threshold.unwrap_or_default())
+                .recover(&parsed)
+                .map_err(|e| HsmError::RecoverFail(e.to_string()))
+        }
+    }
+}
+
+/// Check that secret sharing works on this build / platform: a random
+/// secret is split into `count` shares by each backend, the secret must be
+/// recovered from every combination of `threshold` shares, in every
+/// encoding, and it must not be recovered from any combination of fewer
+/// shares. This doesn't use the YubiHSM.
+pub fn selftest(threshold: u8, count: u8) -> Result<()> {
+    let secret: [u8; 32] = rand::random();
+    let fail = |msg: String| HsmError::SelfTestFail(msg);
+
+    for backend in [ShareBackend::RustySecrets, ShareBackend::Sharks] {
+        let shares = backend.split(threshold, count, &secret)?;
+        if shares.len() != count as usize {
+            return Err(fail(format!(
+                "{}: got {} shares",
+                backend,
+                shares.len()
+            ))
+            .into());
+        }
+
+        for encoding in [ShareEncoding::Plain, ShareEncoding::Base32] {
+            for share in &shares {
+                if decode(&encoding.encode(share)?)? != *share {
+                    return Err(fail(format!(
+                        "{}: {} encoding round trip",
+                        backend, encoding
+                    ))
+                    .into());
+                }
+            }
+        }
+
+        for subset in combinations(count as usize, threshold as usize) {
+            let subset: Vec<String> =
+                subset.iter().map(|i| shares[*i].clone()).collect();
+            if recover(subset)? != secret {
+                return Err(fail(format!(
+                    "{}: recovered the wrong secret",
+                    backend
+                ))
+                .into());
+            }
+        }
+
+        for subset in combinations(count as usize, threshold as usize - 1) {
+            let subset: Vec<String> =
+                subset.iter().map(|i| shares[*i].clone()).collect();
+            if let Ok(recovered) = recover(subset) {
+                if recovered == secret {
+                    return Err(fail(format!(
+                        "{}: recovered the secret w/ fewer than {} shares",
+                        backend, threshold
+                    ))
+                    .into());
+                }
+            }
+        }
+        debug!("{}: selftest passed", backend);
+    }
+
+    Ok(())
+}
+
+// all combinations of `k` of the indices `0..n` in lexicographic order
+pub(crate) fn combinations(n: usize, k: usize) -> Vec<Vec<usize>> {
+    if k == 0 {
+        return vec![Vec::new()];
+    }
+    if k > n {
+        return Vec::new();
+    }
+
+    let mut result = Vec::new();
+    for first in 0..=n - k {
+        for rest in combinations(n - first - 1, k - 1) {
+            let mut combination = vec![first];
+            combination.extend(rest.iter().map(|i| i + first + 1));
+            result.push(combination);
+        }
+    }
+    result
+}
+
+// parse a share produced by the sharks backend into its threshold and share
+fn parse_sharks(share: &str) -> Result<(u8, Share), HsmError> {
+    let bad = || HsmError::RecoverFail(format!("malformed share: {}", share));
+    let parts: Vec<&str> = share.trim().split('-').collect();
+    if parts.len() != 4 {
+        return Err(bad());
+    }
+    let threshold = parts[1].parse().map_err(|_| bad())?;
+    let bytes = hex::decode(parts[3]).map_err(|_| bad())?;
+    let share = Share::try_from(bytes.as_slice()).map_err(|_| bad())?;
+    if parts[2].parse() != Ok(share.x.0) {
+        return Err(bad());
+    }
+
+    Ok((threshold, share))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = &[0x42; 32];
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        for backend in [ShareBackend::RustySecrets, ShareBackend::Sharks] {
+            let shares = backend.split(3, 5, SECRET)?;
+            assert_eq!(shares.len(), 5);
+            for (i, share) in shares.iter().enumerate() {
+                assert_eq!(ShareBackend::detect(share), backend);
+                assert_eq!(index(share), Some(i as u8 + 1));
+            }
+
+            assert_eq!(recover(shares[2..].to_vec())?, SECRET);
+            assert!(recover(shares[3..].to_vec()).is_err());
+        }
+        Ok(())
+    }
+
+    // shares of the secret `0..32` produced by each backend, these must be
+    // recoverable by every future build so that an upgrade can't silently
+    // change the share format
+    const VECTORS: [[&str; 5]; 2] = [
+        [
+            "3-1-/s9+W6p6zUhwWhI7Dft/qS3Y1Di4LCyyxBbd4bvx/lA",
+            "3-2-e+AJp/LiOVcVsZT0deUHASjF+neaHV1OFeLoKaCVgdU",
+            "3-3-hS51/1yd8hht4ozEdBN2pxUMPFw2JGfrye0v0wd5YZo",
+            "3-4-pEP/BhOykrihHKmuD6SXcnXVJhMe8HydmEF6NOI5KNs",
+            "3-5-Wo2DXr3NWffZT7GeDlLm1Egc4DiyyUY4RE69zkXVyJQ",
+        ],
+        [
+            "sharks1-3-1-0169d80fa8d566220b1ab0cc00b0b0d47b72a2819d89e29316b9012fb48b680926",
+            "sharks1-3-2-02f94f6f75e84c3f10cb751c247697d8a6a9f524be487d398d678737a38c693d66",
+            "sharks1-3-3-03909662de392f1b1cd9ccda2fca2a02d2cb46b730d58abc8cc69f020c1b1c2a5f",
+            "sharks1-3-4-04433e191b9091ad1528bd00b184f75e5996824ade5d93a86466ba41bd97b76cc1",
+            "sharks1-3-5-052ae714b041f289193a04c6ba384a842df431d950c0642d65c7a2741200c27bf8",
+        ],
+    ];
+
+    #[test]
+    fn test_digest() -> Result<()> {
+        let shares = ShareBackend::RustySecrets.split(3, 5, SECRET)?;
+        let digest = digest(&shares[0]);
+        assert_eq!(digest.len(), 2 * DIGEST_LEN);
+        assert_eq!(super::digest(&format!(" {}\n", shares[0])), digest);
+        assert_ne!(super::digest(&shares[1]), digest);
+
+        // the digest is the same for a share in any encoding once decoded
+        let base32 = ShareEncoding::Base32.encode(&shares[0])?;
+        assert_eq!(super::digest(&decode(&base32)?), digest);
+
+        assert!(digest_matches("ab12cd34", "AB12-CD34"));
+        assert!(digest_matches("ab12cd34", " ab12 cd34 "));
+        assert!(!digest_matches("ab12cd34", "ab12cd35"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_vectors() -> Result<()> {
+        let secret: Vec<u8> = (0..32).collect();
+        for shares in VECTORS {
+            for subset in combinations(5, 3) {
+                let subset: Vec<String> =
+                    subset.iter().map(|i| shares[*i].to_string()).collect();
+                assert_eq!(recover(subset)?, secret);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_base32() -> Result<()> {
+        for backend in [ShareBackend::RustySecrets, ShareBackend::Sharks] {
+            let shares = backend.split(3, 5, SECRET)?;
+            let encoded = shares
+                .iter()
+                .map(|share| ShareEncoding::Base32.encode(share))
+                .collect::<Result<Vec<String>, HsmError>>()?;
+
+            for (share, encoded) in shares.iter().zip(&encoded) {
+                assert_eq!(
+                    ShareEncoding::detect(encoded),
+                    ShareEncoding::Base32
+                );
+                assert_eq!(&decode(encoded)?, share);
+                // transcription is case insensitive and tolerates O for 0
+                let sloppy = encoded.to_lowercase().replace('0', "o");
+                assert_eq!(&decode(&sloppy)?, share);
+            }
+
+            // a miscopied character is caught by the check symbol
+            let mut typo = encoded[0].clone().into_bytes();
+            let i = BASE32_PREFIX.len() + 2;
+            typo[i] = if typo[i] == b'A' { b'B' } else { b'A' };
+            assert!(decode(&String::from_utf8(typo)?).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrap_share() -> Result<()> {
+        let share = ShareBackend::Sharks.split(3, 5, SECRET)?.remove(0);
+        let wrapped = wrap_share(&share, "correct horse")?;
+        assert!(is_wrapped(&wrapped));
+        assert!(!is_wrapped(&share));
+        assert_ne!(wrapped, wrap_share(&share, "correct horse")?);
+
+        // transcription is case insensitive
+        assert_eq!(
+            unwrap_share(&wrapped.to_lowercase(), "correct horse")?,
+            share
+        );
+        assert!(unwrap_share(&wrapped, "battery staple").is_err());
+        assert!(unwrap_share(&share, "correct horse").is_err());
+
+        // a miscopied character fails authentication
+        let mut typo = wrapped.clone().into_bytes();
+        let i = WRAPPED_PREFIX.len() + 2;
+        typo[i] = if typo[i] == b'A' { b'B' } else { b'A' };
+        assert!(
+            unwrap_share(&String::from_utf8(typo)?, "correct horse").is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_combinations() {
+        assert_eq!(combinations(5, 3).len(), 10);
+        assert_eq!(
+            combinations(3, 2),
+            vec![vec![0, 1], vec![0, 2], vec![1, 2]]
+        );
+        assert_eq!(combinations(2, 0), vec![Vec::<usize>::new()]);
+        assert!(combinations(2, 3).is_empty());
+    }
+
+    #[test]
+    fn test_selftest() -> Result<()> {
+        selftest(3, 5)
+    }
+
+    #[test]
+    fn test_mixed_backends() -> Result<()> {
+        let mut shares = ShareBackend::RustySecrets.split(2, 3, SECRET)?;
+        shares.truncate(1);
+        shares.push(ShareBackend::Sharks.split(2, 3, SECRET)?.remove(1));
+        assert!(recover(shares).is_err());
+        Ok(())
+    }
+}
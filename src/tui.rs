@@ -0,0 +1,284 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A full screen terminal UI for displaying the key shares to the key
+//! custodians. This is an alternative to the plain text prompts that shows
+//! the progress of the ceremony: which custodian is up and which shares
+//! have been recorded. The UI is drawn on the alternate screen and a share
+//! is only ever drawn while its custodian is recording it, so no share is
+//! left on the screen or in the scrollback.
+
+use anyhow::Result;
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        cursor,
+        event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::{Line, Text},
+    widgets::{Block, Gauge, List, ListItem, Paragraph, Wrap},
+    Frame, Terminal,
+};
+use std::{
+    io::{self, Stdout},
+    time::{Duration, Instant},
+};
+
+use crate::HsmError;
+
+/// The state of the ceremony drawn by `render`.
+struct State<'a> {
+    /// Number of shares being displayed.
+    count: usize,
+    /// Index of the custodian that's up, shares before it are recorded.
+    current: usize,
+    /// The title and text of the share being recorded, if it's displayed.
+    share: Option<(&'a str, &'a str)>,
+}
+
+// draw the ceremony progress and, if one is displayed, the share
+fn render(frame: &mut Frame, state: &State) {
+    let [progress, custodians, body, help] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(state.count as u16 + 2),
+        Constraint::Min(5),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(" Key share ceremony "))
+            .ratio(state.current as f64 / state.count as f64)
+            .label(format!("{} of {} recorded", state.current, state.count)),
+        progress,
+    );
+
+    let items: Vec<ListItem> = (0..state.count)
+        .map(|i| {
+            let custodian = format!("Key custodian {}", i + 1);
+            if i < state.current {
+                ListItem::new(format!("{}: recorded", custodian)).green()
+            } else if i == state.current {
+                ListItem::new(format!("{}: up next", custodian)).bold()
+            } else {
+                ListItem::new(custodian).dim()
+            }
+        })
+        .collect();
+    frame.render_widget(
+        List::new(items).block(Block::bordered().title(" Custodians ")),
+        custodians,
+    );
+
+    let (title, text, hint) = match state.share {
+        Some((title, share)) => (
+            format!(" {} ", title),
+            Text::from(share),
+            "Press Enter when you are done recording this key share",
+        ),
+        None => {
+            let custodian = state.current + 1;
+            let mut text = Text::default();
+            if state.current > 0 {
+                text.push_line(
+                    Line::from(format!("Share {} recorded.", state.current))
+                        .green(),
+                );
+            }
+            text.push_line(format!(
+                "When key custodian {custodian} is seated, press Enter to \
+                display share {custodian}."
+            ));
+            (String::new(), text, "Press Enter to display the next share")
+        }
+    };
+    frame.render_widget(
+        Paragraph::new(text)
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered().title(title)),
+        body,
+    );
+    frame.render_widget(
+        Paragraph::new(hint).style(Style::default().reversed()),
+        help,
+    );
+}
+
+/// The terminal while the UI is up. The screen is cleared and restored
+/// when this is dropped, whether the ceremony completed or not.
+struct Screen {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl Screen {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, cursor::Hide)?;
+        Ok(Screen {
+            terminal: Terminal::new(CrosstermBackend::new(io::stdout()))?,
+        })
+    }
+
+    fn leave(&mut self) -> Result<()> {
+        self.terminal.clear()?;
+        execute!(io::stdout(), LeaveAlternateScreen, cursor::Show)?;
+        terminal::disable_raw_mode()?;
+        Ok(())
+    }
+
+    // hand the terminal back for a prompt, e.g. for a passphrase
+    fn suspend<T>(&mut self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.leave()?;
+        let result = f();
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, cursor::Hide)?;
+        self.terminal.clear()?;
+        result
+    }
+
+    /// Draw the state until the operator presses enter. The screen is
+    /// redrawn when the terminal is resized. Ctrl-C or no response within
+    /// the timeout is an error.
+    fn wait_for_enter(
+        &mut self,
+        state: &State,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            self.terminal.draw(|frame| render(frame, state))?;
+
+            let ready = match deadline {
+                Some(deadline) => {
+                    let remaining =
+                        deadline.saturating_duration_since(Instant::now());
+                    event::poll(remaining)?
+                }
+                None => true,
+            };
+            if !ready {
+                return Err(HsmError::PromptTimeout(timeout.unwrap()).into());
+            }
+
+            // raw mode turns Ctrl-C into a key press instead of a SIGINT
+            match event::read()? {
+                Event::Key(key) if key.kind != KeyEventKind::Press => (),
+                Event::Key(key) if key.code == KeyCode::Enter => return Ok(()),
+                Event::Key(key)
+                    if key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL) =>
+                {
+                    return Err(HsmError::Interrupted.into())
+                }
+                // the next draw lays out the UI for the new size
+                Event::Resize(..) => self.terminal.autoresize()?,
+                _ => (),
+            }
+        }
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = self.leave();
+    }
+}
+
+/// Display `count` key shares one at a time. `share` gets the title and
+/// text of the share at the provided index, it's called once the custodian
+/// is seated. If `prompts` is set `share` prompts the custodian (e.g. for a
+/// passphrase) and is called w/ the UI suspended. Once the custodian is
+/// done recording the share `check` is called w/ the UI suspended to read
+/// back its check digest, the share is displayed again until it's `true`.
+pub(crate) fn display_shares(
+    count: usize,
+    mut share: impl FnMut(usize) -> Result<(String, String)>,
+    prompts: bool,
+    mut check: impl FnMut(usize) -> Result<bool>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let mut screen = Screen::enter()?;
+    for current in 0..count {
+        let state = State {
+            count,
+            current,
+            share: None,
+        };
+        screen.wait_for_enter(&state, timeout)?;
+
+        loop {
+            let (title, text) = if prompts {
+                screen.suspend(|| share(current))?
+            } else {
+                share(current)?
+            };
+            let state = State {
+                share: Some((&title, &text)),
+                ..state
+            };
+            screen.wait_for_enter(&state, timeout)?;
+            // suspending the UI clears the share from the screen before its
+            // digest is read back
+            if screen.suspend(|| check(current))? {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+
+    // the text drawn on the test terminal
+    fn screen(terminal: &Terminal<TestBackend>) -> String {
+        let buffer = terminal.backend().buffer();
+        buffer
+            .content()
+            .chunks(buffer.area.width as usize)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_render() -> Result<()> {
+        let mut terminal = Terminal::new(TestBackend::new(60, 20))?;
+
+        let state = State {
+            count: 3,
+            current: 1,
+            share: Some(("Share 2 of 3", "secret-share")),
+        };
+        terminal.draw(|frame| render(frame, &state))?;
+        let drawn = screen(&terminal);
+        assert!(drawn.contains("Share 2 of 3"));
+        assert!(drawn.contains("secret-share"));
+        assert!(drawn.contains("Key custodian 1: recorded"));
+        assert!(drawn.contains("1 of 3 recorded"));
+
+        // once recorded the share is gone, also after a resize
+        let state = State {
+            count: 3,
+            current: 2,
+            share: None,
+        };
+        terminal.backend_mut().resize(40, 24);
+        terminal.autoresize()?;
+        terminal.draw(|frame| render(frame, &state))?;
+        let drawn = screen(&terminal);
+        assert!(!drawn.contains("secret-share"));
+        assert!(drawn.contains("Share 2 recorded."));
+        assert!(drawn.contains("Key custodian 3: up next"));
+        Ok(())
+    }
+}